@@ -0,0 +1,261 @@
+//! The W3C Web Annotation wire format
+//!
+//! One serde type per object of <https://www.w3.org/TR/annotation-model/> that
+//! Orca has a use for, and the collections and pages
+//! <https://www.w3.org/TR/annotation-protocol/> serves them in.
+//! Everything in here is agnostic of Calibre
+//! optional keys are left out rather than serialised as `null` or `[]`
+
+use serde_derive::Serialize;
+
+/// The JSON-LD context of every document served
+pub const CONTEXT: &str = "http://www.w3.org/ns/anno.jsonld";
+
+/// The media type of an annotation, a collection or a page of them
+pub const ANNOTATIONS: &str = r#"application/ld+json; profile="http://www.w3.org/ns/anno.jsonld""#;
+
+/// The rel of a link that leads to the annotations on a resource.
+pub const ANNOTATION_SERVICE: &str = "http://www.w3.org/ns/oa#annotationService";
+
+/// What the value of a `FragmentSelector` is written in: an EPUB CFI.
+pub const EPUB_CFI: &str = "http://www.idpf.org/epub/linking/cfi/epub-cfi.html";
+
+/// Why an annotation was made, and what its body is for.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Motivation {
+    Bookmarking,
+    Commenting,
+    Describing,
+    Highlighting,
+}
+
+/// Every annotation on a resource, or every one a search found. It comes with
+/// its first page; the others are fetched on their own.
+#[derive(Debug, Serialize)]
+pub struct Collection {
+    #[serde(rename = "@context")]
+    pub context: &'static str,
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub label: String,
+    pub total: usize,
+    pub first: Page,
+    pub last: String,
+}
+
+impl Collection {
+    pub fn new(id: String, label: impl Into<String>, total: usize, first: Page, last: String) -> Self {
+        Self {
+            context: CONTEXT,
+            id,
+            kind: "AnnotationCollection",
+            label: label.into(),
+            total,
+            first,
+            last,
+        }
+    }
+}
+
+/// One page of a collection. Served on its own, rather than as the first page
+/// of its collection, it says which collection it is a part of.
+#[derive(Debug, Serialize)]
+pub struct Page {
+    #[serde(rename = "@context", skip_serializing_if = "Option::is_none")]
+    pub context: Option<&'static str>,
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    #[serde(rename = "partOf", skip_serializing_if = "Option::is_none")]
+    pub part_of: Option<PartOf>,
+    /// The position of the first item on this page within the collection, from 0.
+    #[serde(rename = "startIndex")]
+    pub start_index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prev: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
+    pub items: Vec<Annotation>,
+}
+
+impl Page {
+    pub fn new(id: String, start_index: usize, items: Vec<Annotation>) -> Self {
+        Self {
+            context: None,
+            id,
+            kind: "AnnotationPage",
+            part_of: None,
+            start_index,
+            prev: None,
+            next: None,
+            items,
+        }
+    }
+
+    /// The page as a document of its own, a part of `collection`.
+    pub fn part_of(mut self, collection: String, label: impl Into<String>, total: usize) -> Self {
+        self.context = Some(CONTEXT);
+        self.part_of = Some(PartOf {
+            id: collection,
+            label: label.into(),
+            total,
+        });
+        self
+    }
+
+    pub fn prev(mut self, prev: String) -> Self {
+        self.prev = Some(prev);
+        self
+    }
+
+    pub fn next(mut self, next: String) -> Self {
+        self.next = Some(next);
+        self
+    }
+}
+
+/// The collection a page belongs to, as much of it as a page repeats.
+#[derive(Debug, Serialize)]
+pub struct PartOf {
+    pub id: String,
+    pub label: String,
+    pub total: usize,
+}
+
+/// What somebody said about, or marked in, a resource.
+#[derive(Debug, Serialize)]
+pub struct Annotation {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub motivation: Motivation,
+    pub modified: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub body: Vec<TextualBody>,
+    pub target: Target,
+}
+
+impl Annotation {
+    pub fn new(id: String, motivation: Motivation, modified: String, target: Target) -> Self {
+        Self {
+            id,
+            kind: "Annotation",
+            motivation,
+            modified,
+            body: Vec::new(),
+            target,
+        }
+    }
+
+    /// What the annotation says, unless that is nothing at all.
+    pub fn body(mut self, value: impl Into<String>, purpose: Motivation) -> Self {
+        let value = value.into();
+        if !value.trim().is_empty() {
+            self.body.push(TextualBody {
+                kind: "TextualBody",
+                value,
+                format: "text/plain",
+                purpose,
+            });
+        }
+        self
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TextualBody {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub value: String,
+    pub format: &'static str,
+    pub purpose: Motivation,
+}
+
+/// The resource an annotation is about, and where in it.
+#[derive(Debug, Serialize)]
+pub struct Target {
+    pub source: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub selector: Vec<Selector>,
+}
+
+impl Target {
+    pub fn new(source: String) -> Self {
+        Self {
+            source,
+            selector: Vec::new(),
+        }
+    }
+
+    /// Another way to find the same spot; a client uses whichever it understands.
+    pub fn selector(mut self, selector: Selector) -> Self {
+        self.selector.push(selector);
+        self
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type")]
+pub enum Selector {
+    /// The text itself
+    TextQuoteSelector { exact: String },
+    /// A fragment identifier of the syntax `conformsTo` names
+    FragmentSelector {
+        #[serde(rename = "conformsTo")]
+        conforms_to: &'static str,
+        value: String,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, to_value};
+
+    #[test]
+    fn a_highlight_with_a_comment_serialises_as_the_model_has_it() {
+        let target = Target::new("http://localhost/library/file/5/epub".to_string())
+            .selector(Selector::TextQuoteSelector { exact: "Gedanken ohne Inhalt".to_string() })
+            .selector(Selector::FragmentSelector {
+                conforms_to: EPUB_CFI,
+                value: "epubcfi(/6/8!/4/2,/1:0,/1:20)".to_string(),
+            });
+        let highlight = Annotation::new("urn:x".to_string(), Motivation::Highlighting, "2024-12-01T10:00:00Z".to_string(), target)
+            .body("Kant at his best", Motivation::Commenting)
+            .body(" ", Motivation::Describing);
+
+        assert_eq!(
+            to_value(&highlight).unwrap(),
+            json!({
+                "id": "urn:x",
+                "type": "Annotation",
+                "motivation": "highlighting",
+                "modified": "2024-12-01T10:00:00Z",
+                "body": [{"type": "TextualBody", "value": "Kant at his best", "format": "text/plain", "purpose": "commenting"}],
+                "target": {
+                    "source": "http://localhost/library/file/5/epub",
+                    "selector": [
+                        {"type": "TextQuoteSelector", "exact": "Gedanken ohne Inhalt"},
+                        {"type": "FragmentSelector", "conformsTo": EPUB_CFI, "value": "epubcfi(/6/8!/4/2,/1:0,/1:20)"},
+                    ],
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn a_page_on_its_own_says_what_it_is_part_of() {
+        let first = to_value(Page::new("c?page=1".to_string(), 0, Vec::new())).unwrap();
+        assert_eq!(first.get("@context"), None);
+        assert_eq!(first.get("partOf"), None);
+
+        let second = to_value(Page::new("c?page=2".to_string(), 50, Vec::new()).part_of("c".to_string(), "All", 60).prev("c?page=1".to_string()))
+            .unwrap();
+        assert_eq!(second["@context"], CONTEXT);
+        assert_eq!(second["partOf"], json!({"id": "c", "label": "All", "total": 60}));
+        assert_eq!(second["prev"], "c?page=1");
+        assert_eq!(second["startIndex"], 50);
+    }
+}
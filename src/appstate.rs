@@ -1,11 +1,61 @@
+use crate::calibre::{self, CustomColumn};
 use crate::config::Config;
+use crate::reading::Digests;
+use crate::{fulltext, restriction};
 use rusqlite::Connection;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 
 #[derive(Clone)]
 pub struct AppState {
     pub templates: tera::Tera,
     pub config: &'static Config,
     pub db: HashMap<String, Arc<Mutex<Connection>>>,
+    /// The custom columns each library exposes, read once at startup.
+    pub columns: HashMap<String, Vec<CustomColumn>>,
+    /// Where each reader is in each book: Orca's own, never a library's.
+    pub progress: Arc<Mutex<Connection>>,
+    /// What each library was last brought up to date with.
+    pub refreshed: Arc<Mutex<HashMap<String, calibre::Change>>>,
+    /// What KOReader's digests of the books' files were last taken at.
+    pub digests: Arc<Mutex<Digests>>,
+}
+
+impl AppState {
+    /// The connection of one library, locked: none for a library Orca does not serve.
+    /// Once Calibre changed a book, the library's search index first catches up
+    /// with it; its virtual library checks on every call, as Calibre can
+    /// redefine one without changing a book.
+    pub fn library(&self, lib: &str) -> Option<MutexGuard<'_, Connection>> {
+        let db = calibre::lock(self.db.get(lib)?);
+        if let Err(e) = self.refresh(lib, &db) {
+            eprintln!("Could not bring library '{}' up to date: {}", lib, e);
+        }
+        Some(db)
+    }
+
+    fn refresh(&self, lib: &str, db: &Connection) -> rusqlite::Result<()> {
+        if self.config.calibre.libraries.get(lib).is_some_and(|library| library.virtual_library.is_some()) {
+            restriction::refresh(db)?;
+        }
+        let current = calibre::last_change(db)?;
+        if self.refreshed.lock().unwrap_or_else(PoisonError::into_inner).get(lib) == Some(&current) {
+            return Ok(());
+        }
+        fulltext::refresh(db)?;
+        self.refreshed.lock().unwrap_or_else(PoisonError::into_inner).insert(lib.to_string(), current);
+        Ok(())
+    }
+
+    /// The custom columns one library exposes: none for a library Orca does not serve.
+    pub fn columns(&self, lib: &str) -> &[CustomColumn] {
+        self.columns.get(lib).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// The exposed column behind `label`, if its values are shelves of their own.
+    pub fn category_column(&self, lib: &str, label: &str) -> Option<&CustomColumn> {
+        self.columns(lib)
+            .iter()
+            .find(|column| column.label == label && column.is_category())
+    }
 }
@@ -2,7 +2,7 @@ use actix_web::{
     dev::Payload,
     error::ResponseError,
     http::{header, header::HeaderValue, StatusCode},
-    web, Error, FromRequest, HttpRequest, HttpResponse,
+    web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse,
 };
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 
@@ -36,11 +36,19 @@ impl ResponseError for UnauthorizedError {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Authorized {
     pub login: String,
 }
 
+impl Authorized {
+    /// Who logged in to make `req`, once the extractor let them in: nobody
+    /// for a guest on a public path.
+    pub fn of(req: &HttpRequest) -> Option<Authorized> {
+        req.extensions().get::<Authorized>().cloned()
+    }
+}
+
 fn verify_credentials(header: &HeaderValue, config: &Config) -> Option<Authorized> {
     let credentials = header.to_str().ok()?;
     credentials.strip_prefix("Basic ")
@@ -58,6 +66,24 @@ fn verify_credentials(header: &HeaderValue, config: &Config) -> Option<Authorize
                })
 }
 
+/// KOReader's progress sync does not do basic authentication: it sends the
+/// login and the MD5 of the password in headers of its own. They are checked
+/// against `[authentication.kosync]`, and only open the sync.
+fn verify_sync_key(req: &HttpRequest, config: &Config) -> Option<Authorized> {
+    if !req.path().starts_with("/kosync/") {
+        return None;
+    }
+    let login = req.headers().get("x-auth-user")?.to_str().ok()?;
+    let key = req.headers().get("x-auth-key")?.to_str().ok()?;
+    let hash = config.authentication.kosync.get(login)?;
+    match hash::verify_password(key, hash).ok()? {
+        true => Some(Authorized {
+            login: login.to_string(),
+        }),
+        false => None,
+    }
+}
+
 impl FromRequest for Authorized {
     type Error = Error;
     type Future = Ready<Result<Self, Self::Error>>;
@@ -67,8 +93,17 @@ impl FromRequest for Authorized {
         let config = &data.config;
         let path = req.uri().path();
 
+        // Checked once per request: the password hash is slow on purpose, and
+        // the cache validators look before the handler does.
+        if let Some(auth) = req.extensions().get::<Authorized>() {
+            return ready(Ok(auth.clone()));
+        }
         let result = req.headers().get(header::AUTHORIZATION)
-                                  .and_then(|header| verify_credentials(header, config));
+                                  .and_then(|header| verify_credentials(header, config))
+                                  .or_else(|| verify_sync_key(req, config));
+        if let Some(auth) = &result {
+            req.extensions_mut().insert(auth.clone());
+        }
 
         match result {
             Some(auth) => ready(Ok(auth)),
@@ -4,14 +4,27 @@
 
 use html2text::from_read;
 use isolang::Language;
-use rusqlite::{params, params_from_iter, Connection};
-use serde_derive::Serialize;
-use std::collections::HashMap;
+use once_cell::sync::Lazy;
+use once_cell::unsync::OnceCell;
+use regex::Regex;
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OpenFlags, OptionalExtension};
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::{Mutex, MutexGuard};
 
+use crate::fulltext;
+use crate::query;
+
 /// How many books are listed in the "Recently Added" category.
 const RECENTLY_ADDED: usize = 50;
 
+/// How many books by the same authors, and how many on the same subjects,
+/// a book leads on to.
+const RELATED: usize = 5;
+
 pub const SYNOPSIS_WIDTH: usize = 100;
 
 /// no blurb will wrap at this width
@@ -38,6 +51,27 @@ pub struct Book {
     pub tags: Vec<Tag>,
     pub publisher: Option<String>,
     pub series: Option<Series>,
+    /// ISBN, DOI, Goodreads ... by scheme
+    pub identifiers: Vec<Identifier>,
+    /// None for a book nobody rated
+    pub rating: Option<Rating>,
+    /// Only the custom columns the config exposes, and only those with a value
+    /// for this book. Filled in by `with_custom`.
+    pub custom: Vec<CustomField>,
+    pub related: Related,
+    /// How much of it the reader asking has read, from 0 to 1. None for a
+    /// book they never opened. Filled in by `reading::with_progress`.
+    pub progress: Option<f64>,
+}
+
+impl Book {
+    /// The ISBN as a URN, if Calibre has one for this book.
+    pub fn isbn(&self) -> Option<&str> {
+        self.identifiers
+            .iter()
+            .find(|identifier| identifier.scheme == "isbn")
+            .and_then(|identifier| identifier.uri.as_deref())
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -52,23 +86,186 @@ pub struct Tag {
     pub name: String,
 }
 
-/// One of the categories Calibre files a library under -- an author or a tag
+/// One of the categories Calibre files a library under -- an author, a tag, a
+/// series, a publisher or a language
 /// This is the signpost / link. `Author` and `Tag` belong to a book, count lives here
 #[derive(Debug, Serialize)]
 pub struct Category {
     pub id: i32,
     pub name: String,
     pub books: usize,
+    /// What Calibre files it under, where that is not its name: `Lovecraft, H. P.`
+    #[serde(skip)]
+    pub sort: Option<String>,
+}
+
+impl Category {
+    /// What an index files this under: the sort, or else the name.
+    pub fn filed_as(&self) -> &str {
+        self.sort.as_deref().unwrap_or(&self.name)
+    }
 }
 
 /// The series a book is part of. Calibre lets a book belong to one at most.
 #[derive(Debug, Serialize)]
 pub struct Series {
+    pub id: i32,
     pub name: String,
     /// Calibre's `series_index`: volume in series (could also be 1.5)
     pub index: f64,
 }
 
+/// Where a reader can go from a book: the volumes either side of it in its
+/// series, more by the same authors and the books sharing the most tags with it.
+/// No book is listed twice, the series comes first.
+#[derive(Debug, Default, Serialize)]
+pub struct Related {
+    pub previous: Option<Neighbour>,
+    pub next: Option<Neighbour>,
+    pub by_authors: Vec<Neighbour>,
+    pub sharing_tags: Vec<Neighbour>,
+}
+
+/// A book one step away from another, by no more than its title.
+#[derive(Debug, Clone, Serialize)]
+pub struct Neighbour {
+    pub id: i32,
+    pub title: String,
+}
+
+/// How a book was rated in Calibre: out of five stars, halves allowed.
+#[derive(Debug, Serialize)]
+pub struct Rating {
+    pub stars: f64,
+    /// "★★★½"
+    pub text: String,
+}
+
+impl Rating {
+    /// Calibre rates out of ten; no rating and a rating of zero are the same to it.
+    fn of(rating: i64) -> Option<Rating> {
+        match rating {
+            0 => None,
+            rating => Some(Rating {
+                stars: rating as f64 / 2.0,
+                text: stars(rating),
+            }),
+        }
+    }
+}
+
+/// One of the ways the rest of the world knows a book by.
+#[derive(Debug, Serialize)]
+pub struct Identifier {
+    /// Calibre's name for the scheme, in lower case: `isbn`, `doi`, `goodreads` ...
+    pub scheme: String,
+    /// As it was typed into Calibre
+    pub value: String,
+    /// The identifier as a URI, for the schemes that have one: `urn:isbn:9780141439761`
+    pub uri: Option<String>,
+}
+
+/// A column a Calibre user added to their library: "Translator", "Read status" ...
+#[derive(Debug, Clone, Serialize)]
+pub struct CustomColumn {
+    pub id: i32,
+    /// Calibre's lookup name, without the `#`
+    pub label: String,
+    /// The heading Calibre shows above the column
+    pub name: String,
+    pub kind: ColumnKind,
+    /// A text column that takes several values, like tags do
+    pub multiple: bool,
+}
+
+/// The kinds of custom column Orca can read. Calibre computes `composite`
+/// columns on the fly and never stores them, so there is nothing to read.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColumnKind {
+    Text,
+    Series,
+    Enumeration,
+    Rating,
+    Date,
+    Bool,
+    Comments,
+    Int,
+    Float,
+}
+
+impl ColumnKind {
+    /// Calibre's `datatype`
+    fn of(datatype: &str) -> Option<ColumnKind> {
+        match datatype {
+            "text" => Some(ColumnKind::Text),
+            "series" => Some(ColumnKind::Series),
+            "enumeration" => Some(ColumnKind::Enumeration),
+            "rating" => Some(ColumnKind::Rating),
+            "datetime" => Some(ColumnKind::Date),
+            "bool" => Some(ColumnKind::Bool),
+            "comments" => Some(ColumnKind::Comments),
+            "int" => Some(ColumnKind::Int),
+            "float" => Some(ColumnKind::Float),
+            _ => None,
+        }
+    }
+}
+
+impl CustomColumn {
+    /// Calibre's `is_category`: the values live in a table of their own and are
+    /// shared between books, so each of them is a shelf, the same as a tag.
+    pub fn is_category(&self) -> bool {
+        matches!(
+            self.kind,
+            ColumnKind::Text | ColumnKind::Series | ColumnKind::Enumeration | ColumnKind::Rating
+        )
+    }
+}
+
+/// What one custom column says about one book.
+#[derive(Debug, Serialize)]
+pub struct CustomField {
+    pub label: String,
+    pub name: String,
+    pub multiple: bool,
+    pub values: Vec<CustomValue>,
+    /// The values on one line, the way Calibre's book details show them
+    pub text: String,
+}
+
+/// One value of a custom column, typed the way Calibre stores it.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum CustomValue {
+    /// A value of a category column, which is a shelf of its own
+    Category {
+        id: i32,
+        name: String,
+        /// Where the book sits in a series column (Calibre's `extra`)
+        index: Option<f64>,
+    },
+    /// A comment as plain text, or a date
+    Text(String),
+    Bool(bool),
+    Integer(i64),
+    Number(f64),
+}
+
+impl CustomValue {
+    fn text(&self) -> String {
+        match self {
+            CustomValue::Category { name, index: Some(index), .. } => format!("{} [{}]", name, index),
+            CustomValue::Category { name, .. } => name.clone(),
+            CustomValue::Text(text) => text.clone(),
+            CustomValue::Bool(true) => "Yes".to_string(),
+            CustomValue::Bool(false) => "No".to_string(),
+            CustomValue::Integer(number) => number.to_string(),
+            CustomValue::Number(number) => number.to_string(),
+        }
+    }
+}
+
 /// Calibre keeps a blurb as HTML, which neither Atom's `<content type="text">`
 /// nor OPDS 2.0's `description` will take. Rendering is left to the caller.
 pub fn plain_text(html: &str, width: usize) -> String {
@@ -104,7 +301,7 @@ pub fn mime(format: &str) -> &'static str {
 }
 
 /// A library's connection, recovered from a panic in another handler: a poisoned
-/// mutex still guards a perfectly good read-only SQLite connection.
+/// mutex still guards a perfectly good SQLite connection.
 pub fn lock(db: &Mutex<Connection>) -> MutexGuard<'_, Connection> {
     db.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
 }
@@ -124,6 +321,49 @@ fn bcp47(calibre: &str) -> String {
         .to_string()
 }
 
+/// "deu" -> "Deutsch": a reader looking for books in a language knows it by its own name.
+/// English is the fallback, and a code isolang does not know stays a code.
+fn autonym(calibre: &str) -> String {
+    Language::from_639_3(calibre)
+        .map(|language| language.to_autonym().unwrap_or(language.to_name()))
+        .unwrap_or(calibre)
+        .to_string()
+}
+
+/// Calibre rates out of ten, and shows half of that as stars: 7 -> "★★★½"
+fn stars(rating: i64) -> String {
+    let half = match rating % 2 {
+        1 => "½",
+        _ => "",
+    };
+    format!("{}{}", "★".repeat((rating / 2).max(0) as usize), half)
+}
+
+/// "978-0-14-143976-1" -> "9780141439761": Calibre keeps an ISBN the way it was
+/// typed in, hyphens and all. The check digit of an ISBN-10 may be an X.
+fn isbn(typed: &str) -> String {
+    typed
+        .chars()
+        .filter(|c| c.is_ascii_digit() || *c == 'X' || *c == 'x')
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// The schemes with a URI form everyone agrees on. Any other -- `mobi-asin`, or
+/// whatever a metadata plugin made up -- stays a plain value.
+fn identifier_uri(scheme: &str, value: &str) -> Option<String> {
+    match scheme {
+        "isbn" => Some(format!("urn:isbn:{}", isbn(value))),
+        "issn" => Some(format!("urn:issn:{}", value)),
+        "doi" => Some(format!("https://doi.org/{}", value)),
+        "goodreads" => Some(format!("https://www.goodreads.com/book/show/{}", value)),
+        "amazon" => Some(format!("https://www.amazon.com/dp/{}", value)),
+        "google" => Some(format!("https://books.google.com/books?id={}", value)),
+        "uri" | "url" => Some(value.to_string()),
+        _ => None,
+    }
+}
+
 /// When the library as a whole last changed.
 pub fn updated(db: &Connection) -> String {
     let latest: Option<String> = db
@@ -134,6 +374,24 @@ pub fn updated(db: &Connection) -> String {
     to_rfc3339(&latest.unwrap_or_else(|| NEVER_MODIFIED.to_string()))
 }
 
+/// How many books the library holds. Removing a book changes no
+/// `last_modified`, so `updated` alone does not notice.
+pub fn book_count(db: &Connection) -> i64 {
+    db.query_row("SELECT COUNT(*) FROM books;", params![], |row| row.get(0))
+        .unwrap_or_default()
+}
+
+/// How many books a library holds, and when Calibre last changed one of them.
+pub type Change = (i64, Option<String>);
+
+/// The last change to the whole library, even with a virtual library in front
+/// of it: what Orca's index and a virtual library are kept up to date with.
+pub fn last_change(db: &Connection) -> rusqlite::Result<Change> {
+    db.query_row("SELECT COUNT(*), MAX(last_modified) FROM main.books;", params![], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    })
+}
+
 /// Collect the rows that could be read, logging the ones that could not.
 fn collect_rows<T>(rows: impl Iterator<Item = rusqlite::Result<T>>, what: &str) -> Vec<T> {
     rows.filter_map(|row| match row {
@@ -146,9 +404,10 @@ fn collect_rows<T>(rows: impl Iterator<Item = rusqlite::Result<T>>, what: &str)
     .collect()
 }
 
-pub fn authors(db: &Connection) -> rusqlite::Result<Vec<Author>> {
-    let mut stmt = db.prepare("SELECT id, name FROM authors;")?;
-    let rows = stmt.query_map(params![], |row| {
+/// One page of the authors, by the name Calibre sorts them under.
+pub fn authors_page(db: &Connection, limit: usize, offset: usize) -> rusqlite::Result<Vec<Author>> {
+    let mut stmt = db.prepare("SELECT id, name FROM authors ORDER BY sort, id LIMIT ?1 OFFSET ?2;")?;
+    let rows = stmt.query_map(params![limit as i64, offset as i64], |row| {
         Ok(Author {
             id: row.get(0)?,
             name: row.get(1)?,
@@ -157,9 +416,10 @@ pub fn authors(db: &Connection) -> rusqlite::Result<Vec<Author>> {
     Ok(collect_rows(rows, "author"))
 }
 
-pub fn tags(db: &Connection) -> rusqlite::Result<Vec<Tag>> {
-    let mut stmt = db.prepare("SELECT id, name FROM tags;")?;
-    let rows = stmt.query_map(params![], |row| {
+/// One page of the tags, alphabetically.
+pub fn tags_page(db: &Connection, limit: usize, offset: usize) -> rusqlite::Result<Vec<Tag>> {
+    let mut stmt = db.prepare("SELECT id, name FROM tags ORDER BY name, id LIMIT ?1 OFFSET ?2;")?;
+    let rows = stmt.query_map(params![limit as i64, offset as i64], |row| {
         Ok(Tag {
             id: row.get(0)?,
             name: row.get(1)?,
@@ -172,7 +432,7 @@ pub fn tags(db: &Connection) -> rusqlite::Result<Vec<Tag>> {
 pub fn authors_with_books(db: &Connection) -> rusqlite::Result<Vec<Category>> {
     categories(
         db,
-        "SELECT a.id, a.name, COUNT(ba.book) AS books
+        "SELECT a.id, a.name, COUNT(ba.book) AS books, a.sort
             FROM authors a
             JOIN books_authors_link ba ON a.id = ba.author
             GROUP BY a.id
@@ -192,6 +452,67 @@ pub fn tags_with_books(db: &Connection) -> rusqlite::Result<Vec<Category>> {
     )
 }
 
+/// Every series with a volume in the library, in Calibre's sort order for series.
+pub fn series_with_books(db: &Connection) -> rusqlite::Result<Vec<Category>> {
+    categories(
+        db,
+        "SELECT s.id, s.name, COUNT(bs.book) AS books, s.sort
+            FROM series s
+            JOIN books_series_link bs ON s.id = bs.series
+            GROUP BY s.id
+            ORDER BY s.sort;",
+    )
+}
+
+/// Every publisher with a book in the library, alphabetically.
+pub fn publishers_with_books(db: &Connection) -> rusqlite::Result<Vec<Category>> {
+    categories(
+        db,
+        "SELECT p.id, p.name, COUNT(bp.book) AS books
+            FROM publishers p
+            JOIN books_publishers_link bp ON p.id = bp.publisher
+            GROUP BY p.id
+            ORDER BY p.name COLLATE NOCASE;",
+    )
+}
+
+/// Every language a book in the library is written in, by the name the language
+/// has for itself. Calibre only keeps the code, so the sorting happens here.
+pub fn languages_with_books(db: &Connection) -> rusqlite::Result<Vec<Category>> {
+    let mut languages = categories(
+        db,
+        "SELECT l.id, l.lang_code, COUNT(bl.book) AS books
+            FROM languages l
+            JOIN books_languages_link bl ON l.id = bl.lang_code
+            GROUP BY l.id;",
+    )?;
+    for language in &mut languages {
+        language.name = autonym(&language.name);
+    }
+    languages.sort_by_key(|language| language.name.to_lowercase());
+    Ok(languages)
+}
+
+/// One shelf per star level that a book was rated at, the best first.
+/// Unlike every other category, a rating shelf goes by its value (out of ten) rather than by a row id
+pub fn ratings_with_books(db: &Connection) -> rusqlite::Result<Vec<Category>> {
+    let mut ratings = categories(
+        db,
+        "SELECT r.rating, CAST(r.rating AS TEXT), COUNT(br.book) AS books
+            FROM ratings r
+            JOIN books_ratings_link br ON r.id = br.rating
+            WHERE r.rating > 0
+            GROUP BY r.rating
+            ORDER BY r.rating DESC;",
+    )?;
+    for rating in &mut ratings {
+        rating.name = stars(rating.id as i64);
+    }
+    Ok(ratings)
+}
+
+/// id, name and number of books, and what the category is filed under if the
+/// query has a fourth column for it.
 fn categories(db: &Connection, sql: &str) -> rusqlite::Result<Vec<Category>> {
     let mut stmt = db.prepare(sql)?;
     let rows = stmt.query_map(params![], |row| {
@@ -199,6 +520,10 @@ fn categories(db: &Connection, sql: &str) -> rusqlite::Result<Vec<Category>> {
             id: row.get(0)?,
             name: row.get(1)?,
             books: row.get::<_, i64>(2)? as usize,
+            sort: match row.as_ref().column_count() {
+                4 => row.get(3)?,
+                _ => None,
+            },
         })
     })?;
     Ok(collect_rows(rows, "category"))
@@ -214,6 +539,34 @@ pub fn tag_name(db: &Connection, id: i32) -> rusqlite::Result<String> {
     name_of(db, "tags", id)
 }
 
+/// The name of one series, or `QueryReturnedNoRows`.
+pub fn series_name(db: &Connection, id: i32) -> rusqlite::Result<String> {
+    name_of(db, "series", id)
+}
+
+/// The name of one publisher, or `QueryReturnedNoRows`.
+pub fn publisher_name(db: &Connection, id: i32) -> rusqlite::Result<String> {
+    name_of(db, "publishers", id)
+}
+
+/// The name of one language, or `QueryReturnedNoRows`.
+pub fn language_name(db: &Connection, id: i32) -> rusqlite::Result<String> {
+    db.query_row("SELECT lang_code FROM languages WHERE id = ?1;", params![id], |row| {
+        row.get::<_, String>(0)
+    })
+    .map(|code| autonym(&code))
+}
+
+/// The stars of one rating that a book was rated at, or `QueryReturnedNoRows`.
+pub fn rating_name(db: &Connection, rating: i32) -> rusqlite::Result<String> {
+    db.query_row(
+        "SELECT rating FROM ratings WHERE rating = ?1 AND rating > 0;",
+        params![rating],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(stars)
+}
+
 fn name_of(db: &Connection, table: &str, id: i32) -> rusqlite::Result<String> {
     db.query_row(
         &format!("SELECT name FROM {} WHERE id = ?1;", table),
@@ -222,37 +575,18 @@ fn name_of(db: &Connection, table: &str, id: i32) -> rusqlite::Result<String> {
     )
 }
 
-pub fn books(db: &Connection) -> rusqlite::Result<Vec<Book>> {
-    query_books(
-        db,
-        &format!(
-            "SELECT {}
-                FROM books b
-                LEFT JOIN comments c ON b.id = c.book;",
-            BOOK_COLUMNS
-        ),
-        params![],
-    )
-}
-
 /// One page of the library, ordered by the sort title Calibre keeps for this purpose.
-pub fn books_page(db: &Connection, limit: usize, offset: usize) -> rusqlite::Result<Vec<Book>> {
-    query_books(
-        db,
-        &format!(
-            "SELECT {}
-                FROM books b
-                LEFT JOIN comments c ON b.id = c.book
-                ORDER BY b.sort LIMIT ?1 OFFSET ?2;",
-            BOOK_COLUMNS
-        ),
-        params![limit as i64, offset as i64],
-    )
+pub fn books_page(db: &Connection, facets: &Facets, limit: usize, offset: usize) -> rusqlite::Result<Vec<Book>> {
+    shelf_page(db, everything(), facets, limit, offset)
 }
 
 /// How many books the library holds, for the `numberOfItems` of a feed.
-pub fn count_books(db: &Connection) -> rusqlite::Result<usize> {
-    count(db, "SELECT COUNT(*) FROM books;", params![])
+pub fn count_books(db: &Connection, facets: &Facets) -> rusqlite::Result<usize> {
+    count_shelf(db, everything(), facets)
+}
+
+fn everything() -> Selection {
+    Selection::of("TRUE", Vec::new())
 }
 
 /// The size of each way into the library. Counted in one go
@@ -262,19 +596,37 @@ pub struct Counts {
     /// Only authors and tags related to a book
     pub authors: usize,
     pub tags: usize,
+    pub series: usize,
+    pub publishers: usize,
+    pub languages: usize,
+    /// Books with a rating, and the star levels they were rated at
+    pub rated: usize,
+    pub ratings: usize,
 }
 
 pub fn counts(db: &Connection) -> rusqlite::Result<Counts> {
     db.query_row(
         "SELECT (SELECT COUNT(*) FROM books),
                 (SELECT COUNT(DISTINCT author) FROM books_authors_link),
-                (SELECT COUNT(DISTINCT tag) FROM books_tags_link);",
+                (SELECT COUNT(DISTINCT tag) FROM books_tags_link),
+                (SELECT COUNT(DISTINCT series) FROM books_series_link),
+                (SELECT COUNT(DISTINCT publisher) FROM books_publishers_link),
+                (SELECT COUNT(DISTINCT lang_code) FROM books_languages_link),
+                (SELECT COUNT(DISTINCT br.book) FROM books_ratings_link br
+                    JOIN ratings r ON br.rating = r.id WHERE r.rating > 0),
+                (SELECT COUNT(DISTINCT r.rating) FROM books_ratings_link br
+                    JOIN ratings r ON br.rating = r.id WHERE r.rating > 0);",
         params![],
         |row| {
             Ok(Counts {
                 books: row.get::<_, i64>(0)? as usize,
                 authors: row.get::<_, i64>(1)? as usize,
                 tags: row.get::<_, i64>(2)? as usize,
+                series: row.get::<_, i64>(3)? as usize,
+                publishers: row.get::<_, i64>(4)? as usize,
+                languages: row.get::<_, i64>(5)? as usize,
+                rated: row.get::<_, i64>(6)? as usize,
+                ratings: row.get::<_, i64>(7)? as usize,
             })
         },
     )
@@ -315,89 +667,161 @@ pub fn recently_added(db: &Connection) -> rusqlite::Result<Vec<Book>> {
     )
 }
 
-pub fn books_by_tag(db: &Connection, tag: i32) -> rusqlite::Result<Vec<Book>> {
+/// A few books picked at random, different ones every time.
+pub fn random_books(db: &Connection, limit: usize) -> rusqlite::Result<Vec<Book>> {
     query_books(
         db,
         &format!(
             "SELECT {}
                 FROM books b
-                JOIN books_tags_link bt ON b.id = bt.book
                 LEFT JOIN comments c ON b.id = c.book
-                WHERE bt.tag = ?1 GROUP BY b.id;",
+                ORDER BY RANDOM() LIMIT ?1;",
             BOOK_COLUMNS
         ),
-        params![tag],
+        params![limit as i64],
     )
 }
 
-pub fn books_by_author(db: &Connection, author: i32) -> rusqlite::Result<Vec<Book>> {
-    query_books(
-        db,
-        &format!(
-            "SELECT {}
-                FROM books b
-                JOIN books_authors_link ba ON b.id = ba.book
-                LEFT JOIN comments c ON b.id = c.book
-                WHERE ba.author = ?1 GROUP BY b.id;",
-            BOOK_COLUMNS
-        ),
-        params![author],
-    )
+/// The next volume of every series a reader opened a book of, the series read
+/// last first. `opened` is each book the reader opened, with when they last did;
+/// the next volume is the first one after the furthest of those.
+pub fn next_in_series(db: &Connection, opened: &[(i32, f64)]) -> rusqlite::Result<Vec<i32>> {
+    let opened = serde_json::to_string(opened).unwrap_or_else(|_| "[]".to_string());
+    let mut stmt = db.prepare(
+        "WITH opened AS (
+            SELECT value ->> 0 AS book, value ->> 1 AS epoch FROM json_each(?1)
+         ), started AS (
+            SELECT bs.series, MAX(b.series_index) AS reached, MAX(o.epoch) AS read
+                FROM opened o
+                JOIN books b ON b.id = o.book
+                JOIN books_series_link bs ON bs.book = b.id
+                GROUP BY bs.series
+         ), next AS (
+            SELECT s.series, s.read, (SELECT b.id FROM books b JOIN books_series_link bs ON bs.book = b.id
+                WHERE bs.series = s.series AND b.series_index > s.reached
+                ORDER BY b.series_index, b.sort, b.id LIMIT 1) AS book
+                FROM started s
+         )
+         SELECT book FROM next WHERE book IS NOT NULL ORDER BY read DESC, series;",
+    )?;
+    let books = stmt.query_map(params![opened], |row| row.get(0))?;
+    books.collect()
+}
+
+/// One page of a series, in the order of its volumes rather than the order of
+/// the library: volume 4 comes after volume 3, whatever it is called.
+pub fn books_in_series_page(
+    db: &Connection,
+    series: i32,
+    facets: &Facets,
+    limit: usize,
+    offset: usize,
+) -> rusqlite::Result<Vec<Book>> {
+    shelf_page(db, in_series(series), facets, limit, offset)
+}
+
+pub fn count_books_in_series(db: &Connection, series: i32, facets: &Facets) -> rusqlite::Result<usize> {
+    count_shelf(db, in_series(series), facets)
+}
+
+fn in_series(series: i32) -> Selection {
+    Selection::linked("books_series_link", "series", series).order("b.series_index, b.sort")
+}
+
+/// One page of a publisher's books, in the order the whole library is in.
+pub fn books_by_publisher_page(
+    db: &Connection,
+    publisher: i32,
+    facets: &Facets,
+    limit: usize,
+    offset: usize,
+) -> rusqlite::Result<Vec<Book>> {
+    shelf_page(db, Selection::linked("books_publishers_link", "publisher", publisher), facets, limit, offset)
+}
+
+pub fn count_books_by_publisher(db: &Connection, publisher: i32, facets: &Facets) -> rusqlite::Result<usize> {
+    count_shelf(db, Selection::linked("books_publishers_link", "publisher", publisher), facets)
+}
+
+/// One page of the books in a language, in the order the whole library is in.
+pub fn books_by_language_page(
+    db: &Connection,
+    language: i32,
+    facets: &Facets,
+    limit: usize,
+    offset: usize,
+) -> rusqlite::Result<Vec<Book>> {
+    shelf_page(db, Selection::linked("books_languages_link", "lang_code", language), facets, limit, offset)
+}
+
+pub fn count_books_by_language(db: &Connection, language: i32, facets: &Facets) -> rusqlite::Result<usize> {
+    count_shelf(db, Selection::linked("books_languages_link", "lang_code", language), facets)
 }
 
 /// One page of the books with a tag, in the order the whole library is in.
 pub fn books_by_tag_page(
     db: &Connection,
     tag: i32,
+    facets: &Facets,
     limit: usize,
     offset: usize,
 ) -> rusqlite::Result<Vec<Book>> {
-    query_books(
-        db,
-        &format!(
-            "SELECT {}
-                FROM books b
-                JOIN books_tags_link bt ON b.id = bt.book
-                LEFT JOIN comments c ON b.id = c.book
-                WHERE bt.tag = ?1 GROUP BY b.id
-                ORDER BY b.sort LIMIT ?2 OFFSET ?3;",
-            BOOK_COLUMNS
-        ),
-        params![tag, limit as i64, offset as i64],
-    )
+    shelf_page(db, Selection::linked("books_tags_link", "tag", tag), facets, limit, offset)
+}
+
+pub fn count_books_by_tag(db: &Connection, tag: i32, facets: &Facets) -> rusqlite::Result<usize> {
+    count_shelf(db, Selection::linked("books_tags_link", "tag", tag), facets)
 }
 
 /// One page of an author's books, in the order the whole library is in.
 pub fn books_by_author_page(
     db: &Connection,
     author: i32,
+    facets: &Facets,
     limit: usize,
     offset: usize,
 ) -> rusqlite::Result<Vec<Book>> {
-    query_books(
-        db,
-        &format!(
-            "SELECT {}
-                FROM books b
-                JOIN books_authors_link ba ON b.id = ba.book
-                LEFT JOIN comments c ON b.id = c.book
-                WHERE ba.author = ?1 GROUP BY b.id
-                ORDER BY b.sort LIMIT ?2 OFFSET ?3;",
-            BOOK_COLUMNS
-        ),
-        params![author, limit as i64, offset as i64],
+    shelf_page(db, Selection::linked("books_authors_link", "author", author), facets, limit, offset)
+}
+
+pub fn count_books_by_author(db: &Connection, author: i32, facets: &Facets) -> rusqlite::Result<usize> {
+    count_shelf(db, Selection::linked("books_authors_link", "author", author), facets)
+}
+
+/// One page of the rated books. Books that were rated the same keep the order
+/// of the library, so that paging shows every one of them exactly once.
+pub fn top_rated_page(db: &Connection, facets: &Facets, limit: usize, offset: usize) -> rusqlite::Result<Vec<Book>> {
+    shelf_page(db, rated("r.rating > 0", Vec::new()).order("r.rating DESC, b.sort"), facets, limit, offset)
+}
+
+pub fn count_top_rated(db: &Connection, facets: &Facets) -> rusqlite::Result<usize> {
+    count_shelf(db, rated("r.rating > 0", Vec::new()), facets)
+}
+
+fn rated(condition: &str, params: Vec<Value>) -> Selection {
+    Selection::of(condition, params).join(
+        "JOIN books_ratings_link br ON b.id = br.book
+            JOIN ratings r ON br.rating = r.id",
     )
 }
 
-/// search for title or author
-const SEARCH_MATCH: &str = "b.id IN (
-        SELECT s.id FROM books s WHERE s.title LIKE ?1 ESCAPE '\\'
-        UNION
-        SELECT ba.book FROM books_authors_link ba
-            JOIN authors a ON ba.author = a.id
-            WHERE a.name LIKE ?1 ESCAPE '\\')";
+/// One page of the books rated at one star level, in the order the whole library is in.
+pub fn books_by_rating_page(
+    db: &Connection,
+    rating: i32,
+    facets: &Facets,
+    limit: usize,
+    offset: usize,
+) -> rusqlite::Result<Vec<Book>> {
+    shelf_page(db, rated("r.rating = ?", vec![Value::from(rating)]), facets, limit, offset)
+}
+
+pub fn count_books_by_rating(db: &Connection, rating: i32, facets: &Facets) -> rusqlite::Result<usize> {
+    count_shelf(db, rated("r.rating = ?", vec![Value::from(rating)]), facets)
+}
 
-/// wildcards and special characters are treated literally
+/// A term as a LIKE pattern that finds it anywhere in a value. Wildcards and
+/// special characters in it are treated literally.
 pub fn like(term: &str) -> String {
     let escaped: String = term
         .chars()
@@ -409,88 +833,1245 @@ pub fn like(term: &str) -> String {
     format!("%{}%", escaped)
 }
 
-/// One page of the books a term matches
+/// The library as a query sees it: the languages of its books, and Orca's index.
+struct Searched<'a> {
+    db: &'a Connection,
+    languages: Vec<String>,
+}
+
+impl query::Library for Searched<'_> {
+    fn languages(&self) -> &[String] {
+        &self.languages
+    }
+
+    /// A word the index cannot be asked about is still looked for in the titles and authors.
+    fn words(&self, word: &str) -> Option<String> {
+        fulltext::expression(self.db, word).unwrap_or_else(|e| {
+            eprintln!("Searching for '{}' without the index: {}", word, e);
+            None
+        })
+    }
+}
+
+/// A query in Calibre's search language (see `query`) as a condition on the
+/// books `b`. `None` if there is nothing in it to search for.
+fn search(db: &Connection, query: &str) -> rusqlite::Result<Option<query::Search>> {
+    let mut stmt = db.prepare("SELECT lang_code FROM languages;")?;
+    let languages: Vec<String> = stmt.query_map(params![], |row| row.get(0))?.collect::<rusqlite::Result<_>>()?;
+    Ok(query::compile(query, &Searched { db, languages }))
+}
+
+/// One page of the books a query matches, the best matches first. A query
+/// without plain words has nothing to rank by and lists the books by title.
 pub fn books_search_page(
     db: &Connection,
-    term: &str,
+    query: &str,
+    facets: &Facets,
     limit: usize,
     offset: usize,
 ) -> rusqlite::Result<Vec<Book>> {
-    query_books(
-        db,
-        &format!(
-            "SELECT {}
-                FROM books b
-                LEFT JOIN comments c ON b.id = c.book
-                WHERE {}
-                ORDER BY b.sort LIMIT ?2 OFFSET ?3;",
-            BOOK_COLUMNS, SEARCH_MATCH
-        ),
-        params![like(term), limit as i64, offset as i64],
-    )
+    match matching(db, query)? {
+        Some(selection) => shelf_page(db, selection, facets, limit, offset),
+        None => Ok(Vec::new()),
+    }
 }
 
-pub fn count_books_matching(db: &Connection, term: &str) -> rusqlite::Result<usize> {
-    count(
-        db,
-        &format!("SELECT COUNT(*) FROM books b WHERE {};", SEARCH_MATCH),
-        params![like(term)],
-    )
+pub fn count_books_matching(db: &Connection, query: &str, facets: &Facets) -> rusqlite::Result<usize> {
+    match search(db, query)? {
+        Some(search) => count_shelf(db, Selection::of(&search.condition, search.params), facets),
+        None => Ok(0),
+    }
 }
 
-pub fn count_books_by_tag(db: &Connection, tag: i32) -> rusqlite::Result<usize> {
-    count(db, "SELECT COUNT(DISTINCT book) FROM books_tags_link WHERE tag = ?1;", params![tag])
+/// The books a query matches, ranked when there are plain words in it.
+fn matching(db: &Connection, query: &str) -> rusqlite::Result<Option<Selection>> {
+    let Some(search) = search(db, query)? else {
+        return Ok(None);
+    };
+    // The ranking is joined before the condition, so its `?` comes first.
+    let mut params = Vec::new();
+    let joined = match &search.rank {
+        Some(rank) => {
+            params.push(Value::Text(rank.clone()));
+            format!(
+                "LEFT JOIN (SELECT rowid AS book, {} AS score FROM orca.book_text WHERE book_text MATCH ?) r
+                    ON r.book = b.id",
+                fulltext::RANK
+            )
+        }
+        None => String::new(),
+    };
+    params.extend(search.params);
+    let selection = Selection::of(&search.condition, params).join(&joined);
+    // BM25 scores the better matches lower; books found by other means come after.
+    Ok(Some(match search.rank {
+        Some(_) => selection.order("r.score IS NULL, r.score, b.sort"),
+        None => selection,
+    }))
 }
 
-pub fn count_books_by_author(db: &Connection, author: i32) -> rusqlite::Result<usize> {
-    count(db, "SELECT COUNT(DISTINCT book) FROM books_authors_link WHERE author = ?1;", params![author])
-}
 
-fn count(db: &Connection, sql: &str, params: impl rusqlite::Params) -> rusqlite::Result<usize> {
-    let total: i64 = db.query_row(sql, params, |row| row.get(0))?;
-    Ok(total as usize)
+// ------- Notes -------
+
+/// Calibre 7 keeps notes on authors, tags, series and publishers in a database
+/// of its own, `.calnotes/notes.db` next to `metadata.db`, and the images in
+/// them in files beside it. Orca only reads them.
+fn notes_dir(db: &Connection) -> Option<PathBuf> {
+    db.path().map(|path| Path::new(path).with_file_name(".calnotes"))
 }
 
-/// Where a book's cover lives, relative to the library directory.
-/// `QueryReturnedNoRows` means either no such book or no cover for it.
-pub fn cover_path(db: &Connection, book: i32) -> rusqlite::Result<String> {
-    let mut stmt =
-        db.prepare("SELECT books.path FROM books WHERE books.id = ?1 AND books.has_cover = true;")?;
-    let path: String = stmt.query_row(params![book], |row| row.get(0))?;
-    Ok(format!("{}/cover.jpg", path))
+fn notes_db(db: &Connection) -> rusqlite::Result<Option<Connection>> {
+    let Some(path) = notes_dir(db).map(|dir| dir.join("notes.db")) else {
+        return Ok(None);
+    };
+    if !path.is_file() {
+        return Ok(None);
+    }
+    Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX).map(Some)
 }
 
-/// Where one format of a book lives, relative to the library directory.
-/// Calibre files every format of a book under the same stem, so the format only
-/// decides the extension.
-pub fn file_path(db: &Connection, book: i32, format: &str) -> rusqlite::Result<String> {
-    let mut stmt = db.prepare(
-        "SELECT b.path, d.name AS file
-            FROM books b
-            JOIN data d ON b.id = d.book
-            WHERE b.id = ?1 AND d.format = ?2 COLLATE NOCASE;",
+/// The notes on some of the items of a field, as HTML, by item. Calibre calls
+/// the fields `authors`, `tags`, `series` and `publisher`. An item without a
+/// note is left out.
+pub fn notes(db: &Connection, field: &str, items: &[i32]) -> rusqlite::Result<HashMap<i32, String>> {
+    let Some(notes) = notes_db(db)? else {
+        return Ok(HashMap::new());
+    };
+    let mut stmt = notes.prepare(
+        "SELECT item, doc FROM notes
+            WHERE colname = ?1 AND item IN (SELECT value FROM json_each(?2)) AND trim(doc) != '';",
     )?;
-    let (path, file): (String, String) =
-        stmt.query_row(params![book, format], |row| Ok((row.get(0)?, row.get(1)?)))?;
-    Ok(format!("{}/{}.{}", path, file, format))
+    let items = serde_json::to_string(items).unwrap_or_else(|_| "[]".to_string());
+    let rows = stmt.query_map(params![field, items], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    Ok(collect_rows(rows, "note").into_iter().collect())
 }
 
-/// The columns every book feed needs.
-/// `GROUP_CONCAT` : a client should not see the download links shuffle between requests
-const BOOK_COLUMNS: &str = "b.id, b.uuid, b.title, b.pubdate, b.last_modified, b.has_cover, b.series_index, c.text AS synopsis,
-    (SELECT GROUP_CONCAT(format)
-        FROM (SELECT format FROM data WHERE book = b.id ORDER BY format)) AS formats,
-    (SELECT s.name FROM books_series_link bs JOIN series s ON bs.series = s.id
-        WHERE bs.book = b.id) AS series,
-    (SELECT p.name FROM books_publishers_link bp JOIN publishers p ON bp.publisher = p.id
-        WHERE bp.book = b.id) AS publisher";
+/// The note on one item, if it has one.
+pub fn note(db: &Connection, field: &str, item: i32) -> rusqlite::Result<Option<String>> {
+    Ok(notes(db, field, &[item])?.remove(&item))
+}
 
-/// Run one of the book queries and map its rows to `Book`s.
-fn query_books(
-    db: &Connection,
-    sql: &str,
-    params: &[&dyn rusqlite::ToSql],
-) -> rusqlite::Result<Vec<Book>> {
+/// An image in a note: the file Calibre keeps it in, and the name it had.
+#[derive(Debug)]
+pub struct Resource {
+    pub path: PathBuf,
+    pub name: String,
+}
+
+/// The image a note shows as `calres://{algorithm}/{digest}`, if Calibre has it.
+pub fn note_resource(db: &Connection, algorithm: &str, digest: &str) -> rusqlite::Result<Option<Resource>> {
+    // Both end up in a path.
+    let plain = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric());
+    if !plain(algorithm) || !plain(digest) || digest.len() < 2 {
+        return Ok(None);
+    }
+    let (Some(dir), Some(notes)) = (notes_dir(db), notes_db(db)?) else {
+        return Ok(None);
+    };
+    let name: Option<String> = notes
+        .query_row("SELECT name FROM resources WHERE hash = ?1;", params![format!("{}:{}", algorithm, digest)], |row| {
+            row.get(0)
+        })
+        .optional()?;
+    Ok(name.map(|name| Resource {
+        // Calibre files them by the first two digits of the digest.
+        path: dir.join("resources").join(&digest[..2]).join(format!("{}-{}", algorithm, digest)),
+        name,
+    }))
+}
+
+/// Calibre links the images of a note as `calres://{algorithm}/{digest}`,
+/// with a query of its own after that.
+static RESOURCE_URL: Lazy<Regex> = Lazy::new(|| Regex::new(r#"calres://([A-Za-z0-9]+)/([A-Za-z0-9]+)(\?[^\s"'<>]*)?"#).unwrap());
+
+/// A note with its images where Orca serves them, `{prefix}/{algorithm}/{digest}`,
+/// rather than where only Calibre can find them.
+pub fn note_html(doc: &str, prefix: &str) -> String {
+    RESOURCE_URL.replace_all(doc, |found: &regex::Captures| format!("{}/{}/{}", prefix, &found[1], &found[2])).into_owned()
+}
+
+static IMAGE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)<img[^>]*>").unwrap());
+
+/// A note as plain text, for where HTML will not do. Its images have no place
+/// there, not even as their alt text.
+pub fn note_text(doc: &str) -> String {
+    plain_text(&IMAGE.replace_all(doc, ""), UNWRAPPED).trim().to_string()
+}
+
+// ------- The text of the books -------
+
+/// Calibre 6 extracts the text of every book into a database of its own, next
+/// to `metadata.db` -- if full-text searching was switched on. Orca only reads it.
+fn full_text(db: &Connection) -> rusqlite::Result<Option<Connection>> {
+    let Some(path) = db.path().map(|path| Path::new(path).with_file_name("full-text-search.db")) else {
+        return Ok(None);
+    };
+    if !path.is_file() {
+        return Ok(None);
+    }
+    Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX).map(Some)
+}
+
+/// Text with every run of whitespace one space: a quote may well span a line
+/// break, and the reader types a space there.
+fn joined(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// What a reader quotes, and the books whose text holds it, each with the
+/// passage it first appears in. The text is scanned once, the first time the
+/// books are asked for, however many times they are counted and paged.
+pub struct Quote {
+    /// As the reader typed it, quotation marks and all
+    pub said: String,
+    passages: OnceCell<BTreeMap<i32, String>>,
+}
+
+impl Quote {
+    pub fn new(said: String) -> Quote {
+        Quote {
+            said,
+            passages: OnceCell::new(),
+        }
+    }
+
+    /// What to look for: without the quotation marks, and joined like the text.
+    fn quoted(&self) -> String {
+        joined(self.said.trim().trim_matches(|c| matches!(c, '"' | '“' | '”' | '„')))
+    }
+
+    /// Every book whose text holds the quote, with the passage as a synopsis.
+    ///
+    /// Calibre's own index of the text is no use here: it was built with a
+    /// tokenizer that only Calibre has. Scanning the text finds a quote all the
+    /// same, and it is the same scan that cuts out the passage.
+    fn passages(&self, db: &Connection) -> rusqlite::Result<&BTreeMap<i32, String>> {
+        self.passages.get_or_try_init(|| {
+            let quote = self.quoted();
+            let mut passages = BTreeMap::new();
+            let Some(texts) = full_text(db)?.filter(|_| !quote.is_empty()) else {
+                return Ok(passages);
+            };
+            // The first format that has it. Calibre extracts each of them on its own.
+            let mut stmt = texts.prepare("SELECT book, searchable_text FROM books_text ORDER BY book, id;")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let book: i32 = row.get(0)?;
+                if passages.contains_key(&book) {
+                    continue;
+                }
+                if let Some(passage) = row.get::<_, Option<String>>(1)?.and_then(|text| snippet(&text, &quote)) {
+                    passages.insert(book, passage);
+                }
+            }
+            Ok(passages)
+        })
+    }
+
+    /// The books that hold the quote. Calibre may still hold the text of a book
+    /// since deleted, which the shelf then leaves out.
+    fn selection(&self, db: &Connection) -> rusqlite::Result<Selection> {
+        let books: Vec<&i32> = self.passages(db)?.keys().collect();
+        let books = serde_json::to_string(&books).unwrap_or_else(|_| "[]".to_string());
+        Ok(Selection::of("b.id IN (SELECT value FROM json_each(?))", vec![Value::Text(books)]))
+    }
+}
+
+/// How many words to show on either side of a quote
+const SNIPPET_WORDS: usize = 20;
+
+/// The quote where it first appears in `text`, in bold and with a few words
+/// either side of it -- as HTML, the same as a synopsis. `quote` is joined
+/// already; the text is joined the same way.
+fn snippet(text: &str, quote: &str) -> Option<String> {
+    // Case is ignored for ASCII letters only, which keeps the length of every
+    // letter: a position in the lowercase text is one in the text.
+    let text = joined(text);
+    let start = text.to_ascii_lowercase().find(&quote.to_ascii_lowercase())?;
+    let end = start + quote.len();
+
+    // Counted in spaces: the text has no other whitespace left.
+    let from = text[..start]
+        .char_indices()
+        .rev()
+        .filter(|(_, c)| *c == ' ')
+        .nth(SNIPPET_WORDS)
+        .map_or(0, |(at, _)| at + 1);
+    let to = text[end..]
+        .char_indices()
+        .filter(|(_, c)| *c == ' ')
+        .nth(SNIPPET_WORDS)
+        .map_or(text.len(), |(at, _)| end + at);
+
+    Some(format!(
+        "<p>{}{}<b>{}</b>{}{}</p>",
+        if from > 0 { "… " } else { "" },
+        escaped(&text[from..start]),
+        escaped(&text[start..end]),
+        escaped(&text[end..to]),
+        if to < text.len() { " …" } else { "" },
+    ))
+}
+
+fn escaped(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// One page of the books whose text holds `quote`, by title. Their synopsis is
+/// where the quote appears in them.
+pub fn books_containing_page(
+    db: &Connection,
+    quote: &Quote,
+    facets: &Facets,
+    limit: usize,
+    offset: usize,
+) -> rusqlite::Result<Vec<Book>> {
+    let passages = quote.passages(db)?;
+    if passages.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut books = shelf_page(db, quote.selection(db)?, facets, limit, offset)?;
+    for book in &mut books {
+        book.synopsis = passages.get(&book.id).cloned().unwrap_or_default();
+    }
+    Ok(books)
+}
+
+pub fn count_books_containing(db: &Connection, quote: &Quote, facets: &Facets) -> rusqlite::Result<usize> {
+    if quote.passages(db)?.is_empty() {
+        return Ok(0);
+    }
+    count_shelf(db, quote.selection(db)?, facets)
+}
+
+pub fn count_authors(db: &Connection) -> rusqlite::Result<usize> {
+    count(db, "SELECT COUNT(*) FROM authors;", params![])
+}
+
+pub fn count_tags(db: &Connection) -> rusqlite::Result<usize> {
+    count(db, "SELECT COUNT(*) FROM tags;", params![])
+}
+
+
+
+fn count(db: &Connection, sql: &str, params: impl rusqlite::Params) -> rusqlite::Result<usize> {
+    let total: i64 = db.query_row(sql, params, |row| row.get(0))?;
+    Ok(total as usize)
+}
+
+// ------- Annotations -------
+
+/// A highlight or a bookmark, as Calibre's viewer or its content server saved
+/// it. What it says is in `data`, whose fields depend on `kind`.
+pub struct Annotation {
+    pub book: i32,
+    /// The format it was made in: EPUB, AZW3 ...
+    pub format: String,
+    /// The uuid of a highlight, the title of a bookmark
+    pub annot_id: String,
+    /// `highlight` or `bookmark`
+    pub kind: String,
+    /// When it last changed, in RFC 3339 and UTC
+    pub modified: String,
+    pub data: serde_json::Value,
+}
+
+/// Which annotations: the ones on a book, or the ones whose text holds some words.
+pub enum Annotated<'a> {
+    Book(i32),
+    Search(&'a str),
+}
+
+/// A page of the annotations `users` made, each of them `type:name` the way
+/// Calibre tells them apart (`local:viewer`, `web:alice`). Those on a book go
+/// by when they were made, what a search finds by the newest first.
+pub fn annotations(
+    db: &Connection,
+    of: &Annotated,
+    users: &[String],
+    limit: usize,
+    offset: usize,
+) -> rusqlite::Result<Vec<Annotation>> {
+    let Some((condition, mut params)) = annotated(of, users) else {
+        return Ok(Vec::new());
+    };
+    let order = match of {
+        Annotated::Book(_) => "a.timestamp",
+        Annotated::Search(_) => "a.timestamp DESC",
+    };
+    params.extend([Value::Integer(limit as i64), Value::Integer(offset as i64)]);
+    let sql = format!(
+        "SELECT a.book, a.format, a.annot_id, a.annot_type,
+                strftime('%Y-%m-%dT%H:%M:%SZ', a.timestamp, 'unixepoch'), a.annot_data
+         FROM annotations a JOIN books b ON b.id = a.book
+         WHERE {}
+         ORDER BY {}, a.id
+         LIMIT ? OFFSET ?;",
+        condition, order
+    );
+    let mut stmt = db.prepare(&sql)?;
+    let rows = stmt.query_map(params_from_iter(params), |row| {
+        let data: String = row.get(5)?;
+        Ok(Annotation {
+            book: row.get(0)?,
+            format: row.get(1)?,
+            annot_id: row.get(2)?,
+            kind: row.get(3)?,
+            modified: row.get(4)?,
+            data: serde_json::from_str(&data)
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(5, rusqlite::types::Type::Text, Box::new(e)))?,
+        })
+    })?;
+    Ok(collect_rows(rows, "annotation"))
+}
+
+pub fn count_annotations(db: &Connection, of: &Annotated, users: &[String]) -> rusqlite::Result<usize> {
+    let Some((condition, params)) = annotated(of, users) else {
+        return Ok(0);
+    };
+    let sql = format!("SELECT COUNT(*) FROM annotations a JOIN books b ON b.id = a.book WHERE {};", condition);
+    count(db, &sql, params_from_iter(params))
+}
+
+/// The condition for `annotations` and its parameters, or None for a search
+/// without a word to look for. Calibre keeps what was removed, for syncing;
+/// Orca leaves it out. Joined with `books`, so a virtual library keeps the
+/// annotations on the books outside it to itself.
+fn annotated(of: &Annotated, users: &[String]) -> Option<(String, Vec<Value>)> {
+    let users = Value::Text(serde_json::to_string(users).unwrap_or_else(|_| "[]".to_string()));
+    let theirs = "a.user_type || ':' || a.user IN (SELECT value FROM json_each(?))
+                  AND a.annot_type IN ('highlight', 'bookmark')
+                  AND coalesce(json_extract(a.annot_data, '$.removed'), 0) = 0";
+    match of {
+        Annotated::Book(book) => Some((format!("{} AND a.book = ?", theirs), vec![users, Value::Integer(*book as i64)])),
+        Annotated::Search(words) => Some((
+            format!("{} AND a.id IN (SELECT rowid FROM annotations_fts WHERE annotations_fts MATCH ?)", theirs),
+            vec![users, Value::Text(annotation_words(words)?)],
+        )),
+    }
+}
+
+/// Words as a query of `annotations_fts`: every one of them has to be there,
+/// or a word it begins. Each is quoted, so that nothing a reader types is taken
+/// for the syntax of FTS5.
+fn annotation_words(words: &str) -> Option<String> {
+    let words: Vec<String> = words
+        .split_whitespace()
+        .filter(|word| word.chars().any(char::is_alphanumeric))
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect();
+    (!words.is_empty()).then(|| words.join(" "))
+}
+
+/// Where a highlight of Calibre's viewer is, as an EPUB CFI. Calibre keeps
+/// where it starts and ends within one file of the book, and which file of
+/// the spine; a CFI starts at the package document, whose spine is its third
+/// element in any EPUB written to the spec.
+pub fn highlight_cfi(spine_index: u64, start: &str, end: &str) -> String {
+    let steps = |cfi: &str| cfi.trim_start_matches('/').split('/').map(str::to_string).collect::<Vec<_>>();
+    let (start, end) = (steps(start), steps(end));
+    // A range is the steps both ends share, then where below them each one is.
+    let shared = start
+        .iter()
+        .zip(&end)
+        .take_while(|(a, b)| a == b)
+        .count()
+        .min(start.len() - 1)
+        .min(end.len() - 1);
+    let parent: String = start[..shared].iter().map(|step| format!("/{}", step)).collect();
+    format!("epubcfi(/6/{}!{},/{},/{})", (spine_index + 1) * 2, parent, start[shared..].join("/"), end[shared..].join("/"))
+}
+
+/// Where a bookmark of Calibre's viewer points, as an EPUB CFI. Calibre
+/// writes the step into the spine and the path within the file as one path.
+pub fn bookmark_cfi(pos: &str) -> Option<String> {
+    let (spine, path) = pos.strip_prefix("epubcfi(/")?.strip_suffix(')')?.split_once('/')?;
+    Some(format!("epubcfi(/6/{}!/{})", spine, path))
+}
+
+// ------- Reading positions -------
+
+/// Where Calibre's viewer, or a reader of its content server, last was in
+/// one format of a book.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadPosition {
+    pub book: i32,
+    /// The format it was read in, the way Calibre spells it: EPUB, AZW3 ...
+    pub format: String,
+    /// `_` for the viewer on the desktop, the name of a content server user
+    pub user: String,
+    pub device: String,
+    /// Where, as Calibre writes it: `epubcfi(/{step into the spine}/...)`
+    pub cfi: String,
+    /// When, in seconds since the epoch
+    pub epoch: f64,
+    /// How much of the book lies behind, from 0 to 1
+    pub pos_frac: f64,
+}
+
+/// Calibre has kept reading positions since version 4.
+fn has_positions(db: &Connection) -> rusqlite::Result<bool> {
+    db.query_row(
+        "SELECT COUNT(*) > 0 FROM main.sqlite_master WHERE name = 'last_read_positions';",
+        [],
+        |row| row.get(0),
+    )
+}
+
+/// Every position `users` left in the library, the latest first: on any
+/// device, in any format. Joined with `books`, so a virtual library keeps the
+/// positions in the books outside it to itself.
+pub fn read_positions(db: &Connection, users: &[String]) -> rusqlite::Result<Vec<ReadPosition>> {
+    if users.is_empty() || !has_positions(db)? {
+        return Ok(Vec::new());
+    }
+    let users = serde_json::to_string(users).unwrap_or_else(|_| "[]".to_string());
+    let mut stmt = db.prepare(
+        "SELECT p.book, p.format, p.user, p.device, p.cfi, p.epoch, p.pos_frac
+         FROM last_read_positions p JOIN books b ON b.id = p.book
+         WHERE p.user IN (SELECT value FROM json_each(?1))
+         ORDER BY p.epoch DESC, p.id;",
+    )?;
+    let rows = stmt.query_map([users], |row| {
+        Ok(ReadPosition {
+            book: row.get(0)?,
+            format: row.get(1)?,
+            user: row.get(2)?,
+            device: row.get(3)?,
+            cfi: row.get(4)?,
+            epoch: row.get(5)?,
+            pos_frac: row.get(6)?,
+        })
+    })?;
+    Ok(collect_rows(rows, "reading position"))
+}
+
+/// Where a user is in a book on one device, in place of where they were on
+/// it. Calibre's viewer opens a book at the latest of its user's positions,
+/// whichever device left it. Written to Calibre's own table: a virtual
+/// library's view of it takes no writes.
+pub fn set_read_position(db: &Connection, position: &ReadPosition) -> rusqlite::Result<()> {
+    db.execute(
+        "INSERT INTO main.last_read_positions (book, format, user, device, cfi, epoch, pos_frac)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+         ON CONFLICT (user, device, book, format) DO UPDATE SET
+             cfi = excluded.cfi,
+             epoch = excluded.epoch,
+             pos_frac = excluded.pos_frac;",
+        params![
+            position.book,
+            position.format,
+            position.user,
+            position.device,
+            position.cfi,
+            position.epoch,
+            position.pos_frac
+        ],
+    )?;
+    Ok(())
+}
+
+/// One page of the books `ids` names, in the order it names them unless the
+/// reader asks for another.
+pub fn books_among_page(db: &Connection, ids: &[i32], facets: &Facets, limit: usize, offset: usize) -> rusqlite::Result<Vec<Book>> {
+    shelf_page(db, among(ids), facets, limit, offset)
+}
+
+pub fn count_books_among(db: &Connection, ids: &[i32], facets: &Facets) -> rusqlite::Result<usize> {
+    count_shelf(db, among(ids), facets)
+}
+
+fn among(ids: &[i32]) -> Selection {
+    let ids = serde_json::to_string(ids).unwrap_or_else(|_| "[]".to_string());
+    Selection::of("TRUE", vec![Value::Text(ids)])
+        .join("JOIN json_each(?) listed ON listed.value = b.id")
+        .order("listed.key")
+}
+
+/// One file of a book, the way Calibre keeps it.
+pub struct BookFile {
+    pub book: i32,
+    /// EPUB, AZW3 ...
+    pub format: String,
+    /// Below the library
+    pub path: String,
+}
+
+/// Every file of every book in the library.
+pub fn book_files(db: &Connection) -> rusqlite::Result<Vec<BookFile>> {
+    let mut stmt = db.prepare(
+        "SELECT b.id, d.format, b.path || '/' || d.name || '.' || lower(d.format)
+         FROM books b JOIN data d ON d.book = b.id
+         ORDER BY b.id, d.format;",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok(BookFile {
+            book: row.get(0)?,
+            format: row.get(1)?,
+            path: row.get(2)?,
+        })
+    })?;
+    Ok(collect_rows(rows, "book file"))
+}
+
+/// The books of the library among `uuids`, by uuid: a Kobo knows a book by
+/// nothing else.
+pub fn books_by_uuid(db: &Connection, uuids: &[String]) -> rusqlite::Result<HashMap<String, i32>> {
+    let listed = serde_json::to_string(uuids).unwrap_or_else(|_| "[]".to_string());
+    let mut stmt = db.prepare("SELECT b.uuid, b.id FROM books b JOIN json_each(?1) listed ON listed.value = b.uuid;")?;
+    let rows = stmt.query_map([listed], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+// ------- Sorting and narrowing down a shelf -------
+
+/// An order a reader may ask for instead of the one a shelf keeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    Title,
+    Author,
+    /// The most recently published first
+    Published,
+    /// The newest arrivals first
+    Added,
+    /// The best rated first, the unrated last
+    Rating,
+    /// By series, then in the order of the volumes. Books in no series come last.
+    Series,
+}
+
+/// The series a book `b` is in, by Calibre's sort name for it.
+const SERIES_OF_BOOK: &str = "(SELECT s.sort FROM books_series_link l JOIN series s ON l.series = s.id WHERE l.book = b.id)";
+
+impl Sort {
+    pub const ALL: [Sort; 6] = [Sort::Title, Sort::Author, Sort::Published, Sort::Added, Sort::Rating, Sort::Series];
+
+    /// What `?sort=` says in a URL
+    pub fn key(self) -> &'static str {
+        match self {
+            Sort::Title => "title",
+            Sort::Author => "author",
+            Sort::Published => "published",
+            Sort::Added => "added",
+            Sort::Rating => "rating",
+            Sort::Series => "series",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Sort::Title => "Title",
+            Sort::Author => "Author",
+            Sort::Published => "Newest published",
+            Sort::Added => "Newest added",
+            Sort::Rating => "Best rated",
+            Sort::Series => "Series",
+        }
+    }
+
+    /// Ties keep the order of the library; a shelf page breaks what ties remain by id.
+    fn order(self) -> String {
+        match self {
+            Sort::Title => "b.sort".to_string(),
+            Sort::Author => "b.author_sort, b.sort".to_string(),
+            Sort::Published => "b.pubdate DESC, b.sort".to_string(),
+            Sort::Added => "b.timestamp DESC, b.sort".to_string(),
+            Sort::Rating => "(SELECT MAX(r.rating) FROM books_ratings_link l JOIN ratings r ON l.rating = r.id
+                WHERE l.book = b.id) DESC, b.sort"
+                .to_string(),
+            Sort::Series => format!("{0} IS NULL, {0}, b.series_index, b.sort", SERIES_OF_BOOK),
+        }
+    }
+}
+
+/// How a reader sorted and narrowed down a shelf. The default is the shelf as
+/// it is, in its own order.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct Facets {
+    pub sort: Option<Sort>,
+    /// Only the books Calibre has in this format: `EPUB`
+    #[serde(default, deserialize_with = "format_name")]
+    pub format: Option<String>,
+    /// Only the books in this language, by Calibre's id of it
+    pub language: Option<i32>,
+    /// Only the books with a cover
+    #[serde(default)]
+    pub cover: bool,
+}
+
+/// Calibre names its formats in capitals; `?format=epub` is the same facet.
+fn format_name<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let format: Option<String> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(format.map(|format| format.to_uppercase()))
+}
+
+impl Facets {
+    /// The shelf as it is
+    pub const NONE: Facets = Facets {
+        sort: None,
+        format: None,
+        language: None,
+        cover: false,
+    };
+
+    /// The filters as more conditions on the books `b`, each starting with `AND`,
+    /// and the values of their `?`s.
+    fn conditions(&self) -> (String, Vec<Value>) {
+        let mut conditions = String::new();
+        let mut values = Vec::new();
+        if let Some(format) = &self.format {
+            conditions.push_str(" AND b.id IN (SELECT book FROM data WHERE format = ?)");
+            values.push(Value::Text(format.to_uppercase()));
+        }
+        if let Some(language) = self.language {
+            conditions.push_str(" AND b.id IN (SELECT book FROM books_languages_link WHERE lang_code = ?)");
+            values.push(Value::from(language));
+        }
+        if self.cover {
+            conditions.push_str(" AND b.has_cover");
+        }
+        (conditions, values)
+    }
+}
+
+/// Which books a shelf holds: a condition on the books `b`, what has to be
+/// joined for it, and the order the shelf keeps unless a reader asks for another.
+struct Selection {
+    joins: String,
+    condition: String,
+    /// The values of the `?`s in the joins, then in the condition
+    params: Vec<Value>,
+    order: String,
+}
+
+impl Selection {
+    /// In the order of the library
+    fn of(condition: &str, params: Vec<Value>) -> Selection {
+        Selection {
+            joins: String::new(),
+            condition: condition.to_string(),
+            params,
+            order: "b.sort".to_string(),
+        }
+    }
+
+    /// The books linked to one author, tag, series ... in Calibre's `link` table.
+    fn linked(link: &str, column: &str, id: i32) -> Selection {
+        Selection::of(&format!("b.id IN (SELECT book FROM {} WHERE {} = ?)", link, column), vec![Value::from(id)])
+    }
+
+    fn join(mut self, joins: &str) -> Selection {
+        self.joins = joins.to_string();
+        self
+    }
+
+    fn order(mut self, order: &str) -> Selection {
+        self.order = order.to_string();
+        self
+    }
+}
+
+/// One page of the books a shelf holds, as the reader sorted and narrowed it down.
+/// A selection names every book once, so there is nothing to group -- which the
+/// ranking of a search could not have anyway. Books that tie in every order are
+/// taken by id, so that paging shows every book exactly once.
+fn shelf_page(
+    db: &Connection,
+    selection: Selection,
+    facets: &Facets,
+    limit: usize,
+    offset: usize,
+) -> rusqlite::Result<Vec<Book>> {
+    let (conditions, mut values) = facets.conditions();
+    values.splice(0..0, selection.params);
+    values.push(Value::Integer(limit as i64));
+    values.push(Value::Integer(offset as i64));
+    let values: Vec<&dyn rusqlite::ToSql> = values.iter().map(|value| value as &dyn rusqlite::ToSql).collect();
+    let order = facets.sort.map(Sort::order).unwrap_or(selection.order);
+    query_books(
+        db,
+        &format!(
+            "SELECT {}
+                FROM books b
+                {}
+                LEFT JOIN comments c ON b.id = c.book
+                WHERE {}{}
+                ORDER BY {}, b.id LIMIT ? OFFSET ?;",
+            BOOK_COLUMNS, selection.joins, selection.condition, conditions, order
+        ),
+        &values,
+    )
+}
+
+/// How many books a shelf holds once the reader narrowed it down.
+fn count_shelf(db: &Connection, selection: Selection, facets: &Facets) -> rusqlite::Result<usize> {
+    let (conditions, mut values) = facets.conditions();
+    values.splice(0..0, selection.params);
+    count(
+        db,
+        &format!(
+            "SELECT COUNT(DISTINCT b.id) FROM books b {} WHERE {}{};",
+            selection.joins, selection.condition, conditions
+        ),
+        params_from_iter(values),
+    )
+}
+
+/// Every format the library has a book in, for a reader to narrow a shelf down to.
+pub fn formats(db: &Connection) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = db.prepare("SELECT DISTINCT format FROM data ORDER BY format;")?;
+    let formats = stmt.query_map(params![], |row| row.get(0))?;
+    Ok(collect_rows(formats, "format"))
+}
+
+// ------- An index by first letter -------
+
+/// Where an index files whatever starts with a digit or a symbol.
+pub const NOT_A_LETTER: &str = "#";
+
+/// Where an index files a name: under its first letter, in capitals and in
+/// whatever alphabet it is written in. Digits and symbols share `#`.
+pub fn initial(sort: &str) -> String {
+    match sort.trim_start().chars().next() {
+        Some(first) if first.is_alphabetic() => first.to_uppercase().collect(),
+        _ => NOT_A_LETTER.to_string(),
+    }
+}
+
+/// The letters of an index, each with how much is filed under it, `#` first.
+pub fn letters<'a>(sorts: impl IntoIterator<Item = &'a str>) -> Vec<(String, usize)> {
+    let mut letters: BTreeMap<String, usize> = BTreeMap::new();
+    for sort in sorts {
+        *letters.entry(initial(sort)).or_default() += 1;
+    }
+    letters.into_iter().collect()
+}
+
+/// id, name and what it is filed under, for every row of `sql`. SQLite only
+/// knows the capitals of ASCII, so the filing happens here.
+fn filed(db: &Connection, sql: &str) -> rusqlite::Result<Vec<(i32, String, String)>> {
+    let mut stmt = db.prepare(sql)?;
+    let rows = stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    Ok(collect_rows(rows, "name"))
+}
+
+const FILED_BOOKS: &str = "SELECT id, title, COALESCE(sort, title) FROM books ORDER BY sort;";
+const FILED_AUTHORS: &str = "SELECT id, name, COALESCE(sort, name) FROM authors ORDER BY sort;";
+const FILED_TAGS: &str = "SELECT id, name, name FROM tags ORDER BY name;";
+
+fn letters_of(db: &Connection, sql: &str) -> rusqlite::Result<Vec<(String, usize)>> {
+    let filed = filed(db, sql)?;
+    Ok(letters(filed.iter().map(|(_, _, sort)| sort.as_str())))
+}
+
+/// The first letters of the titles, each with how many books it starts.
+pub fn title_letters(db: &Connection) -> rusqlite::Result<Vec<(String, usize)>> {
+    letters_of(db, FILED_BOOKS)
+}
+
+pub fn author_letters(db: &Connection) -> rusqlite::Result<Vec<(String, usize)>> {
+    letters_of(db, FILED_AUTHORS)
+}
+
+pub fn tag_letters(db: &Connection) -> rusqlite::Result<Vec<(String, usize)>> {
+    letters_of(db, FILED_TAGS)
+}
+
+/// Every author filed under `letter`, in Calibre's order.
+pub fn authors_under(db: &Connection, letter: &str) -> rusqlite::Result<Vec<Author>> {
+    Ok(filed(db, FILED_AUTHORS)?
+        .into_iter()
+        .filter(|(_, _, sort)| initial(sort) == letter)
+        .map(|(id, name, _)| Author { id, name })
+        .collect())
+}
+
+/// Every tag that starts with `letter`, alphabetically.
+pub fn tags_under(db: &Connection, letter: &str) -> rusqlite::Result<Vec<Tag>> {
+    Ok(filed(db, FILED_TAGS)?
+        .into_iter()
+        .filter(|(_, _, sort)| initial(sort) == letter)
+        .map(|(id, name, _)| Tag { id, name })
+        .collect())
+}
+
+/// The books whose title is filed under `letter`: their ids, as a JSON array for `json_each`.
+fn titled(db: &Connection, letter: &str) -> rusqlite::Result<Selection> {
+    let books: Vec<i32> = filed(db, FILED_BOOKS)?
+        .into_iter()
+        .filter(|(_, _, sort)| initial(sort) == letter)
+        .map(|(id, _, _)| id)
+        .collect();
+    let books = serde_json::to_string(&books).unwrap_or_else(|_| "[]".to_string());
+    Ok(Selection::of("b.id IN (SELECT value FROM json_each(?))", vec![Value::Text(books)]))
+}
+
+pub fn books_titled_page(
+    db: &Connection,
+    letter: &str,
+    facets: &Facets,
+    limit: usize,
+    offset: usize,
+) -> rusqlite::Result<Vec<Book>> {
+    shelf_page(db, titled(db, letter)?, facets, limit, offset)
+}
+
+pub fn count_books_titled(db: &Connection, letter: &str, facets: &Facets) -> rusqlite::Result<usize> {
+    count_shelf(db, titled(db, letter)?, facets)
+}
+
+// ------- Calibre's preferences -------
+
+/// One of the preferences Calibre keeps in the library, as JSON. A preference
+/// that was never set is the default; one Orca cannot read is logged and ignored.
+fn preference<T: DeserializeOwned + Default>(db: &Connection, key: &str) -> rusqlite::Result<T> {
+    let value: Option<String> = db
+        .query_row("SELECT val FROM preferences WHERE key = ?1;", params![key], |row| row.get(0))
+        .optional()?;
+    Ok(value
+        .and_then(|json| {
+            serde_json::from_str(&json)
+                .map_err(|e| eprintln!("Ignoring Calibre's preference '{}': {}", key, e))
+                .ok()
+        })
+        .unwrap_or_default())
+}
+
+/// A search Calibre keeps under a name: a virtual library, shown as if it were a
+/// library of its own, or a saved search.
+#[derive(Debug, Clone, Serialize)]
+pub struct NamedSearch {
+    pub name: String,
+    pub search: String,
+}
+
+fn named_searches(db: &Connection, key: &str) -> rusqlite::Result<Vec<NamedSearch>> {
+    let defined: BTreeMap<String, String> = preference(db, key)?;
+    let mut searches: Vec<NamedSearch> =
+        defined.into_iter().map(|(name, search)| NamedSearch { name, search }).collect();
+    searches.sort_by_key(|search| search.name.to_lowercase());
+    Ok(searches)
+}
+
+fn named_search(db: &Connection, key: &str, name: &str) -> rusqlite::Result<NamedSearch> {
+    named_searches(db, key)?
+        .into_iter()
+        .find(|search| search.name == name)
+        .ok_or(rusqlite::Error::QueryReturnedNoRows)
+}
+
+/// Every virtual library defined in Calibre, by name.
+pub fn virtual_libraries(db: &Connection) -> rusqlite::Result<Vec<NamedSearch>> {
+    named_searches(db, "virtual_libraries")
+}
+
+/// One virtual library, or `QueryReturnedNoRows` for a name Calibre does not know.
+pub fn virtual_library(db: &Connection, name: &str) -> rusqlite::Result<NamedSearch> {
+    named_search(db, "virtual_libraries", name)
+}
+
+/// Every search saved in Calibre, by name.
+pub fn saved_searches(db: &Connection) -> rusqlite::Result<Vec<NamedSearch>> {
+    named_searches(db, "saved_searches")
+}
+
+/// One saved search, or `QueryReturnedNoRows`.
+pub fn saved_search(db: &Connection, name: &str) -> rusqlite::Result<NamedSearch> {
+    named_search(db, "saved_searches", name)
+}
+
+/// What a user category can list: the kinds of category Calibre keeps them
+/// under, with the table, the link table and its column.
+const LISTABLE: [(&str, ListedKind, &str, &str, &str); 3] = [
+    ("authors", ListedKind::Author, "authors", "books_authors_link", "author"),
+    ("tags", ListedKind::Tag, "tags", "books_tags_link", "tag"),
+    ("series", ListedKind::Series, "series", "books_series_link", "series"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListedKind {
+    Author,
+    Tag,
+    Series,
+}
+
+/// An author, tag or series somebody put in a user category.
+#[derive(Debug)]
+pub struct Listed {
+    pub kind: ListedKind,
+    pub category: Category,
+}
+
+/// A category of the user's own making in Calibre: a hand-picked list of
+/// authors, tags and series, whatever books they have.
+#[derive(Debug)]
+pub struct UserCategory {
+    pub name: String,
+    pub listed: Vec<Listed>,
+}
+
+/// Every user category defined in Calibre, by name. What a category lists is
+/// looked up by name; anything the library has no books for is left out, and so
+/// is what Orca cannot browse -- publishers, say, or custom columns.
+pub fn user_categories(db: &Connection) -> rusqlite::Result<Vec<UserCategory>> {
+    // Calibre keeps each entry as `[name, category, 0]`
+    let defined: BTreeMap<String, Vec<(String, String, serde_json::Value)>> =
+        preference(db, "user_categories")?;
+
+    let mut categories = Vec::new();
+    for (name, entries) in defined {
+        let mut listed = Vec::new();
+        for (value, key, _) in entries {
+            let Some((_, kind, table, link, column)) = LISTABLE.iter().find(|listable| listable.0 == key) else {
+                continue;
+            };
+            let found = db
+                .query_row(
+                    &format!(
+                        "SELECT t.id, t.name, COUNT(l.book)
+                            FROM {0} t
+                            JOIN {1} l ON t.id = l.{2}
+                            WHERE t.name = ?1
+                            GROUP BY t.id;",
+                        table, link, column
+                    ),
+                    params![value],
+                    |row| {
+                        Ok(Category {
+                            id: row.get(0)?,
+                            name: row.get(1)?,
+                            books: row.get::<_, i64>(2)? as usize,
+                            sort: None,
+                        })
+                    },
+                )
+                .optional()?;
+            if let Some(category) = found {
+                listed.push(Listed { kind: *kind, category });
+            }
+        }
+        listed.sort_by_key(|listed| listed.category.name.to_lowercase());
+        categories.push(UserCategory { name, listed });
+    }
+    categories.sort_by_key(|category| category.name.to_lowercase());
+    Ok(categories)
+}
+
+/// Whether Calibre has a user category that lists anything Orca can browse,
+/// without looking up what: that is for where the categories are listed.
+pub fn has_user_categories(db: &Connection) -> rusqlite::Result<bool> {
+    let defined: BTreeMap<String, Vec<(String, String, serde_json::Value)>> =
+        preference(db, "user_categories")?;
+    Ok(defined
+        .values()
+        .flatten()
+        .any(|(_, key, _)| LISTABLE.iter().any(|listable| listable.0 == key)))
+}
+
+/// One user category, or `QueryReturnedNoRows`.
+pub fn user_category(db: &Connection, name: &str) -> rusqlite::Result<UserCategory> {
+    user_categories(db)?
+        .into_iter()
+        .find(|category| category.name == name)
+        .ok_or(rusqlite::Error::QueryReturnedNoRows)
+}
+
+/// The ids of every book a query matches, in no particular order.
+pub fn ids_matching(db: &Connection, query: &str) -> rusqlite::Result<Vec<i32>> {
+    let Some(search) = search(db, query)? else {
+        return Ok(Vec::new());
+    };
+    let mut stmt = db.prepare(&format!("SELECT b.id FROM books b WHERE {};", search.condition))?;
+    let ids = stmt.query_map(params_from_iter(search.params), |row| row.get(0))?;
+    ids.collect()
+}
+
+/// Every custom column of the library Orca can read, as Calibre defines it.
+/// A column Calibre was told to delete is gone as far as Orca is concerned.
+pub fn custom_columns(db: &Connection) -> rusqlite::Result<Vec<CustomColumn>> {
+    let mut stmt = db.prepare(
+        "SELECT id, label, name, datatype, is_multiple
+            FROM custom_columns
+            WHERE mark_for_delete = 0
+            ORDER BY id;",
+    )?;
+    let rows = stmt.query_map(params![], |row| {
+        Ok((
+            row.get::<_, i32>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, bool>(4)?,
+        ))
+    })?;
+    Ok(collect_rows(rows, "custom column")
+        .into_iter()
+        .filter_map(|(id, label, name, datatype, multiple)| {
+            Some(CustomColumn {
+                id,
+                label,
+                name,
+                kind: ColumnKind::of(&datatype)?,
+                multiple,
+            })
+        })
+        .collect())
+}
+
+/// What a category column calls one of its values. Ratings are kept as numbers.
+fn custom_name(column: &CustomColumn, value: String) -> String {
+    match column.kind {
+        ColumnKind::Rating => stars(value.parse().unwrap_or(0)),
+        _ => value,
+    }
+}
+
+/// Every value of a category column that a book has: alphabetically, the best
+/// rating first.
+pub fn custom_categories(db: &Connection, column: &CustomColumn) -> rusqlite::Result<Vec<Category>> {
+    let order = match column.kind {
+        ColumnKind::Rating => "v.value DESC",
+        _ => "v.value",
+    };
+    let mut categories = categories(
+        db,
+        &format!(
+            "SELECT v.id, CAST(v.value AS TEXT), COUNT(bc.book) AS books
+                FROM custom_column_{0} v
+                JOIN books_custom_column_{0}_link bc ON v.id = bc.value
+                GROUP BY v.id
+                ORDER BY {1};",
+            column.id, order
+        ),
+    )?;
+    for category in &mut categories {
+        category.name = custom_name(column, std::mem::take(&mut category.name));
+    }
+    Ok(categories)
+}
+
+/// How many of its values a category column has books for.
+pub fn count_custom_categories(db: &Connection, column: &CustomColumn) -> rusqlite::Result<usize> {
+    count(
+        db,
+        &format!("SELECT COUNT(DISTINCT value) FROM books_custom_column_{}_link;", column.id),
+        params![],
+    )
+}
+
+/// The name of one value of a category column, or `QueryReturnedNoRows`.
+pub fn custom_category_name(db: &Connection, column: &CustomColumn, id: i32) -> rusqlite::Result<String> {
+    db.query_row(
+        &format!("SELECT CAST(value AS TEXT) FROM custom_column_{} WHERE id = ?1;", column.id),
+        params![id],
+        |row| row.get(0),
+    )
+    .map(|value| custom_name(column, value))
+}
+
+/// A series column is read in order of its own index, like Calibre's series.
+fn custom_order(column: &CustomColumn) -> &'static str {
+    match column.kind {
+        ColumnKind::Series => "bc.extra, b.sort",
+        _ => "b.sort",
+    }
+}
+
+pub fn books_by_custom_page(
+    db: &Connection,
+    column: &CustomColumn,
+    id: i32,
+    facets: &Facets,
+    limit: usize,
+    offset: usize,
+) -> rusqlite::Result<Vec<Book>> {
+    shelf_page(db, by_custom(column, id).order(custom_order(column)), facets, limit, offset)
+}
+
+pub fn count_books_by_custom(db: &Connection, column: &CustomColumn, id: i32, facets: &Facets) -> rusqlite::Result<usize> {
+    count_shelf(db, by_custom(column, id), facets)
+}
+
+fn by_custom(column: &CustomColumn, id: i32) -> Selection {
+    Selection::of("bc.value = ?", vec![Value::from(id)])
+        .join(&format!("JOIN books_custom_column_{}_link bc ON b.id = bc.book", column.id))
+}
+
+
+/// What a book's cover is drawn from: the picture Calibre keeps of it, or for a
+/// book without one, what a placeholder says.
+#[derive(Debug)]
+pub struct Cover {
+    /// Relative to the library directory, `None` for a book without a cover.
+    pub path: Option<String>,
+    /// When Calibre last changed the book. Replacing the cover is one of those
+    /// changes, so this tells a thumbnail of the old cover from one of the new.
+    pub modified: String,
+    pub uuid: String,
+    pub title: String,
+    /// All of them, in the order Calibre lists them.
+    pub authors: String,
+}
+
+/// A book's cover, or `QueryReturnedNoRows` for a book the library does not hold.
+pub fn cover(db: &Connection, book: i32) -> rusqlite::Result<Cover> {
+    db.query_row(
+        "SELECT b.path, b.has_cover, b.last_modified, b.uuid, b.title,
+            (SELECT GROUP_CONCAT(name, ' & ') FROM (SELECT a.name FROM books_authors_link ba
+                JOIN authors a ON ba.author = a.id WHERE ba.book = b.id ORDER BY ba.id))
+            FROM books b WHERE b.id = ?1;",
+        params![book],
+        |row| {
+            Ok(Cover {
+                path: match row.get::<_, bool>(1)? {
+                    true => Some(format!("{}/cover.jpg", row.get::<_, String>(0)?)),
+                    false => None,
+                },
+                modified: row.get(2)?,
+                uuid: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                title: row.get(4)?,
+                authors: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+            })
+        },
+    )
+}
+
+/// Where one format of a book lives, relative to the library directory.
+/// Calibre files every format of a book under the same stem, so the format only
+/// decides the extension.
+pub fn file_path(db: &Connection, book: i32, format: &str) -> rusqlite::Result<String> {
+    let mut stmt = db.prepare(
+        "SELECT b.path, d.name AS file
+            FROM books b
+            JOIN data d ON b.id = d.book
+            WHERE b.id = ?1 AND d.format = ?2 COLLATE NOCASE;",
+    )?;
+    let (path, file): (String, String) =
+        stmt.query_row(params![book, format], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    Ok(format!("{}/{}.{}", path, file, format))
+}
+
+/// The columns every book feed needs.
+/// `GROUP_CONCAT` : a client should not see the download links shuffle between requests
+const BOOK_COLUMNS: &str = "b.id, b.uuid, b.title, b.pubdate, b.last_modified, b.has_cover, b.series_index, c.text AS synopsis,
+    (SELECT GROUP_CONCAT(format)
+        FROM (SELECT format FROM data WHERE book = b.id ORDER BY format)) AS formats,
+    (SELECT s.id FROM books_series_link bs JOIN series s ON bs.series = s.id
+        WHERE bs.book = b.id) AS series_id,
+    (SELECT s.name FROM books_series_link bs JOIN series s ON bs.series = s.id
+        WHERE bs.book = b.id) AS series,
+    (SELECT p.name FROM books_publishers_link bp JOIN publishers p ON bp.publisher = p.id
+        WHERE bp.book = b.id) AS publisher,
+    (SELECT r.rating FROM books_ratings_link br JOIN ratings r ON br.rating = r.id
+        WHERE br.book = b.id) AS rating";
+
+/// Run one of the book queries and map its rows to `Book`s.
+fn query_books(
+    db: &Connection,
+    sql: &str,
+    params: &[&dyn rusqlite::ToSql],
+) -> rusqlite::Result<Vec<Book>> {
     let mut stmt = db.prepare(sql)?;
     let rows = stmt.query_map(params, |row| {
         let formats = parse_formats(&row.get::<_, String>("formats").unwrap_or_default());
@@ -499,6 +2080,7 @@ fn query_books(
             .get::<_, Option<String>>("series")
             .unwrap_or(None)
             .map(|name| Series {
+                id: row.get("series_id").unwrap_or_default(),
                 name,
                 index: row.get("series_index").unwrap_or(1.0),
             });
@@ -516,6 +2098,11 @@ fn query_books(
             tags: Vec::new(),
             publisher: row.get("publisher").unwrap_or(None),
             series,
+            identifiers: Vec::new(),
+            rating: row.get::<_, Option<i64>>("rating").unwrap_or(None).and_then(Rating::of),
+            custom: Vec::new(),
+            related: Related::default(),
+            progress: None,
         })
     })?;
 
@@ -524,10 +2111,12 @@ fn query_books(
     let mut authors = authors_by_book(db, &book_ids)?;
     let mut languages = languages_by_book(db, &book_ids)?;
     let mut tags = tags_by_book(db, &book_ids)?;
+    let mut identifiers = identifiers_by_book(db, &book_ids)?;
     for book in &mut books {
         book.authors = authors.remove(&book.id).unwrap_or_default();
         book.languages = languages.remove(&book.id).unwrap_or_default();
         book.tags = tags.remove(&book.id).unwrap_or_default();
+        book.identifiers = identifiers.remove(&book.id).unwrap_or_default();
     }
     Ok(books)
 }
@@ -587,29 +2176,250 @@ fn tags_by_book(db: &Connection, book_ids: &[i32]) -> rusqlite::Result<HashMap<i
         ))
     })?;
 
-    Ok(group_by_book(collect_rows(rows, "book tag")))
+    Ok(group_by_book(collect_rows(rows, "book tag")))
+}
+
+/// The languages of each of the given books, by book id.
+fn languages_by_book(db: &Connection, book_ids: &[i32]) -> rusqlite::Result<HashMap<i32, Vec<String>>> {
+    if book_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let mut stmt = db.prepare(&format!(
+        "SELECT bl.book, l.lang_code
+            FROM books_languages_link bl
+            JOIN languages l ON bl.lang_code = l.id
+            WHERE bl.book IN ({})
+            ORDER BY bl.book, bl.item_order;",
+        placeholders(book_ids.len())
+    ))?;
+
+    let rows = stmt.query_map(params_from_iter(book_ids), |row| {
+        Ok((row.get::<_, i32>(0)?, bcp47(&row.get::<_, String>(1)?)))
+    })?;
+
+    Ok(group_by_book(collect_rows(rows, "book language")))
+}
+
+/// The identifiers of each of the given books, by book id.
+fn identifiers_by_book(db: &Connection, book_ids: &[i32]) -> rusqlite::Result<HashMap<i32, Vec<Identifier>>> {
+    if book_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let mut stmt = db.prepare(&format!(
+        "SELECT book, type, val
+            FROM identifiers
+            WHERE book IN ({})
+            ORDER BY book, type;",
+        placeholders(book_ids.len())
+    ))?;
+
+    let rows = stmt.query_map(params_from_iter(book_ids), |row| {
+        // `type` is case-insensitive in Calibre's schema
+        let scheme = row.get::<_, String>(1)?.to_lowercase();
+        let value = row.get::<_, String>(2)?.trim().to_string();
+        Ok((
+            row.get::<_, i32>(0)?,
+            Identifier {
+                uri: identifier_uri(&scheme, &value),
+                scheme,
+                value,
+            },
+        ))
+    })?;
+
+    Ok(group_by_book(collect_rows(rows, "book identifier")))
+}
+
+/// The neighbours of each of the given books, by book id. Four queries for the
+/// whole page rather than four per book; a volume never shows up again as
+/// "more by the same author".
+fn related_by_book(db: &Connection, book_ids: &[i32]) -> rusqlite::Result<HashMap<i32, Related>> {
+    if book_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let mut previous = neighbours(db, SERIES_BEFORE, book_ids)?;
+    let mut next = neighbours(db, SERIES_AFTER, book_ids)?;
+    let mut by_authors = neighbours(db, BY_SAME_AUTHORS, book_ids)?;
+    let mut sharing_tags = neighbours(db, SHARING_TAGS, book_ids)?;
+
+    Ok(book_ids
+        .iter()
+        .map(|&id| {
+            let previous = previous.remove(&id).and_then(|mut found| found.pop());
+            let next = next.remove(&id).and_then(|mut found| found.pop());
+            let mut listed: HashSet<i32> = previous.iter().chain(&next).map(|book| book.id).collect();
+            let mut unlisted = |found: Option<Vec<Neighbour>>| -> Vec<Neighbour> {
+                found
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|book| listed.insert(book.id))
+                    .collect()
+            };
+            let by_authors = unlisted(by_authors.remove(&id));
+            let sharing_tags = unlisted(sharing_tags.remove(&id));
+            (id, Related { previous, next, by_authors, sharing_tags })
+        })
+        .collect())
+}
+
+/// One of the queries below, which find a book's neighbours as `book, id, title` rows.
+fn neighbours(db: &Connection, sql: &str, book_ids: &[i32]) -> rusqlite::Result<HashMap<i32, Vec<Neighbour>>> {
+    let sql = sql
+        .replace("{ids}", &placeholders(book_ids.len()))
+        .replace("{limit}", &RELATED.to_string());
+    let mut stmt = db.prepare(&sql)?;
+    let rows = stmt.query_map(params_from_iter(book_ids), |row| {
+        Ok((
+            row.get::<_, i32>(0)?,
+            Neighbour {
+                id: row.get(1)?,
+                title: row.get(2)?,
+            },
+        ))
+    })?;
+    Ok(group_by_book(collect_rows(rows, "related book")))
+}
+
+/// The volume right before a book in its series.
+const SERIES_BEFORE: &str = "SELECT bs.book, n.id, n.title
+    FROM books_series_link bs
+    JOIN books b ON b.id = bs.book
+    JOIN books n ON n.id = (SELECT o.id FROM books_series_link os JOIN books o ON o.id = os.book
+        WHERE os.series = bs.series AND o.series_index < b.series_index
+        ORDER BY o.series_index DESC, o.sort DESC LIMIT 1)
+    WHERE bs.book IN ({ids});";
+
+/// The volume right after a book in its series.
+const SERIES_AFTER: &str = "SELECT bs.book, n.id, n.title
+    FROM books_series_link bs
+    JOIN books b ON b.id = bs.book
+    JOIN books n ON n.id = (SELECT o.id FROM books_series_link os JOIN books o ON o.id = os.book
+        WHERE os.series = bs.series AND o.series_index > b.series_index
+        ORDER BY o.series_index, o.sort LIMIT 1)
+    WHERE bs.book IN ({ids});";
+
+/// Other books by any of a book's authors, the oldest first.
+const BY_SAME_AUTHORS: &str = "SELECT book, id, title FROM (
+        SELECT ba.book, o.id, o.title,
+            ROW_NUMBER() OVER (PARTITION BY ba.book ORDER BY o.pubdate, o.sort) AS n
+            FROM books_authors_link ba
+            JOIN books_authors_link oa ON oa.author = ba.author AND oa.book != ba.book
+            JOIN books o ON o.id = oa.book
+            WHERE ba.book IN ({ids})
+            GROUP BY ba.book, o.id)
+        WHERE n <= {limit}
+        ORDER BY book, n;";
+
+/// The books that share the most tags with a book.
+const SHARING_TAGS: &str = "SELECT book, id, title FROM (
+        SELECT bt.book, o.id, o.title,
+            ROW_NUMBER() OVER (PARTITION BY bt.book ORDER BY COUNT(*) DESC, o.sort) AS n
+            FROM books_tags_link bt
+            JOIN books_tags_link ot ON ot.tag = bt.tag AND ot.book != bt.book
+            JOIN books o ON o.id = ot.book
+            WHERE bt.book IN ({ids})
+            GROUP BY bt.book, o.id)
+        WHERE n <= {limit}
+        ORDER BY book, n;";
+
+/// The books of any of the queries above, with what the given custom columns
+/// say about them. `BOOK_COLUMNS` stays the same for every library, so the
+/// columns a library exposes are read in a query of their own, one per column.
+pub fn with_custom(
+    db: &Connection,
+    columns: &[CustomColumn],
+    mut books: Vec<Book>,
+) -> rusqlite::Result<Vec<Book>> {
+    let book_ids: Vec<i32> = books.iter().map(|book| book.id).collect();
+    for column in columns {
+        let mut values = custom_by_book(db, column, &book_ids)?;
+        for book in &mut books {
+            if let Some(values) = values.remove(&book.id) {
+                book.custom.push(CustomField {
+                    label: column.label.clone(),
+                    name: column.name.clone(),
+                    multiple: column.multiple,
+                    text: values.iter().map(CustomValue::text).collect::<Vec<_>>().join(", "),
+                    values,
+                });
+            }
+        }
+    }
+    Ok(books)
+}
+
+/// The books of any of the queries above, each with the books it leads on to.
+/// Only for where the links to them are shown: it takes four queries more.
+pub fn with_related(db: &Connection, mut books: Vec<Book>) -> rusqlite::Result<Vec<Book>> {
+    let book_ids: Vec<i32> = books.iter().map(|book| book.id).collect();
+    let mut related = related_by_book(db, &book_ids)?;
+    for book in &mut books {
+        book.related = related.remove(&book.id).unwrap_or_default();
+    }
+    Ok(books)
 }
 
-/// The languages of each of the given books, by book id.
-fn languages_by_book(db: &Connection, book_ids: &[i32]) -> rusqlite::Result<HashMap<i32, Vec<String>>> {
+/// The values of one custom column for each of the given books, by book id.
+/// A category column links books to its values the way `books_tags_link` does;
+/// any other column keeps one value per book in its own table.
+fn custom_by_book(
+    db: &Connection,
+    column: &CustomColumn,
+    book_ids: &[i32],
+) -> rusqlite::Result<HashMap<i32, Vec<CustomValue>>> {
     if book_ids.is_empty() {
         return Ok(HashMap::new());
     }
 
-    let mut stmt = db.prepare(&format!(
-        "SELECT bl.book, l.lang_code
-            FROM books_languages_link bl
-            JOIN languages l ON bl.lang_code = l.id
-            WHERE bl.book IN ({})
-            ORDER BY bl.book, bl.item_order;",
-        placeholders(book_ids.len())
-    ))?;
+    let sql = match column.is_category() {
+        true => format!(
+            "SELECT bc.book, v.id, CAST(v.value AS TEXT), {}
+                FROM books_custom_column_{}_link bc
+                JOIN custom_column_{} v ON bc.value = v.id
+                WHERE bc.book IN ({})
+                ORDER BY bc.book, bc.id;",
+            match column.kind {
+                ColumnKind::Series => "bc.extra",
+                _ => "NULL",
+            },
+            column.id,
+            column.id,
+            placeholders(book_ids.len())
+        ),
+        false => format!(
+            "SELECT book, value
+                FROM custom_column_{}
+                WHERE book IN ({});",
+            column.id,
+            placeholders(book_ids.len())
+        ),
+    };
 
+    let mut stmt = db.prepare(&sql)?;
     let rows = stmt.query_map(params_from_iter(book_ids), |row| {
-        Ok((row.get::<_, i32>(0)?, bcp47(&row.get::<_, String>(1)?)))
+        let value = match column.kind {
+            ColumnKind::Text | ColumnKind::Series | ColumnKind::Enumeration | ColumnKind::Rating => {
+                CustomValue::Category {
+                    id: row.get(1)?,
+                    name: custom_name(column, row.get(2)?),
+                    index: row.get(3)?,
+                }
+            }
+            ColumnKind::Date => CustomValue::Text(to_rfc3339(&row.get::<_, String>(1)?)),
+            ColumnKind::Comments => {
+                CustomValue::Text(plain_text(&row.get::<_, String>(1)?, UNWRAPPED).trim().to_string())
+            }
+            ColumnKind::Bool => CustomValue::Bool(row.get(1)?),
+            ColumnKind::Int => CustomValue::Integer(row.get(1)?),
+            ColumnKind::Float => CustomValue::Number(row.get(1)?),
+        };
+        Ok((row.get::<_, i32>(0)?, value))
     })?;
 
-    Ok(group_by_book(collect_rows(rows, "book language")))
+    Ok(group_by_book(collect_rows(rows, "custom column value")))
 }
 
 fn placeholders(count: usize) -> String {
@@ -628,8 +2438,15 @@ fn group_by_book<T>(rows: Vec<(i32, T)>) -> HashMap<i32, Vec<T>> {
 mod tests {
     use super::*;
 
+    fn quote(said: &str) -> Quote {
+        Quote::new(said.to_string())
+    }
+
     fn library() -> Connection {
-        Connection::open("tests/calibre/metadata.db").expect("test library")
+        let db = Connection::open("tests/calibre/metadata.db").expect("test library");
+        fulltext::attach(&db, ":memory:").expect("a search index");
+        fulltext::refresh(&db).expect("the search index");
+        db
     }
 
     #[test]
@@ -658,10 +2475,19 @@ mod tests {
         assert_eq!(bcp47("foobar"), "foobar");
     }
 
+    #[test]
+    fn a_language_goes_by_its_own_name() {
+        assert_eq!(autonym("deu"), "Deutsch");
+        assert_eq!(autonym("rus"), "русский");
+        assert_eq!(autonym("eng"), "English");
+        // Typed into Calibre by hand: nothing to translate.
+        assert_eq!(autonym("foobar"), "foobar");
+    }
+
     #[test]
     fn books_carry_their_language() {
         let db = library();
-        let books = books(&db).expect("books");
+        let books = books_page(&db, &Facets::NONE, 50, 0).expect("books");
 
         let kant = books.iter().find(|book| book.id == 5).expect("Kant");
         assert_eq!(kant.languages, ["de"]);
@@ -690,7 +2516,7 @@ mod tests {
     #[test]
     fn books_carry_every_format_the_library_holds() {
         let db = library();
-        let books = books(&db).expect("books");
+        let books = books_page(&db, &Facets::NONE, 50, 0).expect("books");
 
         let alice = books.iter().find(|book| book.id == 4).expect("Alice");
         assert_eq!(alice.formats, ["azw3", "epub"]);
@@ -703,14 +2529,14 @@ mod tests {
         let db = library();
         let ids = |books: Vec<Book>| books.iter().map(|book| book.id).collect::<Vec<_>>();
 
-        assert_eq!(count_books(&db).expect("count"), 7);
-        assert_eq!(ids(books_page(&db, 2, 0).expect("first page")), [4, 8]);
-        assert_eq!(ids(books_page(&db, 2, 2).expect("second page")), [9, 5]);
+        assert_eq!(count_books(&db, &Facets::NONE).expect("count"), 7);
+        assert_eq!(ids(books_page(&db, &Facets::NONE, 2, 0).expect("first page")), [4, 8]);
+        assert_eq!(ids(books_page(&db, &Facets::NONE, 2, 2).expect("second page")), [9, 5]);
         // Kant sorts under K, but Galileo under "sidereal messenger, The".
-        assert_eq!(ids(books_page(&db, 2, 4).expect("third page")), [6, 7]);
+        assert_eq!(ids(books_page(&db, &Facets::NONE, 2, 4).expect("third page")), [6, 7]);
         // Seven books, pages of two: the last one holds the remainder.
-        assert_eq!(ids(books_page(&db, 2, 6).expect("last page")), [2]);
-        assert!(books_page(&db, 10, 7).expect("past the end").is_empty());
+        assert_eq!(ids(books_page(&db, &Facets::NONE, 2, 6).expect("last page")), [2]);
+        assert!(books_page(&db, &Facets::NONE, 10, 7).expect("past the end").is_empty());
     }
 
     #[test]
@@ -726,7 +2552,7 @@ mod tests {
 
         let science_fiction = tags.iter().find(|tag| tag.name == "science fiction").expect("a tag");
         assert_eq!(science_fiction.books, 4);
-        assert_eq!(count_books_by_tag(&db, science_fiction.id).expect("count"), 4);
+        assert_eq!(count_books_by_tag(&db, science_fiction.id, &Facets::NONE).expect("count"), 4);
     }
 
     // Calibre's author order (not alphabetical): Толстой is last.
@@ -760,12 +2586,289 @@ mod tests {
         let ids = |books: Vec<Book>| books.iter().map(|book| book.id).collect::<Vec<_>>();
 
         // The four science fiction books, in the library's own order.
-        assert_eq!(count_books_by_tag(&db, 9).expect("count"), 4);
-        assert_eq!(ids(books_by_tag_page(&db, 9, 2, 0).expect("first page")), [8, 9]);
-        assert_eq!(ids(books_by_tag_page(&db, 9, 2, 2).expect("second page")), [7, 2]);
+        assert_eq!(count_books_by_tag(&db, 9, &Facets::NONE).expect("count"), 4);
+        assert_eq!(ids(books_by_tag_page(&db, 9, &Facets::NONE, 2, 0).expect("first page")), [8, 9]);
+        assert_eq!(ids(books_by_tag_page(&db, 9, &Facets::NONE, 2, 2).expect("second page")), [7, 2]);
+
+        assert_eq!(count_books_by_author(&db, 4, &Facets::NONE).expect("count"), 1);
+        assert_eq!(ids(books_by_author_page(&db, 4, &Facets::NONE, 50, 0).expect("Carroll")), [4]);
+    }
+
+    #[test]
+    fn a_name_is_filed_under_its_first_letter_in_any_alphabet() {
+        assert_eq!(initial("Lovecraft, H. P."), "L");
+        assert_eq!(initial("sidereal messenger of Galileo Galilei, The"), "S");
+        assert_eq!(initial("Толстой, Алексей Николаевич"), "Т");
+        assert_eq!(initial("élan vital"), "É");
+        assert_eq!(initial("1984"), NOT_A_LETTER);
+        assert_eq!(initial("'Salem's Lot"), NOT_A_LETTER);
+        assert_eq!(initial(""), NOT_A_LETTER);
+    }
+
+    #[test]
+    fn an_index_counts_what_is_filed_under_each_letter() {
+        let db = library();
+        let letters = |letters: Vec<(String, usize)>| {
+            letters.into_iter().map(|(letter, count)| format!("{}{}", letter, count)).collect::<Vec<_>>()
+        };
+
+        assert_eq!(letters(title_letters(&db).expect("titles")), ["A2", "G1", "K1", "S1", "V1", "Г1"]);
+        assert_eq!(letters(author_letters(&db).expect("authors")), ["C1", "G1", "K2", "L1", "S1", "W1", "Т1"]);
+        assert_eq!(letters(tag_letters(&db).expect("tags")), ["C1", "F2", "H1", "P1", "S2"]);
+
+        let names = |authors: Vec<Author>| authors.into_iter().map(|author| author.name).collect::<Vec<_>>();
+        assert_eq!(names(authors_under(&db, "K").expect("authors")), ["Immanuel Kant", "Johannes Kepler"]);
+        let tags = tags_under(&db, "S").expect("tags");
+        assert_eq!(tags.into_iter().map(|tag| tag.name).collect::<Vec<_>>(), ["science fiction", "space opera"]);
+
+        let ids = |books: Vec<Book>| books.iter().map(|book| book.id).collect::<Vec<_>>();
+        assert_eq!(count_books_titled(&db, "A", &Facets::NONE).expect("count"), 2);
+        assert_eq!(ids(books_titled_page(&db, "A", &Facets::NONE, 50, 0).expect("books")), [4, 8]);
+        assert!(books_titled_page(&db, "Q", &Facets::NONE, 50, 0).expect("books").is_empty());
+    }
+
+    // The Atom lists of authors and tags page in the same order as the shelves.
+    #[test]
+    fn authors_and_tags_are_paged_like_the_library() {
+        let db = library();
+
+        assert_eq!(count_authors(&db).expect("count"), 8);
+        let names = |authors: Vec<Author>| authors.into_iter().map(|author| author.name).collect::<Vec<_>>();
+        assert_eq!(names(authors_page(&db, 2, 0).expect("first page")), ["Lewis Carroll", "Galileo Galilei"]);
+        assert_eq!(names(authors_page(&db, 2, 6).expect("last page")).len(), 2);
+
+        assert_eq!(count_tags(&db).expect("count"), 7);
+        let names = |tags: Vec<Tag>| tags.into_iter().map(|tag| tag.name).collect::<Vec<_>>();
+        assert_eq!(names(tags_page(&db, 3, 0).expect("first page")), ["children", "fantasy", "fiction"]);
+        assert_eq!(names(tags_page(&db, 3, 6).expect("last page")), ["space opera"]);
+    }
+
+    // Calibre's sort title would put "At the Mountains" first; a series is read
+    // in the order its volumes were numbered.
+    #[test]
+    fn a_series_is_read_volume_by_volume() {
+        let db = library();
+        let ids = |books: Vec<Book>| books.iter().map(|book| book.id).collect::<Vec<_>>();
+
+        let series = series_with_books(&db).expect("series");
+        assert_eq!(series.len(), 1);
+        assert_eq!(series[0].name, "Astounding Stories");
+        assert_eq!(series[0].books, 3);
+
+        assert_eq!(count_books_in_series(&db, series[0].id, &Facets::NONE).expect("count"), 3);
+        assert_eq!(ids(books_in_series_page(&db, series[0].id, &Facets::NONE, 50, 0).expect("volumes")), [7, 8, 9]);
+        assert_eq!(ids(books_in_series_page(&db, series[0].id, &Facets::NONE, 2, 0).expect("first page")), [7, 8]);
+        assert_eq!(ids(books_in_series_page(&db, series[0].id, &Facets::NONE, 2, 2).expect("second page")), [9]);
+    }
+
+    #[test]
+    fn the_library_can_be_sorted_by_publisher_and_by_language() {
+        let db = library();
+        let ids = |books: Vec<Book>| books.iter().map(|book| book.id).collect::<Vec<_>>();
+        let named = |categories: &[Category]| {
+            categories
+                .iter()
+                .map(|category| (category.name.clone(), category.books))
+                .collect::<Vec<_>>()
+        };
+
+        let publishers = publishers_with_books(&db).expect("publishers");
+        assert_eq!(
+            named(&publishers),
+            [
+                ("Gutenberg Project".to_string(), 2),
+                ("Projekt Gutenberg".to_string(), 1),
+                ("Street & Smith".to_string(), 3),
+            ]
+        );
+        assert_eq!(count_books_by_publisher(&db, publishers[2].id, &Facets::NONE).expect("count"), 3);
+        assert_eq!(ids(books_by_publisher_page(&db, publishers[2].id, &Facets::NONE, 50, 0).expect("books")), [8, 9, 7]);
+
+        let languages = languages_with_books(&db).expect("languages");
+        assert_eq!(
+            named(&languages),
+            [
+                ("Deutsch".to_string(), 1),
+                ("English".to_string(), 5),
+                ("русский".to_string(), 1),
+            ]
+        );
+        assert_eq!(language_name(&db, languages[0].id).expect("a language"), "Deutsch");
+        assert_eq!(count_books_by_language(&db, languages[1].id, &Facets::NONE).expect("count"), 5);
+        assert_eq!(ids(books_by_language_page(&db, languages[0].id, &Facets::NONE, 50, 0).expect("books")), [5]);
+        assert_eq!(books_by_language_page(&db, languages[1].id, &Facets::NONE, 50, 0).expect("books").len(), 5);
+    }
+
+    fn column(db: &Connection, label: &str) -> CustomColumn {
+        custom_columns(db)
+            .expect("custom columns")
+            .into_iter()
+            .find(|column| column.label == label)
+            .expect("a custom column")
+    }
+
+    #[test]
+    fn custom_columns_are_read_as_calibre_defines_them() {
+        let db = library();
+        let columns = custom_columns(&db).expect("custom columns");
+
+        let kinds: Vec<(&str, ColumnKind)> =
+            columns.iter().map(|column| (column.label.as_str(), column.kind)).collect();
+        assert_eq!(
+            kinds,
+            [
+                ("translator", ColumnKind::Text),
+                ("read", ColumnKind::Bool),
+                ("shelf", ColumnKind::Enumeration),
+                ("finished", ColumnKind::Date),
+                ("review", ColumnKind::Comments),
+                ("saga", ColumnKind::Series),
+                ("stars", ColumnKind::Rating),
+            ]
+        );
+        assert!(column(&db, "translator").multiple);
+        assert!(column(&db, "shelf").is_category());
+        assert!(!column(&db, "read").is_category());
+    }
+
+    #[test]
+    fn books_carry_their_rating() {
+        let db = library();
+        let rating = |id: i32| book(&db, id).expect("a book").rating.map(|rating| rating.stars);
+
+        assert_eq!(rating(4), Some(5.0));
+        assert_eq!(rating(8), Some(3.5));
+        assert_eq!(book(&db, 8).expect("Mountains").rating.expect("a rating").text, "★★★½");
+        // Nobody rated Tolstoy.
+        assert_eq!(rating(2), None);
+        assert!(Rating::of(0).is_none());
+    }
+
+    // Two books rated four stars: the library's order decides between them.
+    #[test]
+    fn the_best_rated_come_first() {
+        let db = library();
+        let ids = |books: Vec<Book>| books.iter().map(|book| book.id).collect::<Vec<_>>();
+
+        assert_eq!(count_top_rated(&db, &Facets::NONE).expect("count"), 5);
+        assert_eq!(ids(top_rated_page(&db, &Facets::NONE, 50, 0).expect("top rated")), [4, 9, 5, 8, 7]);
+        assert_eq!(ids(top_rated_page(&db, &Facets::NONE, 2, 2).expect("second page")), [5, 8]);
+
+        let levels = ratings_with_books(&db).expect("ratings");
+        let named: Vec<(i32, &str, usize)> =
+            levels.iter().map(|level| (level.id, level.name.as_str(), level.books)).collect();
+        assert_eq!(named, [(10, "★★★★★", 1), (8, "★★★★", 2), (7, "★★★½", 1), (4, "★★", 1)]);
+
+        assert_eq!(rating_name(&db, 8).expect("a rating"), "★★★★");
+        assert_eq!(count_books_by_rating(&db, 8, &Facets::NONE).expect("count"), 2);
+        assert_eq!(ids(books_by_rating_page(&db, 8, &Facets::NONE, 50, 0).expect("books")), [9, 5]);
+        assert_eq!(ids(books_by_rating_page(&db, 8, &Facets::NONE, 1, 1).expect("second page")), [5]);
+        // Nothing was rated two and a half stars.
+        assert!(matches!(rating_name(&db, 5), Err(rusqlite::Error::QueryReturnedNoRows)));
+    }
+
+    #[test]
+    fn a_rating_is_shown_in_stars() {
+        assert_eq!(stars(8), "★★★★");
+        assert_eq!(stars(7), "★★★½");
+        assert_eq!(stars(0), "");
+    }
+
+    #[test]
+    fn an_isbn_is_written_the_way_a_urn_wants_it() {
+        assert_eq!(isbn("978-0-14-143976-1"), "9780141439761");
+        assert_eq!(isbn("0 14 143976 x"), "014143976X");
+        assert_eq!(identifier_uri("isbn", "978-0-14-143976-1").as_deref(), Some("urn:isbn:9780141439761"));
+        assert_eq!(identifier_uri("doi", "10.1000/182").as_deref(), Some("https://doi.org/10.1000/182"));
+        // No agreed way to write it as a URI, so none is made up.
+        assert_eq!(identifier_uri("mobi-asin", "B004TS0T1S"), None);
+    }
+
+    #[test]
+    fn books_carry_their_identifiers() {
+        let db = library();
+        let alice = book(&db, 4).expect("Alice");
+
+        let schemes: Vec<&str> = alice.identifiers.iter().map(|identifier| identifier.scheme.as_str()).collect();
+        assert_eq!(schemes, ["goodreads", "isbn", "mobi-asin"]);
+        assert_eq!(alice.isbn(), Some("urn:isbn:9780141439761"));
+        assert_eq!(alice.identifiers[2].value, "B004TS0T1S");
+
+        let kant = book(&db, 5).expect("Kant");
+        assert_eq!(kant.isbn(), None);
+        assert_eq!(
+            kant.identifiers[0].uri.as_deref(),
+            Some("https://www.projekt-gutenberg.org/kant/krvb/krvb.html")
+        );
+    }
+
+    // Only the columns asked for, and only where a book has a value.
+    #[test]
+    fn a_book_carries_what_its_custom_columns_say() {
+        let db = library();
+        let columns = [column(&db, "read"), column(&db, "shelf"), column(&db, "finished"), column(&db, "review")];
+        let books = with_custom(&db, &columns, books_page(&db, &Facets::NONE, 50, 0).expect("books")).expect("custom values");
+        let custom = |id: i32| {
+            books
+                .iter()
+                .find(|book| book.id == id)
+                .expect("a book")
+                .custom
+                .iter()
+                .map(|field| (field.name.as_str(), field.text.as_str()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            custom(4),
+            [
+                ("Read status", "Yes"),
+                ("Shelf location", "Living room"),
+                ("Finished", "2025-03-14T18:30:00+00:00"),
+                // Calibre keeps a comment as HTML, like the blurb.
+                ("Review", "Curiouser & curiouser!"),
+            ]
+        );
+        assert_eq!(custom(5), [("Read status", "No"), ("Shelf location", "Study")]);
+        assert!(custom(9).is_empty());
+    }
+
+    #[test]
+    fn a_series_column_knows_the_volume() {
+        let db = library();
+        let saga = column(&db, "saga");
+        let patrol = with_custom(&db, &[saga], vec![book(&db, 9).expect("Galactic Patrol")]).expect("custom values");
 
-        assert_eq!(count_books_by_author(&db, 4).expect("count"), 1);
-        assert_eq!(ids(books_by_author_page(&db, 4, 50, 0).expect("Carroll")), [4]);
+        assert_eq!(patrol[0].custom[0].text, "Lensman [1]");
+        assert!(matches!(
+            patrol[0].custom[0].values[..],
+            [CustomValue::Category { index: Some(index), .. }] if index == 1.0
+        ));
+    }
+
+    #[test]
+    fn a_category_column_is_a_way_into_the_library() {
+        let db = library();
+        let ids = |books: Vec<Book>| books.iter().map(|book| book.id).collect::<Vec<_>>();
+        let shelf = column(&db, "shelf");
+
+        let places = custom_categories(&db, &shelf).expect("shelf locations");
+        let named: Vec<(&str, usize)> = places.iter().map(|place| (place.name.as_str(), place.books)).collect();
+        assert_eq!(named, [("Living room", 2), ("Study", 2)]);
+        assert_eq!(count_custom_categories(&db, &shelf).expect("count"), 2);
+
+        let study = places[1].id;
+        assert_eq!(custom_category_name(&db, &shelf, study).expect("a value"), "Study");
+        assert_eq!(count_books_by_custom(&db, &shelf, study, &Facets::NONE).expect("count"), 2);
+        assert_eq!(ids(books_by_custom_page(&db, &shelf, study, &Facets::NONE, 50, 0).expect("books")), [5, 6]);
+        assert_eq!(ids(books_by_custom_page(&db, &shelf, study, &Facets::NONE, 1, 1).expect("second page")), [6]);
+
+        let stars = column(&db, "stars");
+        assert_eq!(custom_categories(&db, &stars).expect("ratings")[0].name, "★★★★");
+        assert!(matches!(
+            custom_category_name(&db, &shelf, 99999),
+            Err(rusqlite::Error::QueryReturnedNoRows)
+        ));
     }
 
     // A feed titled after an author who is not there would have no title.
@@ -782,6 +2885,18 @@ mod tests {
             tag_name(&db, 99999),
             Err(rusqlite::Error::QueryReturnedNoRows)
         ));
+        assert!(matches!(
+            series_name(&db, 99999),
+            Err(rusqlite::Error::QueryReturnedNoRows)
+        ));
+        assert!(matches!(
+            publisher_name(&db, 99999),
+            Err(rusqlite::Error::QueryReturnedNoRows)
+        ));
+        assert!(matches!(
+            language_name(&db, 99999),
+            Err(rusqlite::Error::QueryReturnedNoRows)
+        ));
     }
 
     #[test]
@@ -847,15 +2962,31 @@ mod tests {
     fn a_missing_cover_is_no_rows_rather_than_an_error() {
         let db = library();
         assert!(matches!(
-            cover_path(&db, 99999),
+            cover(&db, 99999),
             Err(rusqlite::Error::QueryReturnedNoRows)
         ));
     }
 
+    // Calibre may not have a picture; the placeholder needs the rest all the same.
+    #[test]
+    fn a_book_without_a_cover_still_has_what_a_placeholder_says() {
+        let db = library();
+        db.execute_batch(
+            "CREATE TEMP TABLE books AS SELECT * FROM main.books;
+             UPDATE temp.books SET has_cover = 0 WHERE id = 6;",
+        )
+        .unwrap();
+        let galileo = cover(&db, 6).expect("The sidereal messenger");
+        assert!(galileo.path.is_none());
+        assert_eq!(galileo.title, "The sidereal messenger of Galileo Galilei");
+        assert_eq!(galileo.authors.matches(" & ").count(), 1);
+        assert!(cover(&db, 5).expect("Kritik").path.is_some());
+    }
+
     #[test]
     fn paths_are_relative_to_the_library() {
         let db = library();
-        assert!(cover_path(&db, 5).expect("cover").ends_with("/cover.jpg"));
+        assert!(cover(&db, 5).expect("cover").path.expect("a cover").ends_with("/cover.jpg"));
         assert!(file_path(&db, 5, "epub").expect("file").ends_with(".epub"));
         // Calibre spells its formats in upper case, the routes in lower.
         assert!(file_path(&db, 4, "azw3").expect("file").ends_with(".azw3"));
@@ -875,7 +3006,7 @@ mod tests {
     #[test]
     fn a_feed_with_no_books_asks_for_no_authors() {
         let db = library();
-        assert!(books_by_tag(&db, 99999).expect("no books").is_empty());
+        assert!(books_by_tag_page(&db, 99999, &Facets::NONE, 50, 0).expect("no books").is_empty());
     }
 
     // a wildcard is just a regular character.
@@ -892,14 +3023,14 @@ mod tests {
     fn an_apostrophe_is_part_of_the_search_term() {
         let db = library();
         assert_eq!(like("Alice's"), "%Alice's%");
-        assert_eq!(count_books_matching(&db, "Alice's").expect("count"), 1);
+        assert_eq!(count_books_matching(&db, "Alice's", &Facets::NONE).expect("count"), 1);
     }
 
     #[test]
     fn a_search_looks_at_the_title_and_at_the_author() {
         let db = library();
         let titles = |term: &str| {
-            books_search_page(&db, term, 50, 0)
+            books_search_page(&db, term, &Facets::NONE, 50, 0)
                 .expect("search")
                 .into_iter()
                 .map(|book| book.title)
@@ -912,18 +3043,354 @@ mod tests {
         assert!(titles("nothing here at all").is_empty());
     }
 
+    // The same queries Calibre's own search bar takes.
+    #[test]
+    fn a_search_speaks_calibres_search_language() {
+        let db = library();
+        let ids = |query: &str| {
+            let books = books_search_page(&db, query, &Facets::NONE, 50, 0).expect("search");
+            assert_eq!(count_books_matching(&db, query, &Facets::NONE).expect("count"), books.len(), "{}", query);
+            books.into_iter().map(|book| book.id).collect::<Vec<_>>()
+        };
+
+        assert_eq!(ids("author:lovecraft"), [8]);
+        assert_eq!(ids("tag:horror or tag:fantasy"), [4, 8]);
+        // `fiction` is part of `science fiction`; `=fiction` is not.
+        assert_eq!(ids("tag:fiction").len(), 5);
+        assert_eq!(ids("tag:=fiction"), [4, 2]);
+        assert_eq!(ids(r#"tag:"space opera""#), [9]);
+        assert_eq!(ids("series:astounding and not author:smith"), [8, 7]);
+        assert_eq!(ids("publisher:=\"Street & Smith\" pubdate:<1935"), [7]);
+        assert_eq!(ids("language:deutsch"), [5]);
+        assert_eq!(ids("format:mobi"), [6]);
+        assert_eq!(ids("format:azw3 or format:false"), [4]);
+        // Four stars and more, and the books nobody rated.
+        assert_eq!(ids("rating:>=4"), [4, 9, 5]);
+        assert_eq!(ids("rating:false"), [6, 2]);
+        assert_eq!(ids("pubdate:1936"), [8]);
+        assert_eq!(ids("pubdate:>=2000-01 and pubdate:<=2014"), [4, 6]);
+        assert_eq!(ids("identifier:isbn:"), [4]);
+        assert_eq!(ids("identifier:isbn:=978-0-14-143976-1"), [4]);
+        assert_eq!(ids("identifiers:uri:gutenberg.org/ebooks and not tag:science"), [6]);
+        assert!(ids("identifier:isbn:0000").is_empty());
+    }
+
     // Galileo is the author and his name also appears in the title. Still the book is only listed once.
     #[test]
     fn a_book_matched_twice_is_found_once() {
         let db = library();
-        assert_eq!(books_search_page(&library(), "galilei", 50, 0).expect("search").len(), 1);
-        assert_eq!(count_books_matching(&db, "galilei").expect("count"), 1);
+        assert_eq!(books_search_page(&library(), "galilei", &Facets::NONE, 50, 0).expect("search").len(), 1);
+        assert_eq!(count_books_matching(&db, "galilei", &Facets::NONE).expect("count"), 1);
+    }
+
+    // Vampires of Space has the word in its title, Galactic Patrol only in a tag.
+    #[test]
+    fn the_best_match_comes_first() {
+        let db = library();
+        let ids = |query: &str| {
+            books_search_page(&db, query, &Facets::NONE, 50, 0).expect("search").into_iter().map(|book| book.id).collect::<Vec<_>>()
+        };
+
+        assert_eq!(ids("space"), [7, 9]);
+        // By title again once there is nothing to rank by.
+        assert_eq!(ids("tag:space"), [9]);
+        assert_eq!(ids("tolstoi"), [2]);
+    }
+
+    #[test]
+    fn random_picks_are_different_books() {
+        let db = library();
+        let mut picked: Vec<i32> = random_books(&db, 3).expect("books").iter().map(|book| book.id).collect();
+        picked.sort();
+        picked.dedup();
+        assert_eq!(picked.len(), 3);
+    }
+
+    // The fixture has no reading positions; a temporary table in front of
+    // Calibre's stands in for the viewer having opened a book or two.
+    #[test]
+    fn the_next_volume_follows_the_furthest_one_opened() {
+        let db = library();
+        assert!(next_in_series(&db, &[]).expect("books").is_empty());
+        assert_eq!(next_in_series(&db, &[(7, 1.0), (4, 2.0)]).expect("books"), [8]);
+        assert_eq!(next_in_series(&db, &[(7, 1.0), (4, 2.0), (8, 3.0)]).expect("books"), [9]);
+
+        // The whole series read, there is nothing next.
+        assert!(next_in_series(&db, &[(9, 4.0)]).expect("books").is_empty());
+    }
+
+    #[test]
+    fn a_book_leads_on_to_its_neighbours() {
+        let db = library();
+        let ids = |books: &[Neighbour]| books.iter().map(|book| book.id).collect::<Vec<_>>();
+        let related = |db: &Connection, id| with_related(db, vec![book(db, id).expect("a book")]).expect("related").remove(0).related;
+        assert!(book(&db, 8).expect("a book").related.next.is_none(), "only when asked for");
+
+        // At the Mountains of Madness is the second of three volumes.
+        let madness = related(&db, 8);
+        assert_eq!(madness.previous.map(|book| book.id), Some(7));
+        assert_eq!(madness.next.map(|book| book.title), Some("Galactic Patrol".to_string()));
+        // All three are science fiction; the series is not listed again.
+        assert_eq!(ids(&madness.sharing_tags), [2]);
+
+        let first = related(&db, 7);
+        assert!(first.previous.is_none());
+        assert_eq!(ids(&first.sharing_tags), [9, 2]);
+
+        // Kant helped Galileo along, and Lovecraft wrote the first volume too.
+        db.execute_batch(
+            "CREATE TEMP TABLE books_authors_link AS SELECT * FROM main.books_authors_link;
+             INSERT INTO books_authors_link (book, author) VALUES (5, 6), (7, 9);",
+        )
+        .unwrap();
+        assert_eq!(ids(&related(&db, 6).by_authors), [5]);
+        assert!(related(&db, 8).by_authors.is_empty());
+    }
+
+    // Ties keep the order of the library: Galactic Patrol and the Kritik are
+    // both rated four stars.
+    #[test]
+    fn a_shelf_can_be_sorted_another_way() {
+        let db = library();
+        let ids = |books: Vec<Book>| books.iter().map(|book| book.id).collect::<Vec<_>>();
+        let sorted = |sort| ids(books_page(&db, &Facets { sort: Some(sort), ..Facets::NONE }, 50, 0).expect("books"));
+
+        assert_eq!(sorted(Sort::Title), ids(books_page(&db, &Facets::NONE, 50, 0).expect("books")));
+        assert_eq!(sorted(Sort::Author), [4, 6, 5, 8, 9, 7, 2]);
+        assert_eq!(sorted(Sort::Published), [6, 4, 2, 9, 8, 7, 5]);
+        assert_eq!(sorted(Sort::Added)[..3], [9, 8, 7]);
+        assert_eq!(sorted(Sort::Rating), [4, 9, 5, 8, 7, 6, 2]);
+        assert_eq!(sorted(Sort::Series), [7, 8, 9, 4, 5, 6, 2]);
+    }
+
+    // Books alike in every order still come one page after the other.
+    #[test]
+    fn a_tie_is_broken_by_id() {
+        let db = library();
+        db.execute_batch(
+            "CREATE TEMP TABLE books AS SELECT * FROM main.books;
+             UPDATE temp.books SET sort = 'Alike', author_sort = 'Alike';",
+        )
+        .unwrap();
+        let pages = (0..7)
+            .map(|offset| books_page(&db, &Facets::NONE, 1, offset).expect("a page")[0].id)
+            .collect::<Vec<_>>();
+        assert_eq!(pages, [2, 4, 5, 6, 7, 8, 9]);
+    }
+
+    // "Newest first by this author": the facets go with whichever shelf they are on,
+    // and a search sorted by title is no longer ranked.
+    #[test]
+    fn the_facets_apply_to_any_shelf() {
+        let db = library();
+        let ids = |books: Vec<Book>| books.iter().map(|book| book.id).collect::<Vec<_>>();
+        let newest = Facets { sort: Some(Sort::Published), ..Facets::NONE };
+
+        assert_eq!(ids(books_in_series_page(&db, 1, &newest, 50, 0).expect("series")), [9, 8, 7]);
+        let by_title = Facets { sort: Some(Sort::Title), ..Facets::NONE };
+        assert_eq!(ids(books_search_page(&db, "space", &by_title, 50, 0).expect("search")), [9, 7]);
+    }
+
+    #[test]
+    fn a_shelf_can_be_narrowed_down() {
+        let db = library();
+        let ids = |books: Vec<Book>| books.iter().map(|book| book.id).collect::<Vec<_>>();
+        let mobi = Facets { format: Some("mobi".to_string()), ..Facets::NONE };
+        assert_eq!(ids(books_page(&db, &mobi, 50, 0).expect("books")), [6]);
+        assert_eq!(count_books(&db, &mobi).expect("count"), 1);
+
+        // Science fiction in English: all of it but the Russian.
+        let english = Facets { language: Some(4), ..Facets::NONE };
+        assert_eq!(count_books_by_tag(&db, 9, &english).expect("count"), 3);
+        assert_eq!(ids(books_by_tag_page(&db, 9, &english, 50, 0).expect("books")), [8, 9, 7]);
+
+        let both = Facets { language: Some(4), format: Some("MOBI".to_string()), cover: true, ..Facets::NONE };
+        assert_eq!(count_books_matching(&db, "galilei", &both).expect("count"), 1);
+        assert_eq!(count_top_rated(&db, &both).expect("count"), 0);
+        assert_eq!(formats(&db).expect("formats"), ["AZW3", "EPUB", "MOBI"]);
+    }
+
+    // tests/calibre/full-text-search.db holds the opening lines of a few books,
+    // as Calibre would have extracted them.
+    #[test]
+    fn a_quote_finds_the_book_that_holds_it() {
+        let db = library();
+        let books = books_containing_page(&db, &quote("\"What is the use of a book\""), &Facets::NONE, 50, 0).expect("search");
+
+        assert_eq!(books.iter().map(|book| book.id).collect::<Vec<_>>(), [4]);
+        assert_eq!(
+            books[0].synopsis,
+            "<p>… she had peeped into the book her sister was reading, but it had no pictures or conversations \
+             in it, \"and <b>what is the use of a book</b>,\" thought Alice \"without pictures or conversations?\" \
+             So she was considering in her own mind (as well as she could, for …</p>"
+        );
+        // Across a line break, in any of its formats -- and once.
+        assert_eq!(count_books_containing(&db, &quote("sixty semi-diameters of the Earth"), &Facets::NONE).expect("count"), 1);
+        assert_eq!(books_containing_page(&db, &quote("the antarctic - with its"), &Facets::NONE, 50, 0).expect("search")[0].id, 8);
+        assert!(books_containing_page(&db, &quote(""), &Facets::NONE, 50, 0).expect("search").is_empty());
+    }
+
+    // Calibre keeps the text of a book it has deleted until it cleans up.
+    #[test]
+    fn a_quote_never_finds_a_deleted_book() {
+        let db = library();
+        assert_eq!(count_books_containing(&db, &quote("Alice was beginning"), &Facets::NONE).expect("count"), 1);
+        assert_eq!(books_containing_page(&db, &quote("Alice was beginning"), &Facets::NONE, 50, 0).expect("search").len(), 1);
+    }
+
+    #[test]
+    fn a_snippet_is_the_quote_in_its_place() {
+        assert_eq!(snippet("one two\nthree", "TWO").as_deref(), Some("<p>one <b>two</b> three</p>"));
+        assert_eq!(snippet("a < b & c", "b").as_deref(), Some("<p>a &lt; <b>b</b> &amp; c</p>"));
+        assert_eq!(snippet("wonderland", "land").as_deref(), Some("<p>wonder<b>land</b></p>"));
+        assert_eq!(snippet("wonderland", "looking glass"), None);
+        // A line ended by CR LF is one space away from the next, as the reader types it.
+        assert_eq!(snippet("down the\r\n\trabbit hole", "the rabbit").as_deref(), Some("<p>down <b>the rabbit</b> hole</p>"));
+    }
+
+    // A library whose text Calibre never extracted has none to search.
+    #[test]
+    fn a_library_without_its_text_finds_no_quotes() {
+        let db = Connection::open_in_memory().expect("an empty library");
+        assert_eq!(count_books_containing(&db, &quote("Alice"), &Facets::NONE).expect("count"), 0);
+    }
+
+    #[test]
+    fn notes_are_read_from_calibres_notes_database() {
+        let db = library();
+        let notes = notes(&db, "authors", &[4, 5]).expect("notes on authors");
+        assert_eq!(notes.keys().collect::<Vec<_>>(), [&5]);
+        assert!(notes[&5].contains("Königsberg"), "{}", notes[&5]);
+
+        assert!(note(&db, "tags", 9).expect("a note on a tag").is_some());
+        assert!(note(&db, "series", 1).expect("a note on a series").is_some());
+        assert_eq!(note(&db, "publisher", 4).expect("no note on a publisher"), None);
+        // An author and a tag may share an id; their notes are not shared.
+        assert_eq!(note(&db, "tags", 5).expect("no note on fiction"), None);
+    }
+
+    #[test]
+    fn the_images_of_a_note_are_where_calibre_filed_them() {
+        let db = library();
+        let kant = note(&db, "authors", 5).unwrap().unwrap();
+        let linked = note_html(&kant, "/library/resource");
+        assert!(linked.contains(r#"src="/library/resource/xxh64/af24ccfc732341f3""#), "{}", linked);
+        assert!(!linked.contains("calres:"), "{}", linked);
+        assert!(note_text(&kant).starts_with("Immanuel Kant (1724–1804)"), "{}", note_text(&kant));
+
+        let image = note_resource(&db, "xxh64", "af24ccfc732341f3").expect("a resource").expect("the portrait");
+        assert_eq!(image.name, "kant.png");
+        assert!(image.path.is_file(), "{}", image.path.display());
+
+        assert!(note_resource(&db, "xxh64", "0000000000000000").expect("no resource").is_none());
+        assert!(note_resource(&db, "xxh64", "..").expect("not a digest").is_none());
+    }
+
+    // Calibre before 7 has no notes; neither has a library nobody wrote one for.
+    #[test]
+    fn a_library_without_notes_has_none() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::copy("tests/calibre/metadata.db", dir.path().join("metadata.db")).unwrap();
+        let db = Connection::open(dir.path().join("metadata.db")).unwrap();
+        assert!(notes(&db, "authors", &[5]).expect("no notes").is_empty());
+        assert!(note_resource(&db, "xxh64", "af24ccfc732341f3").expect("no resource").is_none());
+    }
+
+    #[test]
+    fn a_reader_sees_only_their_own_annotations() {
+        let db = library();
+        let viewer = ["local:viewer".to_string()];
+        let kinds = |users: &[String]| -> Vec<String> {
+            annotations(&db, &Annotated::Book(5), users, 50, 0).unwrap().into_iter().map(|a| a.kind).collect()
+        };
+        // The third one Calibre's viewer made was removed again.
+        assert_eq!(kinds(&viewer), ["highlight", "bookmark"]);
+        assert_eq!(kinds(&["web:bob".to_string()]), ["highlight"]);
+        assert!(kinds(&[]).is_empty());
+        assert_eq!(count_annotations(&db, &Annotated::Book(5), &viewer).unwrap(), 2);
+
+        let first = annotations(&db, &Annotated::Book(5), &viewer, 1, 0).unwrap().remove(0);
+        assert_eq!((first.format.as_str(), first.modified.as_str()), ("EPUB", "2024-12-01T10:00:00Z"));
+        assert_eq!(first.data["notes"], "Der erste Satz der Einleitung");
+    }
+
+    #[test]
+    fn annotations_are_searched_by_the_words_in_them() {
+        let db = library();
+        let viewer = ["local:viewer".to_string()];
+        let found = |words: &str| -> Vec<i32> {
+            annotations(&db, &Annotated::Search(words), &viewer, 50, 0).unwrap().into_iter().map(|a| a.book).collect()
+        };
+        assert_eq!(found("erfahr"), [5]);
+        assert_eq!(found("curiouser"), [4]);
+        assert_eq!(found("ERSTE satz"), [5]);
+        // Bob's, not the viewer's.
+        assert!(found("Gedanken").is_empty());
+        // Not a word, and nothing FTS5 could choke on.
+        assert!(found("\" * ,").is_empty());
+        // Ästhetik begins with an a, as far as the search goes.
+        assert_eq!(count_annotations(&db, &Annotated::Search("a"), &viewer).unwrap(), 3);
+    }
+
+    #[test]
+    fn calibres_positions_become_epub_cfis() {
+        assert_eq!(highlight_cfi(3, "/4/2/6/1:0", "/4/2/6/1:80"), "epubcfi(/6/8!/4/2/6,/1:0,/1:80)");
+        assert_eq!(highlight_cfi(7, "/4/2/12/1:10", "/4/2/14/1:20"), "epubcfi(/6/16!/4/2,/12/1:10,/14/1:20)");
+        // Where both ends are the same, a range is still a range.
+        assert_eq!(highlight_cfi(0, "/2/1:5", "/2/1:5"), "epubcfi(/6/2!/2,/1:5,/1:5)");
+
+        assert_eq!(bookmark_cfi("epubcfi(/10/4/2/1:0)").as_deref(), Some("epubcfi(/6/10!/4/2/1:0)"));
+        assert_eq!(bookmark_cfi("12.5"), None);
+    }
+
+    #[test]
+    fn virtual_libraries_are_read_from_calibres_preferences() {
+        let db = library();
+        let names: Vec<String> = virtual_libraries(&db).expect("virtual libraries").into_iter().map(|vl| vl.name).collect();
+        assert_eq!(names, ["Kids", "Poetry", "Reference", "To Read"]);
+        assert_eq!(virtual_library(&db, "Kids").expect("Kids").search, "tags:children");
+        assert!(matches!(virtual_library(&db, "kids"), Err(rusqlite::Error::QueryReturnedNoRows)));
+    }
+
+    #[test]
+    fn saved_searches_are_read_from_calibres_preferences() {
+        let db = library();
+        let names: Vec<String> = saved_searches(&db).expect("saved searches").into_iter().map(|saved| saved.name).collect();
+        assert_eq!(names, ["Cookbooks", "Cosmic", "Horror"]);
+        assert_eq!(saved_search(&db, "Horror").expect("Horror").search, "tag:horror");
+    }
+
+    // Nobody wrote anything in this library, and Orca cannot browse publishers.
+    #[test]
+    fn a_user_category_lists_what_the_library_has_books_for() {
+        let db = library();
+        let categories = user_categories(&db).expect("user categories");
+        let names: Vec<&str> = categories.iter().map(|category| category.name.as_str()).collect();
+        assert_eq!(names, ["Favourites", "Gone"]);
+        assert!(has_user_categories(&db).expect("user categories"));
+
+        let favourites = user_category(&db, "Favourites").expect("Favourites");
+        let listed: Vec<(ListedKind, &str, usize)> = favourites
+            .listed
+            .iter()
+            .map(|listed| (listed.kind, listed.category.name.as_str(), listed.category.books))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                (ListedKind::Series, "Astounding Stories", 3),
+                (ListedKind::Author, "H. P. Lovecraft", 1),
+                (ListedKind::Tag, "philosophy", 1),
+            ]
+        );
+        assert!(user_category(&db, "Gone").expect("Gone").listed.is_empty());
+        assert!(matches!(user_category(&db, "Nothing"), Err(rusqlite::Error::QueryReturnedNoRows)));
     }
 
     // `%` would otherwise match the whole library.
     #[test]
     fn a_wildcard_matches_nothing_it_does_not_spell() {
         let db = library();
-        assert_eq!(count_books_matching(&db, "%").expect("count"), 0);
+        assert_eq!(count_books_matching(&db, "%", &Facets::NONE).expect("count"), 0);
     }
 }
@@ -0,0 +1,77 @@
+//! Feeds compressed for the clients that accept it
+//!
+//! A page of fifty books with their synopses runs to hundreds of kilobytes of
+//! Atom or JSON, which compress to a fraction of that. Only the content types
+//! listed under `[server.compression]` are compressed, and only when they are
+//! large enough to be worth it; books and covers are compressed already and
+//! pass untouched.
+
+use actix_http::encoding::Encoder;
+use actix_web::{
+    body::{BodySize, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{self, AcceptEncoding, ContentEncoding, Encoding, HeaderValue},
+    middleware::Next,
+    web, Error, HttpMessage,
+};
+
+use crate::appstate::AppState;
+
+/// Compress the response in the encoding the client likes best of those on
+/// offer for its content type.
+pub async fn compress(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let data = req.app_data::<web::Data<AppState>>().expect("the app state").clone();
+    let accepted = req.get_header::<AcceptEncoding>();
+    let res = next.call(req).await?;
+
+    let content_type = res.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default();
+    let settings = data.config.server.compression.of(content_type);
+
+    Ok(res.map_body(move |head, body| {
+        let Some((min_size, encodings)) = settings else {
+            return Encoder::response(ContentEncoding::Identity, head, body);
+        };
+
+        let large = match body.size() {
+            BodySize::Sized(size) => size > 0 && size >= min_size as u64,
+            BodySize::Stream => true,
+            BodySize::None => false,
+        };
+        let offered: Vec<Encoding> = encodings
+            .iter()
+            .filter_map(|name| name.parse().ok())
+            .map(Encoding::Known)
+            .chain([Encoding::identity()])
+            .collect();
+        let encoding = match (large, accepted) {
+            (true, Some(accepted)) => match accepted.negotiate(offered.iter()) {
+                Some(Encoding::Known(encoding)) => encoding,
+                _ => ContentEncoding::Identity,
+            },
+            _ => ContentEncoding::Identity,
+        };
+
+        if encoding == ContentEncoding::Identity {
+            // `Encoder` says so when it compresses; another client's copy may be.
+            head.headers_mut().append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        } else {
+            weaken(head.headers_mut());
+        }
+        Encoder::response(encoding, head, body)
+    }))
+}
+
+/// Turn a strong `ETag` weak. The compressed bytes are not the ones the tag
+/// was made for, though the feed is the same, and a weak tag still matches
+/// `If-None-Match`. Web servers compressing on the fly do the same.
+fn weaken(headers: &mut header::HeaderMap) {
+    let Some(tag) = headers.get(header::ETAG).and_then(|tag| tag.to_str().ok()) else { return };
+    if tag.starts_with('"') {
+        if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", tag)) {
+            headers.insert(header::ETAG, weak);
+        }
+    }
+}
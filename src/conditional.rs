@@ -0,0 +1,217 @@
+//! Conditional requests
+//!
+//! OPDS clients poll. They fetch the same feeds over and over, mostly to learn
+//! that nothing changed, and often over a phone's connection. Every feed gets
+//! an `ETag` and a `Last-Modified` taken from the state of the libraries it
+//! shows, and a client that sends either back gets a 304 without a body --
+//! before the handler renders anything. Covers, thumbnails and books are
+//! files, which answer conditional requests on their own; they only get their
+//! `Cache-Control` here.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{self, EntityTag, HeaderValue, HttpDate, IfModifiedSince, IfNoneMatch},
+    http::{Method, StatusCode},
+    middleware::Next,
+    web, Error, FromRequest, HttpMessage, HttpResponse,
+};
+use once_cell::sync::Lazy;
+
+use crate::appstate::AppState;
+use crate::authorized::Authorized;
+use crate::calibre;
+use crate::progress;
+use crate::routes::origin;
+
+/// A feed shows what is configured as well as what is in the library, and the
+/// configuration may have changed since the last start. A client's copy of a
+/// feed is never trusted across a restart.
+static STARTED: Lazy<SystemTime> = Lazy::new(SystemTime::now);
+
+/// What a request asks for, as far as caching goes.
+enum Served {
+    /// A feed showing these libraries.
+    Feed(Vec<String>),
+    /// A cover, thumbnail, book or image of a note.
+    File,
+    /// Anything else, including what does not exist.
+    Other,
+}
+
+fn served(path: &str, data: &AppState) -> Served {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let library = |lib: &str| match data.db.contains_key(lib) {
+        true => Served::Feed(vec![lib.to_string()]),
+        false => Served::Other,
+    };
+    match segments.as_slice() {
+        [""] | ["v2"] => Served::Feed(data.db.keys().cloned().collect()),
+        ["health", ..] => Served::Other,
+        ["v2", lib, ..] => library(lib),
+        [lib, "cover" | "thumb" | "file" | "resource", ..] if data.db.contains_key(*lib) => Served::File,
+        [lib, ..] => library(lib),
+        [] => Served::Other,
+    }
+}
+
+/// Answer a conditional request for a feed with a 304 while the libraries are
+/// unchanged, and give every feed and file its validators and `Cache-Control`.
+pub async fn revalidate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let data = req.app_data::<web::Data<AppState>>().expect("the app state").clone();
+    if req.method() != Method::GET {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    }
+
+    match served(req.path(), &data) {
+        Served::Other => Ok(next.call(req).await?.map_into_boxed_body()),
+        Served::File => {
+            let mut res = next.call(req).await?;
+            if res.status().is_success() || res.status() == StatusCode::NOT_MODIFIED {
+                cache_control(res.headers_mut(), &data.config.cache.files);
+            }
+            Ok(res.map_into_boxed_body())
+        }
+        Served::Feed(libraries) => {
+            // Who may not see the feed must not learn whether it changed.
+            let Ok(auth) = Authorized::extract(req.request()).await else {
+                return Ok(next.call(req).await?.map_into_boxed_body());
+            };
+            let (tag, modified) = validators(&req, &data, &libraries, &auth.login);
+
+            if fresh(&req, &tag, modified) {
+                let mut res = HttpResponse::NotModified()
+                    .insert_header(header::ETag(tag))
+                    .insert_header(header::LastModified(modified.into()))
+                    .finish();
+                cache_control(res.headers_mut(), &data.config.cache.feeds);
+                return Ok(req.into_response(res));
+            }
+
+            let mut res = next.call(req).await?;
+            if res.status() == StatusCode::OK {
+                let headers = res.headers_mut();
+                headers.insert(header::ETAG, HeaderValue::from_str(&tag.to_string()).expect("a hex tag"));
+                headers.insert(header::LAST_MODIFIED, HeaderValue::from_str(&HttpDate::from(modified).to_string()).expect("a date"));
+                cache_control(headers, &data.config.cache.feeds);
+            }
+            Ok(res.map_into_boxed_body())
+        }
+    }
+}
+
+/// The `ETag` and `Last-Modified` of a feed. The tag covers when a book of the
+/// libraries last changed, how many books they hold, when Calibre last wrote
+/// to them at all (renaming a tag or writing a note changes no book), what
+/// KOReader last synced, the address the links in the feed are made from, the
+/// page and query asked for, and who asked.
+fn validators(req: &ServiceRequest, data: &AppState, libraries: &[String], login: &str) -> (EntityTag, SystemTime) {
+    let mut hasher = DefaultHasher::new();
+    let mut modified = *STARTED;
+    STARTED.hash(&mut hasher);
+
+    let mut libraries = libraries.to_vec();
+    libraries.sort();
+    for lib in &libraries {
+        // Calibre keeps its notes in a database of their own.
+        let written = data.config.calibre.libraries.get(lib)
+            .map(|library| {
+                ["metadata.db", ".calnotes/notes.db"]
+                    .iter()
+                    .filter_map(|file| std::fs::metadata(Path::new(&library.path).join(file)).ok())
+                    .filter_map(|metadata| metadata.modified().ok())
+                    .fold(UNIX_EPOCH, SystemTime::max)
+            })
+            .unwrap_or(UNIX_EPOCH);
+        modified = modified.max(written);
+
+        let Some(db) = data.library(lib) else {
+            continue;
+        };
+        (lib, calibre::updated(&db), calibre::book_count(&db), written).hash(&mut hasher);
+    }
+
+    // How far the login got in each book shows in every feed they are shown,
+    // and KOReader does not write to a library.
+    if let Ok((synced, books)) = progress::changed(&progress::lock(&data.progress), login) {
+        (synced, books).hash(&mut hasher);
+        modified = modified.max(UNIX_EPOCH + Duration::from_secs(synced.max(0) as u64));
+    }
+
+    (origin(req.request(), data.config), req.uri().path(), req.uri().query(), login).hash(&mut hasher);
+
+    // HTTP dates have no fractions of a second; neither may the one compared with them.
+    let seconds = modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    (EntityTag::new_strong(format!("{:016x}", hasher.finish())), UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// Whether the copy the client has is still good. `If-None-Match` decides on
+/// its own; `If-Modified-Since` only counts without it.
+fn fresh(req: &ServiceRequest, tag: &EntityTag, modified: SystemTime) -> bool {
+    match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|theirs| theirs.weak_eq(tag)),
+        None => match req.get_header::<IfModifiedSince>() {
+            Some(IfModifiedSince(since)) => HttpDate::from(modified) <= since,
+            None => false,
+        },
+    }
+}
+
+fn cache_control(headers: &mut header::HeaderMap, policy: &str) {
+    if let Ok(value) = HeaderValue::from_str(policy) {
+        headers.insert(header::CACHE_CONTROL, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn state() -> AppState {
+        let config = crate::config::read_config("tests/orca.http.test.toml").expect("the test config");
+        crate::create_app(Box::leak(Box::new(config))).expect("the test library")
+    }
+
+    #[test]
+    fn feeds_files_and_the_rest() {
+        let data = state();
+        let feed = |path: &str| match served(path, &data) {
+            Served::Feed(libraries) => libraries,
+            _ => panic!("{} is a feed", path),
+        };
+        assert_eq!(feed("/"), ["library"]);
+        assert_eq!(feed("/v2"), ["library"]);
+        assert_eq!(feed("/library/authors/5"), ["library"]);
+        assert_eq!(feed("/v2/library/book/5"), ["library"]);
+
+        assert!(matches!(served("/library/cover/5", &data), Served::File));
+        assert!(matches!(served("/library/file/5/epub", &data), Served::File));
+        assert!(matches!(served("/health", &data), Served::Other));
+        assert!(matches!(served("/nowhere/books", &data), Served::Other));
+    }
+
+    // A book removed leaves every `last_modified` as it was.
+    #[test]
+    fn a_book_removed_changes_the_tag() {
+        let data = state();
+        let req = TestRequest::with_uri("/library/books").to_srv_request();
+        let libraries = ["library".to_string()];
+        let (tag, _) = validators(&req, &data, &libraries, "alice");
+        assert_eq!(validators(&req, &data, &libraries, "alice").0, tag);
+        assert_ne!(validators(&req, &data, &libraries, "bob").0, tag);
+
+        calibre::lock(&data.db["library"])
+            .execute_batch("CREATE TEMP TABLE books AS SELECT * FROM main.books WHERE id != 2;")
+            .unwrap();
+        assert_ne!(validators(&req, &data, &libraries, "alice").0, tag);
+    }
+}
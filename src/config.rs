@@ -1,6 +1,6 @@
 
-use dirs::home_dir;
-use std::{env, fmt, fs, collections::HashMap};
+use dirs::{cache_dir, data_dir, home_dir};
+use std::{env, fmt, fs, collections::HashMap, path::PathBuf};
 // use regex::Regex;
 use serde_derive::{Deserialize, Serialize};
 use crate::pattern::Pattern;
@@ -15,6 +15,12 @@ pub struct Config {
     pub calibre: Calibre,
     #[serde(default)]
     pub catalog: Catalog,
+    #[serde(default)]
+    pub cache: Cache,
+    #[serde(default)]
+    pub progress: Progress,
+    #[serde(default)]
+    pub kobo: Kobo,
 }
 
 impl Config {
@@ -23,6 +29,14 @@ impl Config {
             .and_then(|lib| lib.author.as_deref())
             .unwrap_or(&self.catalog.author)
     }
+
+    /// How long a list of authors, tags or titles may grow before it is split
+    /// up by first letter.
+    pub fn index_above(&self, lib: &str) -> usize {
+        self.calibre.libraries.get(lib)
+            .and_then(|lib| lib.index_above)
+            .unwrap_or(self.catalog.index_above)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -44,15 +58,106 @@ pub struct Server {
     /// X-Forwarded-Host; otherwise feeds derive their own URL from the request.
     #[serde(default)]
     pub public_url: Option<String>,
+    #[serde(default)]
+    pub compression: Compression,
     #[serde(flatten)]
     pub protocol: Protocol,
 }
 
+/// How feeds are compressed for the clients that can take it. Books and
+/// covers are left alone: they are compressed already.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Compression {
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    /// Bodies smaller than this many bytes are sent as they are. Compressing
+    /// them costs more time than it saves.
+    #[serde(default = "min_size")]
+    pub min_size: usize,
+    /// The encodings on offer (`br`, `zstd`, `gzip`, `deflate`). The client
+    /// picks among them.
+    #[serde(default = "encodings")]
+    pub encodings: Vec<String>,
+    /// The content types that are compressed, by their essence (no
+    /// parameters), each with settings of its own if need be.
+    #[serde(default = "compressed_types")]
+    pub types: HashMap<String, Compressed>,
+}
+
+/// Overrides `min_size` and `encodings` for one content type.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Compressed {
+    #[serde(default)]
+    pub min_size: Option<usize>,
+    #[serde(default)]
+    pub encodings: Option<Vec<String>>,
+}
+
+impl Compression {
+    /// What applies to a response of this content type, if it is compressed at all.
+    pub fn of(&self, content_type: &str) -> Option<(usize, &[String])> {
+        let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        let settings = self.types.get(&essence).filter(|_| self.enabled)?;
+        Some((
+            settings.min_size.unwrap_or(self.min_size),
+            settings.encodings.as_deref().unwrap_or(&self.encodings),
+        ))
+    }
+}
+
+fn enabled() -> bool {
+    true
+}
+
+fn min_size() -> usize {
+    1024
+}
+
+fn encodings() -> Vec<String> {
+    ["br", "zstd", "gzip"].map(String::from).to_vec()
+}
+
+fn compressed_types() -> HashMap<String, Compressed> {
+    [
+        "application/atom+xml",
+        "application/opds+json",
+        "application/opds-publication+json",
+        "application/opensearchdescription+xml",
+        "application/ld+json",
+    ]
+        .map(|mime| (mime.to_string(), Compressed::default()))
+        .into()
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression { enabled: enabled(), min_size: min_size(), encodings: encodings(), types: compressed_types() }
+    }
+}
+
 #[derive(Serialize, Deserialize, Default)]
 pub struct Authentication {
     pub login: HashMap<String, String>,
     #[serde(default)]
     pub public: Vec<Pattern>,
+    /// Who each login is in Calibre, by the user Calibre keeps annotations and
+    /// reading positions under: `"local:viewer"` for its viewer, `"web:alice"`
+    /// for a user of its content server. Once called `[authentication.annotations]`,
+    /// which still reads.
+    #[serde(default, alias = "annotations")]
+    pub calibre_users: HashMap<String, Vec<String>>,
+    /// What KOReader's progress sync logs in with: the hash of the MD5 of a
+    /// login's password, which is all KOReader ever sends. `orca --hash` prints it.
+    #[serde(default)]
+    pub kosync: HashMap<String, String>,
+}
+
+impl Authentication {
+    /// The Calibre users whose annotations and positions `login` may read:
+    /// none, unless configured.
+    pub fn calibre_users_of(&self, login: &str) -> &[String] {
+        self.calibre_users.get(login).map(Vec::as_slice).unwrap_or_default()
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -65,6 +170,49 @@ pub struct Library {
     pub path: String,
     #[serde(default)]
     pub author: Option<String>,
+    /// Calibre custom columns to show readers, by lookup name: `["#translator"]`.
+    /// None unless listed -- a column may hold what was never meant for them.
+    #[serde(default)]
+    pub custom_columns: Vec<String>,
+    /// Where Orca keeps its full-text index of the library, or `":memory:"` to
+    /// rebuild it on every start. Never inside the library: that is Calibre's.
+    #[serde(default)]
+    pub search_index: Option<String>,
+    /// Serve only the books of this Calibre virtual library. Point a second
+    /// entry at the same path to serve it next to the whole library.
+    #[serde(default)]
+    pub virtual_library: Option<String>,
+    /// Overrides `catalog.index_above` for this library.
+    #[serde(default)]
+    pub index_above: Option<usize>,
+    /// Where Orca keeps the thumbnails it made of the covers. Never inside the
+    /// library either.
+    #[serde(default)]
+    pub thumbnail_cache: Option<String>,
+    /// Write what KOReader syncs back to Calibre's `last_read_positions`, for
+    /// its viewer to pick up. The only thing Orca ever writes to a library.
+    #[serde(default)]
+    pub write_positions: bool,
+}
+
+impl Library {
+    /// The configured search index, or one in the user's cache directory.
+    pub fn search_index(&self, name: &str) -> String {
+        match (&self.search_index, cache_dir()) {
+            (Some(path), _) => path.clone(),
+            (None, Some(cache)) => cache.join("orca").join(format!("{}.search.db", name)).display().to_string(),
+            (None, None) => ":memory:".to_string(),
+        }
+    }
+
+    /// The configured thumbnail cache, or one in the user's cache directory.
+    pub fn thumbnail_cache(&self, name: &str) -> PathBuf {
+        match (&self.thumbnail_cache, cache_dir()) {
+            (Some(path), _) => PathBuf::from(path),
+            (None, Some(cache)) => cache.join("orca").join(format!("{}.thumbnails", name)),
+            (None, None) => env::temp_dir().join("orca").join(format!("{}.thumbnails", name)),
+        }
+    }
 }
 
 /// How the catalog presents itself, as opposed to where its books live.
@@ -72,18 +220,115 @@ pub struct Library {
 pub struct Catalog {
     #[serde(default = "orca")]
     pub author: String,
+    /// Lists longer than this get an A-Z index in front of them.
+    #[serde(default = "index_above")]
+    pub index_above: usize,
+    /// The widths covers are scaled down to. The smallest is the thumbnail of
+    /// an Atom feed, which has room for only one.
+    #[serde(default = "thumbnail_widths")]
+    pub thumbnail_widths: Vec<u32>,
 }
 
 fn orca() -> String {
     "orca".to_string()
 }
 
+fn index_above() -> usize {
+    100
+}
+
+fn thumbnail_widths() -> Vec<u32> {
+    vec![240, 480, 960]
+}
+
 impl Default for Catalog {
     fn default() -> Self {
-        Catalog { author: orca() }
+        Catalog { author: orca(), index_above: index_above(), thumbnail_widths: thumbnail_widths() }
+    }
+}
+
+/// The `Cache-Control` Orca sends along. Both are private: what a feed lists
+/// may depend on who asked for it.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Cache {
+    /// For every feed. A client may keep one, but asks again before showing
+    /// it, and gets a 304 without a body as long as the library is unchanged.
+    #[serde(default = "feeds")]
+    pub feeds: String,
+    /// For covers, thumbnails and the books themselves, which change far
+    /// less often than the feeds listing them.
+    #[serde(default = "files")]
+    pub files: String,
+}
+
+fn feeds() -> String {
+    "private, no-cache".to_string()
+}
+
+fn files() -> String {
+    "private, max-age=86400".to_string()
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache { feeds: feeds(), files: files() }
+    }
+}
+
+/// Where each reader is in each book, kept by Orca itself.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Progress {
+    /// The SQLite database it is kept in, or `":memory:"` to forget it on
+    /// every restart. Never inside a library: that is Calibre's.
+    #[serde(default)]
+    pub database: Option<String>,
+}
+
+impl Progress {
+    /// The configured database, or one in the user's data directory.
+    pub fn database(&self) -> String {
+        match (&self.database, data_dir()) {
+            (Some(path), _) => path.clone(),
+            (None, Some(data)) => data.join("orca").join("progress.db").display().to_string(),
+            (None, None) => ":memory:".to_string(),
+        }
+    }
+}
+
+/// The Kobo e-readers that sync with Orca as if it were Kobo's own store.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Kobo {
+    /// By a name of the owner's choosing: `[kobo.devices.alices-libra]`.
+    #[serde(default)]
+    pub devices: HashMap<String, Device>,
+}
+
+impl Kobo {
+    /// The device whose store URL holds `token`, with its name.
+    pub fn device(&self, token: &str) -> Option<(&str, &Device)> {
+        let hash = crate::hash::token_hash(token);
+        self.devices.iter().find(|(_, device)| device.token == hash).map(|(name, device)| (name.as_str(), device))
     }
 }
 
+/// One Kobo, and what it holds.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Device {
+    /// The SHA-256 of the token in the device's store URL, in hex. `orca
+    /// --kobo <device>` makes a token and prints both.
+    pub token: String,
+    /// Whose device it is: whose reading state it syncs.
+    pub login: String,
+    /// Where its books come from.
+    pub library: String,
+    /// The shelves of the library it holds the EPUBs of: `"tag:Kobo"`,
+    /// `"series:Discworld"`, `"author:Terry Pratchett"`, `"publisher:Tor"`,
+    /// `"virtual:Kids"`, `"search:Unread"`, and `"reading"` for the books
+    /// the login is reading.
+    #[serde(default)]
+    pub shelves: Vec<String>,
+}
+
 #[derive(Debug)]
 struct PathError {
     path: String,
@@ -197,6 +442,64 @@ mod tests {
         assert_eq!(config.unwrap().server.ip, "127.0.0.1");
     }
 
+    #[test]
+    fn test_compression_per_content_type() {
+        let mut tmp_file = NamedTempFile::new().unwrap();
+        let valid_toml = r#"
+        [server]
+        ip = "127.0.0.1"
+        port = 8080
+        protocol = "Http"
+
+        [server.compression]
+        min_size = 2048
+        encodings = ["gzip"]
+
+        [server.compression.types."application/atom+xml"]
+        [server.compression.types."application/opds+json"]
+        min_size = 512
+        encodings = ["br", "gzip"]
+
+        [authentication.login]
+        alice = "...passwordhash..."
+
+        [calibre]
+        libraries = {}
+        "#;
+        write!(tmp_file, "{}", valid_toml).unwrap();
+
+        let config = read_config(tmp_file.path().to_str().unwrap()).unwrap();
+        let compression = &config.server.compression;
+        assert_eq!(compression.of("application/atom+xml;profile=opds-catalog"), Some((2048, &["gzip".to_string()][..])));
+        assert_eq!(compression.of("application/opds+json"), Some((512, &["br".to_string(), "gzip".to_string()][..])));
+        assert_eq!(compression.of("application/opds-publication+json"), None);
+        assert_eq!(compression.of("application/epub+zip"), None);
+    }
+
+    #[test]
+    fn test_annotations_is_the_old_name_of_calibre_users() {
+        let mut tmp_file = NamedTempFile::new().unwrap();
+        let valid_toml = r#"
+        [server]
+        ip = "127.0.0.1"
+        port = 8080
+        protocol = "Http"
+
+        [authentication.login]
+        alice = "...passwordhash..."
+
+        [authentication.annotations]
+        alice = ["local:viewer"]
+
+        [calibre]
+        libraries = {}
+        "#;
+        write!(tmp_file, "{}", valid_toml).unwrap();
+
+        let config = read_config(tmp_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(config.authentication.calibre_users_of("alice"), ["local:viewer".to_string()]);
+    }
+
     #[test]
     fn test_missing_config_path() {
         let path = "/nonexistent/path/to/config.toml";
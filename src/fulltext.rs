@@ -0,0 +1,397 @@
+//! Orca's own full-text index of a library
+//!
+//! Calibre's `metadata.db` belongs to Calibre: Orca never writes to it. The index
+//! lives in a file of its own, attached to the library's connection as `orca`,
+//! and holds what a reader might remember of a book -- its title, authors, series,
+//! tags, publisher and synopsis. SQLite's FTS5 ranks the matches by BM25, folds
+//! case and diacritics, and finds `tolk` in `Tolkien`.
+//!
+//! Cyrillic names are also kept in Latin letters, and a word the index has never
+//! seen is looked up as the words it is a typo or two away from: `tolstoi` finds
+//! `Толстой`, and `Tolstoy` as well.
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::calibre::{plain_text, UNWRAPPED};
+
+/// Bump when the tables below change: an index built by an older Orca is dropped.
+const VERSION: i64 = 1;
+
+/// How much a match in each column of `book_text` counts, in column order:
+/// a word in the title says more about a book than the same word in its synopsis.
+pub const RANK: &str = "bm25(book_text, 10.0, 8.0, 5.0, 3.0, 2.0, 1.0, 8.0)";
+
+/// Attach the index at `path` -- a file, or `:memory:` -- to a library's
+/// connection, creating it if need be. It is filled by `refresh`.
+pub fn attach(db: &Connection, path: &str) -> rusqlite::Result<()> {
+    db.execute("ATTACH DATABASE ?1 AS orca;", params![path])?;
+
+    let version: i64 = db.query_row("PRAGMA orca.user_version;", [], |row| row.get(0))?;
+    if version != VERSION {
+        db.execute_batch(
+            "DROP TABLE IF EXISTS orca.book_terms;
+             DROP TABLE IF EXISTS orca.book_text;
+             DROP TABLE IF EXISTS orca.indexed;",
+        )?;
+    }
+
+    db.execute_batch(&format!(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS orca.book_text USING fts5(
+             title, authors, series, tags, publisher, synopsis, latin,
+             tokenize = 'unicode61 remove_diacritics 2',
+             prefix = '2 3'
+         );
+         CREATE VIRTUAL TABLE IF NOT EXISTS orca.book_terms USING fts5vocab(book_text, row);
+         CREATE TABLE IF NOT EXISTS orca.indexed (
+             library TEXT NOT NULL,
+             books INTEGER NOT NULL,
+             modified TEXT
+         );
+         PRAGMA orca.user_version = {};",
+        VERSION
+    ))
+}
+
+/// What the index was last built from: which library, how many books it held
+/// and when the latest of them changed. Deleting a book changes only the count.
+#[derive(PartialEq)]
+struct State {
+    library: String,
+    books: i64,
+    modified: Option<String>,
+}
+
+/// Bring the index up to date with the library: the books changed since it was
+/// last built are indexed again, the ones deleted since are dropped.
+pub fn refresh(db: &Connection) -> rusqlite::Result<()> {
+    let library: String = db.query_row(
+        "SELECT file FROM pragma_database_list WHERE name = 'main';",
+        [],
+        |row| row.get(0),
+    )?;
+    let (books, modified) = db.query_row("SELECT COUNT(*), MAX(last_modified) FROM main.books;", [], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    })?;
+    let current = State { library, books, modified };
+
+    let indexed = db
+        .query_row("SELECT library, books, modified FROM orca.indexed;", [], |row| {
+            Ok(State {
+                library: row.get(0)?,
+                books: row.get(1)?,
+                modified: row.get(2)?,
+            })
+        })
+        .optional()?;
+    if indexed.as_ref() == Some(&current) {
+        return Ok(());
+    }
+
+    // An index of some other library is no start at all
+    let since = indexed.filter(|indexed| indexed.library == current.library).and_then(|indexed| indexed.modified);
+
+    let tx = db.unchecked_transaction()?;
+    if since.is_none() {
+        tx.execute("DELETE FROM orca.book_text;", [])?;
+    }
+    tx.execute("DELETE FROM orca.book_text WHERE rowid NOT IN (SELECT id FROM main.books);", [])?;
+
+    {
+        let mut changed = tx.prepare(
+            "SELECT b.id, b.title,
+                (SELECT GROUP_CONCAT(a.name, ' & ') FROM main.books_authors_link ba
+                    JOIN main.authors a ON ba.author = a.id WHERE ba.book = b.id),
+                (SELECT s.name FROM main.books_series_link bs
+                    JOIN main.series s ON bs.series = s.id WHERE bs.book = b.id),
+                (SELECT GROUP_CONCAT(t.name, ', ') FROM main.books_tags_link bt
+                    JOIN main.tags t ON bt.tag = t.id WHERE bt.book = b.id),
+                (SELECT p.name FROM main.books_publishers_link bp
+                    JOIN main.publishers p ON bp.publisher = p.id WHERE bp.book = b.id),
+                c.text
+             FROM main.books b
+             LEFT JOIN main.comments c ON b.id = c.book
+             WHERE ?1 IS NULL OR b.last_modified > ?1;",
+        )?;
+        let mut forget = tx.prepare("DELETE FROM orca.book_text WHERE rowid = ?1;")?;
+        let mut insert = tx.prepare(
+            "INSERT INTO orca.book_text (rowid, title, authors, series, tags, publisher, synopsis, latin)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8);",
+        )?;
+
+        let mut rows = changed.query(params![since])?;
+        while let Some(row) = rows.next()? {
+            let id: i64 = row.get(0)?;
+            let title: String = row.get(1)?;
+            let authors: Option<String> = row.get(2)?;
+            let series: Option<String> = row.get(3)?;
+            let tags: Option<String> = row.get(4)?;
+            let publisher: Option<String> = row.get(5)?;
+            let synopsis = row.get::<_, Option<String>>(6)?.map(|html| plain_text(&html, UNWRAPPED));
+
+            // Only the names: nobody types a Russian synopsis in Latin letters.
+            let names = [Some(&title), authors.as_ref(), series.as_ref()]
+                .into_iter()
+                .flatten()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(" ");
+            let latin = Some(latin(&names)).filter(|latin| *latin != names);
+
+            forget.execute(params![id])?;
+            insert.execute(params![id, title, authors, series, tags, publisher, synopsis, latin])?;
+        }
+    }
+
+    tx.execute("DELETE FROM orca.indexed;", [])?;
+    tx.execute(
+        "INSERT INTO orca.indexed (library, books, modified) VALUES (?1, ?2, ?3);",
+        params![current.library, current.books, current.modified],
+    )?;
+    tx.commit()
+}
+
+/// A word of a query as FTS5 sees it: `None` if nothing in it can be indexed,
+/// as in `&` or `%`. Anything it begins with is a match too.
+///
+/// A word that begins nothing in the index is most likely misspelt, and also
+/// looks for the words in the index it is a typo or two away from.
+pub fn expression(db: &Connection, word: &str) -> rusqlite::Result<Option<String>> {
+    let tokens: Vec<String> = word
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    let phrase = match tokens.is_empty() {
+        true => return Ok(None),
+        false => format!("\"{}\"*", tokens.join(" ")),
+    };
+
+    let [token] = &tokens[..] else {
+        return Ok(Some(phrase));
+    };
+    let typos = match token.chars().count() {
+        0..=3 => return Ok(Some(phrase)),
+        4..=7 => 1,
+        _ => 2,
+    };
+
+    let known: Option<String> = db
+        .query_row("SELECT term FROM orca.book_terms WHERE term >= ?1 ORDER BY term LIMIT 1;", params![token], |row| {
+            row.get(0)
+        })
+        .optional()?;
+    if known.is_some_and(|term| term.starts_with(token.as_str())) {
+        return Ok(Some(phrase));
+    }
+
+    let similar = similar(db, token, typos)?;
+    match similar.is_empty() {
+        true => Ok(Some(phrase)),
+        false => {
+            let alternatives: Vec<String> = similar.iter().map(|term| format!("\"{}\"", term)).collect();
+            Ok(Some(format!("({} OR {})", phrase, alternatives.join(" OR "))))
+        }
+    }
+}
+
+/// The terms in the index at most `typos` edits away from `token`, nearest first.
+/// Only the ones beginning with the same letter: that is rarely the one mistyped,
+/// and it keeps the lookup to a corner of the vocabulary.
+fn similar(db: &Connection, token: &str, typos: usize) -> rusqlite::Result<Vec<String>> {
+    let Some(first) = token.chars().next() else {
+        return Ok(Vec::new());
+    };
+    let Some(after) = char::from_u32(first as u32 + 1) else {
+        return Ok(Vec::new());
+    };
+    let length = token.chars().count();
+
+    let mut stmt = db.prepare(
+        "SELECT term FROM orca.book_terms
+         WHERE term >= ?1 AND term < ?2 AND length(term) BETWEEN ?3 AND ?4;",
+    )?;
+    let terms = stmt.query_map(
+        params![first.to_string(), after.to_string(), length.saturating_sub(typos) as i64, (length + typos) as i64],
+        |row| row.get::<_, String>(0),
+    )?;
+
+    let mut similar: Vec<(usize, String)> = Vec::new();
+    for term in terms {
+        let term = term?;
+        let distance = distance(token, &term);
+        if distance <= typos {
+            similar.push((distance, term));
+        }
+    }
+    similar.sort();
+    Ok(similar.into_iter().take(10).map(|(_, term)| term).collect())
+}
+
+/// Levenshtein: how many letters have to be added, removed or replaced.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let replaced = diagonal + usize::from(a != *b);
+            diagonal = row[j + 1];
+            row[j + 1] = replaced.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Cyrillic in Latin letters, the way English spells Russian names:
+/// Толстой is Tolstoy, Чехов Chekhov. Anything else is left as it is.
+fn latin(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            let lower = c.to_lowercase().next().unwrap_or(c);
+            let latin = match lower {
+                'а' => "a",
+                'б' => "b",
+                'в' => "v",
+                'г' => "g",
+                'ґ' => "g",
+                'д' => "d",
+                'е' | 'ё' | 'є' | 'э' => "e",
+                'ж' => "zh",
+                'з' => "z",
+                'и' | 'і' => "i",
+                'ї' => "yi",
+                'й' | 'ы' => "y",
+                'к' => "k",
+                'л' => "l",
+                'м' => "m",
+                'н' => "n",
+                'о' => "o",
+                'п' => "p",
+                'р' => "r",
+                'с' => "s",
+                'т' => "t",
+                'у' => "u",
+                'ф' => "f",
+                'х' => "kh",
+                'ц' => "ts",
+                'ч' => "ch",
+                'ш' => "sh",
+                'щ' => "shch",
+                'ъ' | 'ь' => "",
+                'ю' => "yu",
+                'я' => "ya",
+                _ => return c.to_string(),
+            };
+            match c.is_uppercase() {
+                true => latin.chars().take(1).flat_map(char::to_uppercase).chain(latin.chars().skip(1)).collect(),
+                false => latin.to_string(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library() -> Connection {
+        let db = Connection::open("tests/calibre/metadata.db").expect("the test library");
+        attach(&db, ":memory:").expect("an index");
+        refresh(&db).expect("a fresh index");
+        db
+    }
+
+    fn matches(db: &Connection, word: &str) -> Vec<i64> {
+        let expression = expression(db, word).expect("an expression").expect("something to look for");
+        let mut stmt = db
+            .prepare(&format!("SELECT rowid FROM orca.book_text WHERE book_text MATCH ?1 ORDER BY {};", RANK))
+            .unwrap();
+        stmt.query_map(params![expression], |row| row.get(0)).unwrap().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn russian_is_spelt_in_latin_letters() {
+        assert_eq!(latin("Алексей Толстой"), "Aleksey Tolstoy");
+        assert_eq!(latin("Чехов"), "Chekhov");
+        assert_eq!(latin("Kant"), "Kant");
+    }
+
+    #[test]
+    fn typos_are_counted() {
+        assert_eq!(distance("tolstoi", "tolstoy"), 1);
+        assert_eq!(distance("tolstoi", "tolstoj"), 1);
+        assert_eq!(distance("kepler", "kepler"), 0);
+        assert_eq!(distance("lovecraft", "lovcraf"), 2);
+        assert_eq!(distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn every_book_is_indexed() {
+        let db = library();
+        let indexed: i64 = db.query_row("SELECT COUNT(*) FROM orca.book_text;", [], |row| row.get(0)).unwrap();
+        let books: i64 = db.query_row("SELECT COUNT(*) FROM books;", [], |row| row.get(0)).unwrap();
+        assert_eq!(indexed, books);
+    }
+
+    #[test]
+    fn a_word_finds_whatever_it_begins() {
+        let db = library();
+        assert_eq!(matches(&db, "lovecr"), [8]);
+        assert_eq!(matches(&db, "Wonderland"), [4]);
+    }
+
+    // Толстой, written the way a keyboard without Cyrillic letters can.
+    #[test]
+    fn tolstoi_finds_tolstoy() {
+        let db = library();
+        assert_eq!(matches(&db, "tolstoy"), [2]);
+        assert_eq!(matches(&db, "tolstoi"), [2]);
+        assert_eq!(matches(&db, "Толстой"), [2]);
+    }
+
+    #[test]
+    fn a_word_the_index_knows_is_not_mistaken_for_a_typo() {
+        let db = library();
+        assert_eq!(expression(&db, "kepler").unwrap().unwrap(), "\"kepler\"*");
+        assert_eq!(expression(&db, "Street & Smith").unwrap().unwrap(), "\"street smith\"*");
+        assert_eq!(expression(&db, "&").unwrap(), None);
+    }
+
+    #[test]
+    fn the_index_follows_the_library() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::copy("tests/calibre/metadata.db", dir.path().join("metadata.db")).unwrap();
+        let db = Connection::open(dir.path().join("metadata.db")).unwrap();
+        attach(&db, ":memory:").unwrap();
+        refresh(&db).unwrap();
+
+        // Calibre's trigger calls a function only Calibre has.
+        db.execute("DROP TRIGGER books_update_trg;", []).unwrap();
+        db.execute("UPDATE books SET title = 'Zanzibar', last_modified = '2100-01-01 00:00:00+00:00' WHERE id = 7;", [])
+            .unwrap();
+        db.execute("DELETE FROM books WHERE id = 9;", []).unwrap();
+        refresh(&db).unwrap();
+
+        assert_eq!(matches(&db, "zanzibar"), [7]);
+        assert!(matches(&db, "vampires").is_empty());
+        assert!(matches(&db, "galactic").is_empty());
+    }
+
+    // The index is Orca's; Calibre's database is left as Calibre wrote it.
+    #[test]
+    fn the_index_lives_in_a_file_of_its_own() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("library.search.db");
+        let db = Connection::open("tests/calibre/metadata.db").unwrap();
+        attach(&db, path.to_str().unwrap()).unwrap();
+        refresh(&db).unwrap();
+
+        let tables: i64 = db
+            .query_row("SELECT COUNT(*) FROM main.sqlite_master WHERE name LIKE 'book_te%';", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(tables, 0);
+        assert!(path.is_file());
+    }
+}
@@ -1,23 +1,44 @@
 use argon2::{
-    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    password_hash::{rand_core::{OsRng, RngCore}, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2
 };
 use anyhow::{Result, anyhow};
+use md5::{Digest, Md5};
+use sha2::Sha256;
 
 pub fn hash(login: &str, password: &str) -> Result<String> {
+    check(login, password)?;
+    argon2(password)
+}
+
+fn check(login: &str, password: &str) -> Result<()> {
     if login.len() < 3 || login.len() > 25 || password.len() < 3 || password.len() > 25 {
         return Err(anyhow!("Invalid login or password format"));
     }
+    Ok(())
+}
 
+fn argon2(secret: &str) -> Result<String> {
     let salt = SaltString::generate(&mut OsRng);
     let argon2 = Argon2::default();
-    let hash = argon2.hash_password(password.as_bytes(), &salt)
+    let hash = argon2.hash_password(secret.as_bytes(), &salt)
                      .map_err(|e| anyhow!("Hash error: {:?}", e))?
                      .to_string();
 
     Ok(hash)
 }
 
+/// What KOReader's progress sync sends for a password: its MD5, in hex.
+pub fn sync_key(password: &str) -> String {
+    Md5::digest(password.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The hash of the key KOReader sends, for `[authentication.kosync]`.
+pub fn sync_hash(login: &str, password: &str) -> Result<String> {
+    check(login, password)?;
+    argon2(&sync_key(password))
+}
+
 pub fn verify_password(password: &str, hash: &str) -> Result<bool> {
     let parsed_hash = PasswordHash::new(hash).map_err(|err| anyhow!("Hash error: {}", err))?;
     Ok(Argon2::default().verify_password(password.as_bytes(), &parsed_hash).is_ok())
@@ -25,12 +46,39 @@ pub fn verify_password(password: &str, hash: &str) -> Result<bool> {
 
 pub fn encode_auth_data(login: &str, password: &str) -> Result<String> {
     let hash_value = hash(login, password)?;
+    let sync_value = sync_hash(login, password)?;
     Ok(format!(
-        "Add this to the [authentication.login] section of your config.toml:\n{} = \"{}\"",
-        login, hash_value
+        "Add this to the [authentication.login] section of your config.toml:\n{} = \"{}\"\n\n\
+         To sync your progress in KOReader, add this to [authentication.kosync] as well:\n{} = \"{}\"",
+        login, hash_value, login, sync_value
     ))
 }
 
+/// A new secret for a Kobo's store URL: 128 random bits, in hex.
+pub fn kobo_token() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// What `[kobo.devices]` keeps of a token: its SHA-256, in hex. A Kobo
+/// sends the token with every cover it fetches -- too often for argon2, and
+/// a random token needs no slow hash to be safe.
+pub fn token_hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn encode_kobo_device(device: &str) -> String {
+    let token = kobo_token();
+    format!(
+        "Set api_endpoint in the [OneStoreServices] section of the Kobo's \"Kobo eReader.conf\" to:\n\
+         https://<your server>/kobo/{}\n\n\
+         and add this to your config.toml, with the device's login, library and shelves:\n\
+         [kobo.devices.{}]\ntoken = \"{}\"\nlogin = \"<login>\"\nlibrary = \"<library>\"\nshelves = [\"reading\"]",
+        token, device, token_hash(&token)
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -62,6 +110,15 @@ mod tests {
         assert!(hash_result.is_err());
     }
 
+    // KOReader hashes the password before it sends it, and Orca hashes that.
+    #[test]
+    fn test_sync_hash_verifies_the_key_koreader_sends() {
+        assert_eq!(sync_key("secretpassword"), "2034f6e32958647fdff75d265b455ebf");
+        let hash_value = sync_hash("alice", "secretpassword").unwrap();
+        assert!(verify_password("2034f6e32958647fdff75d265b455ebf", &hash_value).unwrap());
+        assert!(!verify_password("secretpassword", &hash_value).unwrap());
+    }
+
     #[test]
     fn test_verify_password_matches() {
         let login = "bob";
@@ -84,6 +141,15 @@ mod tests {
         assert!(!verify_result.unwrap());
     }
 
+    #[test]
+    fn test_kobo_tokens_are_random_and_kept_hashed() {
+        assert_eq!(token_hash("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        let token = kobo_token();
+        assert_eq!(token.len(), 32);
+        assert_ne!(token, kobo_token());
+        assert!(encode_kobo_device("libra").contains("[kobo.devices.libra]"));
+    }
+
     #[test]
     fn test_encode_auth_data() {
         let login = "bob";
@@ -0,0 +1,733 @@
+//! Kobo's store sync
+//!
+//! A Kobo e-reader syncs with whatever store the `api_endpoint` in its
+//! `Kobo eReader.conf` points to. Pointed at `{server}/kobo/{token}`, with a
+//! token `orca --kobo` made for `[kobo.devices]`, it syncs with Orca instead:
+//! it downloads the EPUBs on the shelves its config lists, with their
+//! metadata and covers, drops the ones that left the shelves, and keeps its
+//! reading state in Orca's store -- where the catalog's Currently Reading
+//! finds it, next to KOReader's and Calibre's. Whatever else a store does is
+//! answered with an empty object.
+//!
+//! A Kobo knows a book by its uuid. What each device was sent is kept in the
+//! store as well, so that a sync sends only what changed since; a device that
+//! comes without a sync token of Orca's -- new, or reset -- gets everything. The
+//! token gets at the books the device was sent, and no other.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_files as fs;
+use actix_web::error::{ErrorInternalServerError, ErrorNotFound, ErrorUnauthorized};
+use actix_web::{web, Error, HttpRequest, HttpResponse, Responder};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use md5::{Digest, Md5};
+use rusqlite::{Connection, OptionalExtension};
+use serde_json::{json, Map, Value};
+
+use crate::appstate::AppState;
+use crate::calibre::{self, Book, Category, Facets};
+use crate::config::Device;
+use crate::progress::{self, KoboState, Synced};
+use crate::reading::{self, Reading, FINISHED};
+use crate::routes::{file_of, origin, scaled};
+use crate::shelf::Shelf;
+use crate::{hash, thumbnail};
+
+/// The shelf of the books a device's login is reading, in `shelves`.
+const READING: &str = "reading";
+
+/// At most this many changes go to a device at once. It asks again for the rest.
+const SYNC_LIMIT: usize = 100;
+
+/// Where a Kobo keeps how far it synced, between one sync and the next.
+const SYNC_TOKEN: &str = "x-kobo-synctoken";
+
+/// The formats a Kobo reads, as Calibre's `parse_formats` spells them, with
+/// what the store calls them.
+const FORMATS: [(&str, &[&str]); 2] = [("kepub", &["KEPUB"]), ("epub", &["EPUB3", "EPUB"])];
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64
+}
+
+fn internal(what: &str, e: impl std::fmt::Display) -> HttpResponse {
+    eprintln!("{}: {}", what, e);
+    HttpResponse::InternalServerError().finish()
+}
+
+/// The device whose store URL holds `token`. Anybody else learns nothing.
+fn device(data: &AppState, token: &str) -> Result<(&'static str, &'static Device), HttpResponse> {
+    data.config.kobo.device(token).ok_or_else(|| HttpResponse::Unauthorized().finish())
+}
+
+/// A device's library, locked.
+fn library<'a>(data: &'a AppState, device: &Device) -> Result<std::sync::MutexGuard<'a, Connection>, HttpResponse> {
+    match data.library(&device.library) {
+        Some(db) => Ok(db),
+        None => Err(HttpResponse::NotFound().finish()),
+    }
+}
+
+/// The book of the library a Kobo calls `uuid`.
+fn book_called(db: &Connection, uuid: &str) -> Result<Book, HttpResponse> {
+    let found = calibre::books_by_uuid(db, &[uuid.to_string()]).map_err(|e| internal("Error looking up a book", e))?;
+    let Some(id) = found.get(uuid) else {
+        return Err(HttpResponse::NotFound().finish());
+    };
+    calibre::book(db, *id).map_err(|e| internal("Error reading a book", e))
+}
+
+/// The shelf of the library a name from a device's `shelves` stands for, if
+/// the library has it. `reading`: the books the device's login is reading.
+pub(crate) fn shelf(db: &Connection, name: &str, reading: &[i32]) -> rusqlite::Result<Option<Shelf>> {
+    if name == READING {
+        return Ok(Some(Shelf::Reading(reading.to_vec())));
+    }
+    let Some((kind, value)) = name.split_once(':') else {
+        return Ok(None);
+    };
+    let named = |categories: Vec<Category>| categories.into_iter().find(|category| category.name == value).map(|category| category.id);
+    Ok(match kind {
+        "tag" => named(calibre::tags_with_books(db)?).map(Shelf::Tag),
+        "series" => named(calibre::series_with_books(db)?).map(Shelf::Series),
+        "author" => named(calibre::authors_with_books(db)?).map(Shelf::Author),
+        "publisher" => named(calibre::publishers_with_books(db)?).map(Shelf::Publisher),
+        "virtual" => calibre::virtual_library(db, value).optional()?.map(|_| Shelf::Virtual(value.to_string())),
+        "search" => calibre::saved_search(db, value).optional()?.map(|_| Shelf::Saved(value.to_string())),
+        _ => None,
+    })
+}
+
+/// What a device holds: the books of its shelves a Kobo can read, each once,
+/// in the order of the shelves.
+fn selection(data: &AppState, db: &Connection, name: &str, device: &Device) -> rusqlite::Result<Vec<Book>> {
+    let reading = match device.shelves.iter().any(|shelf| shelf == READING) {
+        true => reading::in_progress_of(data, db, &device.library, &device.login)?,
+        false => Vec::new(),
+    };
+    let mut seen = HashSet::new();
+    let mut books = Vec::new();
+    for wanted in &device.shelves {
+        let Some(shelf) = shelf(db, wanted, &reading)? else {
+            eprintln!("Kobo {}: no shelf '{}' in library '{}'", name, wanted, device.library);
+            continue;
+        };
+        let count = shelf.count(db, &Facets::NONE)?;
+        for book in shelf.books(db, &Facets::NONE, count, 0)? {
+            if readable(&book) && seen.insert(book.id) {
+                books.push(book);
+            }
+        }
+    }
+    Ok(books)
+}
+
+fn readable(book: &Book) -> bool {
+    book.formats.iter().any(|format| FORMATS.iter().any(|(readable, _)| format == readable))
+}
+
+// ------- Initialization -------
+
+/// Where the device finds the store. Only what Orca answers is listed; a
+/// Kobo leaves out whatever is missing.
+#[actix_web::get("/kobo/{token}/v1/initialization")]
+async fn initialization(data: web::Data<AppState>, path: web::Path<String>, req: HttpRequest) -> impl Responder {
+    let token = path.into_inner();
+    if let Err(response) = device(&data, &token) {
+        return response;
+    }
+    let origin = origin(&req, data.config);
+    let base = format!("{}/kobo/{}", origin, token);
+    let resources = json!({
+        "add_entitlement": format!("{}/v1/library/{{RevisionIds}}", base),
+        "configuration_data": format!("{}/v1/configuration", base),
+        "deals": format!("{}/v1/deals", base),
+        "delete_entitlement": format!("{}/v1/library/{{Ids}}", base),
+        "device_auth": format!("{}/v1/auth/device", base),
+        "device_refresh": format!("{}/v1/auth/refresh", base),
+        "get_tests_request": format!("{}/v1/analytics/gettests", base),
+        "image_host": origin,
+        "image_url_quality_template": format!("{}/{{ImageId}}/{{Width}}/{{Height}}/{{Quality}}/{{IsGreyscale}}/image.jpg", base),
+        "image_url_template": format!("{}/{{ImageId}}/{{Width}}/{{Height}}/false/image.jpg", base),
+        "library_metadata": format!("{}/v1/library/{{Ids}}/metadata", base),
+        "library_sync": format!("{}/v1/library/sync", base),
+        "post_analytics_event": format!("{}/v1/analytics/event", base),
+        "reading_state": format!("{}/v1/library/{{Ids}}/state", base),
+        "tags": format!("{}/v1/library/tags", base),
+        "user_profile": format!("{}/v1/user/profile", base),
+    });
+    // An API token of `{}`: the device wants one, and has no use for it.
+    HttpResponse::Ok().append_header(("x-kobo-apitoken", "e30=")).json(json!({ "Resources": resources }))
+}
+
+/// The device logs in to the store. Its token in the URL is all the login there is.
+#[actix_web::post("/kobo/{token}/v1/auth/device")]
+async fn authenticate(data: web::Data<AppState>, path: web::Path<String>, body: web::Bytes) -> impl Responder {
+    tokens(&data, &path, &body)
+}
+
+#[actix_web::post("/kobo/{token}/v1/auth/refresh")]
+async fn refresh(data: web::Data<AppState>, path: web::Path<String>, body: web::Bytes) -> impl Responder {
+    tokens(&data, &path, &body)
+}
+
+fn tokens(data: &AppState, token: &str, body: &[u8]) -> HttpResponse {
+    if let Err(response) = device(data, token) {
+        return response;
+    }
+    let sent: Value = serde_json::from_slice(body).unwrap_or_default();
+    HttpResponse::Ok().json(json!({
+        "AccessToken": hash::kobo_token(),
+        "RefreshToken": hash::kobo_token(),
+        "TokenType": "Bearer",
+        "TrackingId": uuid_of(&hash::kobo_token()),
+        "UserKey": sent.get("UserKey").cloned().unwrap_or_default(),
+    }))
+}
+
+// ------- Library sync -------
+
+/// What changed about a book since a device last synced.
+enum Change<'a> {
+    New(&'a Book),
+    Changed(&'a Book),
+    /// Only where the reader is
+    Read(&'a Book),
+    /// Gone from the shelves: by uuid, as modified when the device got it
+    Removed(&'a Synced),
+}
+
+/// Everything that changed on the device's shelves since it last synced, a
+/// page at a time: `x-kobo-sync: continue` while there is more.
+#[actix_web::get("/kobo/{token}/v1/library/sync")]
+async fn sync(data: web::Data<AppState>, path: web::Path<String>, req: HttpRequest) -> impl Responder {
+    let token = path.into_inner();
+    let (name, device) = match device(&data, &token) {
+        Ok(device) => device,
+        Err(response) => return response,
+    };
+
+    // Without a token of Orca's, the device holds nothing Orca sent it.
+    let resumed = req.headers().get(SYNC_TOKEN).and_then(|header| header.to_str().ok()).is_some_and(is_ours);
+    if !resumed {
+        if let Err(e) = progress::forget_synced(&progress::lock(&data.progress), name) {
+            return internal("Error resetting a Kobo", e);
+        }
+    }
+
+    let (books, readings) = {
+        let db = match library(&data, device) {
+            Ok(db) => db,
+            Err(response) => return response,
+        };
+        let books = match selection(&data, &db, name, device) {
+            Ok(books) => books,
+            Err(e) => return internal("Error reading the shelves of a Kobo", e),
+        };
+        match reading::readings(&data, &db, &device.library, &device.login) {
+            Ok(readings) => (books, readings),
+            Err(e) => return internal("Error reading the positions", e),
+        }
+    };
+    let (held, removed, states) = {
+        let store = progress::lock(&data.progress);
+        let known = progress::synced(&store, name).and_then(|held| {
+            Ok((held, progress::removed(&store, name)?, progress::kobo_states(&store, &device.login)?))
+        });
+        match known {
+            Ok((held, removed, states)) => {
+                (held, removed, states.into_iter().map(|state| (state.uuid.clone(), state)).collect::<HashMap<_, _>>())
+            }
+            Err(e) => return internal("Error reading what a Kobo holds", e),
+        }
+    };
+
+    // A book the reader removed stays off the device as long as it is as it
+    // was then; changed, or gone from the shelves, it is sent again.
+    let removed_as_is = |book: &Book| removed.get(&book.uuid).is_some_and(|modified| *modified == kobo_time_of(&book.updated));
+    let returning: Vec<String> = removed
+        .keys()
+        .filter(|uuid| !books.iter().any(|book| book.uuid == **uuid && removed_as_is(book)))
+        .cloned()
+        .collect();
+    if let Err(e) = progress::forget_removed(&progress::lock(&data.progress), name, &returning) {
+        return internal("Error noting what a Kobo may get again", e);
+    }
+
+    let read = |book: &Book| readings.get(&book.id).map(|reading| reading.when as i64).unwrap_or_default();
+    let mut changes: Vec<Change> = books
+        .iter()
+        .filter(|book| !removed_as_is(book))
+        .filter_map(|book| match held.get(&book.uuid) {
+            None => Some(Change::New(book)),
+            Some(synced) if synced.modified != kobo_time_of(&book.updated) => Some(Change::Changed(book)),
+            Some(synced) if synced.read != read(book) => Some(Change::Read(book)),
+            Some(_) => None,
+        })
+        .collect();
+    let shelved: HashSet<&str> = books.iter().map(|book| book.uuid.as_str()).collect();
+    changes.extend(held.values().filter(|synced| !shelved.contains(synced.uuid.as_str())).map(Change::Removed));
+    let more = changes.len() > SYNC_LIMIT;
+    changes.truncate(SYNC_LIMIT);
+
+    // Only the files of the books sent in full are looked up, and only the
+    // lookup waits for the library: their sizes are read without it.
+    let files: HashMap<i32, HashMap<&str, String>> = {
+        let db = match library(&data, device) {
+            Ok(db) => db,
+            Err(response) => return response,
+        };
+        changes
+            .iter()
+            .filter_map(|change| match change {
+                Change::New(book) | Change::Changed(book) => Some((book.id, files_of(&db, book))),
+                _ => None,
+            })
+            .collect()
+    };
+
+    let base = format!("{}/kobo/{}", origin(&req, data.config), token);
+    let mut entries = Vec::new();
+    let mut sent = Vec::new();
+    let mut removed = Vec::new();
+    for change in changes {
+        let book = match change {
+            Change::Removed(synced) => {
+                let entitlement = entitlement(&synced.uuid, &synced.modified, true);
+                entries.push(json!({ "ChangedEntitlement": { "BookEntitlement": entitlement } }));
+                removed.push(synced.uuid.clone());
+                continue;
+            }
+            Change::New(book) | Change::Changed(book) | Change::Read(book) => book,
+        };
+        let state = reading_state(book, states.get(&book.uuid), readings.get(&book.id));
+        entries.push(match change {
+            Change::Read(_) => json!({ "ChangedReadingState": { "ReadingState": state } }),
+            _ => {
+                let kind = if matches!(change, Change::New(_)) { "NewEntitlement" } else { "ChangedEntitlement" };
+                let modified = kobo_time_of(&book.updated);
+                json!({ kind: {
+                    "BookEntitlement": entitlement(&book.uuid, &modified, false),
+                    "BookMetadata": metadata(&data, &device.library, &base, book, &files[&book.id]),
+                    "ReadingState": state,
+                } })
+            }
+        });
+        sent.push(Synced { uuid: book.uuid.clone(), modified: kobo_time_of(&book.updated), read: read(book) });
+    }
+    if let Err(e) = progress::mark_synced(&progress::lock(&data.progress), name, &sent, &removed) {
+        return internal("Error noting what a Kobo was sent", e);
+    }
+
+    let mut response = HttpResponse::Ok();
+    response.append_header((SYNC_TOKEN, BASE64.encode(json!({ "orca": now() }).to_string())));
+    if more {
+        response.append_header(("x-kobo-sync", "continue"));
+    }
+    response.json(entries)
+}
+
+/// A sync token Orca handed out, as opposed to one of Kobo's own store.
+fn is_ours(token: &str) -> bool {
+    BASE64
+        .decode(token)
+        .ok()
+        .and_then(|json| serde_json::from_slice::<Value>(&json).ok())
+        .is_some_and(|token| token.get("orca").is_some())
+}
+
+/// The device's right to a book, or with `removed` the end of it.
+fn entitlement(uuid: &str, modified: &str, removed: bool) -> Value {
+    json!({
+        "Accessibility": "Full",
+        "ActivePeriod": { "From": kobo_time(now()) },
+        "Created": modified,
+        "CrossRevisionId": uuid,
+        "Id": uuid,
+        "IsHiddenFromArchive": false,
+        "IsLocked": false,
+        "IsRemoved": removed,
+        "LastModified": modified,
+        "OriginCategory": "Imported",
+        "RevisionId": uuid,
+        "Status": "Active",
+    })
+}
+
+/// Where in the library the files of a book a Kobo reads are, by format.
+fn files_of(db: &Connection, book: &Book) -> HashMap<&'static str, String> {
+    FORMATS
+        .iter()
+        .filter(|(format, _)| book.formats.iter().any(|has| has == format))
+        .filter_map(|(format, _)| calibre::file_path(db, book.id, format).ok().map(|file| (*format, file)))
+        .collect()
+}
+
+/// What the device shows of a book, and where it downloads it from.
+/// `files`: where the book's files are, from `files_of`.
+fn metadata(data: &AppState, lib: &str, base: &str, book: &Book, files: &HashMap<&str, String>) -> Value {
+    let library = &data.config.calibre.libraries[lib].path;
+    let downloads: Vec<Value> = FORMATS
+        .iter()
+        .filter(|(format, _)| book.formats.iter().any(|has| has == format))
+        .flat_map(|(format, names)| {
+            let size = files
+                .get(format)
+                .and_then(|file| std::fs::metadata(format!("{}/{}", library, file)).ok())
+                .map(|metadata| metadata.len())
+                .unwrap_or_default();
+            let url = format!("{}/download/{}/{}", base, book.id, format);
+            names.iter().map(move |name| json!({ "Format": name, "Size": size, "Url": url, "Platform": "Generic" }))
+        })
+        .collect();
+    let authors: Vec<&str> = book.authors.iter().map(|author| author.name.as_str()).collect();
+
+    let mut metadata = json!({
+        "Categories": ["00000000-0000-0000-0000-000000000001"],
+        "ContributorRoles": authors.iter().map(|name| json!({ "Name": name })).collect::<Vec<_>>(),
+        "Contributors": authors,
+        "CoverImageId": book.uuid,
+        "CrossRevisionId": book.uuid,
+        "CurrentDisplayPrice": { "CurrencyCode": "USD", "TotalAmount": 0 },
+        "CurrentLoveDisplayPrice": { "TotalAmount": 0 },
+        "Description": book.synopsis,
+        "DownloadUrls": downloads,
+        "EntitlementId": book.uuid,
+        "ExternalIds": [],
+        "Genre": "00000000-0000-0000-0000-000000000001",
+        "IsEligibleForKoboLove": false,
+        "IsInternetArchive": false,
+        "IsPreOrder": false,
+        "IsSocialEnabled": true,
+        "Language": book.languages.first().map(String::as_str).unwrap_or("en"),
+        "PhoneticPronunciations": {},
+        "PublicationDate": kobo_time_of(&book.pubdate),
+        "Publisher": { "Imprint": "", "Name": book.publisher.as_deref().unwrap_or_default() },
+        "RevisionId": book.uuid,
+        "Title": book.title,
+        "WorkId": book.uuid,
+    });
+    if let Some(series) = &book.series {
+        metadata["Series"] = json!({
+            "Name": series.name,
+            "Number": format!("{}", series.index),
+            "NumberFloat": series.index,
+            "Id": uuid_of(&series.name),
+        });
+    }
+    metadata
+}
+
+#[actix_web::get("/kobo/{token}/v1/library/{uuid}/metadata")]
+async fn book_metadata(data: web::Data<AppState>, path: web::Path<(String, String)>, req: HttpRequest) -> impl Responder {
+    let (token, uuid) = path.into_inner();
+    let (name, device) = match device(&data, &token) {
+        Ok(device) => device,
+        Err(response) => return response,
+    };
+    if let Err(response) = held(&data, name, &uuid) {
+        return response;
+    }
+    let (book, files) = {
+        let db = match library(&data, device) {
+            Ok(db) => db,
+            Err(response) => return response,
+        };
+        match book_called(&db, &uuid) {
+            Ok(book) => {
+                let files = files_of(&db, &book);
+                (book, files)
+            }
+            Err(response) => return response,
+        }
+    };
+    let base = format!("{}/kobo/{}", origin(&req, data.config), token);
+    HttpResponse::Ok().json([metadata(&data, &device.library, &base, &book, &files)])
+}
+
+/// The reader removed a book from the device. It stays removed until it
+/// changes, or leaves its shelves and comes back.
+#[actix_web::delete("/kobo/{token}/v1/library/{uuid}")]
+async fn remove(data: web::Data<AppState>, path: web::Path<(String, String)>) -> impl Responder {
+    let (token, uuid) = path.into_inner();
+    let (name, _) = match device(&data, &token) {
+        Ok(device) => device,
+        Err(response) => return response,
+    };
+    let store = progress::lock(&data.progress);
+    let removed = progress::synced(&store, name).and_then(|mut held| match held.remove(&uuid) {
+        Some(synced) => progress::mark_removed(&store, name, &synced).map(|_| true),
+        None => Ok(false),
+    });
+    match removed {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e) => internal("Error removing a book from a Kobo", e),
+    }
+}
+
+// ------- Reading state -------
+
+/// Where the reader is in a book, as a Kobo puts it: the state the device
+/// sent last, unless the reader got further elsewhere since. Of KOReader's or
+/// Calibre's position, a Kobo understands only how much of the book it is.
+fn reading_state(book: &Book, kept: Option<&KoboState>, latest: Option<&Reading>) -> Value {
+    if let Some(kept) = kept.filter(|kept| latest.is_none_or(|latest| latest.when <= kept.timestamp as f64)) {
+        if let Ok(state) = serde_json::from_str(&kept.state) {
+            return state;
+        }
+    }
+    let (modified, status) = match latest {
+        None => (kobo_time_of(&book.updated), "ReadyToRead"),
+        Some(reading) if reading.fraction >= FINISHED => (kobo_time(reading.when as i64), "Finished"),
+        Some(reading) if reading.fraction > 0.0 => (kobo_time(reading.when as i64), "Reading"),
+        Some(reading) => (kobo_time(reading.when as i64), "ReadyToRead"),
+    };
+    let mut bookmark = json!({ "LastModified": modified });
+    if let Some(reading) = latest {
+        bookmark["ProgressPercent"] = json!((reading.fraction * 100.0).round());
+    }
+    json!({
+        "EntitlementId": book.uuid,
+        "Created": modified,
+        "LastModified": modified,
+        "PriorityTimestamp": modified,
+        "StatusInfo": { "LastModified": modified, "Status": status },
+        "Statistics": { "LastModified": modified },
+        "CurrentBookmark": bookmark,
+    })
+}
+
+/// How much of the book a Kobo's state has the reader through, from 0 to 1.
+fn percentage(state: &Value) -> f64 {
+    match state["StatusInfo"]["Status"].as_str() {
+        Some("Finished") => 1.0,
+        _ => state["CurrentBookmark"]["ProgressPercent"].as_f64().unwrap_or_default() / 100.0,
+    }
+}
+
+#[actix_web::get("/kobo/{token}/v1/library/{uuid}/state")]
+async fn get_state(data: web::Data<AppState>, path: web::Path<(String, String)>) -> impl Responder {
+    let (token, uuid) = path.into_inner();
+    let (name, device) = match device(&data, &token) {
+        Ok(device) => device,
+        Err(response) => return response,
+    };
+    if let Err(response) = held(&data, name, &uuid) {
+        return response;
+    }
+    let db = match library(&data, device) {
+        Ok(db) => db,
+        Err(response) => return response,
+    };
+    let book = match book_called(&db, &uuid) {
+        Ok(book) => book,
+        Err(response) => return response,
+    };
+    let readings = reading::readings(&data, &db, &device.library, &device.login);
+    drop(db);
+    let state = readings.and_then(|readings| {
+        let kept = progress::kobo_states(&progress::lock(&data.progress), &device.login)?;
+        let kept = kept.into_iter().find(|state| state.uuid == uuid);
+        Ok(reading_state(&book, kept.as_ref(), readings.get(&book.id)))
+    });
+    match state {
+        Ok(state) => HttpResponse::Ok().json([state]),
+        Err(e) => internal("Error reading the positions", e),
+    }
+}
+
+/// The device says where the reader is. What it sends replaces what was kept,
+/// part by part: bookmark, statistics, status.
+#[actix_web::put("/kobo/{token}/v1/library/{uuid}/state")]
+async fn put_state(data: web::Data<AppState>, path: web::Path<(String, String)>, body: web::Bytes) -> impl Responder {
+    let (token, uuid) = path.into_inner();
+    let (name, device) = match device(&data, &token) {
+        Ok(device) => device,
+        Err(response) => return response,
+    };
+    if let Err(response) = held(&data, name, &uuid) {
+        return response;
+    }
+    let Some(update) = serde_json::from_slice::<Value>(&body).ok().and_then(|body| body["ReadingStates"].get(0).cloned()) else {
+        return HttpResponse::BadRequest().finish();
+    };
+    let db = match library(&data, device) {
+        Ok(db) => db,
+        Err(response) => return response,
+    };
+    let book = match book_called(&db, &uuid) {
+        Ok(book) => book,
+        Err(response) => return response,
+    };
+    let readings = match reading::readings(&data, &db, &device.library, &device.login) {
+        Ok(readings) => readings,
+        Err(e) => return internal("Error reading the positions", e),
+    };
+    drop(db);
+
+    let timestamp = now();
+    let modified = kobo_time(timestamp);
+    let saved = (|| {
+        let store = progress::lock(&data.progress);
+        let kept = progress::kobo_states(&store, &device.login)?.into_iter().find(|state| state.uuid == uuid);
+        let mut state = reading_state(&book, kept.as_ref(), readings.get(&book.id));
+        for part in ["CurrentBookmark", "Statistics", "StatusInfo"] {
+            if let Some(Value::Object(sent)) = update.get(part) {
+                let mut sent: Map<String, Value> = sent.clone();
+                sent.insert("LastModified".to_string(), json!(modified));
+                state[part] = Value::Object(sent);
+            }
+        }
+        state["LastModified"] = json!(modified);
+        state["PriorityTimestamp"] = json!(modified);
+        let kept = KoboState { uuid: uuid.clone(), state: state.to_string(), percentage: percentage(&state), timestamp };
+        progress::save_kobo_state(&store, &device.login, &kept)?;
+
+        // The device knows where it is: no need to send it back.
+        if let Some(synced) = progress::synced(&store, name)?.remove(&uuid) {
+            progress::mark_synced(&store, name, &[Synced { read: timestamp, ..synced }], &[])?;
+        }
+        Ok::<_, rusqlite::Error>(())
+    })();
+    if let Err(e) = saved {
+        return internal("Error saving a Kobo's reading state", e);
+    }
+
+    let success = json!({ "Result": "Success" });
+    HttpResponse::Ok().json(json!({
+        "RequestResult": "Success",
+        "UpdateResults": [{
+            "EntitlementId": uuid,
+            "CurrentBookmarkResult": success,
+            "StatisticsResult": success,
+            "StatusInfoResult": success,
+        }],
+    }))
+}
+
+// ------- Books and covers -------
+
+/// Whether Orca sent the device the book called `uuid`: a token gets no
+/// further into the library than the device's shelves.
+fn held(data: &AppState, name: &str, uuid: &str) -> Result<(), HttpResponse> {
+    match progress::synced(&progress::lock(&data.progress), name) {
+        Ok(held) if held.contains_key(uuid) => Ok(()),
+        Ok(_) => Err(HttpResponse::NotFound().finish()),
+        Err(e) => Err(internal("Error reading what a Kobo holds", e)),
+    }
+}
+
+/// One of the files a sync offered the device: an EPUB or a KEPUB, nothing else.
+#[actix_web::get("/kobo/{token}/download/{id}/{format}")]
+async fn download(data: web::Data<AppState>, path: web::Path<(String, i32, String)>) -> Result<fs::NamedFile, Error> {
+    let (token, book, format) = path.into_inner();
+    let Some((name, device)) = data.config.kobo.device(&token) else {
+        return Err(ErrorUnauthorized("Unauthorized"));
+    };
+    if !FORMATS.iter().any(|(readable, _)| readable.eq_ignore_ascii_case(&format)) {
+        return Err(ErrorNotFound("Format not found"));
+    }
+    let uuid = match data.library(&device.library) {
+        Some(db) => calibre::book(&db, book).map_err(|_| ErrorNotFound("Book not found"))?.uuid,
+        None => return Err(ErrorNotFound("Library not found")),
+    };
+    if held(&data, name, &uuid).is_err() {
+        return Err(ErrorNotFound("Book not found"));
+    }
+    file_of(&data, &device.library, book, &format)
+}
+
+/// A cover as the device asks for it: as wide as one of
+/// `catalog.thumbnail_widths`, whatever quality it asks for.
+#[actix_web::get("/kobo/{token}/{uuid}/{width}/{height}/{quality}/{greyscale}/image.jpg")]
+async fn cover(data: web::Data<AppState>, path: web::Path<(String, String, u32, u32, String, String)>) -> Result<fs::NamedFile, Error> {
+    let (token, uuid, width, ..) = path.into_inner();
+    cover_of(&data, &token, &uuid, width).await
+}
+
+#[actix_web::get("/kobo/{token}/{uuid}/{width}/{height}/{greyscale}/image.jpg")]
+async fn plain_cover(data: web::Data<AppState>, path: web::Path<(String, String, u32, u32, String)>) -> Result<fs::NamedFile, Error> {
+    let (token, uuid, width, ..) = path.into_inner();
+    cover_of(&data, &token, &uuid, width).await
+}
+
+async fn cover_of(data: &AppState, token: &str, uuid: &str, width: u32) -> Result<fs::NamedFile, Error> {
+    let Some((name, device)) = data.config.kobo.device(token) else {
+        return Err(ErrorUnauthorized("Unauthorized"));
+    };
+    if held(data, name, uuid).is_err() {
+        return Err(ErrorNotFound("Book not found"));
+    }
+    let found = match data.library(&device.library) {
+        Some(db) => calibre::books_by_uuid(&db, &[uuid.to_string()]).map_err(ErrorInternalServerError)?,
+        None => return Err(ErrorNotFound("Library not found")),
+    };
+    let book = *found.get(uuid).ok_or_else(|| ErrorNotFound("Book not found"))?;
+    let width = thumbnail::width(Some(width), &data.config.catalog.thumbnail_widths);
+    scaled(data, &device.library, book, width).await
+}
+
+/// Whatever else the store does -- deals, analytics, the user's profile --
+/// Orca does not, and says nothing about.
+#[actix_web::route("/kobo/{token}/{tail:.*}", method = "GET", method = "POST", method = "PUT", method = "DELETE")]
+async fn elsewhere(data: web::Data<AppState>, path: web::Path<(String, String)>) -> impl Responder {
+    match device(&data, &path.0) {
+        Ok(_) => HttpResponse::Ok().json(json!({})),
+        Err(response) => response,
+    }
+}
+
+// ------- Helper Functions -------
+
+/// A time the way the store writes it, from seconds since the epoch:
+/// `2025-01-01T00:00:00Z`.
+fn kobo_time(secs: i64) -> String {
+    // Days to a civil date, after Howard Hinnant's `civil_from_days`.
+    let (days, seconds) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, seconds / 3600, seconds % 3600 / 60, seconds % 60)
+}
+
+/// One of Calibre's times, always in UTC, the way the store writes it.
+fn kobo_time_of(calibre: &str) -> String {
+    format!("{}Z", calibre.get(..19).unwrap_or(calibre))
+}
+
+/// A uuid made up from a name, the same for the same name: a series is no
+/// more than a name to Calibre, and a Kobo wants an id for it.
+fn uuid_of(name: &str) -> String {
+    let hex = format!("{:x}", Md5::digest(name.as_bytes()));
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times_are_written_the_way_the_store_writes_them() {
+        assert_eq!(kobo_time(0), "1970-01-01T00:00:00Z");
+        assert_eq!(kobo_time(951_825_599), "2000-02-29T11:59:59Z");
+        assert_eq!(kobo_time(1_735_689_600), "2025-01-01T00:00:00Z");
+        assert_eq!(kobo_time_of("2024-03-01T10:20:30.123456+00:00"), "2024-03-01T10:20:30Z");
+    }
+
+    #[test]
+    fn a_finished_book_is_read_whole() {
+        let reading = json!({ "StatusInfo": { "Status": "Reading" }, "CurrentBookmark": { "ProgressPercent": 42 } });
+        assert_eq!(percentage(&reading), 0.42);
+        let finished = json!({ "StatusInfo": { "Status": "Finished" }, "CurrentBookmark": {} });
+        assert_eq!(percentage(&finished), 1.0);
+        assert_eq!(percentage(&json!({})), 0.0);
+    }
+}
@@ -0,0 +1,258 @@
+//! KOReader's progress sync
+//!
+//! The API of koreader-sync-server, which KOReader's "Progress sync" plugin
+//! speaks: point it at `{server}/kosync` as a custom sync server. A reader
+//! logs in as a login of the catalog, with the key `orca --hash` prints for
+//! `[authentication.kosync]`, and where they are in each book is kept in
+//! Orca's own database. Nobody registers from KOReader; logins are made in
+//! the config.
+//!
+//! KOReader calls a book by a digest of its file, which Orca knows for the
+//! files of its libraries: a book downloaded from the catalog, read on the
+//! desktop in Calibre's viewer, picks up where the viewer left it, and with
+//! `write_positions` the other way round. Both only ever go as far as the
+//! chapter: KOReader and Calibre have no finer place in common.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{http::StatusCode, web, HttpResponse, Responder};
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+
+use crate::appstate::AppState;
+use crate::authorized::Authorized;
+use crate::calibre::{self, ReadPosition};
+use crate::progress::{self, Document, Position};
+use crate::reading;
+
+/// The device Orca writes KOReader's positions to Calibre as. It never
+/// writes to a position of another device.
+const DEVICE: &str = "orca";
+
+/// An error the way koreader-sync-server reports it: a code of its own and a
+/// message KOReader may show.
+fn refusal(status: StatusCode, code: u32, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(json!({ "code": code, "message": message }))
+}
+
+fn unauthorized() -> HttpResponse {
+    refusal(StatusCode::UNAUTHORIZED, 2001, "Unauthorized")
+}
+
+fn invalid() -> HttpResponse {
+    refusal(StatusCode::FORBIDDEN, 2003, "Invalid request")
+}
+
+fn internal(what: &str, e: impl std::fmt::Display) -> HttpResponse {
+    eprintln!("{}: {}", what, e);
+    refusal(StatusCode::INTERNAL_SERVER_ERROR, 2000, "Unknown server error.")
+}
+
+/// Who is syncing: a login of the catalog, never a guest let in on a public path.
+fn reader(data: &AppState, auth: Option<Authorized>) -> Option<String> {
+    auth.map(|auth| auth.login).filter(|login| data.config.authentication.login.contains_key(login))
+}
+
+#[actix_web::get("/kosync/healthcheck")]
+async fn healthcheck() -> impl Responder {
+    HttpResponse::Ok().json(json!({ "state": "OK" }))
+}
+
+#[actix_web::post("/kosync/users/create")]
+async fn register() -> impl Responder {
+    refusal(StatusCode::PAYMENT_REQUIRED, 2005, "User registration is disabled.")
+}
+
+/// KOReader asks before it syncs at all, to tell a wrong password from a
+/// server that is down.
+#[actix_web::get("/kosync/users/auth")]
+async fn authorize(data: web::Data<AppState>, auth: Option<Authorized>) -> impl Responder {
+    match reader(&data, auth) {
+        Some(_) => HttpResponse::Ok().json(json!({ "authorized": "OK" })),
+        None => unauthorized(),
+    }
+}
+
+/// What KOReader sends when a reader leaves a book, or turns enough pages.
+#[derive(Deserialize)]
+struct Update {
+    document: Option<String>,
+    /// An XPointer, or for a book of pages the page number
+    progress: Option<Value>,
+    percentage: Option<f64>,
+    device: Option<String>,
+    device_id: Option<String>,
+}
+
+#[actix_web::put("/kosync/syncs/progress")]
+async fn update_progress(data: web::Data<AppState>, auth: Option<Authorized>, body: web::Bytes) -> impl Responder {
+    let Some(login) = reader(&data, auth) else {
+        return unauthorized();
+    };
+    let Ok(update) = serde_json::from_slice::<Update>(&body) else {
+        return invalid();
+    };
+    let Some(document) = update.document.filter(|document| !document.is_empty()) else {
+        return refusal(StatusCode::FORBIDDEN, 2004, "Field 'document' not provided.");
+    };
+    let (Some(progress), Some(percentage), Some(device)) = (update.progress, update.percentage, update.device) else {
+        return invalid();
+    };
+    // Handed back as it came, as text: KOReader reads a page number from that too.
+    let progress = match progress {
+        Value::String(progress) => progress,
+        Value::Number(page) => page.to_string(),
+        _ => return invalid(),
+    };
+
+    let position = Position {
+        document,
+        progress,
+        percentage,
+        device,
+        device_id: update.device_id.unwrap_or_default(),
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64,
+    };
+    if let Err(e) = progress::save(&progress::lock(&data.progress), &login, &position) {
+        return internal("Error saving progress", e);
+    }
+    // The sync has done its job by now. Calibre is told as a favour, by a
+    // library that asked for it: finding the book may read its files, so
+    // the server goes on meanwhile.
+    if data.config.calibre.libraries.values().any(|library| library.write_positions) {
+        let (data, written) = (data.clone(), position.clone());
+        let blocked = web::block(move || {
+            reading::documents(&data, &written.document).and_then(|books| write_back(&data, &login, &written, &books))
+        })
+        .await;
+        match blocked {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => eprintln!("Error writing the position in {} back to Calibre: {}", position.document, e),
+            Err(e) => eprintln!("Error writing the position in {} back to Calibre: {}", position.document, e),
+        }
+    }
+    HttpResponse::Ok().json(json!({ "document": position.document, "timestamp": position.timestamp }))
+}
+
+/// Where the reader last was in a book, on whichever device. An empty object
+/// for a book they never synced.
+#[actix_web::get("/kosync/syncs/progress/{document}")]
+async fn get_progress(data: web::Data<AppState>, path: web::Path<String>, auth: Option<Authorized>) -> impl Responder {
+    let Some(login) = reader(&data, auth) else {
+        return unauthorized();
+    };
+    let document = path.into_inner();
+    let synced = match progress::position(&progress::lock(&data.progress), &login, &document) {
+        Ok(synced) => synced,
+        Err(e) => return internal("Error reading progress", e),
+    };
+    // Calibre's position is a favour as well: without it, the one synced stands.
+    let viewed = from_calibre(&data, &login, &document).unwrap_or_else(|e| {
+        eprintln!("Error reading Calibre's position in {}: {}", document, e);
+        None
+    });
+    // Of two saved at once, the one synced: Calibre's is what Orca wrote back.
+    match viewed.into_iter().chain(synced).max_by_key(|position| position.timestamp) {
+        Some(position) => HttpResponse::Ok().json(position),
+        None => HttpResponse::Ok().json(json!({})),
+    }
+}
+
+/// KOReader's fragments are the files of an EPUB's spine, the same as the
+/// first step of Calibre's CFI; in any other format the two have nothing in common.
+fn is_epub(document: &&Document) -> bool {
+    document.format.eq_ignore_ascii_case("epub")
+}
+
+/// Where Calibre's viewer last was in the book, as KOReader's progress: the
+/// start of the chapter.
+fn from_calibre(data: &AppState, login: &str, document: &str) -> rusqlite::Result<Option<Position>> {
+    let users = reading::position_users(data, login);
+    if users.is_empty() {
+        return Ok(None);
+    }
+    let mut latest: Option<ReadPosition> = None;
+    for epub in reading::documents(data, document)?.iter().filter(is_epub) {
+        let Some(db) = data.library(&epub.library) else {
+            continue;
+        };
+        let positions = calibre::read_positions(&db, &users)?;
+        drop(db);
+        let position = positions
+            .into_iter()
+            .find(|position| position.book == epub.book && position.format.eq_ignore_ascii_case(&epub.format));
+        if let Some(position) = position.filter(|position| latest.as_ref().is_none_or(|latest| latest.epoch < position.epoch)) {
+            latest = Some(position);
+        }
+    }
+    Ok(latest.and_then(|position| {
+        Some(Position {
+            document: document.to_string(),
+            progress: fragment_of(&position.cfi)?,
+            percentage: position.pos_frac,
+            device: "Calibre".to_string(),
+            device_id: String::new(),
+            timestamp: position.epoch as i64,
+        })
+    }))
+}
+
+/// Tell Calibre where the reader is, in the libraries that asked for it, as
+/// the first of the Calibre users their login is. A page number or a format
+/// other than EPUB is left alone.
+fn write_back(data: &AppState, login: &str, position: &Position, books: &[Document]) -> rusqlite::Result<()> {
+    let Some(user) = reading::position_users(data, login).into_iter().next() else {
+        return Ok(());
+    };
+    let Some(cfi) = cfi_of(&position.progress) else {
+        return Ok(());
+    };
+    for epub in books.iter().filter(is_epub) {
+        let writes = data.config.calibre.libraries.get(&epub.library).is_some_and(|library| library.write_positions);
+        let Some(db) = writes.then(|| data.library(&epub.library)).flatten() else {
+            continue;
+        };
+        calibre::set_read_position(
+            &db,
+            &ReadPosition {
+                book: epub.book,
+                format: epub.format.clone(),
+                user: user.clone(),
+                device: DEVICE.to_string(),
+                cfi: cfi.clone(),
+                epoch: position.timestamp as f64,
+                pos_frac: position.percentage,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+/// The chapter a position of Calibre's is in, as an XPointer of KOReader's:
+/// `epubcfi(/8/4/2/1:0)` is in the fourth file of the spine, `DocFragment[4]`.
+fn fragment_of(cfi: &str) -> Option<String> {
+    let step: u32 = cfi.strip_prefix("epubcfi(/")?.split(['/', ')', '[', '!']).next()?.parse().ok()?;
+    (step >= 2 && step.is_multiple_of(2)).then(|| format!("/body/DocFragment[{}]", step / 2))
+}
+
+/// The chapter KOReader's progress is in, as a position of Calibre's: the
+/// top of that file's `<body>`.
+fn cfi_of(xpointer: &str) -> Option<String> {
+    let fragment: u32 = xpointer.strip_prefix("/body/DocFragment[")?.split(']').next()?.parse().ok()?;
+    (fragment > 0).then(|| format!("epubcfi(/{}/4)", fragment * 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_chapter_is_the_same_to_koreader_and_calibre() {
+        assert_eq!(fragment_of("epubcfi(/10/4/2/1:0)").as_deref(), Some("/body/DocFragment[5]"));
+        assert_eq!(cfi_of("/body/DocFragment[5]/body/p[3]/text().12").as_deref(), Some("epubcfi(/10/4)"));
+        assert_eq!(fragment_of(&cfi_of("/body/DocFragment[12]").unwrap()).as_deref(), Some("/body/DocFragment[12]"));
+
+        assert_eq!(cfi_of("42"), None);
+        assert_eq!(fragment_of("epubcfi(/7/4)"), None);
+    }
+}
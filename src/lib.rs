@@ -9,19 +9,38 @@ pub mod hash;
 pub mod opds2;
 pub mod routes;
 pub mod routes_v2;
+pub mod shelf;
 pub mod pattern;
-
-use actix_web::{web, App, HttpServer};
+pub mod query;
+pub mod fulltext;
+pub mod restriction;
+pub mod thumbnail;
+pub mod placeholder;
+pub mod conditional;
+pub mod compression;
+pub mod annotation;
+pub mod progress;
+pub mod kosync;
+pub mod reading;
+pub mod kobo;
+
+use actix_web::{http::header::ContentEncoding, middleware::from_fn, web, App, HttpServer};
 use anyhow::{anyhow, Result};
 use rusqlite::Connection;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 use tera::{Kwargs, State, Tera};
 
 use config::{Config, Protocol};
 use templates::Template;
-use routes::{health, authors, book_file, books_by_author, books_by_tag, cover, getbooks, index, opds, recently_added, tags};
+use routes::{
+    health, all_series, authors, book_entry, book_file, books_by_author, books_by_custom, books_by_language, books_by_publisher,
+    books_by_rating, books_by_tag, books_in_series, cover, custom_column, note_resource, thumb, getbooks, index, languages, opds, publishers,
+    opensearch, ratings, recently_added, currently_reading, saved_search, saved_searches, search, tags, top_rated, user_categories,
+    user_category, virtual_library, titles, books_titled,
+};
 use appstate::AppState;
 
 // Tera filter to convert format to mime type -- OPDS v2 links can use it too.
@@ -49,8 +68,68 @@ fn open_library(library: &str, path: &str) -> Result<Connection> {
     Ok(db)
 }
 
+/// Orca's own search index, kept next to the library's database rather than in it,
+/// and brought up to date before the first search needs it.
+fn attach_index(library: &str, db: &Connection, path: &str) -> Result<()> {
+    if let Some(dir) = Path::new(path).parent().filter(|_| path != ":memory:") {
+        fs::create_dir_all(dir)
+            .map_err(|e| anyhow!("library '{}': could not create '{}' for its search index: {}", library, dir.display(), e))?;
+    }
+    fulltext::attach(db, path)
+        .map_err(|e| anyhow!("library '{}': could not open its search index at '{}': {}", library, path, e))?;
+    fulltext::refresh(db).map_err(|e| anyhow!("library '{}': could not build its search index: {}", library, e))
+}
+
+/// Orca's own record of where its readers are, in a directory made for it if need be.
+fn open_progress(path: &str) -> Result<Connection> {
+    if let Some(dir) = Path::new(path).parent().filter(|_| path != ":memory:") {
+        fs::create_dir_all(dir)
+            .map_err(|e| anyhow!("[progress]: could not create '{}' for the database: {}", dir.display(), e))?;
+    }
+    progress::open(path).map_err(|e| anyhow!("[progress]: could not open the database at '{}': {}", path, e))
+}
+
+/// A library that is one of Calibre's virtual libraries, or why it cannot be.
+fn restrict(library: &str, db: &Connection, name: &str) -> Result<()> {
+    match calibre::virtual_library(db, name) {
+        Ok(_) => restriction::restrict(db, name)
+            .map_err(|e| anyhow!("library '{}': could not restrict it to '{}': {}", library, name, e)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            Err(anyhow!("library '{}': Calibre has no virtual library '{}'", library, name))
+        }
+        Err(e) => Err(anyhow!("library '{}': could not read its virtual libraries: {}", library, e)),
+    }
+}
+
+/// The custom columns a library's config asks for, or why one of them cannot be shown.
+fn exposed_columns(library: &str, db: &Connection, labels: &[String]) -> Result<Vec<calibre::CustomColumn>> {
+    if labels.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let defined = calibre::custom_columns(db)
+        .map_err(|e| anyhow!("library '{}': could not read its custom columns: {}", library, e))?;
+
+    // Calibre users know a column as `#translator`; either spelling will do.
+    labels
+        .iter()
+        .map(|label| label.trim_start_matches('#'))
+        .map(|label| {
+            defined
+                .iter()
+                .find(|column| column.label == label)
+                .cloned()
+                .ok_or_else(|| anyhow!("library '{}': no custom column '#{}' that Orca can show", library, label))
+        })
+        .collect()
+}
+
 /// Path segments reserved to orca. Can't serve a library under these.
-const RESERVED: [&str; 2] = ["v2", "health"];
+const RESERVED: [&str; 4] = ["v2", "health", "kosync", "kobo"];
+
+/// How often the digests of the books' files catch up with Calibre. Nothing
+/// is read while Calibre changed no book.
+const REINDEX: std::time::Duration = std::time::Duration::from_secs(60);
 
 pub fn create_app(config: &'static Config) -> Result<AppState> {
 
@@ -63,14 +142,71 @@ pub fn create_app(config: &'static Config) -> Result<AppState> {
         return Err(anyhow!("library '{}': the name is reserved by Orca itself", library));
     }
 
+    // Covers are scaled to these widths; a zero would be no cover at all
+    let widths = &config.catalog.thumbnail_widths;
+    if widths.is_empty() || widths.contains(&0) {
+        return Err(anyhow!("[catalog] thumbnail_widths: at least one width, and none of them 0"));
+    }
+
+    // Compressed feeds can only be sent in an encoding actix knows
+    let compression = &config.server.compression;
+    let offered = compression.types.values().filter_map(|settings| settings.encodings.as_ref());
+    if let Some(unknown) = offered.chain([&compression.encodings]).flatten().find(|name| name.parse::<ContentEncoding>().is_err()) {
+        return Err(anyhow!("[server.compression]: no encoding '{}', only br, zstd, gzip and deflate", unknown));
+    }
+
+    // KOReader logs in to the sync as a login of the catalog
+    if let Some(login) = config.authentication.kosync.keys().find(|login| !config.authentication.login.contains_key(*login)) {
+        return Err(anyhow!("[authentication.kosync]: no login '{}'", login));
+    }
+
+    // Annotations and positions are read for a login, under the name Calibre keeps them by
+    for (login, users) in &config.authentication.calibre_users {
+        if !config.authentication.login.contains_key(login) {
+            return Err(anyhow!("[authentication.calibre_users]: no login '{}'", login));
+        }
+        if let Some(user) = users.iter().find(|user| !matches!(user.split_once(':'), Some(("local" | "web", name)) if !name.is_empty())) {
+            return Err(anyhow!("[authentication.calibre_users] {}: '{}' is no Calibre user, try 'local:viewer' or 'web:<name>'", login, user));
+        }
+    }
+
     // Every configured library has to open
     let mut db_map: HashMap<String, Arc<Mutex<Connection>>> = HashMap::new();
+    let mut columns: HashMap<String, Vec<calibre::CustomColumn>> = HashMap::new();
     for (library, settings) in &config.calibre.libraries {
         let db = open_library(library, &settings.path)?;
+        attach_index(library, &db, &settings.search_index(library))?;
+        if let Some(name) = &settings.virtual_library {
+            restrict(library, &db, name)?;
+        }
         println!("Connected to {}", library);
+        columns.insert(library.clone(), exposed_columns(library, &db, &settings.custom_columns)?);
         db_map.insert(library.clone(), Arc::new(Mutex::new(db)));
     }
 
+    // A Kobo syncs the shelves of one library, as a login of the catalog
+    let mut tokens = HashSet::new();
+    for (name, device) in &config.kobo.devices {
+        if !config.authentication.login.contains_key(&device.login) {
+            return Err(anyhow!("[kobo.devices.{}]: no login '{}'", name, device.login));
+        }
+        let Some(db) = db_map.get(&device.library) else {
+            return Err(anyhow!("[kobo.devices.{}]: no library '{}'", name, device.library));
+        };
+        if device.token.len() != 64 || !device.token.chars().all(|c| c.is_ascii_hexdigit()) || !tokens.insert(&device.token) {
+            return Err(anyhow!("[kobo.devices.{}]: the token is no SHA-256 of its own, try `orca --kobo {}`", name, name));
+        }
+        for wanted in &device.shelves {
+            match kobo::shelf(&calibre::lock(db), wanted, &[]) {
+                Ok(Some(_)) => {}
+                Ok(None) => return Err(anyhow!("[kobo.devices.{}]: no shelf '{}' in library '{}'", name, wanted, device.library)),
+                Err(e) => return Err(anyhow!("[kobo.devices.{}]: could not read the shelf '{}': {}", name, wanted, e)),
+            }
+        }
+    }
+
+    let progress = open_progress(&config.progress.database())?;
+
     let mut tera = Tera::default();
 
     // Tera resolves filters when a template is added, so custom filters have to
@@ -91,11 +227,28 @@ pub fn create_app(config: &'static Config) -> Result<AppState> {
 
     tera.add_raw_templates(templates).expect("Failed to add templates");
 
-    Ok(AppState {
+    let state = AppState {
         templates: tera,
         config,
         db: db_map,
-    })
+        columns,
+        progress: Arc::new(Mutex::new(progress)),
+        refreshed: Arc::new(Mutex::new(HashMap::new())),
+        digests: Arc::new(Mutex::new(Default::default())),
+    };
+    // KOReader knows a book by the digest of its file: they are taken in the
+    // background, for a large library to be served while its files are read,
+    // and taken again for the books Calibre adds or changes later on.
+    if !config.authentication.kosync.is_empty() {
+        let indexing = state.clone();
+        std::thread::spawn(move || loop {
+            if let Err(e) = reading::index(&indexing) {
+                eprintln!("Could not take the digests of the books' files: {}", e);
+            }
+            std::thread::sleep(REINDEX);
+        });
+    }
+    Ok(state)
 }
 
 pub async fn run_server(state: AppState) -> std::io::Result<()> {
@@ -139,44 +292,130 @@ pub async fn run_server(state: AppState) -> std::io::Result<()> {
 pub fn init(cfg: &mut web::ServiceConfig) {
     cfg.service(health);
 
+    // KOReader is pointed at `/kosync` and finds its own paths below it.
+    cfg.service(kosync::healthcheck);
+    cfg.service(kosync::register);
+    cfg.service(kosync::authorize);
+    cfg.service(kosync::update_progress);
+    cfg.service(kosync::get_progress);
+
+    // A Kobo is pointed at `/kobo/{token}`; whatever it asks that Orca does
+    // not answer falls through to the last of them.
+    cfg.service(kobo::initialization);
+    cfg.service(kobo::authenticate);
+    cfg.service(kobo::refresh);
+    cfg.service(kobo::sync);
+    cfg.service(kobo::book_metadata);
+    cfg.service(kobo::get_state);
+    cfg.service(kobo::put_state);
+    cfg.service(kobo::remove);
+    cfg.service(kobo::download);
+    cfg.service(kobo::cover);
+    cfg.service(kobo::plain_cover);
+    cfg.service(kobo::elsewhere);
+
+    // Wrapped here rather than around the App, so that every App built on
+    // `init` -- the tests' included -- answers conditional requests and
+    // compresses its feeds. The last wrap is the outermost: what gets
+    // compressed is the response as it leaves, validators and all.
+    cfg.service(
+        web::scope("")
+            .wrap(from_fn(conditional::revalidate))
+            .wrap(from_fn(compression::compress))
+            .configure(catalogs),
+    );
+}
+
+fn catalogs(cfg: &mut web::ServiceConfig) {
     cfg.service(routes_v2::catalog);
     cfg.service(routes_v2::library_root);
     cfg.service(routes_v2::all_books);
+    cfg.service(routes_v2::titles);
+    cfg.service(routes_v2::books_titled);
     cfg.service(routes_v2::recently_added);
+    cfg.service(routes_v2::currently_reading);
+    cfg.service(routes_v2::next_in_series);
+    cfg.service(routes_v2::top_rated);
     cfg.service(routes_v2::single_book);
+    cfg.service(routes_v2::book_annotations);
+    cfg.service(routes_v2::search_annotations);
     cfg.service(routes_v2::authors);
     cfg.service(routes_v2::tags);
     cfg.service(routes_v2::books_by_author);
     cfg.service(routes_v2::books_by_tag);
+    cfg.service(routes_v2::all_series);
+    cfg.service(routes_v2::books_in_series);
+    cfg.service(routes_v2::publishers);
+    cfg.service(routes_v2::books_by_publisher);
+    cfg.service(routes_v2::languages);
+    cfg.service(routes_v2::books_by_language);
+    cfg.service(routes_v2::ratings);
+    cfg.service(routes_v2::books_by_rating);
+    cfg.service(routes_v2::custom_column);
+    cfg.service(routes_v2::books_by_custom);
     cfg.service(routes_v2::search);
+    cfg.service(routes_v2::virtual_library);
+    cfg.service(routes_v2::saved_searches);
+    cfg.service(routes_v2::saved_search);
+    cfg.service(routes_v2::user_categories);
+    cfg.service(routes_v2::user_category);
 
     cfg.service(index);
     cfg.service(opds);
     cfg.service(tags);
     cfg.service(authors);
     cfg.service(getbooks);
+    cfg.service(titles);
+    cfg.service(books_titled);
     cfg.service(recently_added);
+    cfg.service(currently_reading);
+    cfg.service(top_rated);
+    cfg.service(book_entry);
     cfg.service(book_file);
+    cfg.service(note_resource);
     cfg.service(cover);
+    cfg.service(thumb);
     cfg.service(books_by_tag);
     cfg.service(books_by_author);
+    cfg.service(all_series);
+    cfg.service(books_in_series);
+    cfg.service(publishers);
+    cfg.service(books_by_publisher);
+    cfg.service(languages);
+    cfg.service(books_by_language);
+    cfg.service(ratings);
+    cfg.service(books_by_rating);
+    cfg.service(opensearch);
+    cfg.service(search);
+    cfg.service(virtual_library);
+    cfg.service(saved_searches);
+    cfg.service(saved_search);
+    cfg.service(user_categories);
+    cfg.service(user_category);
+    cfg.service(custom_column);
+    cfg.service(books_by_custom);
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use config::{Authentication, Calibre, Catalog, Library, Protocol, Server};
+    use config::{Authentication, Cache, Calibre, Catalog, Compression, Kobo, Library, Progress, Protocol, Server};
     use std::fs;
     use tempfile::TempDir;
 
     // `create_app` takes a `&'static Config`; leaking one per test is cheap and
     // keeps these tests independent of any config file on disk.
     fn config_for(libraries: &[(&str, &str)]) -> &'static Config {
-        Box::leak(Box::new(Config {
+        Box::leak(Box::new(settings_for(libraries)))
+    }
+
+    fn settings_for(libraries: &[(&str, &str)]) -> Config {
+        Config {
             server: Server {
                 ip: "127.0.0.1".to_string(),
                 port: 8080,
                 public_url: None,
+                compression: Compression::default(),
                 protocol: Protocol::Http,
             },
             authentication: Authentication::default(),
@@ -186,13 +425,25 @@ mod tests {
                     .map(|(name, path)| {
                         (
                             name.to_string(),
-                            Library { path: path.to_string(), author: None },
+                            Library {
+                                path: path.to_string(),
+                                author: None,
+                                custom_columns: Vec::new(),
+                                search_index: Some(":memory:".to_string()),
+                                virtual_library: None,
+                                index_above: None,
+                                thumbnail_cache: None,
+                                write_positions: false,
+                            },
                         )
                     })
                     .collect(),
             },
             catalog: Catalog::default(),
-        }))
+            cache: Cache::default(),
+            progress: Progress { database: Some(":memory:".to_string()) },
+            kobo: Kobo::default(),
+        }
     }
 
     // `expect_err` is unavailable here: it needs `AppState: Debug`, which would
@@ -251,6 +502,88 @@ mod tests {
         assert!(err.contains("library 'bad'"), "{}", err);
     }
 
+    #[test]
+    fn a_custom_column_is_asked_for_by_its_lookup_name() {
+        let db = open_library("library", "tests/calibre").unwrap();
+        let columns = exposed_columns("library", &db, &["#read".to_string(), "shelf".to_string()]).unwrap();
+
+        let labels: Vec<&str> = columns.iter().map(|column| column.label.as_str()).collect();
+        assert_eq!(labels, ["read", "shelf"]);
+    }
+
+    // A typo would otherwise show readers nothing, and nobody would know why.
+    #[test]
+    fn a_custom_column_the_library_does_not_have_refuses_to_start() {
+        let db = open_library("library", "tests/calibre").unwrap();
+        let err = exposed_columns("library", &db, &["#translater".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("no custom column '#translater'"), "{}", err);
+    }
+
+    // Otherwise the library would be empty, and nobody would know why.
+    #[test]
+    fn a_virtual_library_calibre_does_not_have_refuses_to_start() {
+        let db = open_library("library", "tests/calibre").unwrap();
+        let err = restrict("kids", &db, "Kinder").unwrap_err();
+        assert!(err.to_string().contains("Calibre has no virtual library 'Kinder'"), "{}", err);
+    }
+
+    // Otherwise feeds would quietly go out uncompressed.
+    #[test]
+    fn an_encoding_actix_does_not_know_refuses_to_start() {
+        let mut config = settings_for(&[("library", "tests/calibre")]);
+        config.server.compression.encodings.push("lzma".to_string());
+
+        let err = refusal(create_app(Box::leak(Box::new(config))), "lzma is not on offer");
+        assert!(err.contains("no encoding 'lzma'"), "{}", err);
+    }
+
+    #[test]
+    fn only_a_login_is_a_calibre_user() {
+        let mut config = settings_for(&[("library", "tests/calibre")]);
+        config.authentication.calibre_users.insert("carol".to_string(), vec!["local:viewer".to_string()]);
+        let err = refusal(create_app(Box::leak(Box::new(config))), "carol cannot log in");
+        assert!(err.contains("no login 'carol'"), "{}", err);
+
+        let mut config = settings_for(&[("library", "tests/calibre")]);
+        config.authentication.login.insert("alice".to_string(), "...".to_string());
+        config.authentication.calibre_users.insert("alice".to_string(), vec!["alice".to_string()]);
+        let err = refusal(create_app(Box::leak(Box::new(config))), "Calibre has no user 'alice'");
+        assert!(err.contains("'alice' is no Calibre user"), "{}", err);
+    }
+
+    #[test]
+    fn a_sync_key_without_a_login_refuses_to_start() {
+        let mut config = settings_for(&[("library", "tests/calibre")]);
+        config.authentication.kosync.insert("carol".to_string(), "...".to_string());
+        let err = refusal(create_app(Box::leak(Box::new(config))), "carol cannot log in");
+        assert!(err.contains("[authentication.kosync]: no login 'carol'"), "{}", err);
+    }
+
+    #[test]
+    fn a_kobo_syncs_shelves_the_library_has() {
+        let kobo = |shelves: &[&str], token: &str| {
+            let mut config = settings_for(&[("library", "tests/calibre")]);
+            config.authentication.login.insert("alice".to_string(), "...".to_string());
+            let device = config::Device {
+                token: token.to_string(),
+                login: "alice".to_string(),
+                library: "library".to_string(),
+                shelves: shelves.iter().map(|shelf| shelf.to_string()).collect(),
+            };
+            config.kobo.devices.insert("libra".to_string(), device);
+            create_app(Box::leak(Box::new(config)))
+        };
+        let token = hash::token_hash("a token");
+        assert!(kobo(&["tag:science fiction", "series:Astounding Stories", "reading"], &token).is_ok());
+
+        let err = refusal(kobo(&["tag:poetry"], &token), "the library has no poetry");
+        assert!(err.contains("[kobo.devices.libra]: no shelf 'tag:poetry' in library 'library'"), "{}", err);
+        let err = refusal(kobo(&["science fiction"], &token), "a shelf says what it is");
+        assert!(err.contains("no shelf 'science fiction'"), "{}", err);
+        let err = refusal(kobo(&[], "a token"), "the token is kept hashed");
+        assert!(err.contains("orca --kobo libra"), "{}", err);
+    }
+
     #[test]
     fn no_libraries_refuses_to_start() {
         let err = refusal(
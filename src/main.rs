@@ -11,6 +11,9 @@ use orca::{config, create_app, run_server, hash};
 struct Cli {
     #[arg(long = "hash", value_name = "login:password")]
     login_password: Option<String>,
+    /// Make a token for a Kobo's store URL, and print what goes in the config
+    #[arg(long = "kobo", value_name = "device")]
+    kobo_device: Option<String>,
 }
 
 #[actix_web::main]
@@ -28,6 +31,11 @@ async fn main() -> std::io::Result<()> {
         exit(0);
     }
 
+    if let Some(device) = args.kobo_device.as_ref() {
+        println!("{}", hash::encode_kobo_device(device));
+        exit(0);
+    }
+
     // report correct version to the logs even when running under `:latest` tag.
     println!("orca v{}", env!("CARGO_PKG_VERSION"));
 
@@ -6,6 +6,7 @@
 //! optional keys are left out rather than serialised as `null` or `[]`
 
 use serde_derive::Serialize;
+use std::collections::BTreeMap;
 
 /// The media type of a feed or the type of a link that leads to one
 pub const FEED: &str = "application/opds+json";
@@ -23,9 +24,18 @@ pub const ACQUISITION: &str = "http://opds-spec.org/acquisition";
 /// looks for this rel, and falls back to whatever image it can find.
 pub const IMAGE: &str = "http://opds-spec.org/image";
 
+/// The rel of a cover scaled down for a list of books.
+pub const THUMBNAIL: &str = "http://opds-spec.org/image/thumbnail";
+
 /// The rel of a feed of what arrived last.
 pub const SORT_NEW: &str = "http://opds-spec.org/sort/new";
 
+/// The rel of a feed of what readers liked best.
+pub const SORT_POPULAR: &str = "http://opds-spec.org/sort/popular";
+
+/// The rel of a link from one book to another a reader may want next.
+pub const RELATED: &str = "related";
+
 /// The rel of the templated link a client fills in to search the catalog.
 pub const SEARCH: &str = "search";
 
@@ -39,6 +49,12 @@ pub struct Feed {
     pub navigation: Vec<Link>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub publications: Vec<Publication>,
+    /// Other ways to sort or narrow down the publications of this feed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub facets: Vec<Facet>,
+    /// Rows of publications shown inline, each leading on to the rest of them
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<Group>,
 }
 
 impl Feed {
@@ -52,6 +68,8 @@ impl Feed {
             links: vec![Link::new(self_url).rel("self").mime(FEED)],
             navigation: Vec::new(),
             publications: Vec::new(),
+            facets: Vec::new(),
+            groups: Vec::new(),
         }
     }
 
@@ -60,6 +78,12 @@ impl Feed {
         self
     }
 
+    /// Plain text. Nothing is said where there is nothing to say.
+    pub fn description(mut self, text: &str) -> Self {
+        self.metadata.description = Some(text.to_string()).filter(|text| !text.is_empty());
+        self
+    }
+
     pub fn navigation(mut self, links: Vec<Link>) -> Self {
         self.navigation = links;
         self
@@ -70,6 +94,16 @@ impl Feed {
         self
     }
 
+    pub fn facets(mut self, facets: Vec<Facet>) -> Self {
+        self.facets = facets;
+        self
+    }
+
+    pub fn groups(mut self, groups: Vec<Group>) -> Self {
+        self.groups = groups;
+        self
+    }
+
     pub fn link(mut self, link: Link) -> Self {
         self.links.push(link);
         self
@@ -85,12 +119,57 @@ impl Feed {
     }
 }
 
+/// One group of facets: a title, and a link for each option. The option in use
+/// is the link with `rel="self"`.
+#[derive(Debug, Serialize)]
+pub struct Facet {
+    pub metadata: FeedMetadata,
+    pub links: Vec<Link>,
+}
+
+impl Facet {
+    pub fn new(title: impl Into<String>, links: Vec<Link>) -> Self {
+        Facet {
+            metadata: FeedMetadata {
+                title: title.into(),
+                ..FeedMetadata::default()
+            },
+            links,
+        }
+    }
+}
+
+/// A row of publications within a feed: a few of them, a title, and a link
+/// with `rel="self"` to the feed that holds them all.
+#[derive(Debug, Serialize)]
+pub struct Group {
+    pub metadata: FeedMetadata,
+    pub links: Vec<Link>,
+    pub publications: Vec<Publication>,
+}
+
+impl Group {
+    pub fn new(title: impl Into<String>, more: impl Into<String>, publications: Vec<Publication>) -> Self {
+        Group {
+            metadata: FeedMetadata {
+                title: title.into(),
+                ..FeedMetadata::default()
+            },
+            links: vec![Link::new(more).rel("self").mime(FEED)],
+            publications,
+        }
+    }
+}
+
 #[derive(Debug, Default, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct FeedMetadata {
     pub title: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub modified: Option<String>,
+    /// What the feed is about, where there is more to say than the title.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub number_of_items: Option<usize>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -115,6 +194,11 @@ pub struct Link {
     pub templated: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub properties: Option<Properties>,
+    /// In pixels, for an image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
 }
 
 /// `templated` is absent rather than `false` on the links that are not templates.
@@ -131,6 +215,8 @@ impl Link {
             title: None,
             templated: false,
             properties: None,
+            width: None,
+            height: None,
         }
     }
 
@@ -151,6 +237,13 @@ impl Link {
         self
     }
 
+    /// How large an image is, so that a client can pick the one that fits.
+    pub fn size(mut self, width: u32, height: u32) -> Self {
+        self.width = Some(width);
+        self.height = Some(height);
+        self
+    }
+
     pub fn templated(mut self) -> Self {
         self.templated = true;
         self
@@ -189,6 +282,9 @@ pub struct BookMetadata {
     pub title: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub identifier: Option<String>,
+    /// Other URIs the same book goes by: an ISBN, a DOI, its page elsewhere
+    #[serde(rename = "altIdentifier", skip_serializing_if = "Vec::is_empty")]
+    pub alt_identifier: Vec<String>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub author: Vec<Contributor>,
     /// BCP 47 tags; the schema checks them against the full grammar.
@@ -207,6 +303,10 @@ pub struct BookMetadata {
     pub subject: Vec<Subject>,
     #[serde(rename = "belongsTo", skip_serializing_if = "Option::is_none")]
     pub belongs_to: Option<BelongsTo>,
+    /// What Readium has no word for, under a key of the catalog's own choosing.
+    /// The schema leaves the metadata object open for exactly this.
+    #[serde(flatten)]
+    pub extensions: BTreeMap<String, serde_json::Value>,
 }
 
 /// A contributor may just be a string. The object is what carries the link to
@@ -240,6 +340,9 @@ pub struct Series {
     /// `series_index` can be 1.5 in Calibre
     #[serde(skip_serializing_if = "Option::is_none")]
     pub position: Option<f64>,
+    /// The feed of every volume, the same as an author's or a subject's.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub links: Vec<Link>,
 }
 
 #[cfg(test)]
@@ -301,6 +404,7 @@ mod tests {
             kind: BOOK,
             title: "Galactic Patrol".to_string(),
             identifier: None,
+            alt_identifier: Vec::new(),
             author: vec![Contributor {
                 name: "E. E. Smith".to_string(),
                 links: Vec::new(),
@@ -318,8 +422,10 @@ mod tests {
                 series: Series {
                     name: "Astounding Stories".to_string(),
                     position: Some(3.0),
+                    links: Vec::new(),
                 },
             }),
+            extensions: BTreeMap::new(),
         };
 
         assert_eq!(
@@ -337,6 +443,35 @@ mod tests {
         );
     }
 
+    // Sits beside the keys Readium defines, not in an object of its own.
+    #[test]
+    fn an_extension_is_one_more_key_of_the_metadata() {
+        let metadata = BookMetadata {
+            kind: BOOK,
+            title: "Alice's Adventures in Wonderland".to_string(),
+            identifier: None,
+            alt_identifier: Vec::new(),
+            author: Vec::new(),
+            language: Vec::new(),
+            published: None,
+            modified: None,
+            description: None,
+            publisher: None,
+            subject: Vec::new(),
+            belongs_to: None,
+            extensions: BTreeMap::from([("#read".to_string(), json!(true))]),
+        };
+
+        assert_eq!(
+            to_value(&metadata).unwrap(),
+            json!({
+                "@type": "http://schema.org/Book",
+                "title": "Alice's Adventures in Wonderland",
+                "#read": true,
+            })
+        );
+    }
+
     // link to everything else by the same author, or about the same subject.
     #[test]
     fn an_author_and_a_subject_lead_somewhere() {
@@ -377,4 +512,32 @@ mod tests {
         assert_eq!(json["metadata"]["currentPage"], 2);
         assert_eq!(json["links"][1]["rel"], "previous");
     }
+
+    #[test]
+    fn a_facet_is_a_titled_group_of_links() {
+        let feed = Feed::new("library", "/v2/library/books?sort=added").facets(vec![Facet::new(
+            "Sort by",
+            vec![
+                Link::new("/v2/library/books?sort=title").mime(FEED).title("Title"),
+                Link::new("/v2/library/books?sort=added").mime(FEED).title("Newest added").rel("self"),
+            ],
+        )]);
+
+        let json = to_value(&feed).unwrap();
+        assert_eq!(json["facets"][0]["metadata"]["title"], "Sort by");
+        assert_eq!(json["facets"][0]["links"][1]["rel"], "self");
+        assert!(to_value(Feed::new("library", "/v2/library")).unwrap().get("facets").is_none());
+    }
+
+    #[test]
+    fn a_group_leads_on_to_the_feed_it_is_a_row_of() {
+        let feed = Feed::new("library", "/v2/library")
+            .groups(vec![Group::new("Top Rated", "/v2/library/top", Vec::new())]);
+
+        let json = to_value(&feed).unwrap();
+        assert_eq!(json["groups"][0]["metadata"]["title"], "Top Rated");
+        assert_eq!(json["groups"][0]["links"][0]["href"], "/v2/library/top");
+        assert_eq!(json["groups"][0]["links"][0]["rel"], "self");
+        assert!(to_value(Feed::new("library", "/v2/library")).unwrap().get("groups").is_none());
+    }
 }
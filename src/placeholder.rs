@@ -0,0 +1,214 @@
+//! A cover for a book Calibre has none for
+//!
+//! Title and authors, set in DejaVu Serif on a colour of the book's own. The
+//! colour comes from the uuid, so a book keeps it from one request to the next
+//! and two books next to each other in a grid rarely share one.
+
+use ab_glyph::{point, Font, FontRef, PxScale, ScaleFont};
+use image::{Rgb, RgbImage};
+use once_cell::sync::Lazy;
+
+static FONT: Lazy<FontRef<'static>> =
+    Lazy::new(|| FontRef::try_from_slice(include_bytes!("../fonts/DejaVuSerif.ttf")).expect("DejaVu Serif"));
+
+/// What the placeholder says, and what it takes its colour from.
+pub struct Placeholder<'a> {
+    pub title: &'a str,
+    pub authors: &'a str,
+    pub uuid: &'a str,
+}
+
+/// The text on every placeholder.
+const INK: Rgb<u8> = Rgb([245, 240, 228]);
+
+/// How many lines the authors may take.
+const AUTHOR_LINES: usize = 2;
+
+impl Placeholder<'_> {
+    /// The placeholder at `width` by `height` pixels.
+    pub fn draw(&self, width: u32, height: u32) -> RgbImage {
+        let mut cover = RgbImage::from_pixel(width, height, self.colour());
+        let margin = width as f32 / 10.0;
+        let room = width as f32 - 2.0 * margin;
+
+        // The title in the middle of the space above the rule. A long title is
+        // set smaller rather than cut short, down to a point.
+        let rule = (height as f32 * 0.75) as u32;
+        let (above, below) = (height as f32 / 8.0, rule as f32 - width as f32 / 20.0);
+        let leading = |size: f32| size * 1.25;
+        let mut size = width as f32 / 8.0;
+        let mut title = wrap(self.title, size, room);
+        while title.len() as f32 * leading(size) > below - above && size > width as f32 / 16.0 {
+            size *= 0.9;
+            title = wrap(self.title, size, room);
+        }
+        let title = lines(title, (((below - above) / leading(size)) as usize).max(1));
+        let mut top = above + (below - above - title.len() as f32 * leading(size)) / 2.0;
+        for line in title {
+            write(&mut cover, &line, size, top + size, room, margin);
+            top += leading(size);
+        }
+
+        // A rule under the title, and the authors at the foot of the cover.
+        for x in (margin * 2.0) as u32..(width as f32 - margin * 2.0) as u32 {
+            cover.put_pixel(x, rule, INK);
+        }
+        let size = (size * 0.6).max(width as f32 / 20.0);
+        let mut top = rule as f32 + size * 0.5;
+        for line in lines(wrap(self.authors, size, room), AUTHOR_LINES) {
+            top += size;
+            write(&mut cover, &line, size, top, room, margin);
+            top += size * 0.25;
+        }
+        cover
+    }
+
+    /// A dark, not too colourful shade of one of 360 hues, chosen by the uuid.
+    /// A book without one goes by its title.
+    fn colour(&self) -> Rgb<u8> {
+        let seed = match self.uuid {
+            "" => self.title,
+            uuid => uuid,
+        };
+        // FNV-1a: the same hue for the same book on every machine and every build.
+        let hash = seed
+            .bytes()
+            .fold(0xcbf29ce484222325_u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+        hsl((hash % 360) as f32, 0.45, 0.32)
+    }
+}
+
+/// Words set at `size` into lines no wider than `room`. A word too long for a
+/// line of its own gets one anyway.
+fn wrap(text: &str, size: f32, room: f32) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for word in text.split_whitespace() {
+        match lines.last_mut() {
+            Some(line) if advance(&format!("{} {}", line, word), size) <= room => {
+                line.push(' ');
+                line.push_str(word);
+            }
+            _ => lines.push(word.to_string()),
+        }
+    }
+    lines
+}
+
+/// At most `most` lines, the last of them ending in an ellipsis if that was not all.
+fn lines(mut lines: Vec<String>, most: usize) -> Vec<String> {
+    if lines.len() > most {
+        lines.truncate(most);
+        if let Some(last) = lines.last_mut() {
+            last.push('…');
+        }
+    }
+    lines
+}
+
+/// How wide `text` is at `size`.
+fn advance(text: &str, size: f32) -> f32 {
+    let font = FONT.as_scaled(PxScale::from(size));
+    let mut previous = None;
+    let mut width = 0.0;
+    for c in text.chars() {
+        let glyph = font.glyph_id(c);
+        if let Some(previous) = previous {
+            width += font.kern(previous, glyph);
+        }
+        width += font.h_advance(glyph);
+        previous = Some(glyph);
+    }
+    width
+}
+
+/// One line of text, centred on the cover, standing on `baseline`. What does
+/// not fit is left out rather than drawn over the edge.
+fn write(cover: &mut RgbImage, text: &str, size: f32, baseline: f32, room: f32, margin: f32) {
+    let font = FONT.as_scaled(PxScale::from(size));
+    let mut x = margin + ((room - advance(text, size)) / 2.0).max(0.0);
+    let mut previous = None;
+    for c in text.chars() {
+        let id = font.glyph_id(c);
+        if let Some(previous) = previous {
+            x += font.kern(previous, id);
+        }
+        previous = Some(id);
+        let glyph = id.with_scale_and_position(size, point(x, baseline));
+        x += font.h_advance(id);
+
+        let Some(outline) = FONT.outline_glyph(glyph) else { continue };
+        let bounds = outline.px_bounds();
+        outline.draw(|gx, gy, coverage| {
+            let (px, py) = (bounds.min.x as i64 + gx as i64, bounds.min.y as i64 + gy as i64);
+            if px < 0 || py < 0 || px >= cover.width() as i64 || py >= cover.height() as i64 {
+                return;
+            }
+            let pixel = cover.get_pixel_mut(px as u32, py as u32);
+            for (channel, ink) in pixel.0.iter_mut().zip(INK.0) {
+                *channel = (*channel as f32 + (ink as f32 - *channel as f32) * coverage.min(1.0)).round() as u8;
+            }
+        });
+    }
+}
+
+/// A colour by hue (degrees), saturation and lightness (both 0 to 1).
+fn hsl(hue: f32, saturation: f32, lightness: f32) -> Rgb<u8> {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let x = chroma * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 / 60 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    let channel = |value: f32| ((value + m) * 255.0).round() as u8;
+    Rgb([channel(r), channel(g), channel(b)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATROL: Placeholder = Placeholder {
+        title: "Galactic Patrol",
+        authors: "E. E. Smith",
+        uuid: "2a5a5c85-2d0c-4b5e-8f19-5b2b5d2f0d6a",
+    };
+
+    #[test]
+    fn a_book_keeps_its_colour_and_shares_it_with_few_others() {
+        let again = Placeholder { title: "Renamed", ..PATROL };
+        assert_eq!(PATROL.colour(), again.colour());
+
+        let other = Placeholder { uuid: "8b9f853c-7171-4f09-ba21-7304603a5128", ..PATROL };
+        assert_ne!(PATROL.colour(), other.colour());
+    }
+
+    #[test]
+    fn the_title_is_written_on_the_cover() {
+        let cover = PATROL.draw(240, 360);
+        assert_eq!(cover.dimensions(), (240, 360));
+
+        let background = PATROL.colour();
+        let inked = |rows: std::ops::Range<u32>| {
+            rows.flat_map(|y| (0..240).map(move |x| (x, y))).filter(|&(x, y)| *cover.get_pixel(x, y) != background).count()
+        };
+        // Nothing above the title, something where the title and the authors are.
+        assert_eq!(inked(0..40), 0);
+        assert!(inked(60..260) > 100);
+        assert!(inked(280..340) > 50);
+    }
+
+    #[test]
+    fn a_line_holds_as_many_words_as_fit() {
+        let lines = wrap("Alice's Adventures in Wonderland", 30.0, 200.0);
+        assert!(lines.len() > 1);
+        assert_eq!(lines.join(" "), "Alice's Adventures in Wonderland");
+        assert!(lines.iter().all(|line| advance(line, 30.0) <= 200.0 || !line.contains(' ')));
+
+        assert_eq!(self::lines(vec!["a".into(), "b".into(), "c".into()], 2), ["a", "b…"]);
+    }
+}
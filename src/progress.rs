@@ -0,0 +1,536 @@
+//! Where each reader is in each book
+//!
+//! Orca keeps this in a database of its own, by login: Calibre's `metadata.db`
+//! is never written to from here. A book is known by whatever its reader calls
+//! it -- KOReader sends a digest of the file -- so the positions depend on no
+//! library. Which file of which library a digest stands for is kept next to
+//! them, for as long as the file stays as it was. A Kobo calls a book by its
+//! uuid, and keeps a reading state of its own for it.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+use std::time::UNIX_EPOCH;
+
+use md5::{Digest, Md5};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_derive::{Deserialize, Serialize};
+
+/// What each version of the schema adds to the one before. The version a
+/// store is at is kept in `PRAGMA user_version`.
+const MIGRATIONS: [&str; 4] = [
+    "CREATE TABLE IF NOT EXISTS progress (
+         login TEXT NOT NULL,
+         document TEXT NOT NULL,
+         progress TEXT NOT NULL,
+         percentage REAL NOT NULL,
+         device TEXT NOT NULL,
+         device_id TEXT NOT NULL,
+         timestamp INTEGER NOT NULL,
+         PRIMARY KEY (login, document)
+     );",
+    "CREATE TABLE IF NOT EXISTS documents (
+         library TEXT NOT NULL,
+         book INTEGER NOT NULL,
+         format TEXT NOT NULL,
+         size INTEGER NOT NULL,
+         modified INTEGER NOT NULL,
+         digest TEXT NOT NULL,
+         PRIMARY KEY (library, book, format)
+     );
+     CREATE INDEX IF NOT EXISTS documents_digest ON documents (digest);",
+    "CREATE TABLE IF NOT EXISTS kobo_states (
+         login TEXT NOT NULL,
+         uuid TEXT NOT NULL,
+         state TEXT NOT NULL,
+         percentage REAL NOT NULL,
+         timestamp INTEGER NOT NULL,
+         PRIMARY KEY (login, uuid)
+     );
+     CREATE TABLE IF NOT EXISTS kobo_synced (
+         device TEXT NOT NULL,
+         uuid TEXT NOT NULL,
+         modified TEXT NOT NULL,
+         read INTEGER NOT NULL,
+         PRIMARY KEY (device, uuid)
+     );",
+    "CREATE TABLE IF NOT EXISTS kobo_removed (
+         device TEXT NOT NULL,
+         uuid TEXT NOT NULL,
+         modified TEXT NOT NULL,
+         PRIMARY KEY (device, uuid)
+     );",
+];
+
+/// Open the store at `path`, or make it, and bring it up to the schema of
+/// this version of Orca. `":memory:"` keeps it for as long as the connection
+/// lives.
+pub fn open(path: &str) -> rusqlite::Result<Connection> {
+    let db = Connection::open(path)?;
+    let version: i64 = db.query_row("PRAGMA user_version;", [], |row| row.get(0))?;
+    for (done, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        db.execute_batch(&format!("BEGIN; {} PRAGMA user_version = {}; COMMIT;", migration, done + 1))?;
+    }
+    Ok(db)
+}
+
+/// The store, recovered from a panic in another handler: every write to it is
+/// a single statement or a transaction, so it is never left half done.
+pub fn lock(db: &Mutex<Connection>) -> MutexGuard<'_, Connection> {
+    db.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// How far a reader got in a book, and on which device.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Position {
+    pub document: String,
+    /// Where, in the reader's own terms: an XPointer, a page number ...
+    pub progress: String,
+    /// How much of the book lies behind, from 0 to 1.
+    pub percentage: f64,
+    pub device: String,
+    pub device_id: String,
+    /// When it was saved, in seconds since the epoch.
+    pub timestamp: i64,
+}
+
+/// Remember where `login` is in a book, in place of where they were.
+pub fn save(db: &Connection, login: &str, position: &Position) -> rusqlite::Result<()> {
+    db.execute(
+        "INSERT INTO progress (login, document, progress, percentage, device, device_id, timestamp)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+         ON CONFLICT (login, document) DO UPDATE SET
+             progress = excluded.progress,
+             percentage = excluded.percentage,
+             device = excluded.device,
+             device_id = excluded.device_id,
+             timestamp = excluded.timestamp;",
+        params![
+            login,
+            position.document,
+            position.progress,
+            position.percentage,
+            position.device,
+            position.device_id,
+            position.timestamp
+        ],
+    )?;
+    Ok(())
+}
+
+/// Where `login` last was in a book, if they ever said.
+pub fn position(db: &Connection, login: &str, document: &str) -> rusqlite::Result<Option<Position>> {
+    db.query_row(
+        "SELECT document, progress, percentage, device, device_id, timestamp
+         FROM progress WHERE login = ?1 AND document = ?2;",
+        params![login, document],
+        |row| {
+            Ok(Position {
+                document: row.get(0)?,
+                progress: row.get(1)?,
+                percentage: row.get(2)?,
+                device: row.get(3)?,
+                device_id: row.get(4)?,
+                timestamp: row.get(5)?,
+            })
+        },
+    )
+    .optional()
+}
+
+/// When `login` last synced anything, and how many books they synced: what
+/// changes with every update, from KOReader or a Kobo.
+pub fn changed(db: &Connection, login: &str) -> rusqlite::Result<(i64, i64)> {
+    db.query_row(
+        "SELECT coalesce(MAX(timestamp), 0), COUNT(*) FROM (
+             SELECT timestamp FROM progress WHERE login = ?1
+             UNION ALL SELECT timestamp FROM kobo_states WHERE login = ?1
+         );",
+        [login],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+}
+
+/// Where `login` is in each book of `library` that a digest is known for, by book.
+pub fn positions_in(db: &Connection, login: &str, library: &str) -> rusqlite::Result<Vec<(i32, Position)>> {
+    let mut stmt = db.prepare(
+        "SELECT d.book, p.document, p.progress, p.percentage, p.device, p.device_id, p.timestamp
+         FROM progress p JOIN documents d ON d.digest = p.document
+         WHERE p.login = ?1 AND d.library = ?2;",
+    )?;
+    let rows = stmt.query_map(params![login, library], |row| {
+        Ok((
+            row.get(0)?,
+            Position {
+                document: row.get(1)?,
+                progress: row.get(2)?,
+                percentage: row.get(3)?,
+                device: row.get(4)?,
+                device_id: row.get(5)?,
+                timestamp: row.get(6)?,
+            },
+        ))
+    })?;
+    rows.collect()
+}
+
+// ------- Documents -------
+
+/// One file of a book in a library, as KOReader knows it: by its digest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document {
+    pub library: String,
+    pub book: i32,
+    /// EPUB, AZW3 ... the way Calibre spells it
+    pub format: String,
+}
+
+/// KOReader's digest of a file: the MD5 of a kilobyte at 0, at 1K, and at
+/// every fourth power of two after that, as far as the file goes. Quick on a
+/// book of any size, and what its progress sync calls a document by.
+pub fn partial_md5(mut file: impl Read + Seek) -> io::Result<String> {
+    let mut md5 = Md5::new();
+    let mut sample = Vec::with_capacity(1024);
+    for offset in std::iter::once(0).chain((0..=10).map(|i| 1024u64 << (2 * i))) {
+        file.seek(SeekFrom::Start(offset))?;
+        sample.clear();
+        (&mut file).take(1024).read_to_end(&mut sample)?;
+        if sample.is_empty() {
+            break;
+        }
+        md5.update(&sample);
+    }
+    Ok(format!("{:x}", md5.finalize()))
+}
+
+/// The files a digest stands for: one, as a rule, but a library served twice
+/// (as a whole and as one of its virtual libraries) has every file twice.
+pub fn documents(db: &Connection, digest: &str) -> rusqlite::Result<Vec<Document>> {
+    let mut stmt = db.prepare("SELECT library, book, format FROM documents WHERE digest = ?1 ORDER BY library;")?;
+    let rows = stmt.query_map([digest], |row| {
+        Ok(Document {
+            library: row.get(0)?,
+            book: row.get(1)?,
+            format: row.get(2)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// One file of a book, by its digest, and the size and time it had when that
+/// was taken.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Digested {
+    pub book: i32,
+    pub format: String,
+    pub size: i64,
+    /// In seconds since the epoch
+    pub modified: i64,
+    pub digest: String,
+}
+
+/// Every file of `library` a digest is known for.
+pub fn indexed(db: &Connection, library: &str) -> rusqlite::Result<Vec<Digested>> {
+    let mut stmt = db.prepare("SELECT book, format, size, modified, digest FROM documents WHERE library = ?1;")?;
+    let rows = stmt.query_map([library], |row| {
+        Ok(Digested {
+            book: row.get(0)?,
+            format: row.get(1)?,
+            size: row.get(2)?,
+            modified: row.get(3)?,
+            digest: row.get(4)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// The digest of every file of a library. `files`: the book, its format and
+/// where the file is. Only a file that is new, or changed size or time since it
+/// was `known`, is read -- without the store, which waits for nobody's disk.
+pub fn digest(known: Vec<Digested>, files: impl IntoIterator<Item = (i32, String, PathBuf)>) -> Vec<Digested> {
+    let mut known: HashMap<(i32, String), Digested> =
+        known.into_iter().map(|file| ((file.book, file.format.clone()), file)).collect();
+    let mut digested = Vec::new();
+    for (book, format, path) in files {
+        // Calibre may list a file that is not there (any more): nothing to sync it by.
+        let Ok(metadata) = path.metadata() else {
+            continue;
+        };
+        let size = metadata.len() as i64;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_secs() as i64)
+            .unwrap_or_default();
+        let digest = match known.remove(&(book, format.clone())) {
+            Some(file) if (file.size, file.modified) == (size, modified) => file.digest,
+            _ => match File::open(&path).and_then(partial_md5) {
+                Ok(digest) => digest,
+                Err(e) => {
+                    eprintln!("Error reading {}: {}", path.display(), e);
+                    continue;
+                }
+            },
+        };
+        digested.push(Digested { book, format, size, modified, digest });
+    }
+    digested
+}
+
+/// Know every file of `library` by its digest, and nothing that is gone from it.
+pub fn index(db: &Connection, library: &str, files: &[Digested]) -> rusqlite::Result<()> {
+    let tx = db.unchecked_transaction()?;
+    tx.execute("DELETE FROM documents WHERE library = ?1;", [library])?;
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO documents (library, book, format, size, modified, digest) VALUES (?1, ?2, ?3, ?4, ?5, ?6);",
+        )?;
+        for file in files {
+            insert.execute(params![library, file.book, file.format, file.size, file.modified, file.digest])?;
+        }
+    }
+    tx.commit()
+}
+
+// ------- Kobo -------
+
+/// A Kobo's reading state of a book, kept as the Kobo sent it: status,
+/// bookmark and statistics, as JSON.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KoboState {
+    pub uuid: String,
+    pub state: String,
+    /// How much of the book lies behind, from 0 to 1.
+    pub percentage: f64,
+    /// When it was saved, in seconds since the epoch.
+    pub timestamp: i64,
+}
+
+/// Remember the reading state `login`'s Kobo sent, in place of the one before.
+pub fn save_kobo_state(db: &Connection, login: &str, state: &KoboState) -> rusqlite::Result<()> {
+    db.execute(
+        "INSERT INTO kobo_states (login, uuid, state, percentage, timestamp) VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT (login, uuid) DO UPDATE SET
+             state = excluded.state,
+             percentage = excluded.percentage,
+             timestamp = excluded.timestamp;",
+        params![login, state.uuid, state.state, state.percentage, state.timestamp],
+    )?;
+    Ok(())
+}
+
+/// Every reading state a Kobo of `login`'s sent, of whichever library.
+pub fn kobo_states(db: &Connection, login: &str) -> rusqlite::Result<Vec<KoboState>> {
+    let mut stmt = db.prepare("SELECT uuid, state, percentage, timestamp FROM kobo_states WHERE login = ?1;")?;
+    let rows = stmt.query_map([login], |row| {
+        Ok(KoboState {
+            uuid: row.get(0)?,
+            state: row.get(1)?,
+            percentage: row.get(2)?,
+            timestamp: row.get(3)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// A book as a Kobo was last sent it: as modified then, and with the reading
+/// of that time (seconds since the epoch, 0 for none).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Synced {
+    pub uuid: String,
+    pub modified: String,
+    pub read: i64,
+}
+
+/// What `device` holds, by uuid, as far as Orca sent it.
+pub fn synced(db: &Connection, device: &str) -> rusqlite::Result<HashMap<String, Synced>> {
+    let mut stmt = db.prepare("SELECT uuid, modified, read FROM kobo_synced WHERE device = ?1;")?;
+    let rows = stmt.query_map([device], |row| {
+        let synced = Synced { uuid: row.get(0)?, modified: row.get(1)?, read: row.get(2)? };
+        Ok((synced.uuid.clone(), synced))
+    })?;
+    rows.collect()
+}
+
+/// Note what one sync sent `device`, and what it told it to remove.
+pub fn mark_synced(db: &Connection, device: &str, sent: &[Synced], removed: &[String]) -> rusqlite::Result<()> {
+    let tx = db.unchecked_transaction()?;
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO kobo_synced (device, uuid, modified, read) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (device, uuid) DO UPDATE SET modified = excluded.modified, read = excluded.read;",
+        )?;
+        for synced in sent {
+            insert.execute(params![device, synced.uuid, synced.modified, synced.read])?;
+        }
+        let mut delete = tx.prepare("DELETE FROM kobo_synced WHERE device = ?1 AND uuid = ?2;")?;
+        for uuid in removed {
+            delete.execute(params![device, uuid])?;
+        }
+    }
+    tx.commit()
+}
+
+/// Forget what `device` holds, and what was removed from it: it starts over,
+/// as after a reset.
+pub fn forget_synced(db: &Connection, device: &str) -> rusqlite::Result<()> {
+    let tx = db.unchecked_transaction()?;
+    tx.execute("DELETE FROM kobo_synced WHERE device = ?1;", [device])?;
+    tx.execute("DELETE FROM kobo_removed WHERE device = ?1;", [device])?;
+    tx.commit()
+}
+
+/// The reader removed a book from `device`: it no longer holds it, and is not
+/// sent it again as it was then.
+pub fn mark_removed(db: &Connection, device: &str, synced: &Synced) -> rusqlite::Result<()> {
+    let tx = db.unchecked_transaction()?;
+    tx.execute("DELETE FROM kobo_synced WHERE device = ?1 AND uuid = ?2;", params![device, synced.uuid])?;
+    tx.execute(
+        "INSERT INTO kobo_removed (device, uuid, modified) VALUES (?1, ?2, ?3)
+         ON CONFLICT (device, uuid) DO UPDATE SET modified = excluded.modified;",
+        params![device, synced.uuid, synced.modified],
+    )?;
+    tx.commit()
+}
+
+/// What the reader removed from `device`: by uuid, as modified then.
+pub fn removed(db: &Connection, device: &str) -> rusqlite::Result<HashMap<String, String>> {
+    let mut stmt = db.prepare("SELECT uuid, modified FROM kobo_removed WHERE device = ?1;")?;
+    let rows = stmt.query_map([device], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+/// Send `device` these books again, should they be on its shelves.
+pub fn forget_removed(db: &Connection, device: &str, uuids: &[String]) -> rusqlite::Result<()> {
+    let tx = db.unchecked_transaction()?;
+    {
+        let mut delete = tx.prepare("DELETE FROM kobo_removed WHERE device = ?1 AND uuid = ?2;")?;
+        for uuid in uuids {
+            delete.execute(params![device, uuid])?;
+        }
+    }
+    tx.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(percentage: f64, device: &str) -> Position {
+        Position {
+            document: "0b1d2c3e4f5a6b7c8d9e0f1a2b3c4d5e".to_string(),
+            progress: "/body/DocFragment[12]/body/p[3]/text().0".to_string(),
+            percentage,
+            device: device.to_string(),
+            device_id: format!("{}-id", device),
+            timestamp: 1735689600,
+        }
+    }
+
+    #[test]
+    fn the_last_position_is_the_one_kept() {
+        let db = open(":memory:").expect("a store");
+        assert_eq!(position(&db, "alice", &at(0.0, "").document).unwrap(), None);
+
+        save(&db, "alice", &at(0.25, "Kobo")).unwrap();
+        save(&db, "alice", &at(0.5, "Kindle")).unwrap();
+        assert_eq!(position(&db, "alice", &at(0.0, "").document).unwrap(), Some(at(0.5, "Kindle")));
+
+        // Bob reads the same file, and is somewhere else.
+        assert_eq!(position(&db, "bob", &at(0.0, "").document).unwrap(), None);
+    }
+
+    #[test]
+    fn the_store_survives_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("progress.db");
+        save(&open(path.to_str().unwrap()).expect("a new store"), "alice", &at(0.75, "Kobo")).unwrap();
+
+        let again = open(path.to_str().unwrap()).expect("the same store");
+        assert_eq!(position(&again, "alice", &at(0.0, "").document).unwrap(), Some(at(0.75, "Kobo")));
+    }
+
+    #[test]
+    fn a_store_of_the_first_version_keeps_its_positions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("progress.db");
+        let old = Connection::open(&path).unwrap();
+        old.execute_batch(&format!("{} PRAGMA user_version = 1;", MIGRATIONS[0])).unwrap();
+        save(&old, "alice", &at(0.75, "Kobo")).unwrap();
+        drop(old);
+
+        let db = open(path.to_str().unwrap()).expect("the store, brought up to date");
+        assert_eq!(position(&db, "alice", &at(0.0, "").document).unwrap(), Some(at(0.75, "Kobo")));
+        assert_eq!(documents(&db, "0b1d2c3e4f5a6b7c8d9e0f1a2b3c4d5e").unwrap(), []);
+    }
+
+    // A file shorter than the first sample is hashed whole.
+    #[test]
+    fn a_digest_samples_the_file() {
+        let short = partial_md5(io::Cursor::new(b"hello".to_vec())).unwrap();
+        assert_eq!(short, "5d41402abc4b2a76b9719d911017c592");
+
+        let book = vec![7u8; 100_000];
+        let mut between = book.clone();
+        between[3000] = 8;
+        let mut sampled = book.clone();
+        sampled[4096] = 8;
+        let digest = |bytes: &Vec<u8>| partial_md5(io::Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(digest(&between), digest(&book));
+        assert_ne!(digest(&sampled), digest(&book));
+    }
+
+    #[test]
+    fn a_file_is_known_by_its_digest_while_it_stays_as_it_was() {
+        let dir = tempfile::tempdir().unwrap();
+        let (epub, mobi) = (dir.path().join("book.epub"), dir.path().join("book.mobi"));
+        std::fs::write(&epub, b"an epub").unwrap();
+        std::fs::write(&mobi, b"a mobi").unwrap();
+        let files = || [(5, "EPUB".to_string(), epub.clone()), (5, "MOBI".to_string(), mobi.clone()), (6, "PDF".to_string(), dir.path().join("gone.pdf"))];
+
+        let db = open(":memory:").unwrap();
+        let reindex = |db: &Connection| index(db, "library", &super::digest(indexed(db, "library").unwrap(), files())).unwrap();
+        reindex(&db);
+        let digest = partial_md5(File::open(&epub).unwrap()).unwrap();
+        let found = Document { library: "library".to_string(), book: 5, format: "EPUB".to_string() };
+        assert_eq!(documents(&db, &digest).unwrap(), [found]);
+
+        std::fs::write(&epub, b"an epub, its metadata updated").unwrap();
+        reindex(&db);
+        assert_eq!(documents(&db, &digest).unwrap(), []);
+
+        index(&db, "library", &[]).unwrap();
+        assert_eq!(db.query_row("SELECT COUNT(*) FROM documents;", [], |row| row.get::<_, i64>(0)).unwrap(), 0);
+    }
+
+    #[test]
+    fn a_kobo_holds_what_it_was_sent_until_told_otherwise() {
+        let db = open(":memory:").unwrap();
+        let sent = |uuid: &str, read: i64| Synced { uuid: uuid.to_string(), modified: "2024-01-01T00:00:00Z".to_string(), read };
+
+        mark_synced(&db, "libra", &[sent("kant", 0), sent("alice", 0)], &[]).unwrap();
+        mark_synced(&db, "libra", &[sent("kant", 1735689600)], &["alice".to_string()]).unwrap();
+        let held = synced(&db, "libra").unwrap();
+        assert_eq!(held.len(), 1);
+        assert_eq!(held["kant"], sent("kant", 1735689600));
+        assert!(synced(&db, "clara").unwrap().is_empty());
+
+        forget_synced(&db, "libra").unwrap();
+        assert!(synced(&db, "libra").unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_kobo_s_reading_state_is_a_change_too() {
+        let db = open(":memory:").unwrap();
+        save(&db, "alice", &at(0.25, "Kobo")).unwrap();
+        let state = KoboState { uuid: "kant".to_string(), state: "{}".to_string(), percentage: 0.5, timestamp: 1735700000 };
+        save_kobo_state(&db, "alice", &state).unwrap();
+
+        assert_eq!(changed(&db, "alice").unwrap(), (1735700000, 2));
+        assert_eq!(kobo_states(&db, "alice").unwrap(), [state]);
+        assert_eq!(kobo_states(&db, "bob").unwrap(), []);
+    }
+}
@@ -0,0 +1,842 @@
+//! Calibre's search language, compiled into SQL
+//!
+//! `author:tolkien and (tag:fantasy or tag:"science fiction") and not rating:<3`
+//! means here what it means in Calibre's search bar. The query becomes a condition
+//! on the books `b`; whatever the user typed ends up in a parameter, never in the SQL.
+//! A plain word is looked up in Orca's full-text index, and ranks the books found.
+//!
+//! Calibre refuses a query it cannot parse. A catalog typed into on an e-reader
+//! keyboard is more forgiving: a stray parenthesis or a dangling `and` is dropped,
+//! and a `field:` Orca does not know is searched for as plain text.
+
+use isolang::Language;
+use rusqlite::types::Value;
+
+use crate::calibre::like;
+
+#[derive(Debug, PartialEq)]
+pub enum Query {
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+    Not(Box<Query>),
+    Term(Term),
+}
+
+/// One word of the query: `tag:fantasy`, `"the mountains"` or just `kant`.
+#[derive(Debug, PartialEq)]
+pub struct Term {
+    /// None for a plain word, which looks at whatever the full-text index holds
+    pub field: Option<Field>,
+    pub value: String,
+    /// `tag:"true"` is a tag called true, `tag:true` any tag at all
+    pub quoted: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Title,
+    Author,
+    Tag,
+    Series,
+    Publisher,
+    Language,
+    Format,
+    Rating,
+    /// When the book was published
+    Pubdate,
+    /// When the book entered the library: Calibre's `date`
+    Added,
+    Identifier,
+}
+
+impl Field {
+    /// Calibre's lookup names, and the singular most people type.
+    fn named(name: &str) -> Option<Field> {
+        match name {
+            "title" => Some(Field::Title),
+            "author" | "authors" => Some(Field::Author),
+            "tag" | "tags" => Some(Field::Tag),
+            "series" => Some(Field::Series),
+            "publisher" => Some(Field::Publisher),
+            "language" | "languages" => Some(Field::Language),
+            "format" | "formats" => Some(Field::Format),
+            "rating" => Some(Field::Rating),
+            "pubdate" => Some(Field::Pubdate),
+            "date" | "timestamp" => Some(Field::Added),
+            "identifier" | "identifiers" => Some(Field::Identifier),
+            _ => None,
+        }
+    }
+}
+
+/// What a query needs to know of the library it searches.
+pub trait Library {
+    /// The codes the library holds books in: Calibre keeps only the code, a reader types the name.
+    fn languages(&self) -> &[String];
+
+    /// A plain word as a query on Orca's full-text index, or `None` to look
+    /// for it in the title and the authors instead.
+    fn words(&self, word: &str) -> Option<String>;
+}
+
+/// A query as a condition on the books `b`, and the values of its `?`s in order.
+#[derive(Debug)]
+pub struct Search {
+    pub condition: String,
+    pub params: Vec<Value>,
+    /// The plain words the books should have, as one full-text query to rank them by
+    pub rank: Option<String>,
+    /// Inside a `not`: words a book must not have say nothing about its rank
+    negated: bool,
+}
+
+/// `None` for a query with nothing to search for.
+pub fn compile(input: &str, library: &dyn Library) -> Option<Search> {
+    let query = parse(input)?;
+    let mut search = Search {
+        condition: String::new(),
+        params: Vec::new(),
+        rank: None,
+        negated: false,
+    };
+    search.condition = search.sql(&query, library);
+    Some(search)
+}
+
+// ------- Reading the query -------
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    /// `quoted` is where the first quote began: `tag:"science fiction"` is a
+    /// field and a phrase, `"tag:science"` only a phrase.
+    Word { text: String, quoted: Option<usize> },
+}
+
+fn tokens(input: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            _ => {
+                let mut text = String::new();
+                let mut quoted = None;
+                while let Some(&c) = chars.peek() {
+                    match c {
+                        c if c.is_whitespace() => break,
+                        '(' | ')' => break,
+                        '"' => {
+                            chars.next();
+                            quoted.get_or_insert(text.len());
+                            // up to the closing quote, or the end of the query
+                            while let Some(c) = chars.next() {
+                                match c {
+                                    '"' => break,
+                                    '\\' => text.extend(chars.next()),
+                                    c => text.push(c),
+                                }
+                            }
+                        }
+                        c => {
+                            chars.next();
+                            text.push(c);
+                        }
+                    }
+                }
+                tokens.push(Token::Word { text, quoted });
+            }
+        }
+    }
+    tokens
+}
+
+fn term(text: &str, quoted: Option<usize>) -> Term {
+    // Only a colon outside the quotes names a field.
+    let unquoted = &text[..quoted.unwrap_or(text.len())];
+    let field = unquoted
+        .find(':')
+        .and_then(|colon| Field::named(&unquoted[..colon].to_lowercase()).map(|field| (field, colon)));
+
+    match field {
+        Some((field, colon)) => Term {
+            field: Some(field),
+            value: text[colon + 1..].to_string(),
+            quoted: quoted.is_some(),
+        },
+        None => Term {
+            field: None,
+            value: text.to_string(),
+            quoted: quoted.is_some(),
+        },
+    }
+}
+
+/// How deep groups and `not`s nest before the parser stops descending. A URL
+/// full of `((((` must not run a worker out of stack.
+const MAX_DEPTH: usize = 64;
+
+/// How many words a query takes; what follows is left out. Long chains of
+/// `and` make deep trees just the same.
+const MAX_TERMS: usize = 256;
+
+/// `or` binds loosest, then `and` -- which two words side by side imply -- then `not`.
+struct Parser {
+    tokens: Vec<Token>,
+    at: usize,
+    /// The groups and `not`s around the token at `at`
+    depth: usize,
+    /// The words read so far
+    terms: usize,
+}
+
+/// `and`, `or` and `not` are operators unless they are quoted.
+fn keyword(token: Option<&Token>, word: &str) -> bool {
+    matches!(token, Some(Token::Word { text, quoted: None }) if text.eq_ignore_ascii_case(word))
+}
+
+/// An operator missing one side is just the other side.
+fn join(left: Option<Query>, right: Option<Query>, op: fn(Box<Query>, Box<Query>) -> Query) -> Option<Query> {
+    match (left, right) {
+        (Some(left), Some(right)) => Some(op(Box::new(left), Box::new(right))),
+        (left, None) => left,
+        (None, right) => right,
+    }
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at)
+    }
+
+    fn or(&mut self) -> Option<Query> {
+        let mut left = self.and();
+        while keyword(self.peek(), "or") {
+            self.at += 1;
+            let right = self.and();
+            left = join(left, right, Query::Or);
+        }
+        left
+    }
+
+    fn and(&mut self) -> Option<Query> {
+        let mut left = self.not();
+        loop {
+            match self.peek() {
+                None | Some(Token::Close) => break,
+                token if keyword(token, "or") => break,
+                token if keyword(token, "and") => self.at += 1,
+                _ => {}
+            }
+            let right = self.not();
+            left = join(left, right, Query::And);
+        }
+        left
+    }
+
+    /// Nested too deep, a `not` is a plain word.
+    fn not(&mut self) -> Option<Query> {
+        match keyword(self.peek(), "not") && self.depth < MAX_DEPTH {
+            true => {
+                self.at += 1;
+                self.depth += 1;
+                let query = self.not();
+                self.depth -= 1;
+                query.map(|query| Query::Not(Box::new(query)))
+            }
+            false => self.primary(),
+        }
+    }
+
+    /// Consumes nothing at the end of a group, or at an operator with nothing before it.
+    fn primary(&mut self) -> Option<Query> {
+        // Nested too deep, a `(` is skipped: what follows is read as if it were not grouped.
+        while self.depth >= MAX_DEPTH && self.peek() == Some(&Token::Open) {
+            self.at += 1;
+        }
+        match self.peek()? {
+            Token::Open => {
+                self.at += 1;
+                self.depth += 1;
+                let query = self.or();
+                self.depth -= 1;
+                // a group nobody closed ends with the query
+                if self.peek() == Some(&Token::Close) {
+                    self.at += 1;
+                }
+                query
+            }
+            Token::Close => None,
+            token if keyword(Some(token), "or") || keyword(Some(token), "and") => None,
+            Token::Word { .. } if self.terms >= MAX_TERMS => {
+                self.at += 1;
+                None
+            }
+            Token::Word { text, quoted } => {
+                let term = term(text, *quoted);
+                self.at += 1;
+                self.terms += 1;
+                Some(Query::Term(term))
+            }
+        }
+    }
+}
+
+/// The query, or `None` if there is nothing in it to search for.
+pub fn parse(input: &str) -> Option<Query> {
+    let mut parser = Parser {
+        tokens: tokens(input),
+        at: 0,
+        depth: 0,
+        terms: 0,
+    };
+
+    let mut query = parser.or();
+    // A `)` nobody opened: skip it and read on.
+    while parser.peek().is_some() {
+        parser.at += 1;
+        let rest = parser.or();
+        query = join(query, rest, Query::And);
+    }
+    query
+}
+
+// ------- Writing the SQL -------
+
+/// How a value is compared.
+enum Match {
+    Contains(String),
+    /// `tag:=fiction` is fiction, but not science fiction
+    Exact(String),
+    /// `tag:true`: any value at all, `tag:false`: none
+    Present(bool),
+}
+
+impl Match {
+    fn of(term: &Term) -> Match {
+        match (term.quoted, term.value.to_lowercase().as_str()) {
+            (false, "true") => Match::Present(true),
+            (false, "false") => Match::Present(false),
+            _ => match term.value.strip_prefix('=') {
+                Some(exact) => Match::Exact(exact.to_string()),
+                None => Match::Contains(term.value.clone()),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Compare {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// `>=3` -> (Ge, "3"). No operator is `=`.
+fn comparison(value: &str) -> (Compare, &str) {
+    for (prefix, compare) in [
+        (">=", Compare::Ge),
+        ("<=", Compare::Le),
+        ("!=", Compare::Ne),
+        (">", Compare::Gt),
+        ("<", Compare::Lt),
+        ("=", Compare::Eq),
+    ] {
+        if let Some(rest) = value.strip_prefix(prefix) {
+            return (compare, rest.trim());
+        }
+    }
+    (Compare::Eq, value.trim())
+}
+
+/// What an ISO date names: a year, a month or a day -- as the first day in it,
+/// and the first day after it.
+fn period(date: &str) -> Option<(String, String)> {
+    let parts: Vec<&str> = date.split('-').collect();
+    let number = |part: &str, digits: usize| match part.len() == digits && part.chars().all(|c| c.is_ascii_digit()) {
+        true => part.parse::<u32>().ok(),
+        false => None,
+    };
+
+    match parts[..] {
+        [year] => {
+            let year = number(year, 4)?;
+            Some((format!("{:04}-01-01", year), format!("{:04}-01-01", year + 1)))
+        }
+        [year, month] => {
+            let (year, month) = (number(year, 4)?, number(month, 2)?);
+            if !(1..=12).contains(&month) {
+                return None;
+            }
+            let (next_year, next_month) = match month {
+                12 => (year + 1, 1),
+                month => (year, month + 1),
+            };
+            Some((
+                format!("{:04}-{:02}-01", year, month),
+                format!("{:04}-{:02}-01", next_year, next_month),
+            ))
+        }
+        [year, month, day] => {
+            let (year, month, day) = (number(year, 4)?, number(month, 2)?, number(day, 2)?);
+            let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+            let days = match month {
+                2 if leap => 29,
+                2 => 28,
+                4 | 6 | 9 | 11 => 30,
+                1..=12 => 31,
+                _ => return None,
+            };
+            if !(1..=days).contains(&day) {
+                return None;
+            }
+            let next = match (day == days, month) {
+                (false, _) => (year, month, day + 1),
+                (true, 12) => (year + 1, 1, 1),
+                (true, month) => (year, month + 1, 1),
+            };
+            Some((
+                format!("{:04}-{:02}-{:02}", year, month, day),
+                format!("{:04}-{:02}-{:02}", next.0, next.1, next.2),
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Every name a reader might know a language by: its code, its own name, its English name.
+fn language_names(code: &str) -> Vec<String> {
+    let mut names = vec![code.to_lowercase()];
+    if let Some(language) = Language::from_639_3(code) {
+        names.push(language.to_name().to_lowercase());
+        names.extend(language.to_autonym().map(str::to_lowercase));
+        names.extend(language.to_639_1().map(str::to_string));
+    }
+    names
+}
+
+/// A condition that holds for no book.
+const NOTHING: &str = "0";
+
+impl Search {
+    fn param(&mut self, value: impl Into<Value>) -> &'static str {
+        self.params.push(value.into());
+        "?"
+    }
+
+    fn sql(&mut self, query: &Query, library: &dyn Library) -> String {
+        match query {
+            Query::And(left, right) => {
+                format!("({} AND {})", self.sql(left, library), self.sql(right, library))
+            }
+            Query::Or(left, right) => {
+                format!("({} OR {})", self.sql(left, library), self.sql(right, library))
+            }
+            Query::Not(query) => {
+                self.negated = !self.negated;
+                let condition = format!("NOT {}", self.sql(query, library));
+                self.negated = !self.negated;
+                condition
+            }
+            Query::Term(term) => self.term(term, library),
+        }
+    }
+
+    fn term(&mut self, term: &Term, library: &dyn Library) -> String {
+        let Some(field) = term.field else {
+            let words = match Match::of(term) {
+                Match::Contains(value) => library.words(&value),
+                _ => None,
+            };
+            if let Some(words) = words {
+                if !self.negated {
+                    self.rank = Some(match self.rank.take() {
+                        Some(rank) => format!("{} OR {}", rank, words),
+                        None => words.clone(),
+                    });
+                }
+                return format!(
+                    "b.id IN (SELECT rowid FROM orca.book_text WHERE book_text MATCH {})",
+                    self.param(words)
+                );
+            }
+            // Without the index, or for `=Kant`: title or author.
+            let title = self.text("b.title", &Match::of(term));
+            let author = self.linked(
+                "SELECT ba.book FROM books_authors_link ba JOIN authors a ON ba.author = a.id",
+                "a.name",
+                &Match::of(term),
+            );
+            return format!("({} OR {})", title, author);
+        };
+
+        match field {
+            Field::Title => self.text("b.title", &Match::of(term)),
+            Field::Author => self.linked(
+                "SELECT ba.book FROM books_authors_link ba JOIN authors a ON ba.author = a.id",
+                "a.name",
+                &Match::of(term),
+            ),
+            Field::Tag => self.linked(
+                "SELECT bt.book FROM books_tags_link bt JOIN tags t ON bt.tag = t.id",
+                "t.name",
+                &Match::of(term),
+            ),
+            Field::Series => self.linked(
+                "SELECT bs.book FROM books_series_link bs JOIN series s ON bs.series = s.id",
+                "s.name",
+                &Match::of(term),
+            ),
+            Field::Publisher => self.linked(
+                "SELECT bp.book FROM books_publishers_link bp JOIN publishers p ON bp.publisher = p.id",
+                "p.name",
+                &Match::of(term),
+            ),
+            Field::Format => self.linked("SELECT d.book FROM data d", "d.format", &Match::of(term)),
+            Field::Language => self.language(&Match::of(term), library.languages()),
+            Field::Rating => self.rating(term),
+            Field::Pubdate => self.date("b.pubdate", &term.value),
+            Field::Added => self.date("b.timestamp", &term.value),
+            Field::Identifier => self.identifier(term),
+        }
+    }
+
+    /// LIKE ignores case, but only for ASCII -- the same as Calibre's own database
+    fn text(&mut self, column: &str, matching: &Match) -> String {
+        match matching {
+            Match::Contains(value) => format!("{} LIKE {} ESCAPE '\\'", column, self.param(like(value))),
+            Match::Exact(value) => format!("{} = {} COLLATE NOCASE", column, self.param(value.clone())),
+            // Every book has a title; true and false are words like any other.
+            Match::Present(present) => {
+                let word = present.to_string();
+                self.text(column, &Match::Contains(word))
+            }
+        }
+    }
+
+    /// A value kept in a table of its own and linked to the book: `select` yields
+    /// the ids of the books, `column` the value to compare.
+    fn linked(&mut self, select: &str, column: &str, matching: &Match) -> String {
+        match matching {
+            Match::Present(true) => format!("b.id IN ({})", select),
+            Match::Present(false) => format!("b.id NOT IN ({})", select),
+            matching => format!("b.id IN ({} WHERE {})", select, self.text(column, matching)),
+        }
+    }
+
+    fn language(&mut self, matching: &Match, languages: &[String]) -> String {
+        let select = "SELECT bl.book FROM books_languages_link bl JOIN languages l ON bl.lang_code = l.id";
+        let wanted = |names: &[String]| match matching {
+            Match::Contains(value) => names.iter().any(|name| name.contains(&value.to_lowercase())),
+            Match::Exact(value) => names.iter().any(|name| *name == value.to_lowercase()),
+            Match::Present(_) => true,
+        };
+
+        let codes: Vec<&String> = languages.iter().filter(|code| wanted(&language_names(code))).collect();
+        match (matching, codes.is_empty()) {
+            (Match::Present(true), _) => format!("b.id IN ({})", select),
+            (Match::Present(false), _) => format!("b.id NOT IN ({})", select),
+            (_, true) => NOTHING.to_string(),
+            (_, false) => {
+                let placeholders: Vec<&str> = codes.iter().map(|code| self.param(code.to_string())).collect();
+                format!("b.id IN ({} WHERE l.lang_code IN ({}))", select, placeholders.join(", "))
+            }
+        }
+    }
+
+    /// In stars, as Calibre shows them, halves allowed. A book nobody rated has none:
+    /// `rating:0` and `rating:false` find it.
+    fn rating(&mut self, term: &Term) -> String {
+        let rating = "COALESCE((SELECT r.rating FROM books_ratings_link br
+                JOIN ratings r ON br.rating = r.id WHERE br.book = b.id), 0)";
+
+        let (compare, stars) = match Match::of(term) {
+            Match::Present(true) => (Compare::Gt, 0.0),
+            Match::Present(false) => (Compare::Eq, 0.0),
+            _ => {
+                let (compare, value) = comparison(&term.value);
+                match value.parse::<f64>() {
+                    Ok(stars) => (compare, stars),
+                    Err(_) => return NOTHING.to_string(),
+                }
+            }
+        };
+
+        let operator = match compare {
+            Compare::Eq => "=",
+            Compare::Ne => "!=",
+            Compare::Lt => "<",
+            Compare::Le => "<=",
+            Compare::Gt => ">",
+            Compare::Ge => ">=",
+        };
+        // Calibre stores ratings out of ten.
+        format!("{} {} {}", rating, operator, self.param(stars * 2.0))
+    }
+
+    /// `pubdate:2000` is the whole year, `pubdate:<2000` before it began and
+    /// `pubdate:>2000-05` after May was over.
+    fn date(&mut self, column: &str, value: &str) -> String {
+        let (compare, date) = comparison(value);
+        let Some((start, end)) = period(date) else {
+            return NOTHING.to_string();
+        };
+
+        let day = format!("substr({}, 1, 10)", column);
+        match compare {
+            Compare::Eq => format!("({} >= {} AND {} < {})", day, self.param(start), day, self.param(end)),
+            Compare::Ne => format!("NOT ({} >= {} AND {} < {})", day, self.param(start), day, self.param(end)),
+            Compare::Lt => format!("{} < {}", day, self.param(start)),
+            Compare::Le => format!("{} < {}", day, self.param(end)),
+            Compare::Gt => format!("{} >= {}", day, self.param(end)),
+            Compare::Ge => format!("{} >= {}", day, self.param(start)),
+        }
+    }
+
+    /// `identifier:isbn:` has an ISBN at all, `identifier:isbn:9780` one that
+    /// contains those digits, and `identifier::24213` that value under any scheme.
+    fn identifier(&mut self, term: &Term) -> String {
+        let select = "SELECT i.book FROM identifiers i";
+        let (typed, exact) = match Match::of(term) {
+            Match::Present(true) => return format!("b.id IN ({})", select),
+            Match::Present(false) => return format!("b.id NOT IN ({})", select),
+            Match::Exact(typed) => (typed, true),
+            Match::Contains(typed) => (typed, false),
+        };
+
+        // A bare `isbn` is a scheme without a value.
+        let (scheme, value) = typed.split_once(':').unwrap_or((typed.as_str(), ""));
+        // `identifier:isbn:=9780141439761` is as exact as `identifier:=isbn:9780141439761`
+        let (exact, value) = match value.strip_prefix('=') {
+            Some(value) => (true, value),
+            None => (exact, value),
+        };
+        let value = match (value.is_empty(), exact) {
+            (true, _) => None,
+            (false, true) => Some(Match::Exact(value.to_string())),
+            (false, false) => Some(Match::Contains(value.to_string())),
+        };
+
+        let mut conditions = Vec::new();
+        if !scheme.is_empty() {
+            conditions.push(format!("i.type = {} COLLATE NOCASE", self.param(scheme.to_string())));
+        }
+        if let Some(value) = value {
+            conditions.push(self.text("i.val", &value));
+        }
+        match conditions.is_empty() {
+            true => format!("b.id IN ({})", select),
+            false => format!("b.id IN ({} WHERE {})", select, conditions.join(" AND ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Languages, but no index
+    impl Library for Vec<String> {
+        fn languages(&self) -> &[String] {
+            self
+        }
+
+        fn words(&self, _: &str) -> Option<String> {
+            None
+        }
+    }
+
+    fn word(field: Option<Field>, value: &str) -> Query {
+        Query::Term(Term {
+            field,
+            value: value.to_string(),
+            quoted: false,
+        })
+    }
+
+    fn and(left: Query, right: Query) -> Query {
+        Query::And(Box::new(left), Box::new(right))
+    }
+
+    fn or(left: Query, right: Query) -> Query {
+        Query::Or(Box::new(left), Box::new(right))
+    }
+
+    #[test]
+    fn words_side_by_side_must_all_match() {
+        assert_eq!(parse("of madness"), Some(and(word(None, "of"), word(None, "madness"))));
+        assert_eq!(parse("of AND madness"), parse("of madness"));
+    }
+
+    // As in Calibre: `a or b and c` is `a or (b and c)`.
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(
+            parse("tag:horror or tag:fantasy and not rating:>3"),
+            Some(or(
+                word(Some(Field::Tag), "horror"),
+                and(
+                    word(Some(Field::Tag), "fantasy"),
+                    Query::Not(Box::new(word(Some(Field::Rating), ">3")))
+                )
+            ))
+        );
+        assert_eq!(
+            parse("(tag:horror or tag:fantasy) and kant"),
+            Some(and(
+                or(word(Some(Field::Tag), "horror"), word(Some(Field::Tag), "fantasy")),
+                word(None, "kant")
+            ))
+        );
+    }
+
+    #[test]
+    fn a_quoted_phrase_is_one_word() {
+        assert_eq!(
+            parse(r#"tag:"science fiction" "and""#),
+            Some(and(
+                Query::Term(Term {
+                    field: Some(Field::Tag),
+                    value: "science fiction".to_string(),
+                    quoted: true
+                }),
+                Query::Term(Term {
+                    field: None,
+                    value: "and".to_string(),
+                    quoted: true
+                })
+            ))
+        );
+        // Quoted, a field name is only text.
+        assert_eq!(parse(r#""tag:horror""#).map(|query| matches!(query, Query::Term(Term { field: None, .. }))), Some(true));
+    }
+
+    // `Star Wars: A New Hope` is a title, not a search of the field `wars`.
+    #[test]
+    fn a_field_orca_does_not_know_is_text() {
+        assert_eq!(parse("Wars:"), Some(word(None, "Wars:")));
+        assert_eq!(parse("AUTHOR:kant"), Some(word(Some(Field::Author), "kant")));
+        assert_eq!(parse("identifier:isbn:978"), Some(word(Some(Field::Identifier), "isbn:978")));
+    }
+
+    #[test]
+    fn what_does_not_parse_is_left_out() {
+        assert_eq!(parse("kant)"), Some(word(None, "kant")));
+        assert_eq!(parse("(kant"), Some(word(None, "kant")));
+        assert_eq!(parse("and kant or"), Some(word(None, "kant")));
+        assert_eq!(parse("kant not"), Some(word(None, "kant")));
+        assert_eq!(parse(") kant ("), Some(word(None, "kant")));
+        for nothing in ["", "   ", "()", "and", "not", "or or"] {
+            assert_eq!(parse(nothing), None, "{:?}", nothing);
+        }
+    }
+
+    fn depth(query: &Query) -> usize {
+        match query {
+            Query::And(left, right) | Query::Or(left, right) => 1 + depth(left).max(depth(right)),
+            Query::Not(query) => 1 + depth(query),
+            Query::Term(_) => 1,
+        }
+    }
+
+    // Past a point, a group is no group and a `not` a word: the query stays shallow.
+    #[test]
+    fn nesting_goes_only_so_deep() {
+        assert_eq!(parse(&format!("{}kant", "(".repeat(100_000))), Some(word(None, "kant")));
+
+        let negated = parse(&format!("{}kant", "not ".repeat(100_000))).expect("a query");
+        assert!(depth(&negated) <= MAX_TERMS + MAX_DEPTH + 1, "{}", depth(&negated));
+        let long = parse(&"kant ".repeat(100_000)).expect("a query");
+        assert_eq!(depth(&long), MAX_TERMS);
+        assert!(compile(&"(kant or not hegel) ".repeat(10_000), &Vec::new()).is_some());
+    }
+
+    // Everything typed is a parameter; only the shape of the query is SQL.
+    #[test]
+    fn values_never_become_sql() {
+        let search = compile(r#"tag:"'); DROP TABLE books;--" or title:=x"#, &Vec::new()).expect("a query");
+        assert!(!search.condition.contains("DROP"));
+        assert_eq!(
+            search.params,
+            [Value::Text("%'); DROP TABLE books;--%".to_string()), Value::Text("x".to_string())]
+        );
+    }
+
+    #[test]
+    fn a_rating_is_counted_in_stars() {
+        let search = compile("rating:>3.5", &Vec::new()).expect("a query");
+        assert!(search.condition.ends_with("> ?"));
+        assert_eq!(search.params, [Value::Real(7.0)]);
+
+        assert_eq!(compile("rating:lots", &Vec::new()).expect("a query").condition, NOTHING);
+    }
+
+    #[test]
+    fn a_date_is_the_period_it_names() {
+        assert_eq!(period("2000"), Some(("2000-01-01".to_string(), "2001-01-01".to_string())));
+        assert_eq!(period("2000-12"), Some(("2000-12-01".to_string(), "2001-01-01".to_string())));
+        assert_eq!(period("2000-02-29"), Some(("2000-02-29".to_string(), "2000-03-01".to_string())));
+        assert_eq!(period("1900-02-29"), None);
+        assert_eq!(period("2000-13"), None);
+        assert_eq!(period("last week"), None);
+
+        // Before 2000 began; after 2000 was over.
+        assert_eq!(compile("pubdate:<2000", &Vec::new()).expect("a query").params, [Value::Text("2000-01-01".to_string())]);
+        assert_eq!(compile("pubdate:>2000", &Vec::new()).expect("a query").params, [Value::Text("2001-01-01".to_string())]);
+    }
+
+    /// An index that knows every word there is.
+    struct Everything;
+
+    impl Library for Everything {
+        fn languages(&self) -> &[String] {
+            &[]
+        }
+
+        fn words(&self, word: &str) -> Option<String> {
+            Some(format!("\"{}\"*", word))
+        }
+    }
+
+    #[test]
+    fn plain_words_rank_the_books_they_find() {
+        let search = compile("kant tag:philosophy", &Everything).expect("a query");
+        assert!(search.condition.contains("book_text MATCH ?"));
+        assert_eq!(search.rank.as_deref(), Some("\"kant\"*"));
+
+        // A word a book must not have does not make it a better match.
+        let search = compile("kant or (critique and not pure)", &Everything).expect("a query");
+        assert_eq!(search.rank.as_deref(), Some("\"kant\"* OR \"critique\"*"));
+
+        // Exactly Kant is the title or the author, not a word in a synopsis.
+        let search = compile("=Kant", &Everything).expect("a query");
+        assert_eq!(search.rank, None);
+        assert!(!search.condition.contains("book_text"));
+    }
+
+    // Calibre keeps `deu`; a reader types German, Deutsch or de.
+    #[test]
+    fn a_language_goes_by_any_of_its_names() {
+        let languages = vec!["deu".to_string(), "eng".to_string(), "rus".to_string()];
+        for name in ["german", "Deutsch", "=de", "deu"] {
+            let search = compile(&format!("language:{}", name), &languages).expect("a query");
+            assert_eq!(search.params, [Value::Text("deu".to_string())], "{}", name);
+        }
+        assert_eq!(compile("language:klingon", &languages).expect("a query").condition, NOTHING);
+    }
+}
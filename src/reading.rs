@@ -0,0 +1,205 @@
+//! What each reader is reading, and how far they got
+//!
+//! There are three records of it: the positions Calibre's viewer and its content
+//! server keep in the library's `last_read_positions`, under the Calibre users
+//! `[authentication.calibre_users]` maps a login to, and what KOReader and the
+//! Kobos synced to Orca's own store. For every book, whichever was saved last counts.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use actix_web::HttpRequest;
+use rusqlite::Connection;
+
+use crate::appstate::AppState;
+use crate::authorized::Authorized;
+use crate::calibre::{self, Book};
+use crate::progress::{self, Document};
+
+/// Read this far, a book is finished rather than being read.
+pub const FINISHED: f64 = 0.99;
+
+/// Where a reader last was in a book, by either record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reading {
+    /// How much of the book lies behind, from 0 to 1
+    pub fraction: f64,
+    /// When, in seconds since the epoch
+    pub when: f64,
+}
+
+/// The user `last_read_positions` keeps the positions of a Calibre user
+/// under: `_` for the viewer on the desktop, the name of a content server user.
+pub fn position_user(user: &str) -> Option<&str> {
+    match user.split_once(':')? {
+        ("local", _) => Some("_"),
+        ("web", name) => Some(name),
+        _ => None,
+    }
+}
+
+/// Every user `last_read_positions` keeps the positions of `login` under.
+pub fn position_users(data: &AppState, login: &str) -> Vec<String> {
+    let mut users: Vec<String> = Vec::new();
+    for user in data.config.authentication.calibre_users_of(login).iter().filter_map(|user| position_user(user)) {
+        if !users.iter().any(|known| known == user) {
+            users.push(user.to_string());
+        }
+    }
+    users
+}
+
+/// Where `login` last was in each book of the library they opened, by book.
+/// `db` is the library's connection, locked; the store is locked after it.
+pub fn readings(data: &AppState, db: &Connection, lib: &str, login: &str) -> rusqlite::Result<HashMap<i32, Reading>> {
+    let mut readings: HashMap<i32, Reading> = HashMap::new();
+    let mut keep = |book: i32, reading: Reading| {
+        if readings.get(&book).is_none_or(|kept| kept.when < reading.when) {
+            readings.insert(book, reading);
+        }
+    };
+
+    for position in calibre::read_positions(db, &position_users(data, login))? {
+        keep(position.book, Reading { fraction: position.pos_frac, when: position.epoch });
+    }
+    let (synced, states) = {
+        let store = progress::lock(&data.progress);
+        (progress::positions_in(&store, login, lib)?, progress::kobo_states(&store, login)?)
+    };
+    for (book, position) in synced {
+        keep(book, Reading { fraction: position.percentage, when: position.timestamp as f64 });
+    }
+    if !states.is_empty() {
+        let uuids: Vec<String> = states.iter().map(|state| state.uuid.clone()).collect();
+        let books = calibre::books_by_uuid(db, &uuids)?;
+        for state in states {
+            if let Some(book) = books.get(&state.uuid) {
+                keep(*book, Reading { fraction: state.percentage, when: state.timestamp as f64 });
+            }
+        }
+    }
+    Ok(readings)
+}
+
+/// The books the login asking started and has not finished, the one read
+/// last first. A guest is reading nothing.
+pub fn in_progress(data: &AppState, db: &Connection, lib: &str, req: &HttpRequest) -> rusqlite::Result<Vec<i32>> {
+    match Authorized::of(req) {
+        Some(auth) => in_progress_of(data, db, lib, &auth.login),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// The books `login` started and has not finished, the one read last first.
+pub fn in_progress_of(data: &AppState, db: &Connection, lib: &str, login: &str) -> rusqlite::Result<Vec<i32>> {
+    let mut started: Vec<(i32, Reading)> = readings(data, db, lib, login)?
+        .into_iter()
+        .filter(|(_, reading)| reading.fraction > 0.0 && reading.fraction < FINISHED)
+        .collect();
+    started.sort_by(|(a, this), (b, that)| that.when.total_cmp(&this.when).then(a.cmp(b)));
+    Ok(started.into_iter().map(|(book, _)| book).collect())
+}
+
+/// The next volume of every series the login asking opened a book of, the
+/// series read last first. A guest has opened nothing.
+pub fn next_in_series(data: &AppState, db: &Connection, lib: &str, req: &HttpRequest) -> rusqlite::Result<Vec<i32>> {
+    let Some(auth) = Authorized::of(req) else {
+        return Ok(Vec::new());
+    };
+    let opened: Vec<(i32, f64)> =
+        readings(data, db, lib, &auth.login)?.into_iter().map(|(book, reading)| (book, reading.when)).collect();
+    calibre::next_in_series(db, &opened)
+}
+
+/// The books, each with how far the login asking got in it. A guest gets
+/// them as they are, and so does everybody when the positions cannot be
+/// read: they are a nicety.
+pub fn with_progress(data: &AppState, db: &Connection, lib: &str, req: &HttpRequest, mut books: Vec<Book>) -> Vec<Book> {
+    let Some(auth) = Authorized::of(req) else {
+        return books;
+    };
+    match readings(data, db, lib, &auth.login) {
+        Ok(readings) => {
+            for book in &mut books {
+                book.progress = readings.get(&book.id).map(|reading| reading.fraction);
+            }
+        }
+        Err(e) => eprintln!("Error reading the positions of {}: {}", auth.login, e),
+    }
+    books
+}
+
+/// What the store knows the files of the libraries by.
+#[derive(Debug, Default)]
+pub struct Digests {
+    /// The change of each library its files were last digested at
+    indexed: HashMap<String, calibre::Change>,
+    /// Digests of no file, since the libraries were last digested
+    unknown: HashSet<String>,
+}
+
+/// Brings the store's digests up to date with every library Calibre changed
+/// since: the library is locked to list its files, the files are read with
+/// only the digests locked, for no two to be read at once, and what they
+/// turned out to be goes to the store.
+pub fn index(data: &AppState) -> rusqlite::Result<()> {
+    let mut digests = lock(&data.digests);
+    for lib in data.db.keys() {
+        let (Some(library), Some(db)) = (data.config.calibre.libraries.get(lib), data.db.get(lib)) else {
+            continue;
+        };
+        let (change, files) = {
+            let db = calibre::lock(db);
+            let change = calibre::last_change(&db)?;
+            if digests.indexed.get(lib) == Some(&change) {
+                continue;
+            }
+            (change, calibre::book_files(&db)?)
+        };
+        let known = progress::indexed(&progress::lock(&data.progress), lib)?;
+        let files = files
+            .into_iter()
+            .map(|file| (file.book, file.format, Path::new(&library.path).join(file.path)));
+        let digested = progress::digest(known, files);
+        progress::index(&progress::lock(&data.progress), lib, &digested)?;
+        digests.indexed.insert(lib.clone(), change);
+        digests.unknown.clear();
+    }
+    Ok(())
+}
+
+/// The files of the libraries KOReader's `digest` stands for. A digest Orca
+/// does not know has the libraries Calibre changed since digested again, and
+/// is not looked for again until one changes once more.
+pub fn documents(data: &AppState, digest: &str) -> rusqlite::Result<Vec<Document>> {
+    let known = progress::documents(&progress::lock(&data.progress), digest)?;
+    if !known.is_empty() {
+        return Ok(known);
+    }
+    index(data)?;
+    if lock(&data.digests).unknown.contains(digest) {
+        return Ok(Vec::new());
+    }
+    let found = progress::documents(&progress::lock(&data.progress), digest)?;
+    if found.is_empty() {
+        lock(&data.digests).unknown.insert(digest.to_string());
+    }
+    Ok(found)
+}
+
+fn lock(digests: &Mutex<Digests>) -> MutexGuard<'_, Digests> {
+    digests.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calibre_keeps_positions_by_fewer_names_than_annotations() {
+        assert_eq!(position_user("local:viewer"), Some("_"));
+        assert_eq!(position_user("web:bob"), Some("bob"));
+        assert_eq!(position_user("bob"), None);
+    }
+}
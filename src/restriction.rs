@@ -0,0 +1,203 @@
+//! A library cut down to one of its virtual libraries
+//!
+//! With `virtual_library = "Kids"` in its config, a library shows the books of
+//! that virtual library and nothing else: in every feed, every count and every
+//! search. Rather than teach each query about it, the connection gets temporary
+//! views named after Calibre's own tables. SQLite looks in `temp` before `main`,
+//! so `FROM books` then means the books of the virtual library, and `FROM tags`
+//! the tags they carry.
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{calibre, fulltext};
+
+/// The tables of values Calibre links to books, with the link table and its column.
+const CATEGORIES: [(&str, &str, &str); 6] = [
+    ("authors", "books_authors_link", "author"),
+    ("tags", "books_tags_link", "tag"),
+    ("series", "books_series_link", "series"),
+    ("publishers", "books_publishers_link", "publisher"),
+    ("languages", "books_languages_link", "lang_code"),
+    ("ratings", "books_ratings_link", "rating"),
+];
+
+/// Restrict the library behind `db` to the virtual library `name`.
+pub fn restrict(db: &Connection, name: &str) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE TEMP TABLE restriction (
+             name TEXT NOT NULL,
+             search TEXT,
+             books INTEGER,
+             modified TEXT,
+             libraries TEXT
+         );
+         CREATE TEMP TABLE shelved (id INTEGER PRIMARY KEY);",
+    )?;
+    db.execute("INSERT INTO temp.restriction (name) VALUES (?1);", params![name])?;
+    refresh(db)
+}
+
+/// How many books a library holds, when Calibre last changed one, and how
+/// Calibre defines its virtual libraries.
+type Change = (Option<i64>, Option<String>, Option<String>);
+
+/// Which books a virtual library holds changes with the books, and with the
+/// virtual libraries defined in Calibre. Only for a library that is restricted,
+/// and nothing to do while Calibre changed neither since the last time.
+pub fn refresh(db: &Connection) -> rusqlite::Result<()> {
+    let (name, indexed): (String, Change) =
+        db.query_row("SELECT name, books, modified, libraries FROM temp.restriction;", [], |row| {
+            Ok((row.get(0)?, (row.get(1)?, row.get(2)?, row.get(3)?)))
+        })?;
+    let current: Change = db.query_row(
+        "SELECT COUNT(*), MAX(last_modified),
+                (SELECT val FROM main.preferences WHERE key = 'virtual_libraries')
+            FROM main.books;",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    if indexed == current {
+        return Ok(());
+    }
+    // A virtual library deleted in Calibre holds no books at all.
+    let search = calibre::virtual_library(db, &name).map(|library| library.search).optional()?;
+    if search.is_none() {
+        eprintln!("Calibre has no virtual library '{}' any more", name);
+    }
+
+    // The search of the virtual library looks words up in the index.
+    fulltext::refresh(db)?;
+
+    // All or nothing: a library half restricted would show books it must not.
+    let tx = db.unchecked_transaction()?;
+    // The search reads Calibre's tables, not the views this is about to replace.
+    forget_views(&tx)?;
+    let ids = match &search {
+        Some(search) => calibre::ids_matching(&tx, search)?,
+        None => Vec::new(),
+    };
+    tx.execute("DELETE FROM temp.shelved;", [])?;
+    {
+        let mut shelve = tx.prepare("INSERT INTO temp.shelved (id) VALUES (?1);")?;
+        for id in ids {
+            shelve.execute(params![id])?;
+        }
+    }
+    tx.execute(
+        "UPDATE temp.restriction SET search = ?1, books = ?2, modified = ?3, libraries = ?4;",
+        params![search, current.0, current.1, current.2],
+    )?;
+    create_views(&tx)?;
+    tx.commit()
+}
+
+fn forget_views(db: &Connection) -> rusqlite::Result<()> {
+    let views: Vec<String> = {
+        let mut stmt = db.prepare("SELECT name FROM temp.sqlite_master WHERE type = 'view';")?;
+        let names = stmt.query_map([], |row| row.get(0))?;
+        names.collect::<rusqlite::Result<_>>()?
+    };
+    for view in views {
+        db.execute(&format!("DROP VIEW temp.\"{}\";", view), [])?;
+    }
+    Ok(())
+}
+
+/// One view for the books, one for every table that says something about a
+/// book -- its formats, its comment, a custom column -- and one for every
+/// table of values linked to the books.
+fn create_views(db: &Connection) -> rusqlite::Result<()> {
+    let per_book: Vec<String> = {
+        let mut stmt = db.prepare(
+            "SELECT m.name FROM main.sqlite_master m, pragma_table_info(m.name, 'main') p
+                WHERE m.type = 'table' AND p.name = 'book';",
+        )?;
+        let names = stmt.query_map([], |row| row.get(0))?;
+        names.collect::<rusqlite::Result<_>>()?
+    };
+
+    let mut views = vec!["CREATE TEMP VIEW books AS SELECT * FROM main.books WHERE id IN (SELECT id FROM temp.shelved);"
+        .to_string()];
+    for table in &per_book {
+        views.push(format!(
+            "CREATE TEMP VIEW \"{0}\" AS SELECT * FROM main.\"{0}\" WHERE book IN (SELECT id FROM temp.shelved);",
+            table
+        ));
+    }
+
+    let linked = CATEGORIES.iter().map(|(table, link, column)| (table.to_string(), link.to_string(), column.to_string()));
+    // A custom column of shared values links them the same way: `custom_column_3`
+    // through `books_custom_column_3_link`.
+    let custom = per_book.iter().filter_map(|table| {
+        let id = table.strip_prefix("books_custom_column_")?.strip_suffix("_link")?;
+        Some((format!("custom_column_{}", id), table.clone(), "value".to_string()))
+    });
+    for (table, link, column) in linked.chain(custom) {
+        views.push(format!(
+            "CREATE TEMP VIEW \"{0}\" AS SELECT * FROM main.\"{0}\"
+                WHERE id IN (SELECT \"{2}\" FROM main.\"{1}\" WHERE book IN (SELECT id FROM temp.shelved));",
+            table, link, column
+        ));
+    }
+
+    for view in views {
+        db.execute(&view, [])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// A copy of the test library: restricting it writes nothing to Calibre's
+    /// database, but changing a virtual library does.
+    fn library(dir: &TempDir) -> Connection {
+        std::fs::copy("tests/calibre/metadata.db", dir.path().join("metadata.db")).unwrap();
+        let db = Connection::open(dir.path().join("metadata.db")).unwrap();
+        fulltext::attach(&db, ":memory:").unwrap();
+        db
+    }
+
+    fn titles(db: &Connection) -> Vec<String> {
+        calibre::books_page(db, &calibre::Facets::NONE, 50, 0).unwrap().into_iter().map(|book| book.title).collect()
+    }
+
+    #[test]
+    fn a_restricted_library_holds_only_the_books_of_its_virtual_library() {
+        let dir = TempDir::new().unwrap();
+        let db = library(&dir);
+        restrict(&db, "Reference").unwrap();
+
+        assert_eq!(titles(&db), ["Kritik der reinen Vernunft - 2. Auflage", "The sidereal messenger of Galileo Galilei"]);
+        let counts = calibre::counts(&db).unwrap();
+        assert_eq!((counts.books, counts.tags, counts.series), (2, 1, 0));
+        assert_eq!(calibre::count_authors(&db).unwrap(), 3);
+        // Not by searching, and not by asking for it.
+        assert_eq!(calibre::count_books_matching(&db, "lovecraft", &calibre::Facets::NONE).unwrap(), 0);
+        assert!(calibre::book(&db, 8).is_err());
+    }
+
+    // Redefined in Calibre, the virtual library holds other books -- once a
+    // book changed, which is all a refresh looks at.
+    #[test]
+    fn a_restriction_follows_its_virtual_library() {
+        let dir = TempDir::new().unwrap();
+        let db = library(&dir);
+        restrict(&db, "Kids").unwrap();
+        assert_eq!(titles(&db), ["Alice's Adventures in Wonderland"]);
+
+        db.execute(
+            "UPDATE preferences SET val = '{\"Kids\": \"tag:horror\"}' WHERE key = 'virtual_libraries';",
+            [],
+        )
+        .unwrap();
+        refresh(&db).unwrap();
+        assert_eq!(titles(&db), ["At the Mountains of Madness"]);
+
+        db.execute("DELETE FROM preferences WHERE key = 'virtual_libraries';", []).unwrap();
+        refresh(&db).unwrap();
+        assert!(titles(&db).is_empty());
+    }
+}
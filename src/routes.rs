@@ -5,8 +5,16 @@ use actix_files as fs;
 use tera::Tera;
 use crate::authorized::Authorized;
 use crate::appstate::AppState;
-use crate::calibre;
+use crate::calibre::{self, Facets};
 use crate::config::Config;
+use crate::reading;
+use crate::thumbnail;
+use crate::shelf::{
+    column_feed, encoded, facet_groups, faceted, letter_path, paged, saved_searches_with_books, searched, started,
+    user_categories_with_books, user_category_path, window, IndexQuery, PageQuery, Shelf, Window, PER_PAGE,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 
 /// The externally visible origin of this request, as `scheme://host` without a
 /// trailing slash. `connection_info` honours X-Forwarded-Proto / X-Forwarded-Host,
@@ -55,6 +63,39 @@ fn wrapped(mut books: Vec<calibre::Book>) -> Vec<calibre::Book> {
     books
 }
 
+/// An author, tag, series or publisher in a list, with Calibre's note on it.
+#[derive(Serialize)]
+struct Noted<T> {
+    #[serde(flatten)]
+    entry: T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+}
+
+/// The entries of a list, each with its note if it has one. Calibre calls the
+/// fields `authors`, `tags`, `series` and `publisher`.
+fn noted<T>(db: &rusqlite::Connection, lib: &str, field: &str, entries: Vec<T>, id: fn(&T) -> i32) -> Vec<Noted<T>> {
+    let ids: Vec<i32> = entries.iter().map(id).collect();
+    // A note is a nicety: a list goes out without them rather than not at all.
+    let mut notes = calibre::notes(db, field, &ids).unwrap_or_else(|e| {
+        eprintln!("Error reading the notes on {}: {}", field, e);
+        Default::default()
+    });
+    entries
+        .into_iter()
+        .map(|entry| Noted {
+            note: notes.remove(&id(&entry)).map(|note| resource_links(&note, lib)),
+            entry,
+        })
+        .collect()
+}
+
+/// A note as the Atom catalog shows it: HTML, with its images linked where
+/// `note_resource` serves them.
+fn resource_links(note: &str, lib: &str) -> String {
+    calibre::note_html(note, &format!("/{}/resource", lib))
+}
+
 /// Log a failure and turn it into a 500
 pub(crate) fn server_error(what: &str, e: impl std::fmt::Display) -> HttpResponse {
     eprintln!("{}: {}", what, e);
@@ -62,14 +103,16 @@ pub(crate) fn server_error(what: &str, e: impl std::fmt::Display) -> HttpRespons
 }
 
 fn render_template(template: &Tera, name: &str, ctx: tera::Context) -> HttpResponse {
+    render_as(template, name, ctx, "application/atom+xml")
+}
+
+fn render_as(template: &Tera, name: &str, ctx: tera::Context, mime: &str) -> HttpResponse {
     match template.render(name, &ctx) {
-        Ok(body) => HttpResponse::Ok()
-            .content_type("application/atom+xml")
-            .body(body),
+        Ok(body) => HttpResponse::Ok().content_type(mime).body(body),
         Err(e) => {
             eprintln!("Template rendering error: {}", e);
             HttpResponse::InternalServerError()
-                .content_type("application/atom+xml")
+                .content_type(mime)
                 .body("Template rendering error")
         }
     }
@@ -94,15 +137,183 @@ fn not_found_or_500(missing: &'static str) -> impl Fn(rusqlite::Error) -> Error
     }
 }
 
-fn missing_shelf(kind: &str, id: i32, e: rusqlite::Error) -> HttpResponse {
-    match e {
-        rusqlite::Error::QueryReturnedNoRows => {
-            HttpResponse::NotFound().body(format!("No {} with id {}", kind, id))
+/// What an OPDS 1.2 feed says about the pages it is one of: OpenSearch's
+/// `totalResults`, `itemsPerPage` and `startIndex`, and a link to each page beside it.
+#[derive(Serialize)]
+struct Paging {
+    total: usize,
+    per_page: usize,
+    start: usize,
+    /// acquisition or navigation, the same as the feed itself
+    kind: &'static str,
+    links: Vec<PageLink>,
+}
+
+#[derive(Serialize)]
+struct PageLink {
+    rel: &'static str,
+    href: String,
+}
+
+/// One option of one facet group, `opds:activeFacet` if the reader picked it.
+#[derive(Serialize)]
+struct FacetLink {
+    group: &'static str,
+    title: String,
+    href: String,
+    active: bool,
+}
+
+/// An entry that is not one of a library's fixed feeds, nor one of a kind of
+/// category: a virtual library, a saved search, a user category and what it lists.
+#[derive(Serialize)]
+struct Entry {
+    title: String,
+    /// below the library, already escaped
+    path: String,
+    /// what the entry refers to, as the last part of its `urn:orca:` id
+    id: String,
+    /// acquisition or navigation, what the feed it leads to is
+    kind: &'static str,
+    content: String,
+}
+
+impl Entry {
+    /// An entry leading to the books on `shelf`. An author, tag or series keeps the
+    /// id its entry has in the feed of all of them.
+    fn shelf(shelf: &Shelf, title: String, content: String) -> Entry {
+        let id = match shelf {
+            Shelf::Author(id) => format!("author:{}", id),
+            Shelf::Tag(id) => format!("tag:{}", id),
+            Shelf::Series(id) => format!("series:{}", id),
+            shelf => atom_path(shelf).replace('/', ":"),
+        };
+        Entry {
+            title,
+            path: atom_path(shelf),
+            id,
+            kind: ACQUISITION,
+            content,
         }
-        e => server_error("Error querying shelf", e),
     }
 }
 
+const ACQUISITION: &str = "acquisition";
+const NAVIGATION: &str = "navigation";
+
+/// A complete catalog entry, served on its own rather than inside a feed.
+const ENTRY: &str = "application/atom+xml;type=entry;profile=opds-catalog";
+
+/// `feed_ctx` for one page of a feed that lives at `path` below the library.
+/// The self link names the page, so that a client resolving against it stays on it.
+fn paged_ctx(
+    req: &HttpRequest,
+    config: &Config,
+    lib: &str,
+    path: &str,
+    total: usize,
+    window: &Window,
+    kind: &'static str,
+) -> tera::Context {
+    let mut ctx = feed_ctx(req, config, Some(lib));
+    let url = format!("{}/{}/{}", origin(req, config), lib, path);
+    ctx.insert("self_url", &paged(url.clone(), window.current));
+    ctx.insert(
+        "paging",
+        &Paging {
+            total,
+            per_page: PER_PAGE,
+            start: window.offset + 1,
+            kind,
+            links: window
+                .neighbours()
+                .into_iter()
+                .map(|(rel, page)| PageLink {
+                    rel,
+                    href: paged(url.clone(), page),
+                })
+                .collect(),
+        },
+    );
+    ctx
+}
+
+/// Where a shelf lives in the Atom catalog: the same place as below `/v2`,
+/// except that OpenSearch calls the search term `q`.
+fn atom_path(shelf: &Shelf) -> String {
+    match shelf {
+        Shelf::Search(term) => format!("search?q={}", encoded(term)),
+        Shelf::Content(quote) => format!("search?q={}&in=content", encoded(&quote.said)),
+        shelf => shelf.path(),
+    }
+}
+
+/// One page of books, whichever shelf they come off. Pages and facets the same as `/v2`.
+fn books_feed(
+    data: &AppState,
+    req: &HttpRequest,
+    lib: &str,
+    shelf: Shelf,
+    facets: Facets,
+    requested: usize,
+) -> HttpResponse {
+    let db = match data.library(lib) {
+        Some(db) => db,
+        None => return HttpResponse::NotFound().body(format!("Database '{}' not found", lib)),
+    };
+
+    let name = match shelf.name(&db) {
+        Ok(name) => name,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return HttpResponse::NotFound().body(format!("Nothing shelved under {}", shelf.path()))
+        }
+        Err(e) => return server_error("Error querying shelf", e),
+    };
+
+    let total = match shelf.count(&db, &facets) {
+        Ok(total) => total,
+        Err(e) => return server_error("Error counting books", e),
+    };
+    let window = window(total, PER_PAGE, requested);
+
+    let books = match shelf
+        .books(&db, &facets, PER_PAGE, window.offset)
+        .and_then(|books| calibre::with_custom(&db, data.columns(lib), books))
+        .and_then(|books| calibre::with_related(&db, books))
+    {
+        Ok(books) => wrapped(reading::with_progress(data, &db, lib, req, books)),
+        Err(e) => return server_error("Error querying books", e),
+    };
+
+    let groups = match facet_groups(&db, &facets) {
+        Ok(groups) => groups,
+        Err(e) => return server_error("Error querying facets", e),
+    };
+    let base = origin(req, data.config);
+    let mut facet_links = Vec::new();
+    for group in groups {
+        for option in group.options {
+            facet_links.push(FacetLink {
+                group: group.title,
+                href: format!("{}/{}/{}", base, lib, faceted(atom_path(&shelf), &option.facets)),
+                title: option.title,
+                active: option.active,
+            });
+        }
+    }
+
+    let path = faceted(atom_path(&shelf), &facets);
+    let mut ctx = paged_ctx(req, data.config, lib, &path, total, &window, ACQUISITION);
+    ctx.insert("facets", &facet_links);
+    ctx.insert("feed_title", &format!("{} | {}", lib, name));
+    if let Some(note) = shelf.note(&db) {
+        ctx.insert("subtitle", &resource_links(&note, lib));
+    }
+    ctx.insert("books", &books);
+    ctx.insert("updated", &calibre::updated(&db));
+    render_template(&data.templates, "books.xml.tera", ctx)
+}
+
 fn attachment(path: &str) -> Result<fs::NamedFile, Error> {
     let file = fs::NamedFile::open(path).map_err(actix_web::error::ErrorInternalServerError)?;
     Ok(file
@@ -118,6 +329,8 @@ async fn health(_auth: Authorized) -> impl Responder {
     HttpResponse::Ok().body("OK")
 }
 
+/// The cover as Calibre keeps it. A book without one gets a placeholder, as
+/// large as the largest thumbnail.
 #[actix_web::get("/{lib}/cover/{id}")]
 async fn cover(
     data: web::Data<AppState>,
@@ -126,15 +339,66 @@ async fn cover(
     _req: HttpRequest,
 ) -> Result<fs::NamedFile, Error> {
     let (lib, book) = path.into_inner();
-    let db = match data.db.get(&lib) {
-        Some(db) => calibre::lock(db),
+    let found = cover_of(&data, &lib, book)?;
+    match &found.path {
+        Some(original) => attachment(&format!("{}/{}", library_path(&data, &lib)?, original)),
+        None => {
+            let widest = data.config.catalog.thumbnail_widths.iter().copied().max().unwrap_or_default();
+            drawn(&data, &lib, book, found, widest).await
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ThumbQuery {
+    /// The width a client would like, in pixels. It gets the next configured one up.
+    w: Option<u32>,
+}
+
+/// A cover scaled down to one of `catalog.thumbnail_widths`, or the placeholder
+/// of a book without one.
+#[actix_web::get("/{lib}/thumb/{id}")]
+async fn thumb(
+    data: web::Data<AppState>,
+    path: web::Path<(String, i32)>,
+    query: web::Query<ThumbQuery>,
+    _auth: Authorized,
+    _req: HttpRequest,
+) -> Result<fs::NamedFile, Error> {
+    let (lib, book) = path.into_inner();
+    let width = thumbnail::width(query.w, &data.config.catalog.thumbnail_widths);
+    scaled(&data, &lib, book, width).await
+}
+
+/// A book's cover or its placeholder, at one of `catalog.thumbnail_widths`:
+/// what `thumb` serves a reader, and the Kobo sync a device.
+pub(crate) async fn scaled(data: &AppState, lib: &str, book: i32, width: u32) -> Result<fs::NamedFile, Error> {
+    let found = cover_of(data, lib, book)?;
+    drawn(data, lib, book, found, width).await
+}
+
+/// What Calibre knows about a book's cover. The library is locked no longer than that.
+fn cover_of(data: &AppState, lib: &str, book: i32) -> Result<calibre::Cover, Error> {
+    let db = match data.library(lib) {
+        Some(db) => db,
         None => return Err(actix_web::error::ErrorNotFound("Library not found")),
     };
-    let library = library_path(&data, &lib)?;
+    calibre::cover(&db, book).map_err(not_found_or_500("Book not found"))
+}
 
-    let cover = calibre::cover_path(&db, book).map_err(not_found_or_500("Cover not found"))?;
+/// A thumbnail or a placeholder `width` pixels wide, from the cache or made on the spot.
+async fn drawn(data: &AppState, lib: &str, book: i32, found: calibre::Cover, width: u32) -> Result<fs::NamedFile, Error> {
+    let library = PathBuf::from(library_path(data, lib)?);
+    let cache = data.config.calibre.libraries[lib].thumbnail_cache(lib);
 
-    attachment(&format!("{}/{}", library, cover))
+    // Scaling a cover takes a while; the server goes on meanwhile.
+    let thumbnail = web::block(move || thumbnail::thumbnail(&library, &found, &cache, book, width))
+        .await?
+        .map_err(|e| {
+            eprintln!("Error drawing the cover of book {}: {:#}", book, e);
+            actix_web::error::ErrorInternalServerError("Error drawing cover")
+        })?;
+    Ok(fs::NamedFile::open(thumbnail)?.use_last_modified(true))
 }
 
 #[actix_web::get("/{lib}/file/{id}/{format}")]
@@ -145,18 +409,47 @@ async fn book_file(
     _req: HttpRequest,
 ) -> Result<fs::NamedFile, Error> {
     let (lib, book, format) = path.into_inner();
-    let db = match data.db.get(&lib) {
-        Some(db) => calibre::lock(db),
+    file_of(&data, &lib, book, &format)
+}
+
+/// One format of a book, as a download: for a reader of the catalog, and for
+/// a Kobo syncing.
+pub(crate) fn file_of(data: &AppState, lib: &str, book: i32, format: &str) -> Result<fs::NamedFile, Error> {
+    let db = match data.library(lib) {
+        Some(db) => db,
         None => return Err(actix_web::error::ErrorNotFound("Library not found")),
     };
-    let library = library_path(&data, &lib)?;
+    let library = library_path(data, lib)?;
 
     let file =
-        calibre::file_path(&db, book, &format).map_err(not_found_or_500("Book not found"))?;
+        calibre::file_path(&db, book, format).map_err(not_found_or_500("Book not found"))?;
 
     attachment(&format!("{}/{}", library, file))
 }
 
+/// An image from Calibre's note on an author, tag, series or publisher.
+#[actix_web::get("/{lib}/resource/{algorithm}/{digest}")]
+async fn note_resource(
+    data: web::Data<AppState>,
+    path: web::Path<(String, String, String)>,
+    _auth: Authorized,
+    _req: HttpRequest,
+) -> Result<fs::NamedFile, Error> {
+    let (lib, algorithm, digest) = path.into_inner();
+    let db = match data.library(&lib) {
+        Some(db) => db,
+        None => return Err(actix_web::error::ErrorNotFound("Library not found")),
+    };
+    let resource = calibre::note_resource(&db, &algorithm, &digest)
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .ok_or_else(|| actix_web::error::ErrorNotFound("Resource not found"))?;
+
+    // Calibre files it under its hash; the name it came with says what it is.
+    let extension = Path::new(&resource.name).extension().and_then(|extension| extension.to_str());
+    let mime = fs::file_extension_to_mime(extension.unwrap_or_default());
+    Ok(fs::NamedFile::open(resource.path)?.set_content_type(mime).use_last_modified(true))
+}
+
 #[actix_web::get("/")]
 async fn index(data: web::Data<AppState>, _auth: Authorized, req: HttpRequest) -> impl Responder {
     let libraries: Vec<String> = data.db.keys().cloned().collect();
@@ -170,8 +463,9 @@ async fn index(data: web::Data<AppState>, _auth: Authorized, req: HttpRequest) -
 
     let updated = data
         .db
-        .values()
-        .map(|db| calibre::updated(&calibre::lock(db)))
+        .keys()
+        .filter_map(|lib| data.library(lib))
+        .map(|db| calibre::updated(&db))
         .max()
         .unwrap_or_else(|| "2000-01-01T00:00:00+00:00".to_string());
 
@@ -189,36 +483,136 @@ async fn opds(
     req: HttpRequest,
 ) -> impl Responder {
     let lib = path.into_inner();
-    let db = match data.db.get(&lib) {
-        Some(db) => calibre::lock(db),
+    let db = match data.library(&lib) {
+        Some(db) => db,
         None => return HttpResponse::NotFound().body(format!("Database '{}' not found", lib)),
     };
 
+    let columns: Vec<&calibre::CustomColumn> =
+        data.columns(&lib).iter().filter(|column| column.is_category()).collect();
+
+    // The virtual libraries that hold any books, and where to find them.
+    let virtual_libraries = match calibre::virtual_libraries(&db) {
+        Ok(libraries) => libraries,
+        Err(e) => return server_error("Error reading the virtual libraries", e),
+    };
+    let mut shelves = Vec::new();
+    for library in virtual_libraries {
+        let shelf = Shelf::Virtual(library.name.clone());
+        match shelf.count(&db, &Facets::NONE) {
+            Ok(0) => {}
+            Ok(_) => {
+                let content = format!("Calibre's virtual library {}", library.name);
+                shelves.push(Entry::shelf(&shelf, library.name, content))
+            }
+            Err(e) => return server_error("Error counting the library", e),
+        }
+    }
+
+    // Left out when Calibre has none, the same as in `/v2`.
+    let searches = match calibre::saved_searches(&db) {
+        Ok(searches) => !searches.is_empty(),
+        Err(e) => return server_error("Error reading the saved searches", e),
+    };
+    let categories = match calibre::has_user_categories(&db) {
+        Ok(categories) => categories,
+        Err(e) => return server_error("Error reading the user categories", e),
+    };
+
+    // A library too large to scroll through starts with the first letters of its titles.
+    let indexed = match calibre::count_books(&db, &Facets::NONE) {
+        Ok(books) => books > data.config.index_above(&lib),
+        Err(e) => return server_error("Error counting the library", e),
+    };
+
+    // Only for a reader with a book to pick up again, the same as in `/v2`.
+    let started = match reading::in_progress(&data, &db, &lib, &req) {
+        Ok(started) => started.len(),
+        Err(e) => return server_error("Error reading the positions", e),
+    };
+
     let mut ctx = feed_ctx(&req, data.config, Some(&lib));
+    ctx.insert("titles_indexed", &indexed);
+    ctx.insert("currently_reading", &started);
+    ctx.insert("columns", &columns);
+    ctx.insert("virtual_libraries", &shelves);
+    ctx.insert("saved_searches", &searches);
+    ctx.insert("user_categories", &categories);
     ctx.insert("updated", &calibre::updated(&db));
     render_template(&data.templates, "opds.xml.tera", ctx)
 }
 
+/// The first letters of a list too long to scroll through, each an entry
+/// leading to the part of the list filed under it.
+fn letter_entries(feed: &str, letters: Vec<(String, usize)>, what: &str) -> Vec<Entry> {
+    letters
+        .into_iter()
+        .map(|(letter, count)| Entry {
+            path: letter_path(feed, &letter),
+            id: format!("{}:letter:{}", feed, encoded(&letter)),
+            kind: NAVIGATION,
+            content: format!("{} {}", count, what),
+            title: letter,
+        })
+        .collect()
+}
+
+/// One page of `entries`: all there is under one letter, or of a list Calibre
+/// hands over whole.
+fn page_of<T>(mut entries: Vec<T>, window: &Window) -> Vec<T> {
+    entries.truncate(window.offset + PER_PAGE);
+    entries.split_off(window.offset.min(entries.len()))
+}
+
 #[actix_web::get("/{lib}/tags")]
 async fn tags(
     data: web::Data<AppState>,
     path: web::Path<String>,
+    query: web::Query<IndexQuery>,
     _auth: Authorized,
     req: HttpRequest,
 ) -> impl Responder {
     let lib = path.into_inner();
-    let db = match data.db.get(&lib) {
-        Some(db) => calibre::lock(db),
+    let db = match data.library(&lib) {
+        Some(db) => db,
         None => return HttpResponse::NotFound().body(format!("Database '{}' not found", lib)),
     };
 
-    let tags = match calibre::tags(&db) {
-        Ok(tags) => tags,
-        Err(e) => return server_error("Error querying tags", e),
+    let total = match calibre::count_tags(&db) {
+        Ok(total) => total,
+        Err(e) => return server_error("Error counting tags", e),
     };
 
-    let mut ctx = feed_ctx(&req, data.config, Some(&lib));
-    ctx.insert("tags", &tags);
+    let (tags, total, window, path) = match &query.letter {
+        Some(letter) => {
+            let tags = match calibre::tags_under(&db, letter) {
+                Ok(tags) => tags,
+                Err(e) => return server_error("Error querying tags", e),
+            };
+            let total = tags.len();
+            let window = window(total, PER_PAGE, query.page.unwrap_or(1));
+            (page_of(tags, &window), total, window, letter_path("tags", letter))
+        }
+        None if total > data.config.index_above(&lib) => {
+            return match calibre::tag_letters(&db) {
+                Ok(letters) => {
+                    let entries = letter_entries("tags", letters, "tags");
+                    entries_feed(&data, &req, &lib, "Tags", entries, calibre::updated(&db))
+                }
+                Err(e) => server_error("Error querying tags", e),
+            };
+        }
+        None => {
+            let window = window(total, PER_PAGE, query.page.unwrap_or(1));
+            match calibre::tags_page(&db, PER_PAGE, window.offset) {
+                Ok(tags) => (tags, total, window, "tags".to_string()),
+                Err(e) => return server_error("Error querying tags", e),
+            }
+        }
+    };
+
+    let mut ctx = paged_ctx(&req, data.config, &lib, &path, total, &window, NAVIGATION);
+    ctx.insert("tags", &noted(&db, &lib, "tags", tags, |entry: &calibre::Tag| entry.id));
     ctx.insert("updated", &calibre::updated(&db));
     render_template(&data.templates, "tags.xml.tera", ctx)
 }
@@ -227,107 +621,250 @@ async fn tags(
 async fn books_by_tag(
     data: web::Data<AppState>,
     path: web::Path<(String, i32)>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
     _auth: Authorized,
     req: HttpRequest,
 ) -> impl Responder {
     let (lib, tag) = path.into_inner();
-    let db = match data.db.get(&lib) {
-        Some(db) => calibre::lock(db),
+    books_feed(&data, &req, &lib, Shelf::Tag(tag), facets.into_inner(), query.page.unwrap_or(1))
+}
+
+#[actix_web::get("{lib}/authors")]
+async fn authors(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<IndexQuery>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    let db = match data.library(&lib) {
+        Some(db) => db,
         None => return HttpResponse::NotFound().body(format!("Database '{}' not found", lib)),
     };
 
-    if let Err(e) = calibre::tag_name(&db, tag) {
-        return missing_shelf("tag", tag, e);
-    }
+    let total = match calibre::count_authors(&db) {
+        Ok(total) => total,
+        Err(e) => return server_error("Error counting authors", e),
+    };
 
-    let books = match calibre::books_by_tag(&db, tag) {
-        Ok(books) => wrapped(books),
-        Err(e) => return server_error("Error querying books", e),
+    let (authors, total, window, path) = match &query.letter {
+        Some(letter) => {
+            let authors = match calibre::authors_under(&db, letter) {
+                Ok(authors) => authors,
+                Err(e) => return server_error("Error querying authors", e),
+            };
+            let total = authors.len();
+            let window = window(total, PER_PAGE, query.page.unwrap_or(1));
+            (page_of(authors, &window), total, window, letter_path("authors", letter))
+        }
+        None if total > data.config.index_above(&lib) => {
+            return match calibre::author_letters(&db) {
+                Ok(letters) => {
+                    let entries = letter_entries("authors", letters, "authors");
+                    entries_feed(&data, &req, &lib, "Authors", entries, calibre::updated(&db))
+                }
+                Err(e) => server_error("Error querying authors", e),
+            };
+        }
+        None => {
+            let window = window(total, PER_PAGE, query.page.unwrap_or(1));
+            match calibre::authors_page(&db, PER_PAGE, window.offset) {
+                Ok(authors) => (authors, total, window, "authors".to_string()),
+                Err(e) => return server_error("Error querying authors", e),
+            }
+        }
     };
 
-    let mut ctx = feed_ctx(&req, data.config, Some(&lib));
-    ctx.insert("feed_title", &format!("{} | {} books", lib, books.len()));
-    ctx.insert("books", &books);
+    let mut ctx = paged_ctx(&req, data.config, &lib, &path, total, &window, NAVIGATION);
+    ctx.insert("authors", &noted(&db, &lib, "authors", authors, |entry: &calibre::Author| entry.id));
     ctx.insert("updated", &calibre::updated(&db));
-    render_template(&data.templates, "books.xml.tera", ctx)
+    render_template(&data.templates, "authors.xml.tera", ctx)
 }
 
-#[actix_web::get("{lib}/authors")]
-async fn authors(
+#[actix_web::get("{lib}/authors/{id}")]
+async fn books_by_author(
+    data: web::Data<AppState>,
+    path: web::Path<(String, i32)>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, author) = path.into_inner();
+    books_feed(&data, &req, &lib, Shelf::Author(author), facets.into_inner(), query.page.unwrap_or(1))
+}
+
+#[actix_web::get("{lib}/series")]
+async fn all_series(
     data: web::Data<AppState>,
     path: web::Path<String>,
+    query: web::Query<PageQuery>,
     _auth: Authorized,
     req: HttpRequest,
 ) -> impl Responder {
     let lib = path.into_inner();
-    let db = match data.db.get(&lib) {
-        Some(db) => calibre::lock(db),
+    let db = match data.library(&lib) {
+        Some(db) => db,
         None => return HttpResponse::NotFound().body(format!("Database '{}' not found", lib)),
     };
 
-    let authors = match calibre::authors(&db) {
-        Ok(authors) => authors,
-        Err(e) => return server_error("Error querying authors", e),
+    let series = match calibre::series_with_books(&db) {
+        Ok(series) => series,
+        Err(e) => return server_error("Error querying series", e),
     };
 
-    let mut ctx = feed_ctx(&req, data.config, Some(&lib));
-    ctx.insert("authors", &authors);
+    let total = series.len();
+    let window = window(total, PER_PAGE, query.page.unwrap_or(1));
+    let mut ctx = paged_ctx(&req, data.config, &lib, "series", total, &window, NAVIGATION);
+    ctx.insert("series", &noted(&db, &lib, "series", page_of(series, &window), |entry: &calibre::Category| entry.id));
     ctx.insert("updated", &calibre::updated(&db));
-    render_template(&data.templates, "authors.xml.tera", ctx)
+    render_template(&data.templates, "series.xml.tera", ctx)
 }
 
-#[actix_web::get("{lib}/authors/{id}")]
-async fn books_by_author(
+#[actix_web::get("{lib}/series/{id}")]
+async fn books_in_series(
     data: web::Data<AppState>,
     path: web::Path<(String, i32)>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
     _auth: Authorized,
     req: HttpRequest,
 ) -> impl Responder {
-    let (lib, author) = path.into_inner();
-    let db = match data.db.get(&lib) {
-        Some(db) => calibre::lock(db),
+    let (lib, series) = path.into_inner();
+    books_feed(&data, &req, &lib, Shelf::Series(series), facets.into_inner(), query.page.unwrap_or(1))
+}
+
+#[actix_web::get("{lib}/publishers")]
+async fn publishers(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<PageQuery>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    let db = match data.library(&lib) {
+        Some(db) => db,
         None => return HttpResponse::NotFound().body(format!("Database '{}' not found", lib)),
     };
 
-    if let Err(e) = calibre::author_name(&db, author) {
-        return missing_shelf("author", author, e);
-    }
+    let publishers = match calibre::publishers_with_books(&db) {
+        Ok(publishers) => publishers,
+        Err(e) => return server_error("Error querying publishers", e),
+    };
 
-    let books = match calibre::books_by_author(&db, author) {
-        Ok(books) => wrapped(books),
-        Err(e) => return server_error("Error querying books", e),
+    let total = publishers.len();
+    let window = window(total, PER_PAGE, query.page.unwrap_or(1));
+    let mut ctx = paged_ctx(&req, data.config, &lib, "publishers", total, &window, NAVIGATION);
+    ctx.insert("publishers", &noted(&db, &lib, "publisher", page_of(publishers, &window), |entry: &calibre::Category| entry.id));
+    ctx.insert("updated", &calibre::updated(&db));
+    render_template(&data.templates, "publishers.xml.tera", ctx)
+}
+
+#[actix_web::get("{lib}/publishers/{id}")]
+async fn books_by_publisher(
+    data: web::Data<AppState>,
+    path: web::Path<(String, i32)>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, publisher) = path.into_inner();
+    books_feed(&data, &req, &lib, Shelf::Publisher(publisher), facets.into_inner(), query.page.unwrap_or(1))
+}
+
+#[actix_web::get("{lib}/languages")]
+async fn languages(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<PageQuery>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    let db = match data.library(&lib) {
+        Some(db) => db,
+        None => return HttpResponse::NotFound().body(format!("Database '{}' not found", lib)),
     };
 
-    let mut ctx = feed_ctx(&req, data.config, Some(&lib));
-    ctx.insert("feed_title", &format!("{} | {} books", lib, books.len()));
-    ctx.insert("books", &books);
+    let languages = match calibre::languages_with_books(&db) {
+        Ok(languages) => languages,
+        Err(e) => return server_error("Error querying languages", e),
+    };
+
+    let total = languages.len();
+    let window = window(total, PER_PAGE, query.page.unwrap_or(1));
+    let mut ctx = paged_ctx(&req, data.config, &lib, "languages", total, &window, NAVIGATION);
+    ctx.insert("languages", &page_of(languages, &window));
     ctx.insert("updated", &calibre::updated(&db));
-    render_template(&data.templates, "books.xml.tera", ctx)
+    render_template(&data.templates, "languages.xml.tera", ctx)
+}
+
+#[actix_web::get("{lib}/languages/{id}")]
+async fn books_by_language(
+    data: web::Data<AppState>,
+    path: web::Path<(String, i32)>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, language) = path.into_inner();
+    books_feed(&data, &req, &lib, Shelf::Language(language), facets.into_inner(), query.page.unwrap_or(1))
 }
 
 #[actix_web::get("{lib}/books")]
 async fn getbooks(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    books_feed(&data, &req, &lib, Shelf::Everything, facets.into_inner(), query.page.unwrap_or(1))
+}
+
+/// The first letters of the titles, each leading to the books that start with it.
+#[actix_web::get("{lib}/titles")]
+async fn titles(
     data: web::Data<AppState>,
     path: web::Path<String>,
     _auth: Authorized,
     req: HttpRequest,
 ) -> impl Responder {
     let lib = path.into_inner();
-    let db = match data.db.get(&lib) {
-        Some(db) => calibre::lock(db),
+    let db = match data.library(&lib) {
+        Some(db) => db,
         None => return HttpResponse::NotFound().body(format!("Database '{}' not found", lib)),
     };
 
-    let books = match calibre::books(&db) {
-        Ok(books) => wrapped(books),
-        Err(e) => return server_error("Error querying books", e),
+    let entries = match calibre::title_letters(&db) {
+        Ok(letters) => letters
+            .into_iter()
+            .map(|(letter, books)| Entry::shelf(&Shelf::Titled(letter.clone()), letter, format!("{} books", books)))
+            .collect(),
+        Err(e) => return server_error("Error querying titles", e),
     };
 
-    let mut ctx = feed_ctx(&req, data.config, Some(&lib));
-    ctx.insert("feed_title", &format!("{} | {} books", lib, books.len()));
-    ctx.insert("books", &books);
-    ctx.insert("updated", &calibre::updated(&db));
-    render_template(&data.templates, "books.xml.tera", ctx)
+    entries_feed(&data, &req, &lib, "Titles", entries, calibre::updated(&db))
+}
+
+#[actix_web::get("{lib}/titles/{letter}")]
+async fn books_titled(
+    data: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, letter) = path.into_inner();
+    books_feed(&data, &req, &lib, Shelf::Titled(letter), facets.into_inner(), query.page.unwrap_or(1))
 }
 
 #[actix_web::get("{lib}/new")]
@@ -338,13 +875,16 @@ async fn recently_added(
     req: HttpRequest,
 ) -> impl Responder {
     let lib = path.into_inner();
-    let db = match data.db.get(&lib) {
-        Some(db) => calibre::lock(db),
+    let db = match data.library(&lib) {
+        Some(db) => db,
         None => return HttpResponse::NotFound().body(format!("Database '{}' not found", lib)),
     };
 
-    let books = match calibre::recently_added(&db) {
-        Ok(books) => wrapped(books),
+    let books = match calibre::recently_added(&db)
+        .and_then(|books| calibre::with_custom(&db, data.columns(&lib), books))
+        .and_then(|books| calibre::with_related(&db, books))
+    {
+        Ok(books) => wrapped(reading::with_progress(&data, &db, &lib, &req, books)),
         Err(e) => return server_error("Error querying books", e),
     };
 
@@ -354,3 +894,328 @@ async fn recently_added(
     ctx.insert("updated", &calibre::updated(&db));
     render_template(&data.templates, "books.xml.tera", ctx)
 }
+
+/// The books the reader asking started and has not finished, the one read last first.
+#[actix_web::get("{lib}/reading")]
+async fn currently_reading(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    let started = match started(&data, &lib, &req) {
+        Ok(started) => started,
+        Err(response) => return response,
+    };
+    books_feed(&data, &req, &lib, Shelf::Reading(started), facets.into_inner(), query.page.unwrap_or(1))
+}
+
+/// A single book as a complete catalog entry, outside of any feed. Every entry
+/// links to its own, and to those of the books related to it.
+#[actix_web::get("{lib}/book/{id}")]
+async fn book_entry(
+    data: web::Data<AppState>,
+    path: web::Path<(String, i32)>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, id) = path.into_inner();
+    let db = match data.library(&lib) {
+        Some(db) => db,
+        None => return HttpResponse::NotFound().body(format!("Database '{}' not found", lib)),
+    };
+
+    let book = match calibre::book(&db, id)
+        .and_then(|book| calibre::with_custom(&db, data.columns(&lib), vec![book]))
+        .and_then(|books| calibre::with_related(&db, books))
+    {
+        Ok(books) => wrapped(reading::with_progress(&data, &db, &lib, &req, books)).remove(0),
+        Err(rusqlite::Error::QueryReturnedNoRows) => return HttpResponse::NotFound().body("Book not found"),
+        Err(e) => return server_error("Error querying book", e),
+    };
+
+    let mut ctx = feed_ctx(&req, data.config, Some(&lib));
+    ctx.insert("book", &book);
+    render_as(&data.templates, "book.xml.tera", ctx, ENTRY)
+}
+
+/// Every book somebody rated, the best first.
+#[actix_web::get("{lib}/top")]
+async fn top_rated(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    books_feed(&data, &req, &lib, Shelf::TopRated, facets.into_inner(), query.page.unwrap_or(1))
+}
+
+#[actix_web::get("{lib}/ratings")]
+async fn ratings(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<PageQuery>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    let db = match data.library(&lib) {
+        Some(db) => db,
+        None => return HttpResponse::NotFound().body(format!("Database '{}' not found", lib)),
+    };
+
+    let ratings = match calibre::ratings_with_books(&db) {
+        Ok(ratings) => ratings,
+        Err(e) => return server_error("Error querying ratings", e),
+    };
+
+    let total = ratings.len();
+    let window = window(total, PER_PAGE, query.page.unwrap_or(1));
+    let mut ctx = paged_ctx(&req, data.config, &lib, "ratings", total, &window, NAVIGATION);
+    ctx.insert("ratings", &page_of(ratings, &window));
+    ctx.insert("updated", &calibre::updated(&db));
+    render_template(&data.templates, "ratings.xml.tera", ctx)
+}
+
+/// `{id}` is the rating out of ten: `/ratings/8` for four stars.
+#[actix_web::get("{lib}/ratings/{id}")]
+async fn books_by_rating(
+    data: web::Data<AppState>,
+    path: web::Path<(String, i32)>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, rating) = path.into_inner();
+    books_feed(&data, &req, &lib, Shelf::Rating(rating), facets.into_inner(), query.page.unwrap_or(1))
+}
+
+/// The books of one of Calibre's virtual libraries.
+#[actix_web::get("/{lib}/virtual/{name}")]
+async fn virtual_library(
+    data: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, name) = path.into_inner();
+    books_feed(&data, &req, &lib, Shelf::Virtual(name), facets.into_inner(), query.page.unwrap_or(1))
+}
+
+/// A navigation feed of entries that are not one kind of category.
+fn entries_feed(data: &AppState, req: &HttpRequest, lib: &str, title: &str, entries: Vec<Entry>, updated: String) -> HttpResponse {
+    let mut ctx = feed_ctx(req, data.config, Some(lib));
+    ctx.insert("feed_title", &format!("{} | {}", lib, title));
+    ctx.insert("entries", &entries);
+    ctx.insert("updated", &updated);
+    render_template(&data.templates, "entries.xml.tera", ctx)
+}
+
+/// The searches saved in Calibre, each leading to the books it finds.
+#[actix_web::get("/{lib}/searches")]
+async fn saved_searches(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    let db = match data.library(&lib) {
+        Some(db) => db,
+        None => return HttpResponse::NotFound().body(format!("Database '{}' not found", lib)),
+    };
+
+    let entries = match saved_searches_with_books(&db) {
+        Ok(searches) => searches
+            .into_iter()
+            .map(|(saved, books)| {
+                let content = format!("{} books found by {}", books, saved.search);
+                Entry::shelf(&Shelf::Saved(saved.name.clone()), saved.name, content)
+            })
+            .collect(),
+        Err(e) => return server_error("Error reading the saved searches", e),
+    };
+
+    entries_feed(&data, &req, &lib, "Saved Searches", entries, calibre::updated(&db))
+}
+
+#[actix_web::get("/{lib}/searches/{name}")]
+async fn saved_search(
+    data: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, name) = path.into_inner();
+    books_feed(&data, &req, &lib, Shelf::Saved(name), facets.into_inner(), query.page.unwrap_or(1))
+}
+
+/// The user categories defined in Calibre, each leading to what it lists.
+#[actix_web::get("/{lib}/categories")]
+async fn user_categories(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    let db = match data.library(&lib) {
+        Some(db) => db,
+        None => return HttpResponse::NotFound().body(format!("Database '{}' not found", lib)),
+    };
+
+    let entries = match user_categories_with_books(&db) {
+        Ok(categories) => categories
+            .into_iter()
+            .map(|category| {
+                let path = user_category_path(&category.name);
+                Entry {
+                    content: format!("{} authors, tags and series", category.listed.len()),
+                    title: category.name,
+                    id: path.replace('/', ":"),
+                    path,
+                    kind: NAVIGATION,
+                }
+            })
+            .collect(),
+        Err(e) => return server_error("Error reading the user categories", e),
+    };
+
+    entries_feed(&data, &req, &lib, "User Categories", entries, calibre::updated(&db))
+}
+
+/// The authors, tags and series one user category lists.
+#[actix_web::get("/{lib}/categories/{name}")]
+async fn user_category(
+    data: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, name) = path.into_inner();
+    let db = match data.library(&lib) {
+        Some(db) => db,
+        None => return HttpResponse::NotFound().body(format!("Database '{}' not found", lib)),
+    };
+
+    let category = match calibre::user_category(&db, &name) {
+        Ok(category) => category,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return HttpResponse::NotFound().body(format!("No user category '{}'", name))
+        }
+        Err(e) => return server_error("Error reading the user categories", e),
+    };
+    let entries = category
+        .listed
+        .iter()
+        .map(|listed| {
+            let content = format!("{} books", listed.category.books);
+            Entry::shelf(&Shelf::listed(listed), listed.category.name.clone(), content)
+        })
+        .collect();
+
+    entries_feed(&data, &req, &lib, &category.name, entries, calibre::updated(&db))
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: Option<String>,
+    page: Option<usize>,
+    #[serde(rename = "in")]
+    within: Option<String>,
+}
+
+/// The OpenSearch description every feed of a library links to with `rel="search"`.
+/// It tells a client how to fill in `{searchTerms}`.
+#[actix_web::get("{lib}/opensearch.xml")]
+async fn opensearch(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    if !data.db.contains_key(&lib) {
+        return HttpResponse::NotFound().body(format!("Database '{}' not found", lib));
+    }
+
+    let ctx = feed_ctx(&req, data.config, Some(&lib));
+    render_as(&data.templates, "opensearch.xml.tera", ctx, "application/opensearchdescription+xml")
+}
+
+/// Everything `?q=` matches, in Calibre's search language, paged like any other shelf.
+/// `&in=content` searches the text of the books, as `/v2` does.
+#[actix_web::get("{lib}/search")]
+async fn search(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    asked: web::Query<SearchQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    let term = asked.q.clone().unwrap_or_default();
+    let shelf = searched(term, asked.within.as_deref());
+    books_feed(&data, &req, &lib, shelf, facets.into_inner(), asked.page.unwrap_or(1))
+}
+
+#[actix_web::get("{lib}/columns/{label}")]
+async fn custom_column(
+    data: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<PageQuery>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, label) = path.into_inner();
+    let db = match data.library(&lib) {
+        Some(db) => db,
+        None => return HttpResponse::NotFound().body(format!("Database '{}' not found", lib)),
+    };
+    let column = match data.category_column(&lib, &label) {
+        Some(column) => column,
+        None => return HttpResponse::NotFound().body(format!("No custom column '#{}' to browse", label)),
+    };
+
+    let values = match calibre::custom_categories(&db, column) {
+        Ok(values) => values,
+        Err(e) => return server_error("Error querying custom column", e),
+    };
+
+    let total = values.len();
+    let window = window(total, PER_PAGE, query.page.unwrap_or(1));
+    let mut ctx = paged_ctx(&req, data.config, &lib, &column_feed(column), total, &window, NAVIGATION);
+    ctx.insert("column", column);
+    ctx.insert("values", &page_of(values, &window));
+    ctx.insert("updated", &calibre::updated(&db));
+    render_template(&data.templates, "columns.xml.tera", ctx)
+}
+
+#[actix_web::get("{lib}/columns/{label}/{id}")]
+async fn books_by_custom(
+    data: web::Data<AppState>,
+    path: web::Path<(String, String, i32)>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, label, id) = path.into_inner();
+    let column = match data.category_column(&lib, &label) {
+        Some(column) => column.clone(),
+        None => return HttpResponse::NotFound().body(format!("No custom column '#{}' to browse", label)),
+    };
+    books_feed(&data, &req, &lib, Shelf::Column(column, id), facets.into_inner(), query.page.unwrap_or(1))
+}
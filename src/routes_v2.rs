@@ -11,35 +11,39 @@ use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use rusqlite::Connection;
 use serde::Serialize;
 use serde_derive::Deserialize;
+use serde_json::Value;
 use std::sync::MutexGuard;
 
+use crate::annotation::{self, Annotation, Motivation, Selector, Target, ANNOTATIONS, ANNOTATION_SERVICE, EPUB_CFI};
 use crate::appstate::AppState;
 use crate::authorized::Authorized;
-use crate::calibre::{self, Book};
+use crate::calibre::{self, Book, CustomField, CustomValue, Facets, Sort};
 use crate::opds2::{
-    BelongsTo, BookMetadata, Contributor, Feed, Link, Publication, Series, Subject, ACQUISITION,
-    BOOK, FEED, IMAGE, PUBLICATION, SEARCH, SORT_NEW,
+    BelongsTo, BookMetadata, Contributor, Facet, Feed, Group, Link, Publication, Series, Subject, ACQUISITION,
+    BOOK, FEED, IMAGE, PUBLICATION, RELATED, SEARCH, SORT_NEW, SORT_POPULAR, THUMBNAIL,
 };
+use crate::reading;
 use crate::routes::{origin, server_error};
-
-/// How many books one page of the catalog holds.
-const PER_PAGE: usize = 50;
-
-#[derive(Deserialize)]
-struct PageQuery {
-    page: Option<usize>,
-}
+use crate::shelf::{
+    column_feed, encoded, facet_groups, faceted, letter_path, paged, saved_searches_with_books, searched, started,
+    user_categories_with_books, user_category_path, window, with_query, IndexQuery, PageQuery, Shelf, Window,
+    PER_PAGE, SAVED_SEARCHES, USER_CATEGORIES,
+};
+use crate::thumbnail;
 
 #[derive(Deserialize)]
 struct SearchQuery {
     query: Option<String>,
     page: Option<usize>,
+    /// `content` for the text of the books instead of what Calibre knows about them
+    #[serde(rename = "in")]
+    within: Option<String>,
 }
 
 /// The connection of one library, or a 404 for a library Orca does not serve.
 fn library<'a>(data: &'a AppState, lib: &str) -> Result<MutexGuard<'a, Connection>, HttpResponse> {
-    match data.db.get(lib) {
-        Some(db) => Ok(calibre::lock(db)),
+    match data.library(lib) {
+        Some(db) => Ok(db),
         None => Err(HttpResponse::NotFound().body(format!("Database '{}' not found", lib))),
     }
 }
@@ -66,57 +70,17 @@ fn json(value: &impl Serialize, mime: &str) -> HttpResponse {
     }
 }
 
-/// Which slice of the library a request for `page` asks for.
-/// Pages count from one, and one past the end is the last page: An empty library still has a page one.
-fn window(total: usize, per_page: usize, requested: usize) -> Window {
-    let last = total.div_ceil(per_page).max(1);
-    let current = requested.clamp(1, last);
-    Window {
-        current,
-        last,
-        offset: (current - 1) * per_page,
-    }
-}
-
-struct Window {
-    current: usize,
-    last: usize,
-    offset: usize,
-}
-
-/// join with whichever separator is still free (there may already be a query, e.g. search)
 fn page_url(base: &str, lib: &str, feed: &str, page: usize) -> String {
-    let url = format!("{}/v2/{}/{}", base, lib, feed);
-    match page {
-        1 => url,
-        n => {
-            let separator = match feed.contains('?') {
-                true => '&',
-                false => '?',
-            };
-            format!("{}{}page={}", url, separator, n)
-        }
-    }
+    paged(format!("{}/v2/{}/{}", base, lib, feed), page)
 }
 
 /// The links from one page of a book feed to its neighbours.
 fn page_links(base: &str, lib: &str, feed: &str, window: &Window) -> Vec<Link> {
-    let sibling = |rel: &str, number: usize| {
-        Link::new(page_url(base, lib, feed, number))
-            .rel(rel.to_string())
-            .mime(FEED)
-    };
-
-    let mut links = Vec::new();
-    if window.current > 1 {
-        links.push(sibling("first", 1));
-        links.push(sibling("previous", window.current - 1));
-    }
-    if window.current < window.last {
-        links.push(sibling("next", window.current + 1));
-        links.push(sibling("last", window.last));
-    }
-    links
+    window
+        .neighbours()
+        .into_iter()
+        .map(|(rel, number)| Link::new(page_url(base, lib, feed, number)).rel(rel).mime(FEED))
+        .collect()
 }
 
 fn identifier(book: &Book, lib: &str) -> String {
@@ -127,8 +91,61 @@ fn identifier(book: &Book, lib: &str) -> String {
     }
 }
 
-/// One Calibre book as an OPDS 2.0 publication.
-fn publication(book: &Book, lib: &str, base: &str) -> Publication {
+/// The identifier stays the uuid Calibre gave the book, whatever else it is
+/// known by. An ISBN is what other catalogs know it by, so it leads the
+/// alternatives: whatever else can be written as a URI.
+fn identifiers(book: &Book, lib: &str) -> (String, Vec<String>) {
+    let mut alternatives: Vec<String> = book
+        .identifiers
+        .iter()
+        .filter_map(|identifier| identifier.uri.clone())
+        .filter(|uri| Some(uri.as_str()) != book.isbn())
+        .collect();
+    if let Some(isbn) = book.isbn() {
+        alternatives.insert(0, isbn.to_string());
+    }
+    (identifier(book, lib), alternatives)
+}
+
+/// One custom column as OPDS 2.0 metadata, under the lookup name Calibre users
+/// know it by: `#translator`. A value that is a shelf of its own links to it, the
+/// same as a subject does, and a series column says where the book sits in it.
+fn custom_metadata(field: &CustomField, lib: &str, base: &str) -> (String, Value) {
+    let values: Vec<Value> = field
+        .values
+        .iter()
+        .map(|value| {
+            let json = match value {
+                CustomValue::Category { id, name, index } => {
+                    let links = vec![Link::new(format!("{}/v2/{}/columns/{}/{}", base, lib, field.label, id))
+                        .mime(FEED)];
+                    match index {
+                        Some(_) => serde_json::to_value(Series {
+                            name: name.clone(),
+                            position: *index,
+                            links,
+                        }),
+                        None => serde_json::to_value(Subject {
+                            name: name.clone(),
+                            links,
+                        }),
+                    }
+                }
+                value => serde_json::to_value(value),
+            };
+            json.unwrap_or(Value::Null)
+        })
+        .collect();
+
+    let value = match (field.multiple, &values[..]) {
+        (false, [one]) => one.clone(),
+        _ => Value::Array(values),
+    };
+    (format!("#{}", field.label), value)
+}
+
+/// One Calibre book as an OPDS 2.0 publication. `widths`: the thumbnails it offers.
+fn publication(book: &Book, lib: &str, base: &str, widths: &[u32]) -> Publication {
     let mut links = vec![Link::new(format!("{}/v2/{}/book/{}", base, lib, book.id))
         .rel("self")
         .mime(PUBLICATION)];
@@ -140,19 +157,37 @@ fn publication(book: &Book, lib: &str, base: &str) -> Publication {
             .title(format!("{}.{}", book.title, format))
     }));
 
-    // Calibre keeps one cover and no thumbnail, so the one image is the cover.
-    let images = match book.has_cover {
-        true => vec![Link::new(format!("{}/{}/cover/{}", base, lib, book.id))
-            .rel(IMAGE)
-            .mime("image/jpeg")],
-        false => Vec::new(),
-    };
+    // Empty for whoever may not read any of them.
+    links.push(
+        Link::new(format!("{}/v2/{}/book/{}/annotations", base, lib, book.id))
+            .rel(ANNOTATION_SERVICE)
+            .mime(ANNOTATIONS)
+            .title("Annotations"),
+    );
+
+    links.extend(related(book, |id| format!("{}/v2/{}/book/{}", base, lib, id)).map(|(href, title)| {
+        Link::new(href).rel(RELATED).mime(PUBLICATION).title(title)
+    }));
+
+    // The cover as Calibre keeps it, then a thumbnail made of it at each
+    // configured width. A book without a cover has a placeholder at all of them.
+    let images = std::iter::once(Link::new(format!("{}/{}/cover/{}", base, lib, book.id)).rel(IMAGE).mime("image/jpeg"))
+        .chain(widths.iter().map(|&width| {
+            Link::new(format!("{}/{}/thumb/{}?w={}", base, lib, book.id, width))
+                .rel(THUMBNAIL)
+                .mime("image/jpeg")
+                .size(width, thumbnail::height(width))
+        }))
+        .collect();
+
+    let (identifier, alt_identifier) = identifiers(book, lib);
 
     Publication {
         metadata: BookMetadata {
             kind: BOOK,
             title: book.title.clone(),
-            identifier: Some(identifier(book, lib)),
+            identifier: Some(identifier),
+            alt_identifier,
             // link from a book to the rest of what its author wrote.
             author: book
                 .authors
@@ -184,14 +219,48 @@ fn publication(book: &Book, lib: &str, base: &str) -> Publication {
                 series: Series {
                     name: series.name.clone(),
                     position: Some(series.index),
+                    links: vec![
+                        Link::new(format!("{}/v2/{}/series/{}", base, lib, series.id)).mime(FEED),
+                    ],
                 },
             }),
+            extensions: book
+                .custom
+                .iter()
+                .map(|field| custom_metadata(field, lib, base))
+                .chain(book.rating.as_ref().map(|rating| ("rating".to_string(), rating.stars.into())))
+                // Readium's word for how much of a publication lies behind.
+                .chain(book.progress.map(|progress| ("totalProgression".to_string(), progress.into())))
+                .collect(),
         },
         links,
         images,
     }
 }
 
+/// Where to go from a book, as addresses and titles: the series first, since a
+/// reader who finished one volume most likely wants the next.
+fn related<'a>(
+    book: &'a Book,
+    href: impl Fn(i32) -> String + 'a,
+) -> impl Iterator<Item = (String, String)> + 'a {
+    let series = book.series.as_ref().map(|series| series.name.as_str()).unwrap_or_default();
+    let related = &book.related;
+    let volumes = [("Previous", &related.previous), ("Next", &related.next)]
+        .into_iter()
+        .filter_map(move |(which, neighbour)| {
+            neighbour
+                .as_ref()
+                .map(|neighbour| (neighbour.id, format!("{} in {}: {}", which, series, neighbour.title)))
+        });
+    let others = related
+        .by_authors
+        .iter()
+        .chain(&related.sharing_tags)
+        .map(|neighbour| (neighbour.id, neighbour.title.clone()));
+    volumes.chain(others).map(move |(id, title)| (href(id), title))
+}
+
 /// The catalog root: one navigation entry per library,
 /// or a redirect when there is only one
 #[actix_web::get("/v2")]
@@ -209,8 +278,9 @@ async fn catalog(data: web::Data<AppState>, _auth: Authorized, req: HttpRequest)
     // The whole catalog is as new as its newest library.
     let updated = data
         .db
-        .values()
-        .map(|db| calibre::updated(&calibre::lock(db)))
+        .keys()
+        .filter_map(|lib| data.library(lib))
+        .map(|db| calibre::updated(&db))
         .max();
 
     let navigation = libraries
@@ -259,13 +329,36 @@ async fn library_root(
             .count(count)
     };
 
+    // A library too large to scroll through starts with the first letters of its titles.
+    let everything = match counts.books > data.config.index_above(&lib) {
+        true => Shelf::Titled(String::new()).feed(),
+        false => Shelf::Everything.feed(),
+    };
     let mut navigation = vec![
-        browse(Shelf::Everything.feed(), "All Books", counts.books),
+        browse(everything, "All Books", counts.books),
         Link::new(format!("{}/v2/{}/new", base, lib))
             .rel(SORT_NEW)
             .mime(FEED)
             .title("Recently Added"),
     ];
+    if counts.rated > 0 {
+        navigation.push(
+            Link::new(page_url(&base, &lib, Shelf::TopRated.feed(), 1))
+                .rel(SORT_POPULAR)
+                .mime(FEED)
+                .title("Top Rated")
+                .count(counts.rated),
+        );
+    }
+
+    // Only for a reader with a book to pick up again, wherever they put it down.
+    let started = match reading::in_progress(&data, &db, &lib, &req) {
+        Ok(started) => started.len(),
+        Err(e) => return server_error("Error reading the positions", e),
+    };
+    if started > 0 {
+        navigation.push(browse(Shelf::Reading(Vec::new()).feed(), "Currently Reading", started));
+    }
 
     // A library nobody has tagged should not offer a way in that leads nowhere.
     if counts.authors > 0 {
@@ -274,85 +367,104 @@ async fn library_root(
     if counts.tags > 0 {
         navigation.push(browse(feed_of(Shelf::Tag), "Tags", counts.tags));
     }
-
-    let root = library_feed(lib.clone(), format!("{}/v2/{}", base, lib), &base, &lib)
-        .modified(calibre::updated(&db))
-        .navigation(navigation);
-
-    json(&root, FEED)
-}
-
-/// a `shelf` carries no books -- it says only how to ask Calibre and what to call the result.
-/// paging works identical on all of them.
-enum Shelf {
-    Everything,
-    Author(i32),
-    Tag(i32),
-    /// a term nothing matches is an empty shelf, not a 404.
-    Search(String),
-}
-
-impl Shelf {
-    /// The feed every shelf of this kind lives in, below the library. The only
-    /// place these path segments are spelled out.
-    fn feed(&self) -> &'static str {
-        match self {
-            Shelf::Everything => "books",
-            Shelf::Author(_) => "authors",
-            Shelf::Tag(_) => "tags",
-            Shelf::Search(_) => "search",
-        }
+    if counts.series > 0 {
+        navigation.push(browse(feed_of(Shelf::Series), "Series", counts.series));
     }
-
-    /// Where this one shelf lives below the library.
-    fn path(&self) -> String {
-        match self {
-            Shelf::Everything => self.feed().to_string(),
-            Shelf::Author(id) | Shelf::Tag(id) => format!("{}/{}", self.feed(), id),
-            // The term is part of the address, so every page of a search finds
-            // its way back to the same books.
-            Shelf::Search(term) => format!("{}?query={}", self.feed(), encoded(term)),
-        }
+    if counts.publishers > 0 {
+        navigation.push(browse(feed_of(Shelf::Publisher), "Publishers", counts.publishers));
+    }
+    if counts.languages > 0 {
+        navigation.push(browse(feed_of(Shelf::Language), "Languages", counts.languages));
+    }
+    if counts.ratings > 0 {
+        navigation.push(browse(feed_of(Shelf::Rating), "Ratings", counts.ratings));
     }
 
-    /// What to call this feed. `QueryReturnedNoRows` for a shelf the library does not have.
-    fn name(&self, db: &Connection) -> rusqlite::Result<String> {
-        match self {
-            Shelf::Everything => Ok("All Books".to_string()),
-            Shelf::Author(id) => calibre::author_name(db, *id),
-            Shelf::Tag(id) => calibre::tag_name(db, *id),
-            Shelf::Search(term) => Ok(match term.trim() {
-                "" => "Search".to_string(),
-                term => format!("Search: {}", term),
-            }),
+    // The custom columns the config exposes, under the heading Calibre gives them.
+    for column in data.columns(&lib).iter().filter(|column| column.is_category()) {
+        let values = match calibre::count_custom_categories(&db, column) {
+            Ok(values) => values,
+            Err(e) => return server_error("Error counting the library", e),
+        };
+        if values > 0 {
+            navigation.push(browse(&column_feed(column), &column.name, values));
         }
     }
 
-    fn count(&self, db: &Connection) -> rusqlite::Result<usize> {
-        match self {
-            Shelf::Everything => calibre::count_books(db),
-            Shelf::Author(id) => calibre::count_books_by_author(db, *id),
-            Shelf::Tag(id) => calibre::count_books_by_tag(db, *id),
-            Shelf::Search(term) if term.trim().is_empty() => Ok(0),
-            Shelf::Search(term) => calibre::count_books_matching(db, term.trim()),
+    // Calibre's virtual libraries, each a shelf of its own. An empty one leads nowhere.
+    let virtual_libraries = match calibre::virtual_libraries(&db) {
+        Ok(libraries) => libraries,
+        Err(e) => return server_error("Error reading the virtual libraries", e),
+    };
+    for library in virtual_libraries {
+        let shelf = Shelf::Virtual(library.name.clone());
+        match shelf.count(&db, &Facets::NONE) {
+            Ok(0) => {}
+            Ok(books) => navigation.push(browse(&shelf.path(), &library.name, books)),
+            Err(e) => return server_error("Error counting the library", e),
         }
     }
 
-    fn books(&self, db: &Connection, limit: usize, offset: usize) -> rusqlite::Result<Vec<Book>> {
-        match self {
-            Shelf::Everything => calibre::books_page(db, limit, offset),
-            Shelf::Author(id) => calibre::books_by_author_page(db, *id, limit, offset),
-            Shelf::Tag(id) => calibre::books_by_tag_page(db, *id, limit, offset),
-            // Nothing typed is nothing found: a bare `%%` would be the whole library.
-            Shelf::Search(term) if term.trim().is_empty() => Ok(Vec::new()),
-            Shelf::Search(term) => calibre::books_search_page(db, term.trim(), limit, offset),
-        }
+    // What the librarian filed in Calibre by hand: saved searches, user categories.
+    // What they find is only looked up once a reader opens them.
+    let by_hand = |feed: &str, title: &str| {
+        Link::new(page_url(&base, &lib, feed, 1)).rel("subsection").mime(FEED).title(title.to_string())
+    };
+    match calibre::saved_searches(&db) {
+        Ok(searches) if searches.is_empty() => {}
+        Ok(_) => navigation.push(by_hand(SAVED_SEARCHES, "Saved Searches")),
+        Err(e) => return server_error("Error reading the saved searches", e),
+    }
+    match calibre::has_user_categories(&db) {
+        Ok(false) => {}
+        Ok(true) => navigation.push(by_hand(USER_CATEGORIES, "User Categories")),
+        Err(e) => return server_error("Error reading the user categories", e),
     }
+
+    let groups = match featured(&data, &db, &lib, &req, &base) {
+        Ok(groups) => groups,
+        Err(e) => return server_error("Error querying books", e),
+    };
+
+    let root = library_feed(lib.clone(), format!("{}/v2/{}", base, lib), &base, &lib)
+        .modified(calibre::updated(&db))
+        .navigation(navigation)
+        .groups(groups);
+
+    json(&root, FEED)
 }
 
-/// `NON_ALPHANUMERIC` keeps `&`, `=` and `+` out of a URL
-fn encoded(term: &str) -> String {
-    utf8_percent_encode(term, NON_ALPHANUMERIC).to_string()
+/// How many books a row on the start page of a library shows.
+const FEATURED: usize = 10;
+
+/// The rows of books on the start page of a library, each leading on to the
+/// shelf that holds the rest of them. A row with nothing in it is left out.
+fn featured(data: &AppState, db: &Connection, lib: &str, req: &HttpRequest, base: &str) -> rusqlite::Result<Vec<Group>> {
+    let newest = Facets {
+        sort: Some(Sort::Added),
+        ..Facets::NONE
+    };
+    // Only for a reader who opened a volume of a series: a guest has none.
+    let next = Shelf::Next(reading::next_in_series(data, db, lib, req)?);
+    let rows = [
+        ("Recently Added", faceted(Shelf::Everything.path(), &newest), calibre::books_page(db, &newest, FEATURED, 0)?),
+        ("Top Rated", Shelf::TopRated.path(), calibre::top_rated_page(db, &Facets::NONE, FEATURED, 0)?),
+        ("Random Picks", Shelf::Everything.path(), calibre::random_books(db, FEATURED)?),
+        ("Next in Series", next.path(), next.books(db, &Facets::NONE, FEATURED, 0)?),
+    ];
+
+    let mut groups = Vec::new();
+    for (title, more, books) in rows {
+        if books.is_empty() {
+            continue;
+        }
+        let books = calibre::with_related(db, calibre::with_custom(db, data.columns(lib), books)?)?;
+        let books = reading::with_progress(data, db, lib, req, books);
+        let widths = &data.config.catalog.thumbnail_widths;
+        let publications = books.iter().map(|book| publication(book, lib, base, widths)).collect();
+        groups.push(Group::new(title, page_url(base, lib, &more, 1), publications));
+    }
+    Ok(groups)
 }
 
 /// One page of books, whichever shelf they come off.
@@ -361,6 +473,7 @@ fn books_feed(
     req: &HttpRequest,
     lib: &str,
     shelf: Shelf,
+    facets: Facets,
     requested: usize,
 ) -> HttpResponse {
     let db = match library(data, lib) {
@@ -376,19 +489,28 @@ fn books_feed(
         Err(e) => return server_error("Error querying shelf", e),
     };
 
-    let total = match shelf.count(&db) {
+    let total = match shelf.count(&db, &facets) {
         Ok(total) => total,
         Err(e) => return server_error("Error counting books", e),
     };
     let window = window(total, PER_PAGE, requested);
 
-    let books = match shelf.books(&db, PER_PAGE, window.offset) {
-        Ok(books) => books,
+    let books = match shelf
+        .books(&db, &facets, PER_PAGE, window.offset)
+        .and_then(|books| calibre::with_custom(&db, data.columns(lib), books))
+        .and_then(|books| calibre::with_related(&db, books))
+    {
+        Ok(books) => reading::with_progress(data, &db, lib, req, books),
         Err(e) => return server_error("Error querying books", e),
     };
 
+    let groups = match facet_groups(&db, &facets) {
+        Ok(groups) => groups,
+        Err(e) => return server_error("Error querying facets", e),
+    };
+
     let base = origin(req, data.config);
-    let path = shelf.path();
+    let path = faceted(shelf.path(), &facets);
     let mut page = library_feed(
         format!("{} | {}", lib, name),
         page_url(&base, lib, &path, window.current),
@@ -397,25 +519,49 @@ fn books_feed(
     )
     .modified(calibre::updated(&db))
     .page(total, PER_PAGE, window.current);
-    page = holding(page, &books, lib, &base);
+    if let Some(note) = shelf.note(&db) {
+        page = page.description(&calibre::note_text(&note));
+    }
+    page = holding(page, &books, lib, &base, &data.config.catalog.thumbnail_widths);
 
     for link in page_links(&base, lib, &path, &window) {
         page = page.link(link);
     }
 
-    json(&page, FEED)
+    // The option the reader picked is where they are: `self`.
+    let facets = groups
+        .into_iter()
+        .map(|group| {
+            let links = group
+                .options
+                .into_iter()
+                .map(|option| {
+                    let link = Link::new(page_url(&base, lib, &faceted(shelf.path(), &option.facets), 1))
+                        .mime(FEED)
+                        .title(option.title);
+                    match option.active {
+                        true => link.rel("self"),
+                        false => link,
+                    }
+                })
+                .collect();
+            Facet::new(group.title, links)
+        })
+        .collect();
+
+    json(&page.facets(facets), FEED)
 }
 
 /// A feed has to hold one of `publications`, `navigation` or `groups`, so a
 /// search that matched nothing cannot simply leave `publications` out.
-fn holding(feed: Feed, books: &[Book], lib: &str, base: &str) -> Feed {
+fn holding(feed: Feed, books: &[Book], lib: &str, base: &str, widths: &[u32]) -> Feed {
     match books.is_empty() {
         true => feed.navigation(vec![Link::new(format!("{}/v2/{}", base, lib))
             .rel("up")
             .mime(FEED)
             .title(format!("Back to {}", lib))]),
         false => {
-            feed.publications(books.iter().map(|book| publication(book, lib, base)).collect())
+            feed.publications(books.iter().map(|book| publication(book, lib, base, widths)).collect())
         }
     }
 }
@@ -426,11 +572,65 @@ async fn all_books(
     data: web::Data<AppState>,
     path: web::Path<String>,
     query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
     _auth: Authorized,
     req: HttpRequest,
 ) -> impl Responder {
     let lib = path.into_inner();
-    books_feed(&data, &req, &lib, Shelf::Everything, query.page.unwrap_or(1))
+    books_feed(&data, &req, &lib, Shelf::Everything, facets.into_inner(), query.page.unwrap_or(1))
+}
+
+/// The first letters of the titles in the library, each leading to the books
+/// whose title starts with it.
+#[actix_web::get("/v2/{lib}/titles")]
+async fn titles(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    let db = match library(&data, &lib) {
+        Ok(db) => db,
+        Err(response) => return response,
+    };
+
+    let letters = match calibre::title_letters(&db) {
+        Ok(letters) => letters,
+        Err(e) => return server_error("Error querying titles", e),
+    };
+
+    let base = origin(&req, data.config);
+    let navigation = letters
+        .into_iter()
+        .map(|(letter, books)| {
+            Link::new(page_url(&base, &lib, &Shelf::Titled(letter.clone()).path(), 1))
+                .rel("subsection")
+                .mime(FEED)
+                .title(letter)
+                .count(books)
+        })
+        .collect();
+    let titles = Shelf::Titled(String::new());
+    json(
+        &navigation_feed(&base, &lib, "Titles", titles.feed(), navigation, calibre::updated(&db)),
+        FEED,
+    )
+}
+
+/// The books whose title starts with one letter: `/titles/A`, or `/titles/%23`
+/// for the ones that start with a digit or a symbol.
+#[actix_web::get("/v2/{lib}/titles/{letter}")]
+async fn books_titled(
+    data: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, letter) = path.into_inner();
+    books_feed(&data, &req, &lib, Shelf::Titled(letter), facets.into_inner(), query.page.unwrap_or(1))
 }
 
 /// Everything one author wrote.
@@ -439,11 +639,12 @@ async fn books_by_author(
     data: web::Data<AppState>,
     path: web::Path<(String, i32)>,
     query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
     _auth: Authorized,
     req: HttpRequest,
 ) -> impl Responder {
     let (lib, author) = path.into_inner();
-    books_feed(&data, &req, &lib, Shelf::Author(author), query.page.unwrap_or(1))
+    books_feed(&data, &req, &lib, Shelf::Author(author), facets.into_inner(), query.page.unwrap_or(1))
 }
 
 /// Everything under one tag.
@@ -452,25 +653,69 @@ async fn books_by_tag(
     data: web::Data<AppState>,
     path: web::Path<(String, i32)>,
     query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
     _auth: Authorized,
     req: HttpRequest,
 ) -> impl Responder {
     let (lib, tag) = path.into_inner();
-    books_feed(&data, &req, &lib, Shelf::Tag(tag), query.page.unwrap_or(1))
+    books_feed(&data, &req, &lib, Shelf::Tag(tag), facets.into_inner(), query.page.unwrap_or(1))
 }
 
-/// Everything whose title or author matches `?query=`, paginated.
+/// The volumes of one series, first to last.
+#[actix_web::get("/v2/{lib}/series/{id}")]
+async fn books_in_series(
+    data: web::Data<AppState>,
+    path: web::Path<(String, i32)>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, series) = path.into_inner();
+    books_feed(&data, &req, &lib, Shelf::Series(series), facets.into_inner(), query.page.unwrap_or(1))
+}
+
+#[actix_web::get("/v2/{lib}/publishers/{id}")]
+async fn books_by_publisher(
+    data: web::Data<AppState>,
+    path: web::Path<(String, i32)>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, publisher) = path.into_inner();
+    books_feed(&data, &req, &lib, Shelf::Publisher(publisher), facets.into_inner(), query.page.unwrap_or(1))
+}
+
+#[actix_web::get("/v2/{lib}/languages/{id}")]
+async fn books_by_language(
+    data: web::Data<AppState>,
+    path: web::Path<(String, i32)>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, language) = path.into_inner();
+    books_feed(&data, &req, &lib, Shelf::Language(language), facets.into_inner(), query.page.unwrap_or(1))
+}
+
+/// Everything `?query=` matches, in Calibre's search language, paginated.
+/// With `&in=content`, the books whose text holds it, each with the passage.
 #[actix_web::get("/v2/{lib}/search")]
 async fn search(
     data: web::Data<AppState>,
     path: web::Path<String>,
     asked: web::Query<SearchQuery>,
+    facets: web::Query<Facets>,
     _auth: Authorized,
     req: HttpRequest,
 ) -> impl Responder {
     let lib = path.into_inner();
     let term = asked.query.clone().unwrap_or_default();
-    books_feed(&data, &req, &lib, Shelf::Search(term), asked.page.unwrap_or(1))
+    let shelf = searched(term, asked.within.as_deref());
+    books_feed(&data, &req, &lib, shelf, facets.into_inner(), asked.page.unwrap_or(1))
 }
 
 /// The feed a kind of shelf lives in: `authors` for `Shelf::Author`
@@ -479,17 +724,49 @@ fn feed_of(shelf: fn(i32) -> Shelf) -> &'static str {
 }
 
 /// One navigation entry per category, each leading to the shelf of its books.
-/// `shelf` is the variant those entries lead to -- `Shelf::Author` for the feed of authors
+/// `shelf` is the variant its entries lead to -- `Shelf::Author` for the feed of authors
+///
+/// More of them than `index_above`, and the list first offers their first
+/// letters, each leading to the ones filed under it. Either way a page at a time.
 fn shelves(
     base: &str,
     lib: &str,
     title: &str,
-    shelf: fn(i32) -> Shelf,
-    categories: &[calibre::Category],
-    modified: String,
+    shelf: impl Fn(i32) -> Shelf,
+    mut categories: Vec<calibre::Category>,
+    query: &IndexQuery,
+    index_above: usize,
 ) -> Feed {
-    let navigation = categories
+    let feed = &shelf(0).listed_in();
+    let (title, path) = match &query.letter {
+        Some(letter) => {
+            categories.retain(|category| calibre::initial(category.filed_as()) == *letter);
+            (format!("{} | {}: {}", lib, title, letter), letter_path(feed, letter))
+        }
+        None if categories.len() > index_above => {
+            let letters = calibre::letters(categories.iter().map(|category| category.filed_as()));
+            let navigation = letters
+                .into_iter()
+                .map(|(letter, count)| {
+                    Link::new(page_url(base, lib, &letter_path(feed, &letter), 1))
+                        .rel("subsection")
+                        .mime(FEED)
+                        .title(letter)
+                        .count(count)
+                })
+                .collect();
+            return library_feed(format!("{} | {}", lib, title), page_url(base, lib, feed, 1), base, lib)
+                .navigation(navigation);
+        }
+        None => (format!("{} | {}", lib, title), feed.to_string()),
+    };
+
+    let total = categories.len();
+    let window = window(total, PER_PAGE, query.page.unwrap_or(1));
+    let navigation: Vec<Link> = categories
         .iter()
+        .skip(window.offset)
+        .take(PER_PAGE)
         .map(|category| {
             Link::new(page_url(base, lib, &shelf(category.id).path(), 1))
                 .rel("subsection")
@@ -499,19 +776,161 @@ fn shelves(
         })
         .collect();
 
-    library_feed(
-        format!("{} | {}", lib, title),
-        page_url(base, lib, feed_of(shelf), 1),
-        base,
-        lib,
+    let mut page =
+        library_feed(title, page_url(base, lib, &path, window.current), base, lib).page(total, PER_PAGE, window.current);
+    // A letter nothing is filed under still has to lead somewhere.
+    page = match navigation.is_empty() {
+        true => page.navigation(vec![Link::new(page_url(base, lib, feed, 1)).rel("up").mime(FEED)]),
+        false => page.navigation(navigation),
+    };
+    for link in page_links(base, lib, &path, &window) {
+        page = page.link(link);
+    }
+    page
+}
+
+/// The feed of every category of one kind -- authors, tags, series ... -- each
+/// leading to its own shelf.
+fn categories_feed(
+    data: &AppState,
+    req: &HttpRequest,
+    lib: &str,
+    title: &str,
+    shelf: fn(i32) -> Shelf,
+    categories: fn(&Connection) -> rusqlite::Result<Vec<calibre::Category>>,
+    query: &IndexQuery,
+) -> HttpResponse {
+    let db = match library(data, lib) {
+        Ok(db) => db,
+        Err(response) => return response,
+    };
+
+    let entries = match categories(&db) {
+        Ok(entries) => entries,
+        Err(e) => return server_error(&format!("Error querying {}", title.to_lowercase()), e),
+    };
+
+    let base = origin(req, data.config);
+    let index_above = data.config.index_above(lib);
+    json(
+        &shelves(&base, lib, title, shelf, entries, query, index_above).modified(calibre::updated(&db)),
+        FEED,
     )
-    .modified(modified)
-    .navigation(navigation)
 }
 
 /// Who the library has books by.
 #[actix_web::get("/v2/{lib}/authors")]
 async fn authors(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<IndexQuery>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    categories_feed(&data, &req, &lib, "Authors", Shelf::Author, calibre::authors_with_books, &query)
+}
+
+#[actix_web::get("/v2/{lib}/tags")]
+async fn tags(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<IndexQuery>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    categories_feed(&data, &req, &lib, "Tags", Shelf::Tag, calibre::tags_with_books, &query)
+}
+
+#[actix_web::get("/v2/{lib}/series")]
+async fn all_series(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<IndexQuery>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    categories_feed(&data, &req, &lib, "Series", Shelf::Series, calibre::series_with_books, &query)
+}
+
+#[actix_web::get("/v2/{lib}/publishers")]
+async fn publishers(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<IndexQuery>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    categories_feed(&data, &req, &lib, "Publishers", Shelf::Publisher, calibre::publishers_with_books, &query)
+}
+
+/// Every language the library has books in, by its own name.
+#[actix_web::get("/v2/{lib}/languages")]
+async fn languages(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<IndexQuery>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    categories_feed(&data, &req, &lib, "Languages", Shelf::Language, calibre::languages_with_books, &query)
+}
+
+/// One shelf per star level, the best first.
+#[actix_web::get("/v2/{lib}/ratings")]
+async fn ratings(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<IndexQuery>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    categories_feed(&data, &req, &lib, "Ratings", Shelf::Rating, calibre::ratings_with_books, &query)
+}
+
+/// The books rated at one star level: `/ratings/8` for four stars.
+#[actix_web::get("/v2/{lib}/ratings/{rating}")]
+async fn books_by_rating(
+    data: web::Data<AppState>,
+    path: web::Path<(String, i32)>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, rating) = path.into_inner();
+    books_feed(&data, &req, &lib, Shelf::Rating(rating), facets.into_inner(), query.page.unwrap_or(1))
+}
+
+/// The books of one of Calibre's virtual libraries, found by its search.
+#[actix_web::get("/v2/{lib}/virtual/{name}")]
+async fn virtual_library(
+    data: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, name) = path.into_inner();
+    books_feed(&data, &req, &lib, Shelf::Virtual(name), facets.into_inner(), query.page.unwrap_or(1))
+}
+
+/// A navigation feed below the library, of entries that are not one kind of category.
+fn navigation_feed(base: &str, lib: &str, title: &str, path: &str, navigation: Vec<Link>, modified: String) -> Feed {
+    library_feed(format!("{} | {}", lib, title), page_url(base, lib, path, 1), base, lib)
+        .modified(modified)
+        .navigation(navigation)
+}
+
+/// The searches saved in Calibre, each leading to the books it finds.
+#[actix_web::get("/v2/{lib}/searches")]
+async fn saved_searches(
     data: web::Data<AppState>,
     path: web::Path<String>,
     _auth: Authorized,
@@ -523,20 +942,46 @@ async fn authors(
         Err(response) => return response,
     };
 
-    let entries = match calibre::authors_with_books(&db) {
-        Ok(entries) => entries,
-        Err(e) => return server_error("Error querying authors", e),
+    let searches = match saved_searches_with_books(&db) {
+        Ok(searches) => searches,
+        Err(e) => return server_error("Error reading the saved searches", e),
     };
 
     let base = origin(&req, data.config);
+    let navigation = searches
+        .into_iter()
+        .map(|(saved, books)| {
+            Link::new(page_url(&base, &lib, &Shelf::Saved(saved.name.clone()).path(), 1))
+                .rel("subsection")
+                .mime(FEED)
+                .title(saved.name)
+                .count(books)
+        })
+        .collect();
+
     json(
-        &shelves(&base, &lib, "Authors", Shelf::Author, &entries, calibre::updated(&db)),
+        &navigation_feed(&base, &lib, "Saved Searches", SAVED_SEARCHES, navigation, calibre::updated(&db)),
         FEED,
     )
 }
 
-#[actix_web::get("/v2/{lib}/tags")]
-async fn tags(
+/// The books one saved search finds.
+#[actix_web::get("/v2/{lib}/searches/{name}")]
+async fn saved_search(
+    data: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, name) = path.into_inner();
+    books_feed(&data, &req, &lib, Shelf::Saved(name), facets.into_inner(), query.page.unwrap_or(1))
+}
+
+/// The user categories defined in Calibre, each leading to what it lists.
+#[actix_web::get("/v2/{lib}/categories")]
+async fn user_categories(
     data: web::Data<AppState>,
     path: web::Path<String>,
     _auth: Authorized,
@@ -548,18 +993,179 @@ async fn tags(
         Err(response) => return response,
     };
 
-    let entries = match calibre::tags_with_books(&db) {
+    let categories = match user_categories_with_books(&db) {
+        Ok(categories) => categories,
+        Err(e) => return server_error("Error reading the user categories", e),
+    };
+
+    let base = origin(&req, data.config);
+    let navigation = categories
+        .into_iter()
+        .map(|category| {
+            Link::new(page_url(&base, &lib, &user_category_path(&category.name), 1))
+                .rel("subsection")
+                .mime(FEED)
+                .title(category.name)
+                .count(category.listed.len())
+        })
+        .collect();
+
+    json(
+        &navigation_feed(&base, &lib, "User Categories", USER_CATEGORIES, navigation, calibre::updated(&db)),
+        FEED,
+    )
+}
+
+/// The authors, tags and series one user category lists, each leading to its books.
+#[actix_web::get("/v2/{lib}/categories/{name}")]
+async fn user_category(
+    data: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, name) = path.into_inner();
+    let db = match library(&data, &lib) {
+        Ok(db) => db,
+        Err(response) => return response,
+    };
+
+    let category = match calibre::user_category(&db, &name) {
+        Ok(category) => category,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return HttpResponse::NotFound().body(format!("No user category '{}'", name))
+        }
+        Err(e) => return server_error("Error reading the user categories", e),
+    };
+
+    let base = origin(&req, data.config);
+    let navigation = category
+        .listed
+        .iter()
+        .map(|listed| {
+            Link::new(page_url(&base, &lib, &Shelf::listed(listed).path(), 1))
+                .rel("subsection")
+                .mime(FEED)
+                .title(listed.category.name.clone())
+                .count(listed.category.books)
+        })
+        .collect();
+
+    let path = user_category_path(&category.name);
+    json(
+        &navigation_feed(&base, &lib, &category.name, &path, navigation, calibre::updated(&db)),
+        FEED,
+    )
+}
+
+/// Every value of one custom column the config exposes -- every shelf location,
+/// say -- each leading to its books.
+#[actix_web::get("/v2/{lib}/columns/{label}")]
+async fn custom_column(
+    data: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<IndexQuery>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, label) = path.into_inner();
+    let db = match library(&data, &lib) {
+        Ok(db) => db,
+        Err(response) => return response,
+    };
+    let column = match data.category_column(&lib, &label) {
+        Some(column) => column,
+        None => return HttpResponse::NotFound().body(format!("No custom column '#{}' to browse", label)),
+    };
+
+    let entries = match calibre::custom_categories(&db, column) {
         Ok(entries) => entries,
-        Err(e) => return server_error("Error querying tags", e),
+        Err(e) => return server_error("Error querying custom column", e),
     };
 
     let base = origin(&req, data.config);
+    let shelf = |id: i32| Shelf::Column(column.clone(), id);
+    let index_above = data.config.index_above(&lib);
     json(
-        &shelves(&base, &lib, "Tags", Shelf::Tag, &entries, calibre::updated(&db)),
+        &shelves(&base, &lib, &column.name, shelf, entries, &query, index_above).modified(calibre::updated(&db)),
         FEED,
     )
 }
 
+/// The books filed under one value of a custom column.
+#[actix_web::get("/v2/{lib}/columns/{label}/{id}")]
+async fn books_by_custom(
+    data: web::Data<AppState>,
+    path: web::Path<(String, String, i32)>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, label, id) = path.into_inner();
+    let column = match data.category_column(&lib, &label) {
+        Some(column) => column.clone(),
+        None => return HttpResponse::NotFound().body(format!("No custom column '#{}' to browse", label)),
+    };
+    books_feed(&data, &req, &lib, Shelf::Column(column, id), facets.into_inner(), query.page.unwrap_or(1))
+}
+
+/// Every book somebody rated, the best first. Unlike the newest arrivals there
+/// is no cut-off, so this one pages like any other shelf.
+#[actix_web::get("/v2/{lib}/top")]
+async fn top_rated(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    books_feed(&data, &req, &lib, Shelf::TopRated, facets.into_inner(), query.page.unwrap_or(1))
+}
+
+/// The books the reader asking started and has not finished, in Calibre's
+/// viewer or in KOReader, the one read last first.
+#[actix_web::get("/v2/{lib}/reading")]
+async fn currently_reading(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    let started = match started(&data, &lib, &req) {
+        Ok(started) => started,
+        Err(response) => return response,
+    };
+    books_feed(&data, &req, &lib, Shelf::Reading(started), facets.into_inner(), query.page.unwrap_or(1))
+}
+
+/// The next volume of every series the reader asking opened a book of, the
+/// series read last first.
+#[actix_web::get("/v2/{lib}/next")]
+async fn next_in_series(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<PageQuery>,
+    facets: web::Query<Facets>,
+    _auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    let next = match library(&data, &lib) {
+        Ok(db) => reading::next_in_series(&data, &db, &lib, &req),
+        Err(response) => return response,
+    };
+    match next {
+        Ok(next) => books_feed(&data, &req, &lib, Shelf::Next(next), facets.into_inner(), query.page.unwrap_or(1)),
+        Err(e) => server_error("Error reading the positions", e),
+    }
+}
+
 /// The newest arrivals, in the order they arrived.
 #[actix_web::get("/v2/{lib}/new")]
 async fn recently_added(
@@ -574,8 +1180,11 @@ async fn recently_added(
         Err(response) => return response,
     };
 
-    let books = match calibre::recently_added(&db) {
-        Ok(books) => books,
+    let books = match calibre::recently_added(&db)
+        .and_then(|books| calibre::with_custom(&db, data.columns(&lib), books))
+        .and_then(|books| calibre::with_related(&db, books))
+    {
+        Ok(books) => reading::with_progress(&data, &db, &lib, &req, books),
         Err(e) => return server_error("Error querying books", e),
     };
 
@@ -588,7 +1197,7 @@ async fn recently_added(
     )
     .modified(calibre::updated(&db));
 
-    json(&holding(new, &books, &lib, &base), FEED)
+    json(&holding(new, &books, &lib, &base, &data.config.catalog.thumbnail_widths), FEED)
 }
 
 /// A single book, outside of any feed. This is what the `self` link of every
@@ -606,8 +1215,11 @@ async fn single_book(
         Err(response) => return response,
     };
 
-    let book = match calibre::book(&db, id) {
-        Ok(book) => book,
+    let book = match calibre::book(&db, id)
+        .and_then(|book| calibre::with_custom(&db, data.columns(&lib), vec![book]))
+        .and_then(|books| calibre::with_related(&db, books))
+    {
+        Ok(books) => reading::with_progress(&data, &db, &lib, &req, books).remove(0),
         Err(rusqlite::Error::QueryReturnedNoRows) => {
             return HttpResponse::NotFound().body("Book not found")
         }
@@ -615,48 +1227,150 @@ async fn single_book(
     };
 
     let base = origin(&req, data.config);
-    json(&publication(&book, &lib, &base), PUBLICATION)
+    json(&publication(&book, &lib, &base, &data.config.catalog.thumbnail_widths), PUBLICATION)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// The highlights and bookmarks made in a book with Calibre's viewer, as W3C
+/// Web Annotations: those of the Calibre users the login is allowed to read.
+/// The collection holds the first page; `?page=` serves any page on its own.
+#[actix_web::get("/v2/{lib}/book/{id}/annotations")]
+async fn book_annotations(
+    data: web::Data<AppState>,
+    path: web::Path<(String, i32)>,
+    query: web::Query<PageQuery>,
+    auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lib, id) = path.into_inner();
+    let db = match library(&data, &lib) {
+        Ok(db) => db,
+        Err(response) => return response,
+    };
+    let book = match calibre::book(&db, id) {
+        Ok(book) => book,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return HttpResponse::NotFound().body("Book not found"),
+        Err(e) => return server_error("Error querying book", e),
+    };
 
-    #[test]
-    fn a_library_that_fits_on_one_page_has_only_that_page() {
-        let one = window(4, 50, 1);
-        assert_eq!((one.current, one.last, one.offset), (1, 1, 0));
+    let base = origin(&req, data.config);
+    let collection = format!("{}/v2/{}/book/{}/annotations", base, lib, id);
+    let label = format!("Annotations on {}", book.title);
+    let users = data.config.authentication.calibre_users_of(&auth.login);
+    annotations_response(&db, &calibre::Annotated::Book(id), users, collection, label, query.page, |annotation| {
+        web_annotation(annotation, &base, &lib)
+    })
+}
 
-        // A library with nothing in it is still a page, not a 404.
-        let empty = window(0, 50, 1);
-        assert_eq!((empty.current, empty.last, empty.offset), (1, 1, 0));
-    }
+/// The highlights and bookmarks whose text holds every word of `?query=`, on
+/// any book of the library, the newest first.
+#[actix_web::get("/v2/{lib}/annotations")]
+async fn search_annotations(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    asked: web::Query<SearchQuery>,
+    auth: Authorized,
+    req: HttpRequest,
+) -> impl Responder {
+    let lib = path.into_inner();
+    let db = match library(&data, &lib) {
+        Ok(db) => db,
+        Err(response) => return response,
+    };
 
-    #[test]
-    fn pages_cover_the_library_exactly() {
-        // 120 books, 50 to a page: 50 + 50 + 20.
-        assert_eq!(window(120, 50, 1).offset, 0);
-        assert_eq!(window(120, 50, 2).offset, 50);
-        assert_eq!(window(120, 50, 3).offset, 100);
-        assert_eq!(window(120, 50, 3).last, 3);
+    let base = origin(&req, data.config);
+    let words = asked.query.clone().unwrap_or_default();
+    let collection = format!("{}/v2/{}/annotations?query={}", base, lib, encoded(&words));
+    let label = format!("Annotations matching {}", words);
+    let users = data.config.authentication.calibre_users_of(&auth.login);
+    annotations_response(&db, &calibre::Annotated::Search(&words), users, collection, label, asked.page, |annotation| {
+        web_annotation(annotation, &base, &lib)
+    })
+}
 
-        // A page that divides evenly does not add an empty one at the end.
-        assert_eq!(window(100, 50, 1).last, 2);
+/// Annotations the way the Web Annotation protocol serves them: without a
+/// page asked for, the collection with its first page in it; with one, that
+/// page on its own.
+fn annotations_response(
+    db: &Connection,
+    of: &calibre::Annotated,
+    users: &[String],
+    collection: String,
+    label: String,
+    page: Option<usize>,
+    annotate: impl Fn(&calibre::Annotation) -> Annotation,
+) -> HttpResponse {
+    let total = match calibre::count_annotations(db, of, users) {
+        Ok(total) => total,
+        Err(e) => return server_error("Error counting annotations", e),
+    };
+    let window = window(total, PER_PAGE, page.unwrap_or(1));
+    let items = match calibre::annotations(db, of, users, PER_PAGE, window.offset) {
+        Ok(annotations) => annotations.iter().map(annotate).collect(),
+        Err(e) => return server_error("Error querying annotations", e),
+    };
+
+    // Page one is a page too, with an address of its own: not the collection's.
+    let page_id = |number: usize| with_query(collection.clone(), &format!("page={}", number));
+    let mut current = annotation::Page::new(page_id(window.current), window.offset, items);
+    if window.current > 1 {
+        current = current.prev(page_id(window.current - 1));
+    }
+    if window.current < window.last {
+        current = current.next(page_id(window.current + 1));
     }
 
-    // A `next` link a client kept from before half the library was deleted.
-    #[test]
-    fn a_page_past_the_end_is_the_last_page() {
-        let past = window(120, 50, 99);
-        assert_eq!((past.current, past.last, past.offset), (3, 3, 100));
+    match page {
+        Some(_) => json(&current.part_of(collection, label, total), ANNOTATIONS),
+        None => {
+            let last = page_id(window.last);
+            json(&annotation::Collection::new(collection, label, total, current, last), ANNOTATIONS)
+        }
     }
+}
 
-    // `?page=0` should not underflow.
-    #[test]
-    fn a_page_before_the_beginning_is_the_first_page() {
-        assert_eq!(window(120, 50, 0).current, 1);
-        assert_eq!(window(120, 50, 0).offset, 0);
+/// A highlight or bookmark of Calibre's as a Web Annotation on the file of the
+/// book it was made in. Calibre's viewer tells where by EPUB CFI; the text of
+/// a highlight is there as well, for a client that cannot follow a CFI.
+fn web_annotation(annotation: &calibre::Annotation, base: &str, lib: &str) -> Annotation {
+    let id = format!(
+        "{}/v2/{}/book/{}/annotations#{}",
+        base,
+        lib,
+        annotation.book,
+        utf8_percent_encode(&annotation.annot_id, NON_ALPHANUMERIC)
+    );
+    let source = format!("{}/{}/file/{}/{}", base, lib, annotation.book, annotation.format.to_lowercase());
+    let data = &annotation.data;
+    let text = |key: &str| data[key].as_str().unwrap_or_default().to_string();
+    let cfi = |value: String| Selector::FragmentSelector { conforms_to: EPUB_CFI, value };
+
+    match annotation.kind.as_str() {
+        "bookmark" => {
+            let mut target = Target::new(source);
+            if let Some(pos) = calibre::bookmark_cfi(&text("pos")).filter(|_| text("pos_type") == "epubcfi") {
+                target = target.selector(cfi(pos));
+            }
+            Annotation::new(id, Motivation::Bookmarking, annotation.modified.clone(), target)
+                .body(text("title"), Motivation::Describing)
+        }
+        _ => {
+            let mut target = Target::new(source);
+            if !text("highlighted_text").is_empty() {
+                target = target.selector(Selector::TextQuoteSelector { exact: text("highlighted_text") });
+            }
+            if let (Some(spine), false, false) = (data["spine_index"].as_u64(), text("start_cfi").is_empty(), text("end_cfi").is_empty()) {
+                target = target.selector(cfi(calibre::highlight_cfi(spine, &text("start_cfi"), &text("end_cfi"))));
+            }
+            Annotation::new(id, Motivation::Highlighting, annotation.modified.clone(), target)
+                .body(text("notes"), Motivation::Commenting)
+        }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibre::CustomColumn;
 
     // The fixture library fits on one page, so this is where the way from one
     // page to the next is checked.
@@ -693,6 +1407,26 @@ mod tests {
         assert_eq!(page_url("https://books.example", "lib", "books", 2), "https://books.example/v2/lib/books?page=2");
     }
 
+    // Paging through "newest first" stays newest first.
+    #[test]
+    fn the_facets_stay_with_every_page() {
+        let facets = Facets {
+            sort: Some(Sort::Added),
+            format: Some("EPUB".to_string()),
+            ..Facets::NONE
+        };
+        let path = faceted(Shelf::Author(5).path(), &facets);
+        assert_eq!(
+            page_url("https://books.example", "lib", &path, 2),
+            "https://books.example/v2/lib/authors/5?sort=added&format=EPUB&page=2"
+        );
+        assert_eq!(
+            faceted(Shelf::Search("kant".to_string()).path(), &facets),
+            "search?query=kant&sort=added&format=EPUB"
+        );
+        assert_eq!(faceted(Shelf::Everything.path(), &Facets::NONE), "books");
+    }
+
     // A shelf is paged the same way the library is, and its pages are pages of
     // the shelf rather than of the catalog.
     #[test]
@@ -700,8 +1434,20 @@ mod tests {
         let path = Shelf::Author(5).path();
         assert_eq!(path, "authors/5");
         assert_eq!(Shelf::Tag(9).path(), "tags/9");
+        assert_eq!(Shelf::Series(1).path(), "series/1");
+        assert_eq!(Shelf::Publisher(4).path(), "publishers/4");
+        assert_eq!(Shelf::Language(3).path(), "languages/3");
         assert_eq!(Shelf::Everything.path(), "books");
 
+        let location = CustomColumn {
+            id: 3,
+            label: "shelf".to_string(),
+            name: "Shelf location".to_string(),
+            kind: calibre::ColumnKind::Enumeration,
+            multiple: false,
+        };
+        assert_eq!(Shelf::Column(location, 2).path(), "columns/shelf/2");
+
         assert_eq!(
             page_url("https://books.example", "lib", &path, 2),
             "https://books.example/v2/lib/authors/5?page=2"
@@ -0,0 +1,457 @@
+//! Shelves of books, and how either catalog pages through them
+//!
+//! A shelf, its address and its pages are the same in the Atom catalog and
+//! below `/v2`: only how a feed is written differs.
+
+use actix_web::{HttpRequest, HttpResponse};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rusqlite::Connection;
+use serde_derive::Deserialize;
+
+use crate::appstate::AppState;
+use crate::calibre::{self, Book, CustomColumn, Facets, ListedKind, Sort};
+use crate::reading;
+use crate::routes::server_error;
+
+/// How many books one page of the catalog holds, in either format.
+pub(crate) const PER_PAGE: usize = 50;
+
+#[derive(Deserialize)]
+pub(crate) struct PageQuery {
+    pub(crate) page: Option<usize>,
+}
+
+/// Which part of a long list: the names filed under one letter, a page at a time.
+#[derive(Deserialize)]
+pub(crate) struct IndexQuery {
+    pub(crate) letter: Option<String>,
+    pub(crate) page: Option<usize>,
+}
+
+/// A search of the metadata, or with `in=content` of the text of the books.
+pub(crate) fn searched(term: String, within: Option<&str>) -> Shelf {
+    match within {
+        Some("content") => Shelf::Content(calibre::Quote::new(term)),
+        _ => Shelf::Search(term),
+    }
+}
+
+/// Which slice of the library a request for `page` asks for.
+/// Pages count from one, and one past the end is the last page: An empty library still has a page one.
+pub(crate) fn window(total: usize, per_page: usize, requested: usize) -> Window {
+    let last = total.div_ceil(per_page).max(1);
+    let current = requested.clamp(1, last);
+    Window {
+        current,
+        last,
+        offset: (current - 1) * per_page,
+    }
+}
+
+pub(crate) struct Window {
+    pub(crate) current: usize,
+    pub(crate) last: usize,
+    pub(crate) offset: usize,
+}
+
+impl Window {
+    /// The pages around this one, by the rel a link to each of them wears.
+    pub(crate) fn neighbours(&self) -> Vec<(&'static str, usize)> {
+        let mut pages = Vec::new();
+        if self.current > 1 {
+            pages.push(("first", 1));
+            pages.push(("previous", self.current - 1));
+        }
+        if self.current < self.last {
+            pages.push(("next", self.current + 1));
+            pages.push(("last", self.last));
+        }
+        pages
+    }
+}
+
+/// Page one is the bare URL. Any other page joins with whichever separator is
+/// still free (there may already be a query, e.g. search)
+pub(crate) fn paged(url: String, page: usize) -> String {
+    match page {
+        1 => url,
+        n => with_query(url, &format!("page={}", n)),
+    }
+}
+
+pub(crate) fn with_query(url: String, query: &str) -> String {
+    let separator = match url.contains('?') {
+        true => '&',
+        false => '?',
+    };
+    format!("{}{}{}", url, separator, query)
+}
+
+/// The address of a shelf as the reader sorted and narrowed it down. Everything
+/// built on it -- the self link, the pages, the other facets -- keeps that.
+pub(crate) fn faceted(path: String, facets: &Facets) -> String {
+    let mut query = Vec::new();
+    if let Some(sort) = facets.sort {
+        query.push(format!("sort={}", sort.key()));
+    }
+    if let Some(format) = &facets.format {
+        query.push(format!("format={}", encoded(format)));
+    }
+    if let Some(language) = facets.language {
+        query.push(format!("language={}", language));
+    }
+    if facets.cover {
+        query.push("cover=true".to_string());
+    }
+    match query.is_empty() {
+        true => path,
+        false => with_query(path, &query.join("&")),
+    }
+}
+
+/// One way of sorting or narrowing down a feed of books, in either catalog:
+/// a group of options, each the facets the reader would have after picking it.
+pub(crate) struct FacetGroup {
+    pub(crate) title: &'static str,
+    pub(crate) options: Vec<FacetOption>,
+}
+
+pub(crate) struct FacetOption {
+    pub(crate) title: String,
+    pub(crate) facets: Facets,
+    /// the one the reader picked
+    pub(crate) active: bool,
+}
+
+/// What a reader can do to a feed of books, from where they are now. A format
+/// or a language is only offered when the library has more than one of them.
+pub(crate) fn facet_groups(db: &Connection, current: &Facets) -> rusqlite::Result<Vec<FacetGroup>> {
+    let option = |title: &str, facets: Facets| FacetOption {
+        title: title.to_string(),
+        active: facets == *current,
+        facets,
+    };
+
+    let mut groups = vec![FacetGroup {
+        title: "Sort by",
+        options: Sort::ALL
+            .iter()
+            .map(|sort| {
+                option(sort.name(), Facets { sort: Some(*sort), ..current.clone() })
+            })
+            .collect(),
+    }];
+
+    let formats = calibre::formats(db)?;
+    if formats.len() > 1 {
+        let mut options = vec![option("All formats", Facets { format: None, ..current.clone() })];
+        options.extend(formats.iter().map(|format| {
+            option(format, Facets { format: Some(format.clone()), ..current.clone() })
+        }));
+        groups.push(FacetGroup { title: "Format", options });
+    }
+
+    let spoken = calibre::languages_with_books(db)?;
+    if spoken.len() > 1 {
+        let mut options = vec![option("All languages", Facets { language: None, ..current.clone() })];
+        options.extend(spoken.iter().map(|language| {
+            option(&language.name, Facets { language: Some(language.id), ..current.clone() })
+        }));
+        groups.push(FacetGroup { title: "Language", options });
+    }
+
+    groups.push(FacetGroup {
+        title: "Cover",
+        options: vec![
+            option("With or without", Facets { cover: false, ..current.clone() }),
+            option("With a cover", Facets { cover: true, ..current.clone() }),
+        ],
+    });
+    Ok(groups)
+}
+
+/// Where the values of one custom column are listed, below the library.
+pub(crate) fn column_feed(column: &CustomColumn) -> String {
+    format!("columns/{}", column.label)
+}
+
+/// a `shelf` carries no books -- it says only how to ask Calibre and what to call the result.
+/// paging works identical on all of them, and in both catalogs.
+pub(crate) enum Shelf {
+    Everything,
+    Author(i32),
+    Tag(i32),
+    /// read in order of `series_index`, not by title
+    Series(i32),
+    Publisher(i32),
+    /// Calibre's id of the language, not its code
+    Language(i32),
+    /// One value of a custom column: the books on the shelf "Study"
+    Column(CustomColumn, i32),
+    /// Every rated book, the best first
+    TopRated,
+    /// The books rated at one star level, out of ten the way Calibre stores it
+    Rating(i32),
+    /// a term nothing matches is an empty shelf, not a 404.
+    Search(String),
+    /// The books whose text holds a quote, as far as Calibre has extracted it
+    Content(calibre::Quote),
+    /// One of Calibre's virtual libraries, by name
+    Virtual(String),
+    /// One of the searches saved in Calibre, by name
+    Saved(String),
+    /// The books whose title an index files under one letter
+    Titled(String),
+    /// The books a reader started and has not finished, the one read last
+    /// first. Who is reading is not part of the address: it is who asked.
+    Reading(Vec<i32>),
+    /// The next volume of every series the reader asking opened a book of, the
+    /// series read last first
+    Next(Vec<i32>),
+}
+
+impl Shelf {
+    /// The feed every shelf of this kind lives in, below the library. The only
+    /// place these path segments are spelled out.
+    pub(crate) fn feed(&self) -> &'static str {
+        match self {
+            Shelf::Everything => "books",
+            Shelf::Author(_) => "authors",
+            Shelf::Tag(_) => "tags",
+            Shelf::Series(_) => "series",
+            Shelf::Publisher(_) => "publishers",
+            Shelf::Language(_) => "languages",
+            Shelf::Column(..) => "columns",
+            Shelf::TopRated => "top",
+            Shelf::Rating(_) => "ratings",
+            Shelf::Search(_) | Shelf::Content(_) => "search",
+            Shelf::Virtual(_) => "virtual",
+            Shelf::Saved(_) => "searches",
+            Shelf::Titled(_) => "titles",
+            Shelf::Reading(_) => "reading",
+            Shelf::Next(_) => "next",
+        }
+    }
+
+    /// Where the list of every shelf of this kind lives, below the library.
+    pub(crate) fn listed_in(&self) -> String {
+        match self {
+            Shelf::Column(column, _) => column_feed(column),
+            shelf => shelf.feed().to_string(),
+        }
+    }
+
+    /// Where this one shelf lives below the library.
+    pub(crate) fn path(&self) -> String {
+        match self {
+            Shelf::Everything | Shelf::TopRated | Shelf::Reading(_) | Shelf::Next(_) => self.feed().to_string(),
+            Shelf::Author(id)
+            | Shelf::Tag(id)
+            | Shelf::Series(id)
+            | Shelf::Publisher(id)
+            | Shelf::Language(id)
+            | Shelf::Rating(id) => format!("{}/{}", self.feed(), id),
+            Shelf::Column(column, id) => format!("{}/{}", column_feed(column), id),
+            // The term is part of the address, so every page of a search finds
+            // its way back to the same books.
+            Shelf::Search(term) => format!("{}?query={}", self.feed(), encoded(term)),
+            Shelf::Content(quote) => format!("{}?query={}&in=content", self.feed(), encoded(&quote.said)),
+            Shelf::Virtual(name) | Shelf::Saved(name) | Shelf::Titled(name) => {
+                format!("{}/{}", self.feed(), encoded(name))
+            }
+        }
+    }
+
+    /// What to call this feed. `QueryReturnedNoRows` for a shelf the library does not have.
+    pub(crate) fn name(&self, db: &Connection) -> rusqlite::Result<String> {
+        match self {
+            Shelf::Everything => Ok("All Books".to_string()),
+            Shelf::Author(id) => calibre::author_name(db, *id),
+            Shelf::Tag(id) => calibre::tag_name(db, *id),
+            Shelf::Series(id) => calibre::series_name(db, *id),
+            Shelf::Publisher(id) => calibre::publisher_name(db, *id),
+            Shelf::Language(id) => calibre::language_name(db, *id),
+            Shelf::Column(column, id) => calibre::custom_category_name(db, column, *id)
+                .map(|value| format!("{}: {}", column.name, value)),
+            Shelf::TopRated => Ok("Top Rated".to_string()),
+            Shelf::Rating(rating) => calibre::rating_name(db, *rating),
+            Shelf::Search(term) => Ok(match term.trim() {
+                "" => "Search".to_string(),
+                term => format!("Search: {}", term),
+            }),
+            Shelf::Content(quote) => Ok(match quote.said.trim() {
+                "" => "Search the text".to_string(),
+                quote => format!("Search the text: {}", quote),
+            }),
+            Shelf::Virtual(name) => calibre::virtual_library(db, name).map(|library| library.name),
+            Shelf::Saved(name) => calibre::saved_search(db, name).map(|saved| saved.name),
+            Shelf::Titled(letter) => Ok(format!("Titles: {}", letter)),
+            Shelf::Reading(_) => Ok("Currently Reading".to_string()),
+            Shelf::Next(_) => Ok("Next in Series".to_string()),
+        }
+    }
+
+    /// Calibre's note on the author, tag, series or publisher of the shelf, as
+    /// HTML. A note that cannot be read is left out: it is a nicety.
+    pub(crate) fn note(&self, db: &Connection) -> Option<String> {
+        let (field, item) = match self {
+            Shelf::Author(id) => ("authors", *id),
+            Shelf::Tag(id) => ("tags", *id),
+            Shelf::Series(id) => ("series", *id),
+            Shelf::Publisher(id) => ("publisher", *id),
+            _ => return None,
+        };
+        calibre::note(db, field, item).unwrap_or_else(|e| {
+            eprintln!("Error reading the note on {} {}: {}", field, item, e);
+            None
+        })
+    }
+
+    /// How many books are on the shelf, as the reader narrowed it down.
+    pub(crate) fn count(&self, db: &Connection, facets: &Facets) -> rusqlite::Result<usize> {
+        match self {
+            Shelf::Everything => calibre::count_books(db, facets),
+            Shelf::Author(id) => calibre::count_books_by_author(db, *id, facets),
+            Shelf::Tag(id) => calibre::count_books_by_tag(db, *id, facets),
+            Shelf::Series(id) => calibre::count_books_in_series(db, *id, facets),
+            Shelf::Publisher(id) => calibre::count_books_by_publisher(db, *id, facets),
+            Shelf::Language(id) => calibre::count_books_by_language(db, *id, facets),
+            Shelf::Column(column, id) => calibre::count_books_by_custom(db, column, *id, facets),
+            Shelf::TopRated => calibre::count_top_rated(db, facets),
+            Shelf::Rating(rating) => calibre::count_books_by_rating(db, *rating, facets),
+            Shelf::Search(term) if term.trim().is_empty() => Ok(0),
+            Shelf::Search(term) => calibre::count_books_matching(db, term.trim(), facets),
+            Shelf::Content(quote) => calibre::count_books_containing(db, quote, facets),
+            Shelf::Virtual(name) => {
+                calibre::count_books_matching(db, &calibre::virtual_library(db, name)?.search, facets)
+            }
+            Shelf::Saved(name) => calibre::count_books_matching(db, &calibre::saved_search(db, name)?.search, facets),
+            Shelf::Titled(letter) => calibre::count_books_titled(db, letter, facets),
+            Shelf::Reading(books) | Shelf::Next(books) => calibre::count_books_among(db, books, facets),
+        }
+    }
+
+    pub(crate) fn books(&self, db: &Connection, facets: &Facets, limit: usize, offset: usize) -> rusqlite::Result<Vec<Book>> {
+        match self {
+            Shelf::Everything => calibre::books_page(db, facets, limit, offset),
+            Shelf::Author(id) => calibre::books_by_author_page(db, *id, facets, limit, offset),
+            Shelf::Tag(id) => calibre::books_by_tag_page(db, *id, facets, limit, offset),
+            Shelf::Series(id) => calibre::books_in_series_page(db, *id, facets, limit, offset),
+            Shelf::Publisher(id) => calibre::books_by_publisher_page(db, *id, facets, limit, offset),
+            Shelf::Language(id) => calibre::books_by_language_page(db, *id, facets, limit, offset),
+            Shelf::Column(column, id) => calibre::books_by_custom_page(db, column, *id, facets, limit, offset),
+            Shelf::TopRated => calibre::top_rated_page(db, facets, limit, offset),
+            Shelf::Rating(rating) => calibre::books_by_rating_page(db, *rating, facets, limit, offset),
+            // Nothing typed is nothing found: a bare `%%` would be the whole library.
+            Shelf::Search(term) if term.trim().is_empty() => Ok(Vec::new()),
+            Shelf::Search(term) => calibre::books_search_page(db, term.trim(), facets, limit, offset),
+            Shelf::Content(quote) => calibre::books_containing_page(db, quote, facets, limit, offset),
+            Shelf::Virtual(name) => {
+                calibre::books_search_page(db, &calibre::virtual_library(db, name)?.search, facets, limit, offset)
+            }
+            Shelf::Saved(name) => {
+                calibre::books_search_page(db, &calibre::saved_search(db, name)?.search, facets, limit, offset)
+            }
+            Shelf::Titled(letter) => calibre::books_titled_page(db, letter, facets, limit, offset),
+            Shelf::Reading(books) | Shelf::Next(books) => calibre::books_among_page(db, books, facets, limit, offset),
+        }
+    }
+
+    /// The shelf of an author, tag or series a user category lists.
+    pub(crate) fn listed(listed: &calibre::Listed) -> Shelf {
+        match listed.kind {
+            ListedKind::Author => Shelf::Author(listed.category.id),
+            ListedKind::Tag => Shelf::Tag(listed.category.id),
+            ListedKind::Series => Shelf::Series(listed.category.id),
+        }
+    }
+}
+
+/// Where the saved searches are listed, below the library.
+pub(crate) const SAVED_SEARCHES: &str = "searches";
+/// Where the user categories are listed, below the library.
+pub(crate) const USER_CATEGORIES: &str = "categories";
+
+/// Where one user category lists what it holds, below the library.
+pub(crate) fn user_category_path(name: &str) -> String {
+    format!("{}/{}", USER_CATEGORIES, encoded(name))
+}
+
+/// The saved searches that find any books, each with how many it finds.
+pub(crate) fn saved_searches_with_books(db: &Connection) -> rusqlite::Result<Vec<(calibre::NamedSearch, usize)>> {
+    let mut found = Vec::new();
+    for saved in calibre::saved_searches(db)? {
+        match Shelf::Saved(saved.name.clone()).count(db, &Facets::NONE)? {
+            0 => {}
+            books => found.push((saved, books)),
+        }
+    }
+    Ok(found)
+}
+
+/// The user categories that list anything the library has books for.
+pub(crate) fn user_categories_with_books(db: &Connection) -> rusqlite::Result<Vec<calibre::UserCategory>> {
+    let mut categories = calibre::user_categories(db)?;
+    categories.retain(|category| !category.listed.is_empty());
+    Ok(categories)
+}
+
+/// `NON_ALPHANUMERIC` keeps `&`, `=` and `+` out of a URL
+pub(crate) fn encoded(term: &str) -> String {
+    utf8_percent_encode(term, NON_ALPHANUMERIC).to_string()
+}
+
+/// The part of a list filed under one letter, below the library.
+pub(crate) fn letter_path(feed: &str, letter: &str) -> String {
+    format!("{}?letter={}", feed, encoded(letter))
+}
+
+/// What `reading::in_progress` finds, for a feed to lock the library again for.
+pub(crate) fn started(data: &AppState, lib: &str, req: &HttpRequest) -> Result<Vec<i32>, HttpResponse> {
+    let db = match data.library(lib) {
+        Some(db) => db,
+        None => return Err(HttpResponse::NotFound().body(format!("Database '{}' not found", lib))),
+    };
+    reading::in_progress(data, &db, lib, req).map_err(|e| server_error("Error reading the positions", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_library_that_fits_on_one_page_has_only_that_page() {
+        let one = window(4, 50, 1);
+        assert_eq!((one.current, one.last, one.offset), (1, 1, 0));
+
+        // A library with nothing in it is still a page, not a 404.
+        let empty = window(0, 50, 1);
+        assert_eq!((empty.current, empty.last, empty.offset), (1, 1, 0));
+    }
+
+    #[test]
+    fn pages_cover_the_library_exactly() {
+        // 120 books, 50 to a page: 50 + 50 + 20.
+        assert_eq!(window(120, 50, 1).offset, 0);
+        assert_eq!(window(120, 50, 2).offset, 50);
+        assert_eq!(window(120, 50, 3).offset, 100);
+        assert_eq!(window(120, 50, 3).last, 3);
+
+        // A page that divides evenly does not add an empty one at the end.
+        assert_eq!(window(100, 50, 1).last, 2);
+    }
+
+    // A `next` link a client kept from before half the library was deleted.
+    #[test]
+    fn a_page_past_the_end_is_the_last_page() {
+        let past = window(120, 50, 99);
+        assert_eq!((past.current, past.last, past.offset), (3, 3, 100));
+    }
+
+    // `?page=0` should not underflow.
+    #[test]
+    fn a_page_before_the_beginning_is_the_first_page() {
+        assert_eq!(window(120, 50, 0).current, 1);
+        assert_eq!(window(120, 50, 0).offset, 0);
+    }
+}
@@ -0,0 +1,162 @@
+//! Covers scaled down for a list of books
+//!
+//! Calibre keeps one `cover.jpg` per book, often a few megabytes of it, and a
+//! page of fifty books is a lot to download for a phone that shows each cover
+//! the size of a thumbnail. A thumbnail is cut to one of the configured widths
+//! and to the 2:3 shape of a book, so a feed can say how large it is without
+//! looking at the cover. A book without a cover gets a placeholder instead.
+//! Each is made once, on the first request for it, and kept on disk under the
+//! book id and the time Calibre last changed the book.
+
+use std::fs;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{Context, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::RgbImage;
+
+use crate::calibre::Cover;
+use crate::placeholder::Placeholder;
+
+/// Good enough for a cover the size of a thumbnail, and a third of the bytes of 95.
+const QUALITY: u8 = 80;
+
+/// Tells apart the files written at the same time by one process.
+static PARTIAL: AtomicUsize = AtomicUsize::new(0);
+
+/// How tall a thumbnail of the given width is: books are two by three.
+pub fn height(width: u32) -> u32 {
+    width * 3 / 2
+}
+
+/// The width to serve for the one a client asked for: the smallest configured
+/// one at least as wide, or the widest there is. A client that does not say
+/// gets the smallest. Anything else would fill the cache with every width a
+/// client can think of.
+pub fn width(requested: Option<u32>, widths: &[u32]) -> u32 {
+    let smallest = widths.iter().copied().min().unwrap_or_default();
+    let widest = widths.iter().copied().max().unwrap_or_default();
+    match requested {
+        None => smallest,
+        Some(requested) => widths
+            .iter()
+            .copied()
+            .filter(|&width| width >= requested)
+            .min()
+            .unwrap_or(widest),
+    }
+}
+
+/// The thumbnail of `cover` at `width`, made now if the cache does not hold it
+/// yet. Thumbnails of an older cover of the book are removed.
+pub fn thumbnail(library: &Path, cover: &Cover, cache: &Path, book: i32, width: u32) -> Result<PathBuf> {
+    // Calibre's timestamps hold colons and a plus sign; the digits are enough.
+    let stamp: String = cover.modified.chars().filter(char::is_ascii_digit).collect();
+    let path = cache.join(format!("{}-{}-{}.jpg", book, stamp, width));
+    if path.is_file() {
+        return Ok(path);
+    }
+
+    fs::create_dir_all(cache).with_context(|| format!("cannot create '{}'", cache.display()))?;
+    forget_older(cache, book, &stamp)?;
+    let drawn = draw(library, cover, width)?;
+
+    // Written under a name of its own and then renamed, so that a request
+    // arriving meanwhile never finds half a thumbnail, and two requests for
+    // the same one never write into the same file.
+    let partial = PARTIAL.fetch_add(1, Ordering::Relaxed);
+    let partial = cache.join(format!("{}-{}-{}.{}-{}.part", book, stamp, width, std::process::id(), partial));
+    let mut file = BufWriter::new(fs::File::create(&partial)?);
+    JpegEncoder::new_with_quality(&mut file, QUALITY).encode_image(&drawn)?;
+    drop(file);
+    fs::rename(&partial, &path)?;
+    Ok(path)
+}
+
+/// The cover Calibre keeps, scaled to fill the thumbnail, or the placeholder at its size.
+fn draw(library: &Path, cover: &Cover, width: u32) -> Result<RgbImage> {
+    match &cover.path {
+        Some(path) => {
+            let original = library.join(path);
+            Ok(image::open(&original)
+                .with_context(|| format!("cannot read '{}'", original.display()))?
+                .resize_to_fill(width, height(width), FilterType::Lanczos3)
+                .to_rgb8())
+        }
+        None => Ok(Placeholder {
+            title: &cover.title,
+            authors: &cover.authors,
+            uuid: &cover.uuid,
+        }
+        .draw(width, height(width))),
+    }
+}
+
+/// Remove the thumbnails made of a cover the book no longer has.
+fn forget_older(cache: &Path, book: i32, stamp: &str) -> Result<()> {
+    let prefix = format!("{}-", book);
+    let current = format!("{}-{}-", book, stamp);
+    for entry in fs::read_dir(cache)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with(&prefix) && !name.starts_with(&current) && name.ends_with(".jpg") {
+            // Another request may have removed it first.
+            let _ = fs::remove_file(cache.join(name.as_ref()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kant(modified: &str, has_cover: bool) -> Cover {
+        Cover {
+            path: has_cover.then(|| "Immanuel Kant/Kritik der reinen Vernunft - 2. Auflage (5)/cover.jpg".to_string()),
+            modified: modified.to_string(),
+            uuid: String::new(),
+            title: "Kritik der reinen Vernunft".to_string(),
+            authors: "Immanuel Kant".to_string(),
+        }
+    }
+
+    #[test]
+    fn a_client_gets_the_next_width_up() {
+        let widths = [240, 480, 960];
+        assert_eq!(width(None, &widths), 240);
+        assert_eq!(width(Some(100), &widths), 240);
+        assert_eq!(width(Some(480), &widths), 480);
+        assert_eq!(width(Some(481), &widths), 960);
+        assert_eq!(width(Some(5000), &widths), 960);
+    }
+
+    #[test]
+    fn a_thumbnail_is_made_once_and_replaced_with_the_cover() {
+        let library = Path::new("tests/calibre");
+        let cache = tempfile::tempdir().expect("a cache");
+        let first = thumbnail(library, &kant("2024-11-30 10:23:04+00:00", true), cache.path(), 5, 240).expect("a thumbnail");
+        assert_eq!(image::image_dimensions(&first).expect("a jpeg"), (240, 360));
+
+        let made = fs::metadata(&first).unwrap().modified().unwrap();
+        let again = thumbnail(library, &kant("2024-11-30 10:23:04+00:00", true), cache.path(), 5, 240).unwrap();
+        assert_eq!((again.clone(), fs::metadata(&again).unwrap().modified().unwrap()), (first.clone(), made));
+
+        // Calibre changed the book: the old thumbnail goes.
+        let newer = thumbnail(library, &kant("2025-01-01 00:00:00+00:00", true), cache.path(), 5, 240).unwrap();
+        assert_ne!(newer, first);
+        assert!(!first.exists());
+        assert_eq!(fs::read_dir(cache.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn a_book_without_a_cover_gets_a_placeholder_the_same_size() {
+        let cache = tempfile::tempdir().expect("a cache");
+        let drawn = thumbnail(Path::new("tests/calibre"), &kant("2025-01-01 00:00:00+00:00", false), cache.path(), 5, 480)
+            .expect("a placeholder");
+        assert_eq!(image::image_dimensions(&drawn).expect("a jpeg"), (480, 720));
+    }
+}
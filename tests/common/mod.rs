@@ -0,0 +1,75 @@
+//! What the tests of reading and syncing share: the app, the logins they call
+//! it as, and copies of the test library for a test to write to.
+
+#![allow(dead_code)]
+
+use actix_http::Request;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::{test, web, App};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use orca::config::Config;
+use orca::{create_app, init};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+/// The MD5 of alice's password, which is what KOReader keeps and sends.
+pub const ALICE: (&str, &str) = ("alice", "2034f6e32958647fdff75d265b455ebf");
+
+pub async fn serve(config: &'static Config) -> impl Service<Request, Response = ServiceResponse, Error = actix_web::Error> {
+    let state = create_app(config).expect("Failed to create app");
+    test::init_service(App::new().app_data(web::Data::new(state)).configure(init)).await
+}
+
+/// A page of the catalog, as `login` sees it.
+pub async fn call_as(
+    app: &impl Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+    uri: &str,
+    login: &str,
+) -> ServiceResponse {
+    let credentials = BASE64.encode(format!("{}:secretpassword", login));
+    let request = test::TestRequest::with_uri(uri)
+        .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
+        .to_request();
+    let response = test::call_service(app, request).await;
+    assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+    response
+}
+
+/// A request the way KOReader's plugin makes it: the login and the MD5 of the
+/// password in headers of their own.
+pub async fn koreader(
+    app: &impl Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+    request: test::TestRequest,
+    (user, key): (&str, &str),
+) -> ServiceResponse {
+    let request = request
+        .insert_header(("x-auth-user", user))
+        .insert_header(("x-auth-key", key))
+        .insert_header((header::ACCEPT, "application/vnd.koreader.v1+json"))
+        .to_request();
+    test::call_service(app, request).await
+}
+
+pub async fn json(response: ServiceResponse) -> Value {
+    serde_json::from_slice(&test::read_body(response).await).expect("a JSON body")
+}
+
+pub async fn text(response: ServiceResponse) -> String {
+    String::from_utf8(test::read_body(response).await.to_vec()).expect("UTF-8")
+}
+
+/// A library copied whole, its books and covers with it.
+pub fn copy(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        match entry.file_type().unwrap().is_dir() {
+            true => copy(&entry.path(), &to.join(entry.file_name())),
+            false => {
+                fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+            }
+        }
+    }
+}
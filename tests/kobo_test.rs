@@ -0,0 +1,277 @@
+//! Kobo's store sync, the way a Kobo pointed at Orca talks to it
+//!
+//! Alice's Kobo holds the science fiction of the test library, and what she
+//! is reading. It knows nothing but the token in its store URL.
+
+mod common;
+
+use actix_http::Request;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::test;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use common::{call_as, json};
+use orca::config::{read_config, Config};
+use orca::hash::token_hash;
+use rusqlite::Connection;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+const TOKEN: &str = "0123456789abcdef0123456789abcdef";
+
+const KANT: &str = "33188aea-9595-4bdd-8982-3c7249a5268e";
+const GALACTIC_PATROL: &str = "5df0105a-9067-459e-a43c-98722e079610";
+
+#[test]
+async fn only_its_token_lets_a_kobo_in() {
+    let app = setup().await;
+    let stranger = test::call_service(&app, get("/kobo/fedcba9876543210fedcba9876543210/v1/initialization")).await;
+    assert_eq!(stranger.status(), StatusCode::UNAUTHORIZED);
+
+    let response = test::call_service(&app, get(&format!("/kobo/{}/v1/initialization", TOKEN))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get("x-kobo-apitoken").unwrap(), "e30=");
+    let resources = &json(response).await["Resources"];
+    assert_eq!(resources["library_sync"], format!("http://localhost:8080/kobo/{}/v1/library/sync", TOKEN));
+    assert_eq!(resources["reading_state"], format!("http://localhost:8080/kobo/{}/v1/library/{{Ids}}/state", TOKEN));
+}
+
+#[test]
+async fn a_kobo_gets_its_shelves_once() {
+    let app = setup().await;
+
+    let (first, token) = sync(&app, None).await;
+    let token = token.expect("a sync token");
+    let mut titles: Vec<&str> = first
+        .iter()
+        .map(|entry| entry["NewEntitlement"]["BookMetadata"]["Title"].as_str().expect("a new book"))
+        .collect();
+    titles.sort();
+    assert_eq!(titles, ["At the Mountains of Madness", "Galactic Patrol", "Vampires of Space", "Гиперболоид инженера Гарина. Аэлита (Художник Г. Зубковский)"]);
+
+    let patrol = first.iter().find(|entry| entry["NewEntitlement"]["BookEntitlement"]["Id"] == GALACTIC_PATROL).unwrap();
+    assert_eq!(patrol["NewEntitlement"]["ReadingState"]["StatusInfo"]["Status"], "ReadyToRead");
+    let downloads = patrol["NewEntitlement"]["BookMetadata"]["DownloadUrls"].as_array().unwrap();
+    assert_eq!(downloads.iter().map(|download| download["Format"].as_str().unwrap()).collect::<Vec<_>>(), ["EPUB3", "EPUB"]);
+    assert!(downloads[0]["Size"].as_u64().unwrap() > 0);
+
+    let (again, _) = sync(&app, Some(&token)).await;
+    assert!(again.is_empty(), "nothing changed since: {:?}", again);
+
+    // A Kobo reset comes without a token, and gets everything again.
+    let (reset, _) = sync(&app, None).await;
+    assert_eq!(reset.len(), 4);
+}
+
+#[test]
+async fn a_kobo_downloads_its_books_and_their_covers() {
+    let app = setup().await;
+    let (first, _) = sync(&app, None).await;
+    let url = first[0]["NewEntitlement"]["BookMetadata"]["DownloadUrls"][0]["Url"].as_str().unwrap().to_string();
+
+    let download = test::call_service(&app, get(url.strip_prefix("http://localhost:8080").unwrap())).await;
+    assert_eq!(download.status(), StatusCode::OK);
+    assert!(!test::read_body(download).await.is_empty());
+
+    for cover in [
+        format!("/kobo/{}/{}/355/530/85/false/image.jpg", TOKEN, GALACTIC_PATROL),
+        format!("/kobo/{}/{}/355/530/false/image.jpg", TOKEN, GALACTIC_PATROL),
+    ] {
+        let response = test::call_service(&app, get(&cover)).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", cover);
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/jpeg");
+    }
+    let unknown = test::call_service(&app, get(&format!("/kobo/{}/not-a-uuid/355/530/false/image.jpg", TOKEN))).await;
+    assert_eq!(unknown.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+async fn a_kobo_gets_nothing_that_is_not_on_its_shelves() {
+    let app = setup().await;
+    sync(&app, None).await;
+
+    // Kant is in the library, but not science fiction.
+    for uri in [
+        format!("/kobo/{}/download/5/EPUB", TOKEN),
+        format!("/kobo/{}/{}/355/530/false/image.jpg", TOKEN, KANT),
+        format!("/kobo/{}/v1/library/{}/metadata", TOKEN, KANT),
+        format!("/kobo/{}/v1/library/{}/state", TOKEN, KANT),
+    ] {
+        let response = test::call_service(&app, get(&uri)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", uri);
+    }
+    let state = json!({ "ReadingStates": [{ "EntitlementId": KANT, "StatusInfo": { "Status": "Finished" } }] });
+    let request = test::TestRequest::put().uri(&format!("/kobo/{}/v1/library/{}/state", TOKEN, KANT)).set_json(state);
+    assert_eq!(test::call_service(&app, request.to_request()).await.status(), StatusCode::NOT_FOUND);
+    let reading = json(call_as(&app, "/v2/library/book/5", "alice").await).await;
+    assert_eq!(reading["metadata"].get("totalProgression"), None);
+    let patrol = test::call_service(&app, get(&format!("/kobo/{}/v1/library/{}/metadata", TOKEN, GALACTIC_PATROL))).await;
+    assert_eq!(patrol.status(), StatusCode::OK);
+}
+
+#[test]
+async fn reading_on_a_kobo_is_reading_in_the_catalog() {
+    // Alice opened Kant on the desktop: he is on the shelf of what she reads now.
+    let dir = TempDir::new().unwrap();
+    let app = common::serve(config(&opened(&dir, 5))).await;
+
+    let (first, token) = sync(&app, None).await;
+    let kant = first.iter().find(|entry| entry["NewEntitlement"]["BookEntitlement"]["Id"] == KANT).expect("Kant on the device");
+    assert_eq!(kant["NewEntitlement"]["ReadingState"]["CurrentBookmark"]["ProgressPercent"].as_f64(), Some(20.0));
+
+    let result = json(put_state(&app, KANT, json!({ "Status": "Reading" }), 30).await).await;
+    assert_eq!(result["UpdateResults"][0]["CurrentBookmarkResult"]["Result"], "Success");
+
+    let reading = json(call_as(&app, "/v2/library/reading", "alice").await).await;
+    assert_eq!(reading["publications"][0]["metadata"]["title"], "Kritik der reinen Vernunft - 2. Auflage");
+    assert_eq!(reading["publications"][0]["metadata"]["totalProgression"], 0.3);
+
+    // The device knows where it is: nothing to send it back.
+    let (next, token) = sync(&app, token.as_deref()).await;
+    assert!(next.is_empty(), "{:?}", next);
+
+    let state = json(test::call_service(&app, get(&format!("/kobo/{}/v1/library/{}/state", TOKEN, KANT))).await).await;
+    assert_eq!(state[0]["StatusInfo"]["Status"], "Reading");
+    assert_eq!(state[0]["EntitlementId"], KANT);
+    assert_eq!(state[0]["CurrentBookmark"]["ProgressPercent"], 30);
+
+    // Finished, he leaves the shelf, and the device.
+    put_state(&app, KANT, json!({ "Status": "Finished" }), 100).await;
+    let (last, _) = sync(&app, token.as_deref()).await;
+    assert_eq!(last.len(), 1);
+    assert_eq!(last[0]["ChangedEntitlement"]["BookEntitlement"]["Id"], KANT);
+    assert_eq!(last[0]["ChangedEntitlement"]["BookEntitlement"]["IsRemoved"], true);
+}
+
+#[test]
+async fn a_book_removed_from_the_kobo_stays_removed_until_it_changes() {
+    let dir = TempDir::new().unwrap();
+    let library = opened(&dir, 5);
+    let app = common::serve(config(&library)).await;
+    let (_, token) = sync(&app, None).await;
+
+    let remove = |uuid: &str| test::TestRequest::delete().uri(&format!("/kobo/{}/v1/library/{}", TOKEN, uuid)).to_request();
+    assert_eq!(test::call_service(&app, remove(GALACTIC_PATROL)).await.status(), StatusCode::NO_CONTENT);
+    // Not the device's to remove twice.
+    assert_eq!(test::call_service(&app, remove(GALACTIC_PATROL)).await.status(), StatusCode::NOT_FOUND);
+    let download = test::call_service(&app, get(&format!("/kobo/{}/download/9/epub", TOKEN))).await;
+    assert_eq!(download.status(), StatusCode::NOT_FOUND);
+
+    let (again, token) = sync(&app, token.as_deref()).await;
+    assert!(again.is_empty(), "{:?}", again);
+
+    // Calibre changed the book: it comes back.
+    let db = Connection::open(library.join("metadata.db")).unwrap();
+    db.execute("DROP TRIGGER IF EXISTS main.books_update_trg;", []).unwrap();
+    db.execute("UPDATE books SET last_modified = '2030-01-01 00:00:00+00:00' WHERE id = 9;", []).unwrap();
+    let (back, _) = sync(&app, token.as_deref()).await;
+    assert_eq!(back.len(), 1);
+    assert_eq!(back[0]["NewEntitlement"]["BookEntitlement"]["Id"], GALACTIC_PATROL);
+}
+
+// Alice is in the library as an EPUB and an AZW3: a Kobo reads only the one.
+#[test]
+async fn a_kobo_downloads_only_what_it_reads() {
+    let dir = TempDir::new().unwrap();
+    let app = common::serve(config(&opened(&dir, 4))).await;
+    sync(&app, None).await;
+
+    let epub = test::call_service(&app, get(&format!("/kobo/{}/download/4/epub", TOKEN))).await;
+    assert_eq!(epub.status(), StatusCode::OK);
+    let azw3 = test::call_service(&app, get(&format!("/kobo/{}/download/4/azw3", TOKEN))).await;
+    assert_eq!(azw3.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+async fn what_orca_does_not_do_it_says_nothing_about() {
+    let app = setup().await;
+    let request = test::TestRequest::post().uri(&format!("/kobo/{}/v1/analytics/event", TOKEN)).to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json(response).await, json!({}));
+
+    let auth = test::TestRequest::post()
+        .uri(&format!("/kobo/{}/v1/auth/device", TOKEN))
+        .set_json(json!({ "UserKey": "a-kobo-user" }))
+        .to_request();
+    let tokens = json(test::call_service(&app, auth).await).await;
+    assert_eq!(tokens["UserKey"], "a-kobo-user");
+    assert_eq!(tokens["TokenType"], "Bearer");
+}
+
+// ------- Helper Functions -------
+
+async fn setup() -> impl Service<Request, Response = ServiceResponse, Error = actix_web::Error> {
+    common::serve(config(Path::new("tests/calibre"))).await
+}
+
+/// A copy of the test library in `dir`, in which Alice opened `book` on the
+/// desktop and got a fifth of the way through it.
+fn opened(dir: &TempDir, book: i32) -> PathBuf {
+    let library = dir.path().join("library");
+    common::copy(Path::new("tests/calibre"), &library);
+    Connection::open(library.join("metadata.db"))
+        .unwrap()
+        .execute(
+            "INSERT INTO last_read_positions (book, format, user, device, cfi, epoch, pos_frac)
+             VALUES (?1, 'EPUB', '_', '_', 'epubcfi(/10/4/2/1:0)', 1700000000, 0.2);",
+            [book],
+        )
+        .unwrap();
+    library
+}
+
+/// The test config, with Alice's Kobo and its library at `library`.
+fn config(library: &Path) -> &'static Config {
+    let dir = TempDir::new().unwrap();
+    let settings = fs::read_to_string("tests/orca.http.test.toml").unwrap();
+    let mut settings = settings.replace("path = \"tests/calibre\"", &format!("path = {:?}", library.display().to_string()));
+    settings.push_str(&format!(
+        "\n[kobo.devices.libra]\ntoken = \"{}\"\nlogin = \"alice\"\nlibrary = \"library\"\nshelves = [\"tag:science fiction\", \"reading\"]\n",
+        token_hash(TOKEN)
+    ));
+    let path = dir.path().join("orca.toml");
+    fs::write(&path, settings).unwrap();
+    Box::leak(Box::new(read_config(path.to_str().unwrap()).expect("the config")))
+}
+
+fn get(uri: &str) -> Request {
+    test::TestRequest::get().uri(uri).to_request()
+}
+
+/// One page of a sync, and the token the device keeps for the next.
+async fn sync(
+    app: &impl Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+    token: Option<&str>,
+) -> (Vec<Value>, Option<String>) {
+    let mut request = test::TestRequest::get().uri(&format!("/kobo/{}/v1/library/sync", TOKEN));
+    if let Some(token) = token {
+        request = request.insert_header(("x-kobo-synctoken", token));
+    }
+    let response = test::call_service(app, request.to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("x-kobo-sync").is_none(), "one page is enough for the test library");
+    let token = response.headers().get("x-kobo-synctoken").map(|token| token.to_str().unwrap().to_string());
+    assert!(token.as_ref().is_some_and(|token| BASE64.decode(token).is_ok()));
+    let entries = json(response).await;
+    (entries.as_array().expect("a list of changes").clone(), token)
+}
+
+async fn put_state(
+    app: &impl Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+    uuid: &str,
+    status: Value,
+    percent: u32,
+) -> ServiceResponse {
+    let state = json!({ "ReadingStates": [{
+        "EntitlementId": uuid,
+        "StatusInfo": status,
+        "CurrentBookmark": { "ProgressPercent": percent, "Location": { "Value": "kobo.1.1", "Type": "KoboSpan", "Source": "text/part0001.html" } },
+    }] });
+    let request = test::TestRequest::put().uri(&format!("/kobo/{}/v1/library/{}/state", TOKEN, uuid)).set_json(state).to_request();
+    let response = test::call_service(app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    response
+}
@@ -0,0 +1,131 @@
+//! KOReader's progress sync, the way KOReader's plugin talks to it
+//!
+//! KOReader sends the login and the MD5 of the password in `x-auth-user` and
+//! `x-auth-key`, and JSON both ways.
+
+mod common;
+
+use actix_http::Request;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::test;
+use common::{json, koreader, ALICE};
+use once_cell::sync::Lazy;
+use orca::config::{read_config, Config};
+use serde_json::{json, Value};
+
+static TEST_HTTP_CONFIG: Lazy<Config> =
+    Lazy::new(|| read_config("tests/orca.http.test.toml").expect("Failed to read test config"));
+
+const DOCUMENT: &str = "5c3ff3d1e0d18ea0a7b1b4d2c7e2b5a9";
+
+#[test]
+async fn the_server_says_it_is_there() {
+    let app = setup().await;
+    let response = test::call_service(&app, test::TestRequest::with_uri("/kosync/healthcheck").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json(response).await, json!({ "state": "OK" }));
+}
+
+#[test]
+async fn koreader_logs_in_with_the_md5_of_the_password() {
+    let app = setup().await;
+    let response = koreader(&app, test::TestRequest::get().uri("/kosync/users/auth"), ALICE).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json(response).await, json!({ "authorized": "OK" }));
+
+    let response = koreader(&app, test::TestRequest::get().uri("/kosync/users/auth"), ("alice", "secretpassword")).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(json(response).await["code"], 2001);
+
+    // Bob may read the catalog, but has no key for the sync.
+    let response = koreader(&app, test::TestRequest::get().uri("/kosync/users/auth"), ("bob", ALICE.1)).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+// The key is the MD5 of a password, sent with every sync: it does not open the catalog.
+#[test]
+async fn the_key_opens_nothing_but_the_sync() {
+    let app = setup().await;
+    let response = koreader(&app, test::TestRequest::get().uri("/v2/library"), ALICE).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+async fn logins_are_made_in_the_config_not_in_koreader() {
+    let app = setup().await;
+    let request = test::TestRequest::post()
+        .uri("/kosync/users/create")
+        .set_json(json!({ "username": "mallory", "password": "8d5e957f297893487bd98fa830fa6413" }));
+    let response = test::call_service(&app, request.to_request()).await;
+    assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+    assert_eq!(json(response).await["code"], 2005);
+}
+
+#[test]
+async fn the_last_position_synced_is_the_one_handed_out() {
+    let app = setup().await;
+    let nothing = koreader(&app, test::TestRequest::get().uri(&format!("/kosync/syncs/progress/{}", DOCUMENT)), ALICE).await;
+    assert_eq!(nothing.status(), StatusCode::OK);
+    assert_eq!(json(nothing).await, json!({}));
+
+    for (progress, percentage, device) in [("/body/DocFragment[3]/body/p[1]/text().0", 0.1, "Kobo"), ("/body/DocFragment[9]/body/p[4]/text().12", 0.42, "Boox")] {
+        let update = json!({
+            "document": DOCUMENT,
+            "progress": progress,
+            "percentage": percentage,
+            "device": device,
+            "device_id": format!("{}-0001", device),
+        });
+        let response = koreader(&app, test::TestRequest::put().uri("/kosync/syncs/progress").set_json(update), ALICE).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let saved = json(response).await;
+        assert_eq!(saved["document"], DOCUMENT);
+        assert!(saved["timestamp"].as_i64().unwrap() > 1_700_000_000);
+    }
+
+    let response = koreader(&app, test::TestRequest::get().uri(&format!("/kosync/syncs/progress/{}", DOCUMENT)), ALICE).await;
+    let position = json(response).await;
+    assert_eq!(position["document"], DOCUMENT);
+    assert_eq!(position["progress"], "/body/DocFragment[9]/body/p[4]/text().12");
+    assert_eq!(position["percentage"], 0.42);
+    assert_eq!(position["device"], "Boox");
+    assert_eq!(position["device_id"], "Boox-0001");
+    assert!(position["timestamp"].is_i64());
+}
+
+// A PDF is read by the page, and KOReader sends the page as a number.
+#[test]
+async fn a_page_number_comes_back_as_text() {
+    let app = setup().await;
+    let update = json!({ "document": DOCUMENT, "progress": 42, "percentage": 0.3, "device": "Kindle" });
+    let response = koreader(&app, test::TestRequest::put().uri("/kosync/syncs/progress").set_json(update), ALICE).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = koreader(&app, test::TestRequest::get().uri(&format!("/kosync/syncs/progress/{}", DOCUMENT)), ALICE).await;
+    let position = json(response).await;
+    assert_eq!((position["progress"].clone(), position["device_id"].clone()), (json!("42"), json!("")));
+}
+
+#[test]
+async fn an_update_without_what_it_updates_is_refused() {
+    let app = setup().await;
+    let refused = |body: Value| koreader(&app, test::TestRequest::put().uri("/kosync/syncs/progress").set_json(body), ALICE);
+
+    let response = refused(json!({ "progress": "x", "percentage": 0.5, "device": "Kobo" })).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(json(response).await["code"], 2004);
+
+    let response = refused(json!({ "document": DOCUMENT, "progress": "x", "device": "Kobo" })).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(json(response).await["code"], 2003);
+
+    let response = koreader(&app, test::TestRequest::put().uri("/kosync/syncs/progress").set_json(json!({})), ("alice", "")).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+// ------- Helper Functions -------
+
+async fn setup() -> impl Service<Request, Response = ServiceResponse, Error = actix_web::Error> {
+    common::serve(&TEST_HTTP_CONFIG).await
+}
@@ -57,12 +57,133 @@ async fn a_library_offers_its_books() {
     assert_eq!(library["metadata"]["title"], "library");
     assert_eq!(
         titles(&library["navigation"]),
-        ["All Books", "Recently Added", "Authors", "Tags"]
+        [
+            "All Books", "Recently Added", "Top Rated", "Authors", "Tags", "Series", "Publishers",
+            "Languages", "Ratings",
+            // The custom columns the config exposes that can be browsed.
+            "Translator", "Shelf location", "Saga",
+            // Calibre's virtual libraries, but for Poetry: nothing in it.
+            "Kids", "Reference", "To Read",
+            // What Calibre's saved searches and user categories hold.
+            "Saved Searches", "User Categories",
+        ]
     );
-    // Seven books, by eight authors, under seven tags.
+    // Seven books, five of them rated, by eight authors, under seven tags, in
+    // one series, from three publishers, in three languages, at four star
+    // levels, on two shelves.
     assert_eq!(library["navigation"][0]["properties"]["numberOfItems"], 7);
-    assert_eq!(library["navigation"][2]["properties"]["numberOfItems"], 8);
-    assert_eq!(library["navigation"][3]["properties"]["numberOfItems"], 7);
+    assert_eq!(library["navigation"][2]["properties"]["numberOfItems"], 5);
+    assert_eq!(library["navigation"][3]["properties"]["numberOfItems"], 8);
+    assert_eq!(library["navigation"][4]["properties"]["numberOfItems"], 7);
+    assert_eq!(library["navigation"][5]["properties"]["numberOfItems"], 1);
+    assert_eq!(library["navigation"][6]["properties"]["numberOfItems"], 3);
+    assert_eq!(library["navigation"][7]["properties"]["numberOfItems"], 3);
+    assert_eq!(library["navigation"][8]["properties"]["numberOfItems"], 4);
+    assert_eq!(library["navigation"][10]["properties"]["numberOfItems"], 2);
+    assert_eq!(library["navigation"][12]["properties"]["numberOfItems"], 1);
+    assert_eq!(library["navigation"][14]["properties"]["numberOfItems"], 2);
+    assert_eq!(library["navigation"][14]["href"], "http://localhost:8080/v2/library/virtual/To%20Read");
+    // What a saved search or a user category holds is looked up once it is opened.
+    assert!(library["navigation"][15]["properties"]["numberOfItems"].is_null());
+    assert_eq!(library["navigation"][16]["href"], "http://localhost:8080/v2/library/categories");
+    assert_eq!(library["navigation"][2]["rel"], "http://opds-spec.org/sort/popular");
+}
+
+// Rows of books on the start page. Nobody opened a book of the series in
+// Calibre's viewer, so there is no next one to read.
+#[test]
+async fn a_library_shows_a_few_books_right_away() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let library = feed(&app, "/v2/library").await;
+
+    let groups: Vec<&str> = library["groups"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|group| group["metadata"]["title"].as_str().unwrap())
+        .collect();
+    assert_eq!(groups, ["Recently Added", "Top Rated", "Random Picks"]);
+
+    let newest = &library["groups"][0];
+    assert_eq!(newest["links"][0]["rel"], "self");
+    assert_eq!(newest["links"][0]["href"], "http://localhost:8080/v2/library/books?sort=added");
+    assert_eq!(newest["publications"][0]["metadata"]["title"], "Galactic Patrol");
+    assert_eq!(library["groups"][1]["links"][0]["href"], "http://localhost:8080/v2/library/top");
+    assert_eq!(library["groups"][1]["publications"].as_array().unwrap().len(), 5);
+    assert_eq!(library["groups"][2]["publications"].as_array().unwrap().len(), 7);
+}
+
+// ------- Virtual libraries -------
+
+// "To Read" is what Calibre's search `rating:false` finds: the books nobody rated.
+#[test]
+async fn a_virtual_library_is_a_shelf_of_its_own() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let to_read = feed(&app, "/v2/library/virtual/To%20Read").await;
+
+    validates(&to_read, FEED);
+    assert_eq!(to_read["metadata"]["title"], "library | To Read");
+    assert_eq!(to_read["metadata"]["numberOfItems"], 2);
+    let unread: Vec<&str> = to_read["publications"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|publication| publication["metadata"]["title"].as_str().unwrap())
+        .collect();
+    assert_eq!(unread, ["The sidereal messenger of Galileo Galilei", "Гиперболоид инженера Гарина. Аэлита (Художник Г. Зубковский)"]);
+}
+
+#[test]
+async fn a_virtual_library_calibre_does_not_have_is_not_found() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let req = call_authorized(&app, "/v2/library/virtual/Cookbooks").await;
+    assert_eq!(req.status(), StatusCode::NOT_FOUND);
+}
+
+// ------- Saved searches and user categories -------
+
+// Cookbooks finds nothing in this library, so it is left out.
+#[test]
+async fn saved_searches_lead_to_the_books_they_find() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let searches = feed(&app, "/v2/library/searches").await;
+
+    validates(&searches, FEED);
+    assert_eq!(searches["metadata"]["title"], "library | Saved Searches");
+    assert_eq!(titles(&searches["navigation"]), ["Cosmic", "Horror"]);
+    assert_eq!(searches["navigation"][0]["properties"]["numberOfItems"], 3);
+    assert_eq!(searches["navigation"][1]["href"], "http://localhost:8080/v2/library/searches/Horror");
+
+    let horror = feed(&app, "/v2/library/searches/Horror").await;
+    validates(&horror, FEED);
+    assert_eq!(horror["metadata"]["title"], "library | Horror");
+    assert_eq!(horror["publications"][0]["metadata"]["title"], "At the Mountains of Madness");
+
+    let req = call_authorized(&app, "/v2/library/searches/Gardening").await;
+    assert_eq!(req.status(), StatusCode::NOT_FOUND);
+}
+
+// Gone lists nobody the library has books by, so only Favourites is shown.
+#[test]
+async fn a_user_category_leads_to_what_it_lists() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let categories = feed(&app, "/v2/library/categories").await;
+
+    validates(&categories, FEED);
+    assert_eq!(titles(&categories["navigation"]), ["Favourites"]);
+    assert_eq!(categories["navigation"][0]["properties"]["numberOfItems"], 3);
+
+    let favourites = feed(&app, "/v2/library/categories/Favourites").await;
+    validates(&favourites, FEED);
+    assert_eq!(favourites["metadata"]["title"], "library | Favourites");
+    assert_eq!(titles(&favourites["navigation"]), ["Astounding Stories", "H. P. Lovecraft", "philosophy"]);
+    assert_eq!(favourites["navigation"][0]["href"], "http://localhost:8080/v2/library/series/1");
+    assert_eq!(favourites["navigation"][1]["href"], "http://localhost:8080/v2/library/authors/9");
+    assert_eq!(favourites["navigation"][2]["href"], "http://localhost:8080/v2/library/tags/8");
+    assert_eq!(favourites["navigation"][0]["properties"]["numberOfItems"], 3);
+
+    let req = call_authorized(&app, "/v2/library/categories/Nothing").await;
+    assert_eq!(req.status(), StatusCode::NOT_FOUND);
 }
 
 // ------- Browsing by author and by tag -------
@@ -83,6 +204,76 @@ async fn the_library_can_be_browsed_by_author_and_by_tag() {
     }
 }
 
+// Three volumes of Astounding Stories, in the order they were published
+// rather than the order of their titles.
+#[test]
+async fn the_library_can_be_browsed_by_series() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let series = feed(&app, "/v2/library/series").await;
+
+    validates(&series, FEED);
+    assert_eq!(series["metadata"]["title"], "library | Series");
+    assert_eq!(titles(&series["navigation"]), ["Astounding Stories"]);
+    assert_eq!(series["navigation"][0]["properties"]["numberOfItems"], 3);
+
+    let href = series["navigation"][0]["href"].as_str().unwrap();
+    let volumes = feed(&app, href.strip_prefix("http://localhost:8080").unwrap()).await;
+    validates(&volumes, FEED);
+    assert_eq!(volumes["metadata"]["title"], "library | Astounding Stories");
+    let read_in_order: Vec<&str> = volumes["publications"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|publication| publication["metadata"]["title"].as_str().unwrap())
+        .collect();
+    assert_eq!(read_in_order, ["Vampires of Space", "At the Mountains of Madness", "Galactic Patrol"]);
+}
+
+#[test]
+async fn the_library_can_be_browsed_by_publisher_and_by_language() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+
+    for (path, title, expected) in [
+        ("/v2/library/publishers", "library | Publishers",
+         ["Gutenberg Project", "Projekt Gutenberg", "Street & Smith"]),
+        // Each language by the name it has for itself.
+        ("/v2/library/languages", "library | Languages", ["Deutsch", "English", "русский"]),
+    ] {
+        let shelves = feed(&app, path).await;
+        validates(&shelves, FEED);
+        assert_eq!(shelves["metadata"]["title"], title);
+        assert_eq!(titles(&shelves["navigation"]), expected);
+    }
+}
+
+#[test]
+async fn a_language_shelf_holds_the_books_written_in_it() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let languages = feed(&app, "/v2/library/languages").await;
+
+    let deutsch = &languages["navigation"][0];
+    assert_eq!(deutsch["properties"]["numberOfItems"], 1);
+
+    let books = feed(&app, deutsch["href"].as_str().unwrap().strip_prefix("http://localhost:8080").unwrap()).await;
+    validates(&books, FEED);
+    assert_eq!(books["metadata"]["title"], "library | Deutsch");
+    assert_eq!(
+        books["publications"][0]["metadata"]["title"],
+        "Kritik der reinen Vernunft - 2. Auflage"
+    );
+}
+
+// A volume leads back to the rest of its series.
+#[test]
+async fn a_series_leads_to_its_own_feed() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let patrol = publication(&app, 9).await;
+
+    let series = &patrol["metadata"]["belongsTo"]["series"];
+    assert_eq!(series["links"][0]["href"], "http://localhost:8080/v2/library/series/1");
+    assert_eq!(series["links"][0]["type"], "application/opds+json");
+}
+
 // A navigation entry doesn't lie about its size
 #[test]
 async fn a_shelf_says_how_much_stands_on_it() {
@@ -120,7 +311,10 @@ async fn a_shelf_holds_only_what_belongs_on_it() {
 async fn a_shelf_that_is_not_there_is_404() {
     let app = setup(&TEST_HTTP_CONFIG).await;
 
-    for path in ["/v2/library/authors/99999", "/v2/library/tags/99999"] {
+    for path in ["/v2/library/authors/99999", "/v2/library/tags/99999", "/v2/library/series/99999",
+                 "/v2/library/publishers/99999", "/v2/library/languages/99999",
+                 // Nothing was rated two and a half stars, and nothing rates eleven.
+                 "/v2/library/ratings/5", "/v2/library/ratings/11"] {
         assert_eq!(call_authorized(&app, path).await.status(), StatusCode::NOT_FOUND, "{}", path);
     }
 }
@@ -203,6 +397,17 @@ async fn a_library_that_is_not_there_is_404() {
         "/v2/nope/authors/4",
         "/v2/nope/tags",
         "/v2/nope/tags/9",
+        "/v2/nope/series",
+        "/v2/nope/series/1",
+        "/v2/nope/publishers",
+        "/v2/nope/publishers/4",
+        "/v2/nope/languages",
+        "/v2/nope/languages/3",
+        "/v2/nope/columns/shelf",
+        "/v2/nope/columns/shelf/2",
+        "/v2/nope/top",
+        "/v2/nope/ratings",
+        "/v2/nope/ratings/8",
     ] {
         assert_eq!(call_authorized(&app, path).await.status(), StatusCode::NOT_FOUND, "{}", path);
     }
@@ -221,6 +426,17 @@ async fn opds2_is_no_more_public_than_opds1() {
         "/v2/library/authors/4",
         "/v2/library/tags",
         "/v2/library/tags/9",
+        "/v2/library/series",
+        "/v2/library/series/1",
+        "/v2/library/publishers",
+        "/v2/library/publishers/4",
+        "/v2/library/languages",
+        "/v2/library/languages/3",
+        "/v2/library/columns/shelf",
+        "/v2/library/columns/shelf/2",
+        "/v2/library/top",
+        "/v2/library/ratings",
+        "/v2/library/ratings/8",
     ] {
         assert_eq!(call(&app, path).await.status(), StatusCode::UNAUTHORIZED, "{}", path);
     }
@@ -262,6 +478,60 @@ async fn a_publication_carries_the_shelf_it_came_off() {
     assert_eq!(metadata["belongsTo"]["series"]["position"], 3.0);
 }
 
+// A client picks the smallest cover that fills the space it has.
+#[test]
+async fn a_publication_offers_its_cover_in_several_sizes() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let alice = publication(&app, 4).await;
+
+    validates(&alice, PUBLICATION);
+    let images = &alice["images"];
+    assert_eq!(
+        rels(images),
+        [
+            "http://opds-spec.org/image",
+            "http://opds-spec.org/image/thumbnail",
+            "http://opds-spec.org/image/thumbnail",
+            "http://opds-spec.org/image/thumbnail",
+        ]
+    );
+    assert_eq!(images[1]["href"], "http://localhost:8080/library/thumb/4?w=240");
+    assert_eq!((&images[1]["width"], &images[1]["height"]), (&Value::from(240), &Value::from(360)));
+    assert_eq!(images[3]["width"], 960);
+    // Calibre's cover is whatever size it is.
+    assert!(images[0].get("width").is_none());
+}
+
+// What another catalog knows the book by comes first among the alternatives
+// to the uuid.
+#[test]
+async fn an_isbn_identifies_a_book_beyond_this_catalog() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let alice = publication(&app, 4).await;
+
+    validates(&alice, PUBLICATION);
+    assert_eq!(
+        alice["metadata"]["altIdentifier"],
+        serde_json::json!([
+            "urn:isbn:9780141439761",
+            "https://www.goodreads.com/book/show/24213",
+        ])
+    );
+
+    // No ISBN: the other identifiers are all there is besides the uuid.
+    let kant = publication(&app, 5).await;
+    validates(&kant, PUBLICATION);
+    assert!(kant["metadata"]["identifier"].as_str().unwrap().starts_with("urn:uuid:"));
+    assert_eq!(
+        kant["metadata"]["altIdentifier"],
+        serde_json::json!(["https://www.projekt-gutenberg.org/kant/krvb/krvb.html"])
+    );
+
+    // Nothing but the uuid, and nothing to add to it.
+    let tolstoy = publication(&app, 2).await;
+    assert!(tolstoy["metadata"].get("altIdentifier").is_none());
+}
+
 // no empty `belongsTo`
 #[test]
 async fn a_book_on_no_shelf_belongs_to_nothing() {
@@ -273,6 +543,146 @@ async fn a_book_on_no_shelf_belongs_to_nothing() {
     assert_eq!(names(&alice["metadata"]["subject"]), ["children", "fantasy", "fiction"]);
 }
 
+// Whoever finished a volume is one step from the next.
+#[test]
+async fn a_publication_leads_on_to_its_neighbours() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let madness = publication(&app, 8).await;
+
+    validates(&madness, PUBLICATION);
+    let related: Vec<&Value> = madness["links"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|link| link["rel"] == "related")
+        .collect();
+    assert_eq!(
+        titles(&Value::Array(related.iter().map(|&link| link.clone()).collect())),
+        [
+            "Previous in Astounding Stories: Vampires of Space",
+            "Next in Astounding Stories: Galactic Patrol",
+            // The other science fiction in the library.
+            "Гиперболоид инженера Гарина. Аэлита (Художник Г. Зубковский)",
+        ]
+    );
+    assert_eq!(related[1]["href"], "http://localhost:8080/v2/library/book/9");
+    assert_eq!(related[1]["type"], "application/opds-publication+json");
+
+    // The last volume has nothing after it.
+    let patrol = publication(&app, 9).await;
+    assert!(!titles(&patrol["links"]).iter().any(|title| title.starts_with("Next in")));
+}
+
+// ------- Custom columns -------
+
+// ------- Ratings -------
+
+// Calibre rates out of ten; a publication says it in stars, halves allowed.
+#[test]
+async fn a_publication_carries_its_rating() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+
+    let alice = publication(&app, 4).await;
+    validates(&alice, PUBLICATION);
+    assert_eq!(alice["metadata"]["rating"], 5.0);
+    assert_eq!(publication(&app, 8).await["metadata"]["rating"], 3.5);
+    // Nobody rated Tolstoy.
+    assert!(publication(&app, 2).await["metadata"].get("rating").is_none());
+}
+
+// Kant and the Lensman both have four stars: the library's order decides.
+#[test]
+async fn the_top_rated_come_best_first() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let top = feed(&app, "/v2/library/top").await;
+
+    validates(&top, FEED);
+    assert_eq!(top["metadata"]["title"], "library | Top Rated");
+    assert_eq!(top["metadata"]["numberOfItems"], 5);
+    let ratings: Vec<f64> = top["publications"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|publication| publication["metadata"]["rating"].as_f64().expect("a rating"))
+        .collect();
+    assert_eq!(ratings, [5.0, 4.0, 4.0, 3.5, 2.0]);
+}
+
+#[test]
+async fn a_rating_is_a_shelf_of_its_own() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let ratings = feed(&app, "/v2/library/ratings").await;
+
+    validates(&ratings, FEED);
+    assert_eq!(ratings["metadata"]["title"], "library | Ratings");
+    assert_eq!(titles(&ratings["navigation"]), ["★★★★★", "★★★★", "★★★½", "★★"]);
+    assert_eq!(ratings["navigation"][1]["href"], "http://localhost:8080/v2/library/ratings/8");
+    assert_eq!(ratings["navigation"][1]["properties"]["numberOfItems"], 2);
+
+    let four = feed(&app, "/v2/library/ratings/8").await;
+    validates(&four, FEED);
+    assert_eq!(four["metadata"]["title"], "library | ★★★★");
+    assert_eq!(four["metadata"]["numberOfItems"], 2);
+    assert_eq!(four["publications"][0]["metadata"]["rating"], 4.0);
+}
+
+// Under the lookup name a Calibre user knows the column by.
+#[test]
+async fn a_publication_carries_its_custom_columns() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let alice = publication(&app, 4).await;
+
+    validates(&alice, PUBLICATION);
+    let metadata = &alice["metadata"];
+    assert_eq!(metadata["#read"], true);
+    assert_eq!(metadata["#finished"], "2025-03-14T18:30:00+00:00");
+    assert_eq!(metadata["#review"], "Curiouser & curiouser!");
+    // A value books share leads to the rest of them, the same as a subject.
+    assert_eq!(metadata["#shelf"]["name"], "Living room");
+    assert_eq!(metadata["#shelf"]["links"][0]["href"], "http://localhost:8080/v2/library/columns/shelf/1");
+
+    // A column that takes several values is a list, even of one.
+    let galileo = publication(&app, 6).await;
+    assert_eq!(names(&galileo["metadata"]["#translator"]), ["Edward Stafford Carlos"]);
+
+    let patrol = publication(&app, 9).await;
+    assert_eq!(patrol["metadata"]["#saga"]["name"], "Lensman");
+    assert_eq!(patrol["metadata"]["#saga"]["position"], 1.0);
+    // Nothing to say, nothing said.
+    assert!(patrol["metadata"].get("#read").is_none());
+}
+
+#[test]
+async fn a_custom_column_is_browsed_like_a_tag() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let locations = feed(&app, "/v2/library/columns/shelf").await;
+
+    validates(&locations, FEED);
+    assert_eq!(locations["metadata"]["title"], "library | Shelf location");
+    assert_eq!(titles(&locations["navigation"]), ["Living room", "Study"]);
+
+    let href = locations["navigation"][1]["href"].as_str().unwrap();
+    let study = feed(&app, href.strip_prefix("http://localhost:8080").unwrap()).await;
+    validates(&study, FEED);
+    assert_eq!(study["metadata"]["title"], "library | Shelf location: Study");
+    assert_eq!(study["metadata"]["numberOfItems"], 2);
+    assert_eq!(study["publications"][0]["metadata"]["#shelf"]["name"], "Study");
+}
+
+// `#stars` is in the library but not in the config; `#read` has no values to
+// browse; `#nope` is nowhere.
+#[test]
+async fn a_column_that_is_not_exposed_is_not_there() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+
+    for path in ["/v2/library/columns/stars", "/v2/library/columns/stars/1", "/v2/library/columns/read",
+                 "/v2/library/columns/nope", "/v2/library/columns/shelf/99999"] {
+        assert_eq!(call_authorized(&app, path).await.status(), StatusCode::NOT_FOUND, "{}", path);
+    }
+    let kant = publication(&app, 5).await;
+    assert!(kant["metadata"].get("#stars").is_none());
+}
+
 // The Atom feed wraps a blurb at 100 columns to fit `<content type="text">`.
 // In JSON the same blurb keeps only the breaks its author wrote.
 #[test]
@@ -322,8 +732,8 @@ async fn what_a_publication_links_to_can_be_downloaded() {
         assert!(call_authorized(&app, path).await.status().is_success(), "{}", href);
         followed += 1;
     }
-    // self, two acquisitions, one cover.
-    assert_eq!(followed, 4);
+    // self, two acquisitions, the annotations, one related book, one cover and three thumbnails.
+    assert_eq!(followed, 9);
 }
 
 // ------- Paging -------
@@ -353,6 +763,106 @@ async fn a_page_past_the_end_still_holds_books() {
     assert_eq!(books["links"][0]["href"], "http://localhost:8080/v2/library/books");
 }
 
+// ------- An index by first letter -------
+
+// library2 lists no more than three of anything before it offers their first letters.
+#[test]
+async fn a_long_list_starts_with_an_index_of_first_letters() {
+    let app = setup(&TEST_HTTPS_CONFIG).await;
+    let authors = feed(&app, "/v2/library2/authors").await;
+
+    validates(&authors, FEED);
+    assert_eq!(titles(&authors["navigation"]), ["C", "G", "K", "L", "S", "W", "Т"]);
+    assert_eq!(authors["navigation"][2]["properties"]["numberOfItems"], 2);
+    assert_eq!(authors["navigation"][2]["href"], "http://localhost:8080/v2/library2/authors?letter=K");
+    // Filed under the sort, not the name: Tolstoy in Cyrillic capitals.
+    assert_eq!(authors["navigation"][6]["href"], "http://localhost:8080/v2/library2/authors?letter=%D0%A2");
+
+    let k = feed(&app, "/v2/library2/authors?letter=K").await;
+    validates(&k, FEED);
+    assert_eq!(k["metadata"]["title"], "library2 | Authors: K");
+    assert_eq!(k["metadata"]["numberOfItems"], 2);
+    assert_eq!(titles(&k["navigation"]), ["Immanuel Kant", "Johannes Kepler"]);
+    assert_eq!(k["links"][0]["href"], "http://localhost:8080/v2/library2/authors?letter=K");
+
+    // Below the threshold the list is the list, a page at a time.
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let authors = feed(&app, "/v2/library/authors").await;
+    assert_eq!(authors["metadata"]["numberOfItems"], 8);
+    assert_eq!(authors["metadata"]["currentPage"], 1);
+}
+
+#[test]
+async fn a_large_library_is_browsed_by_title_from_a_to_z() {
+    let app = setup(&TEST_HTTPS_CONFIG).await;
+    let library = feed(&app, "/v2/library2").await;
+    assert_eq!(library["navigation"][0]["href"], "http://localhost:8080/v2/library2/titles");
+
+    let index = feed(&app, "/v2/library2/titles").await;
+    validates(&index, FEED);
+    assert_eq!(titles(&index["navigation"]), ["A", "G", "K", "S", "V", "Г"]);
+    assert_eq!(index["navigation"][0]["properties"]["numberOfItems"], 2);
+
+    let a = feed(&app, "/v2/library2/titles/A").await;
+    validates(&a, FEED);
+    assert_eq!(a["metadata"]["title"], "library2 | Titles: A");
+    assert_eq!(a["metadata"]["numberOfItems"], 2);
+    assert_eq!(a["publications"][1]["metadata"]["title"], "At the Mountains of Madness");
+    // "The sidereal messenger" is filed under S, the way Calibre sorts it.
+    assert_eq!(feed(&app, "/v2/library2/titles/S").await["metadata"]["numberOfItems"], 1);
+}
+
+// ------- Facets -------
+
+#[test]
+async fn a_book_feed_can_be_sorted_another_way() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let newest = feed(&app, "/v2/library/books?sort=added").await;
+
+    validates(&newest, FEED);
+    assert_eq!(newest["publications"][0]["metadata"]["title"], "Galactic Patrol");
+    assert_eq!(newest["links"][0]["href"], "http://localhost:8080/v2/library/books?sort=added");
+
+    let sort = &newest["facets"][0];
+    assert_eq!(sort["metadata"]["title"], "Sort by");
+    assert_eq!(
+        titles(&sort["links"]),
+        ["Title", "Author", "Newest published", "Newest added", "Best rated", "Series"]
+    );
+    // The sort in use is the feed itself.
+    assert_eq!(sort["links"][3]["rel"], "self");
+    assert!(sort["links"][0].get("rel").is_none());
+    assert_eq!(sort["links"][0]["href"], "http://localhost:8080/v2/library/books?sort=title");
+}
+
+// Every facet keeps the others: narrowing a sorted feed down leaves it sorted.
+#[test]
+async fn a_book_feed_can_be_narrowed_down() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let english = feed(&app, "/v2/library/tags/9?sort=published&language=4").await;
+
+    validates(&english, FEED);
+    assert_eq!(english["metadata"]["numberOfItems"], 3);
+    let groups: Vec<&str> = english["facets"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|group| group["metadata"]["title"].as_str().unwrap())
+        .collect();
+    assert_eq!(groups, ["Sort by", "Format", "Language", "Cover"]);
+    let covers = &english["facets"][3]["links"][1];
+    assert_eq!(covers["title"], "With a cover");
+    assert_eq!(
+        covers["href"],
+        "http://localhost:8080/v2/library/tags/9?sort=published&language=4&cover=true"
+    );
+
+    let req = call_authorized(&app, "/v2/library/books?format=mobi").await;
+    let books = json(req).await;
+    assert_eq!(books["metadata"]["numberOfItems"], 1);
+    assert_eq!(books["publications"][0]["metadata"]["title"], "The sidereal messenger of Galileo Galilei");
+}
+
 // ------- search -------
 
 // A client finds the search endpoint by expanding this
@@ -361,7 +871,10 @@ async fn every_feed_of_a_library_says_how_to_search_it() {
     let app = setup(&TEST_HTTP_CONFIG).await;
 
     for path in ["/v2/library", "/v2/library/books", "/v2/library/new", "/v2/library/authors",
-                 "/v2/library/tags", "/v2/library/authors/4", "/v2/library/tags/9"] {
+                 "/v2/library/tags", "/v2/library/authors/4", "/v2/library/tags/9",
+                 "/v2/library/series", "/v2/library/series/1", "/v2/library/publishers",
+                 "/v2/library/languages/3", "/v2/library/top", "/v2/library/ratings",
+                 "/v2/library/ratings/8"] {
         let feed = feed(&app, path).await;
         let search = feed["links"]
             .as_array()
@@ -463,6 +976,56 @@ async fn a_search_term_is_escaped_into_its_own_address() {
     assert_eq!(found["metadata"]["numberOfItems"], 0);
 }
 
+// Calibre's own search language, the same as in its search bar: the science
+// fiction nobody rated above three stars.
+#[test]
+async fn a_search_understands_calibres_search_language() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let found = feed(&app, "/v2/library/search?query=tag%3A%22science%20fiction%22%20and%20not%20rating%3A%3E3").await;
+
+    validates(&found, FEED);
+    assert_eq!(found["metadata"]["title"], r#"library | Search: tag:"science fiction" and not rating:>3"#);
+    let found: Vec<&str> = found["publications"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|publication| publication["metadata"]["title"].as_str().unwrap())
+        .collect();
+    assert_eq!(found, ["Vampires of Space", "Гиперболоид инженера Гарина. Аэлита (Художник Г. Зубковский)"]);
+}
+
+// Толстой, as typed on a keyboard without Cyrillic letters
+#[test]
+async fn a_search_forgives_a_foreign_spelling() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let found = feed(&app, "/v2/library/search?query=tolstoi").await;
+
+    validates(&found, FEED);
+    assert_eq!(found["metadata"]["numberOfItems"], 1);
+    assert_eq!(
+        found["publications"][0]["metadata"]["title"],
+        "Гиперболоид инженера Гарина. Аэлита (Художник Г. Зубковский)"
+    );
+}
+
+// Which book holds the quote, and where: the passage is its description.
+#[test]
+async fn a_search_finds_a_quote_inside_a_book() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let found = feed(&app, "/v2/library/search?query=%22what%20is%20the%20use%20of%20a%20book%22&in=content").await;
+
+    validates(&found, FEED);
+    assert_eq!(found["metadata"]["numberOfItems"], 1);
+    let alice = &found["publications"][0]["metadata"];
+    assert_eq!(alice["title"], "Alice's Adventures in Wonderland");
+    let passage = alice["description"].as_str().unwrap();
+    assert!(passage.contains("\"and **what is the use of a book**,\" thought Alice"), "{}", passage);
+
+    // The metadata knows nothing of it.
+    let elsewhere = feed(&app, "/v2/library/search?query=%22what%20is%20the%20use%20of%20a%20book%22").await;
+    assert_eq!(elsewhere["metadata"]["numberOfItems"], 0);
+}
+
 // https://xkcd.com/327/
 #[test]
 async fn a_search_cannot_drop_the_students_table() {
@@ -480,6 +1043,25 @@ async fn a_search_cannot_drop_the_students_table() {
     assert_eq!(books["metadata"]["numberOfItems"], 7);
 }
 
+// Calibre's note on an author is what the feed of their books is about.
+#[test]
+async fn a_note_in_calibre_describes_its_author() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+
+    let kant = feed(&app, "/v2/library/authors/5").await;
+    validates(&kant, FEED);
+    let description = kant["metadata"]["description"].as_str().expect("a description");
+    assert!(description.starts_with("Immanuel Kant (1724–1804), philosopher of"), "{}", description);
+    assert!(!description.contains('<'), "plain text: {}", description);
+
+    let carroll = feed(&app, "/v2/library/authors/4").await;
+    assert!(carroll["metadata"].get("description").is_none());
+
+    for shelf in ["/v2/library/tags/9", "/v2/library/series/1"] {
+        assert!(feed(&app, shelf).await["metadata"]["description"].is_string(), "{}", shelf);
+    }
+}
+
 // does the schema catch a feed without a `self` link
 #[test]
 async fn the_schema_check_has_teeth() {
@@ -492,6 +1074,120 @@ async fn the_schema_check_has_teeth() {
     assert!(validate(&feed, FEED).is_err());
 }
 
+// The JSON feeds carry the same validators as the Atom ones.
+#[test]
+async fn a_publication_is_not_sent_again_while_the_library_is_unchanged() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let response = call_authorized(&app, "/v2/library/book/5").await;
+    let etag = response.headers().get(header::ETAG).expect("an etag").clone();
+
+    let credentials = BASE64.encode("alice:secretpassword");
+    let request = test::TestRequest::with_uri("/v2/library/book/5")
+        .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
+        .insert_header((header::IF_NONE_MATCH, etag.clone()))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+    let response = call_authorized(&app, "/v2/library/book/6").await;
+    assert_ne!(response.headers().get(header::ETAG).unwrap(), etag);
+}
+
+// ------- Annotations -------
+
+const ANNOTATIONS: &str = r#"application/ld+json; profile="http://www.w3.org/ns/anno.jsonld""#;
+
+// What Alice marked in Calibre's viewer, as Web Annotations on the book file.
+#[test]
+async fn a_reader_sees_their_highlights_and_bookmarks() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let response = call_authorized(&app, "/v2/library/book/5/annotations").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), ANNOTATIONS);
+
+    let kant = json(response).await;
+    assert_eq!(kant["@context"], "http://www.w3.org/ns/anno.jsonld");
+    assert_eq!(kant["type"], "AnnotationCollection");
+    assert_eq!(kant["id"], "http://localhost:8080/v2/library/book/5/annotations");
+    assert_eq!(kant["total"], 2);
+
+    let items = &kant["first"]["items"];
+    let highlight = &items[0];
+    assert_eq!(highlight["motivation"], "highlighting");
+    assert_eq!(highlight["id"], "http://localhost:8080/v2/library/book/5/annotations#b5YWb1mBp2mS3jNi8fGZ7A");
+    assert_eq!(highlight["modified"], "2024-12-01T10:00:00Z");
+    assert_eq!(highlight["body"][0]["value"], "Der erste Satz der Einleitung");
+    assert_eq!(highlight["target"]["source"], "http://localhost:8080/library/file/5/epub");
+    assert!(highlight["target"]["selector"][0]["exact"].as_str().unwrap().starts_with("Daß alle unsere Erkenntnis"));
+    assert_eq!(highlight["target"]["selector"][1]["value"], "epubcfi(/6/8!/4/2/6,/1:0,/1:80)");
+
+    let bookmark = &items[1];
+    assert_eq!(bookmark["motivation"], "bookmarking");
+    assert_eq!(bookmark["body"][0]["value"], "Transzendentale Ästhetik");
+    assert_eq!(bookmark["target"]["selector"][0]["value"], "epubcfi(/6/10!/4/2/1:0)");
+
+    // The file an annotation is on is there to download.
+    assert!(call_authorized(&app, "/library/file/5/epub").await.status().is_success());
+}
+
+// Bob's highlight is his own, and a login nobody mapped to Calibre has none.
+#[test]
+async fn annotations_stay_with_whoever_made_them() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let request = test::TestRequest::with_uri("/v2/library/book/5/annotations")
+        .insert_header((header::AUTHORIZATION, format!("Basic {}", BASE64.encode("bob:secretpassword"))))
+        .to_request();
+    let bob = json(test::call_service(&app, request).await).await;
+    assert_eq!(bob["total"], 1);
+    assert_eq!(bob["first"]["items"][0]["target"]["selector"][1]["value"], "epubcfi(/6/16!/4/2,/12/1:10,/14/1:20)");
+    assert!(bob["first"]["items"][0].get("body").is_none());
+
+    let app = setup(&TEST_HTTPS_CONFIG).await;
+    let nobody = json(call_authorized(&app, "/v2/library/book/5/annotations").await).await;
+    assert_eq!(nobody["total"], 0);
+    assert_eq!(nobody["first"]["items"], serde_json::json!([]));
+
+    assert_eq!(call(&app, "/v2/library/book/5/annotations").await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(call_authorized(&app, "/v2/library/book/999/annotations").await.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+async fn a_publication_links_to_its_annotations() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let kant = publication(&app, 5).await;
+    validates(&kant, PUBLICATION);
+
+    let link = kant["links"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|link| link["rel"] == "http://www.w3.org/ns/oa#annotationService")
+        .expect("a link to the annotations");
+    assert_eq!(link["href"], "http://localhost:8080/v2/library/book/5/annotations");
+    assert_eq!(link["type"], ANNOTATIONS);
+}
+
+// A search looks through the reader's annotations on every book; a page of
+// the results stands on its own.
+#[test]
+async fn annotations_are_searched_by_their_words() {
+    let app = setup(&TEST_HTTP_CONFIG).await;
+    let found = json(call_authorized(&app, "/v2/library/annotations?query=curiouser").await).await;
+    assert_eq!(found["total"], 1);
+    assert_eq!(found["id"], "http://localhost:8080/v2/library/annotations?query=curiouser");
+    assert_eq!(found["first"]["items"][0]["target"]["source"], "http://localhost:8080/library/file/4/epub");
+
+    let bobs = json(call_authorized(&app, "/v2/library/annotations?query=Gedanken").await).await;
+    assert_eq!(bobs["total"], 0);
+
+    let page = json(call_authorized(&app, "/v2/library/annotations?query=erfahr&page=1").await).await;
+    assert_eq!(page["type"], "AnnotationPage");
+    assert_eq!(page["id"], "http://localhost:8080/v2/library/annotations?query=erfahr&page=1");
+    assert_eq!(page["partOf"]["id"], "http://localhost:8080/v2/library/annotations?query=erfahr");
+    assert_eq!(page["partOf"]["total"], 1);
+    assert_eq!(page["startIndex"], 0);
+}
+
 // ------- OPDS 1.2 is untouched -------
 
 // `/v2` is registered before `/{lib}`, which would otherwise swallow it.
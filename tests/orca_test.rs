@@ -103,6 +103,54 @@ async fn book_ids_are_uuid_urns() {
     assert!(!content.contains("<id>4</id>"));
 }
 
+// Dublin Core is how an Atom entry says which ISBN it is.
+#[test]
+async fn entries_carry_the_identifiers_calibre_holds() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+    let alice = body_of(&app, "/library/authors/4", &credentials).await;
+
+    assert!(alice.contains(r#"xmlns:dc="http://purl.org/dc/terms/""#));
+    assert!(alice.contains("<dc:identifier>urn:isbn:9780141439761</dc:identifier>"), "{}", alice);
+    assert!(alice.contains("<dc:identifier>https://www.goodreads.com/book/show/24213</dc:identifier>"));
+    // An ASIN has no URI to go by.
+    assert!(!alice.contains("B004TS0T1S"));
+}
+
+// Whoever finished a volume is one step from the next: every entry links to
+// the complete entries of the books around it.
+#[test]
+async fn an_entry_leads_on_to_its_neighbours() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+    let series = body_of(&app, "/library/series/1", &credentials).await;
+
+    assert!(
+        series.contains(r#"<link href="/library/book/9" type="application/atom+xml;type=entry;profile=opds-catalog" rel="related" title="Next in Astounding Stories: Galactic Patrol"/>"#),
+        "{}",
+        series
+    );
+
+    let req = test::TestRequest::with_uri("/library/book/8")
+        .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/atom+xml;type=entry;profile=opds-catalog"
+    );
+    let madness = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+    assert!(madness.contains("<title>At the Mountains of Madness</title>"));
+    assert!(madness.contains(r#"title="Previous in Astounding Stories: Vampires of Space""#));
+    assert_eq!(count_links(&madness, "/library/book/2"), 1);
+
+    let req = test::TestRequest::with_uri("/library/book/99999")
+        .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+}
+
 // Atom requires a feed id to be permanent, so it is derived from the request
 // path rather than the full URL
 #[test]
@@ -117,6 +165,11 @@ async fn each_feed_carries_its_own_id() {
         ("/library/tags", "<id>urn:orca:library:tags</id>"),
         ("/library/authors/5", "<id>urn:orca:library:authors:5</id>"),
         ("/library/tags/5", "<id>urn:orca:library:tags:5</id>"),
+        ("/library/series", "<id>urn:orca:library:series</id>"),
+        ("/library/series/1", "<id>urn:orca:library:series:1</id>"),
+        ("/library/publishers/4", "<id>urn:orca:library:publishers:4</id>"),
+        ("/library/languages/3", "<id>urn:orca:library:languages:3</id>"),
+        ("/library/columns/shelf/2", "<id>urn:orca:library:columns:shelf:2</id>"),
     ] {
         let req = test::TestRequest::with_uri(path)
             .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
@@ -140,6 +193,15 @@ async fn entries_are_identified_by_what_they_refer_to() {
         ("/library", "<id>urn:orca:library:books</id>"),
         ("/library/authors", "<id>urn:orca:library:author:5</id>"),
         ("/library/tags", "<id>urn:orca:library:tag:5</id>"),
+        ("/library", "<id>urn:orca:library:series</id>"),
+        ("/library/series", "<id>urn:orca:library:series:1</id>"),
+        ("/library", "<id>urn:orca:library:publishers</id>"),
+        ("/library", "<id>urn:orca:library:languages</id>"),
+        ("/library/publishers", "<id>urn:orca:library:publisher:4</id>"),
+        ("/library/languages", "<id>urn:orca:library:language:3</id>"),
+        ("/library", "<id>urn:orca:library:columns:shelf</id>"),
+        ("/library/columns/shelf", "<id>urn:orca:library:column:shelf:2</id>"),
+        ("/library", "<id>urn:orca:library:virtual:To%20Read</id>"),
     ] {
         let req = test::TestRequest::with_uri(path)
             .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
@@ -199,6 +261,43 @@ async fn recently_added_lists_the_newest_arrivals_first() {
     assert_eq!(order, sorted, "books are not ordered newest first");
 }
 
+// Best first; Kant and the Lensman share four stars and keep the library's order.
+#[test]
+async fn top_rated_lists_the_best_rated_first() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+    let content = body_of(&app, "/library/top", &credentials).await;
+
+    assert!(content.contains("<title>library | Top Rated</title>"));
+    assert_eq!(count_items(&content), 5);
+    assert!(content.contains("Rating: ★★★★★"));
+
+    let order: Vec<usize> = ["Carroll", "Smith", "Kant", "Lovecraft", "Wright"]
+        .iter()
+        .map(|name| content.find(name).unwrap_or_else(|| panic!("{} missing", name)))
+        .collect();
+    let mut sorted = order.clone();
+    sorted.sort_unstable();
+    assert_eq!(order, sorted, "books are not ordered best first");
+    // Nobody rated Tolstoy or Galileo.
+    assert!(!content.contains("Толстой"));
+}
+
+#[test]
+async fn every_star_level_is_a_shelf() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+
+    let ratings = body_of(&app, "/library/ratings", &credentials).await;
+    assert_eq!(count_items(&ratings), 4);
+    assert!(ratings.contains("<title>★★★½</title>"));
+    assert!(ratings.contains("<id>urn:orca:library:rating:7</id>"));
+
+    let four = body_of(&app, "/library/ratings/8", &credentials).await;
+    assert!(four.contains("<title>library | ★★★★</title>"));
+    assert_eq!(count_items(&four), 2);
+}
+
 // A feed of books is an acquisition feed; only a feed of other feeds is a
 // navigation feed. Clients use `kind` to decide which of the two to render.
 #[test]
@@ -211,6 +310,12 @@ async fn feeds_of_books_are_advertised_as_acquisition_feeds() {
         ("/library", "/library/new"),
         ("/library/authors", "/library/authors/5"),
         ("/library/tags", "/library/tags/5"),
+        ("/library/series", "/library/series/1"),
+        ("/library/publishers", "/library/publishers/4"),
+        ("/library/languages", "/library/languages/3"),
+        ("/library/columns/shelf", "/library/columns/shelf/2"),
+        ("/library", "/library/top"),
+        ("/library/ratings", "/library/ratings/8"),
     ] {
         let req = test::TestRequest::with_uri(path)
             .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
@@ -237,6 +342,36 @@ async fn feeds_of_books_are_advertised_as_acquisition_feeds() {
     }
 }
 
+// Calibre's notes on Kant, on science fiction and on Astounding Stories.
+#[test]
+async fn a_note_in_calibre_describes_its_author() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+
+    let authors = body_of(&app, "/library/authors", &credentials).await;
+    assert!(authors.contains(r#"<content type="html">&lt;div&gt;&lt;p&gt;&lt;img src=&quot;/library/resource/xxh64/af24ccfc732341f3&quot;"#), "{}", authors);
+    assert!(authors.contains("Books by Lewis Carroll"));
+
+    let kant = body_of(&app, "/library/authors/5", &credentials).await;
+    assert!(kant.contains(r#"<subtitle type="html">"#), "{}", kant);
+    assert!(kant.contains("Königsberg"));
+    assert!(!body_of(&app, "/library/authors/4", &credentials).await.contains("<subtitle"));
+
+    for (list, shelf) in [("/library/tags", "/library/tags/9"), ("/library/series", "/library/series/1")] {
+        assert!(body_of(&app, list, &credentials).await.contains(r#"<content type="html">"#), "{}", list);
+        assert!(body_of(&app, shelf, &credentials).await.contains(r#"<subtitle type="html">"#), "{}", shelf);
+    }
+
+    let req = test::TestRequest::with_uri("/library/resource/xxh64/af24ccfc732341f3")
+        .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), "image/png");
+    let portrait = image::load_from_memory(&test::read_body(resp).await).expect("a png");
+    assert_eq!((portrait.width(), portrait.height()), (16, 24));
+}
+
 // The http config has no [catalog] section at all.
 #[test]
 async fn an_unsigned_catalog_names_no_one() {
@@ -466,6 +601,82 @@ async fn mime_types_are_not_escaped() {
     assert_eq!(count_items(&content), 1);
 }
 
+// Far more books than fit on one page, in a feed the template has to link
+// both ways from the middle.
+#[test]
+async fn a_page_links_to_the_pages_around_it() {
+    let state = create_app(&TEST_HTTP_CONFIG).expect("Failed to create app");
+    let mut context = book_feed_context(state.config, serde_json::json!([]));
+    context.insert("paging", &serde_json::json!({
+        "total": 40000,
+        "per_page": 50,
+        "start": 101,
+        "kind": "acquisition",
+        "links": [
+            {"rel": "first", "href": "http://localhost:8888/library/books"},
+            {"rel": "previous", "href": "http://localhost:8888/library/books?page=2"},
+            {"rel": "next", "href": "http://localhost:8888/library/books?page=4"},
+            {"rel": "last", "href": "http://localhost:8888/library/books?page=800"}
+        ]
+    }));
+
+    let content = state
+        .templates
+        .render("books.xml.tera", &context)
+        .expect("Failed to render books template");
+
+    assert!(content.contains("<opensearch:totalResults>40000</opensearch:totalResults>"));
+    assert!(content.contains("<opensearch:itemsPerPage>50</opensearch:itemsPerPage>"));
+    assert!(content.contains("<opensearch:startIndex>101</opensearch:startIndex>"));
+    assert!(content.contains(r#"<link rel="next" href="http://localhost:8888/library/books?page=4" type="application/atom+xml;profile=opds-catalog;kind=acquisition" />"#));
+    assert!(content.contains(r#"<link rel="first" href="http://localhost:8888/library/books" "#));
+}
+
+// Seven books fit on one page of fifty: there is no other page to link to.
+#[test]
+async fn atom_feeds_say_how_many_pages_they_are_one_of() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+
+    for (path, total) in [
+        ("/library/books", 7),
+        ("/library/authors", 8),
+        ("/library/tags", 7),
+        ("/library/tags/9", 4),
+        ("/library/series/1", 3),
+        ("/library/top", 5),
+        ("/library/columns/shelf/2", 2),
+        ("/library/series", 1),
+        ("/library/publishers", 3),
+        ("/library/languages", 3),
+        ("/library/ratings", 4),
+        ("/library/columns/shelf", 2),
+    ] {
+        let content = body_of(&app, path, &credentials).await;
+        assert!(
+            content.contains(&format!("<opensearch:totalResults>{}</opensearch:totalResults>", total)),
+            "{} should hold {} in all",
+            path,
+            total
+        );
+        assert!(content.contains("<opensearch:itemsPerPage>50</opensearch:itemsPerPage>"), "{}", path);
+        assert!(!content.contains(r#"rel="next""#), "{} has no next page", path);
+        assert_eq!(count_items(&content), total, "{}", path);
+    }
+}
+
+// A page past the end is the last page, and says so in its self link.
+#[test]
+async fn an_atom_page_past_the_end_is_the_last_page() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+    let content = body_of(&app, "/library/books?page=3", &credentials).await;
+
+    assert_eq!(count_items(&content), 7);
+    assert!(content.contains(r#"<link rel="self" href="http://localhost:8080/library/books" "#));
+    assert!(content.contains("<opensearch:startIndex>1</opensearch:startIndex>"));
+}
+
 #[test]
 async fn list_authors() {
     let app = setup(Http).await;
@@ -530,6 +741,83 @@ async fn list_books_tagged_fiction() {
     assert_eq!(count_items(&content), 2);
 }
 
+// Volume by volume, not title by title: "At the Mountains of Madness" is the
+// second of the three, though it sorts first.
+#[test]
+async fn list_books_in_a_series_in_reading_order() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+
+    let series = body_of(&app, "/library/series", &credentials).await;
+    assert_eq!(count_items(&series), 1);
+    assert!(series.contains("<title>Astounding Stories</title>"));
+
+    let content = body_of(&app, "/library/series/1", &credentials).await;
+    assert!(content.contains("<title>library | Astounding Stories</title>"));
+    assert_eq!(count_items(&content), 3);
+
+    let order: Vec<usize> = ["Vampires of Space", "At the Mountains of Madness", "Galactic Patrol"]
+        .iter()
+        .map(|title| content.find(title).unwrap_or_else(|| panic!("{} missing", title)))
+        .collect();
+    let mut sorted = order.clone();
+    sorted.sort_unstable();
+    assert_eq!(order, sorted, "volumes are not in series order");
+}
+
+#[test]
+async fn list_books_by_publisher_and_by_language() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+
+    let publishers = body_of(&app, "/library/publishers", &credentials).await;
+    assert_eq!(count_items(&publishers), 3);
+    assert!(publishers.contains("<title>Street &amp; Smith</title>"));
+    assert_eq!(count_items(&body_of(&app, "/library/publishers/4", &credentials).await), 3);
+
+    let languages = body_of(&app, "/library/languages", &credentials).await;
+    assert_eq!(count_items(&languages), 3);
+    assert!(languages.contains("<title>Deutsch</title>"));
+    let deutsch = body_of(&app, "/library/languages/3", &credentials).await;
+    assert!(deutsch.contains("<title>library | Deutsch</title>"));
+    assert_eq!(count_items(&deutsch), 1);
+}
+
+// What Calibre shows under "Shelf location", a reader sees in the entry.
+#[test]
+async fn custom_columns_are_part_of_an_entry() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+
+    let alice = body_of(&app, "/library/authors/4", &credentials).await;
+    assert!(alice.contains("Read status: Yes\n"), "{}", alice);
+    assert!(alice.contains("Shelf location: Living room\n"));
+    assert!(alice.contains("Review: Curiouser &amp; curiouser!\n"));
+
+    // Left out of the config, left out of the feed.
+    let kant = body_of(&app, "/library/languages/3", &credentials).await;
+    assert!(!kant.contains("Stars:"));
+}
+
+#[test]
+async fn list_books_by_custom_column() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+
+    let library = body_of(&app, "/library", &credentials).await;
+    assert!(library.contains("<title>Shelf location</title>"));
+    // Nothing to browse in a yes or a no.
+    assert!(!library.contains("<title>Read status</title>"));
+
+    let locations = body_of(&app, "/library/columns/shelf", &credentials).await;
+    assert_eq!(count_items(&locations), 2);
+    assert!(locations.contains("<title>Study</title>"));
+
+    let study = body_of(&app, "/library/columns/shelf/2", &credentials).await;
+    assert!(study.contains("<title>library | Shelf location: Study</title>"));
+    assert_eq!(count_items(&study), 2);
+}
+
 #[test]
 async fn download_cover() {
     let app = setup(Http).await;
@@ -542,6 +830,26 @@ async fn download_cover() {
     assert_eq!(resp.headers().get("content-type").unwrap(), "image/jpeg");
 }
 
+// A client asking for 300 pixels gets the next width up, cut to the shape of a book.
+#[test]
+async fn download_thumbnail() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+    let req = test::TestRequest::with_uri("/library/thumb/5?w=300")
+        .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/jpeg");
+
+    let body = test::read_body(resp).await;
+    let thumbnail = image::load_from_memory(&body).expect("a jpeg");
+    assert_eq!((thumbnail.width(), thumbnail.height()), (480, 720));
+
+    let feed = body_of(&app, "/library/authors/5", &credentials).await;
+    assert!(feed.contains(r#"<link href="/library/thumb/5" type="image/jpeg" rel="http://opds-spec.org/image/thumbnail"/>"#));
+}
+
 #[test]
 async fn download_epub() {
     let app = setup(Http).await;
@@ -554,6 +862,138 @@ async fn download_epub() {
     assert_eq!(resp.headers().get("content-type").unwrap(), "application/epub+zip");
 }
 
+// A client that still has the current feed gets a 304 and no feed.
+#[test]
+async fn a_feed_is_not_sent_again_while_the_library_is_unchanged() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+    let get = |uri: &str, condition: Option<(header::HeaderName, String)>| {
+        let mut req = test::TestRequest::with_uri(uri)
+            .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)));
+        if let Some(condition) = condition {
+            req = req.insert_header(condition);
+        }
+        req.to_request()
+    };
+
+    let resp = test::call_service(&app, get("/library/books", None)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get(header::CACHE_CONTROL).unwrap(), "private, no-cache");
+    let etag = resp.headers().get(header::ETAG).expect("an etag").to_str().unwrap().to_string();
+    let modified = resp.headers().get(header::LAST_MODIFIED).expect("a date").to_str().unwrap().to_string();
+    assert!(etag.starts_with('"'), "a strong tag: {}", etag);
+
+    let resp = test::call_service(&app, get("/library/books", Some((header::IF_NONE_MATCH, etag.clone())))).await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(resp.headers().get(header::ETAG).unwrap(), etag.as_str());
+    assert!(test::read_body(resp).await.is_empty());
+
+    let resp = test::call_service(&app, get("/library/books", Some((header::IF_MODIFIED_SINCE, modified)))).await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+    // Another tag, another page or another query is another feed.
+    let resp = test::call_service(&app, get("/library/books", Some((header::IF_NONE_MATCH, r#""0""#.to_string())))).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    for other in ["/library/books?page=2", "/library/books?sort=rating", "/library/tags"] {
+        let resp = test::call_service(&app, get(other, Some((header::IF_NONE_MATCH, etag.clone())))).await;
+        assert_eq!(resp.status(), StatusCode::OK, "{}", other);
+        assert_ne!(resp.headers().get(header::ETAG).unwrap(), etag.as_str(), "{}", other);
+    }
+}
+
+// A client that takes gzip gets the feed in gzip; its tag still matches.
+#[test]
+async fn a_feed_is_compressed_for_a_client_that_accepts_it() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+    let get = |uri: &str, encoding: &str| {
+        test::TestRequest::with_uri(uri)
+            .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
+            .insert_header((header::ACCEPT_ENCODING, encoding.to_string()))
+            .to_request()
+    };
+
+    let plain = test::call_service(&app, get("/library/books", "identity")).await;
+    assert!(plain.headers().get(header::CONTENT_ENCODING).is_none());
+    assert_eq!(plain.headers().get(header::VARY).unwrap(), "accept-encoding");
+    let plain = test::read_body(plain).await;
+
+    for (accepted, sent) in [("gzip", "gzip"), ("br;q=1.0, gzip;q=0.5", "br"), ("zstd", "zstd")] {
+        let resp = test::call_service(&app, get("/library/books", accepted)).await;
+        assert_eq!(resp.headers().get(header::CONTENT_ENCODING).unwrap(), sent);
+        let etag = resp.headers().get(header::ETAG).unwrap().to_str().unwrap().to_string();
+        assert!(etag.starts_with("W/"), "{}", etag);
+        let body = test::read_body(resp).await;
+        assert!(body.len() < plain.len() / 2, "{}: {} of {} bytes", sent, body.len(), plain.len());
+
+        let req = test::TestRequest::with_uri("/library/books")
+            .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
+            .insert_header((header::ACCEPT_ENCODING, accepted.to_string()))
+            .insert_header((header::IF_NONE_MATCH, etag))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_MODIFIED);
+    }
+}
+
+// Books and covers are compressed already, and a short description of the
+// search is not worth compressing.
+#[test]
+async fn files_are_sent_as_they_are() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+    for uri in ["/library/file/5/epub", "/library/cover/5", "/library/thumb/5", "/library/opensearch.xml"] {
+        let req = test::TestRequest::with_uri(uri)
+            .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
+            .insert_header((header::ACCEPT_ENCODING, "gzip, br"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success(), "{}", uri);
+        assert!(resp.headers().get(header::CONTENT_ENCODING).is_none_or(|encoding| encoding == "identity"), "{}", uri);
+    }
+}
+
+// Without credentials the tag of a feed is worth nothing.
+#[test]
+async fn a_conditional_request_still_has_to_log_in() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+    let req = test::TestRequest::with_uri("/library")
+        .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let etag = resp.headers().get(header::ETAG).unwrap().clone();
+
+    let req = test::TestRequest::with_uri("/library")
+        .insert_header((header::IF_NONE_MATCH, etag))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(resp.headers().get(header::ETAG).is_none());
+}
+
+#[test]
+async fn covers_and_books_are_validated_too() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+    for uri in ["/library/cover/5", "/library/thumb/5", "/library/file/5/epub"] {
+        let req = test::TestRequest::with_uri(uri)
+            .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK, "{}", uri);
+        assert_eq!(resp.headers().get(header::CACHE_CONTROL).unwrap(), "private, max-age=86400", "{}", uri);
+        let etag = resp.headers().get(header::ETAG).expect("an etag").clone();
+
+        let req = test::TestRequest::with_uri(uri)
+            .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
+            .insert_header((header::IF_NONE_MATCH, etag))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED, "{}", uri);
+        assert_eq!(resp.headers().get(header::CACHE_CONTROL).unwrap(), "private, max-age=86400", "{}", uri);
+    }
+}
+
 #[test]
 async fn download_mobi() {
     let app = setup(Http).await;
@@ -575,7 +1015,7 @@ async fn missing_ids_return_404_and_leave_the_library_usable() {
     let credentials = BASE64.encode("alice:secretpassword");
     let auth = || (header::AUTHORIZATION, format!("Basic {}", credentials));
 
-    for uri in ["/library/cover/99999", "/library/file/99999/epub"] {
+    for uri in ["/library/cover/99999", "/library/thumb/99999", "/library/file/99999/epub", "/library/resource/xxh64/0000000000000000"] {
         let req = test::TestRequest::with_uri(uri).insert_header(auth()).to_request();
         let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{} should be 404", uri);
@@ -592,7 +1032,9 @@ async fn a_shelf_that_is_not_there_is_404() {
     let app = setup(Http).await;
     let credentials = BASE64.encode("alice:secretpassword");
 
-    for uri in ["/library/tags/99999", "/library/authors/99999"] {
+    for uri in ["/library/tags/99999", "/library/authors/99999", "/library/series/99999",
+                "/library/publishers/99999", "/library/languages/99999",
+                "/library/columns/shelf/99999", "/library/columns/stars", "/library/ratings/5"] {
         let req = test::TestRequest::with_uri(uri)
             .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
             .to_request();
@@ -609,6 +1051,11 @@ async fn unknown_library_is_not_found() {
 
     for uri in ["/nosuchlib", "/nosuchlib/books", "/nosuchlib/tags",
                 "/nosuchlib/authors", "/nosuchlib/tags/5", "/nosuchlib/authors/5",
+                "/nosuchlib/series", "/nosuchlib/series/1",
+                "/nosuchlib/publishers", "/nosuchlib/languages/3",
+                "/nosuchlib/columns/shelf", "/nosuchlib/columns/shelf/2",
+                "/nosuchlib/top", "/nosuchlib/ratings", "/nosuchlib/ratings/8",
+                "/nosuchlib/opensearch.xml", "/nosuchlib/search?q=kant",
                 "/nosuchlib/cover/5", "/nosuchlib/file/5/epub"] {
         let req = test::TestRequest::with_uri(uri)
             .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
@@ -618,6 +1065,178 @@ async fn unknown_library_is_not_found() {
     }
 }
 
+// ------- OpenSearch -------
+
+// A reader that speaks only OPDS 1.2 finds the search through the feed it is on.
+#[test]
+async fn every_feed_of_a_library_links_to_its_search() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+
+    for path in ["/library", "/library/books", "/library/authors", "/library/tags/9", "/library/ratings"] {
+        let content = body_of(&app, path, &credentials).await;
+        assert!(
+            content.contains(r#"<link rel="search" href="http://localhost:8080/library/opensearch.xml" type="application/opensearchdescription+xml" />"#),
+            "{} should link to its search",
+            path
+        );
+    }
+    // The index spans libraries; there is no one library to search.
+    let https = setup(Https).await;
+    let index = body_of(&https, "/", &credentials).await;
+    assert!(!index.contains(r#"rel="search""#));
+}
+
+#[test]
+async fn the_search_describes_itself() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+    let req = test::TestRequest::with_uri("/library/opensearch.xml")
+        .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+
+    assert!(resp.status().is_success());
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/opensearchdescription+xml"
+    );
+    let body = test::read_body(resp).await;
+    let content = String::from_utf8(body.to_vec()).expect("Failed to convert to String");
+    assert!(content.contains(r#"template="http://localhost:8080/library/search?q={searchTerms}""#));
+    assert!(content.contains("<ShortName>library</ShortName>"));
+}
+
+#[test]
+async fn a_search_finds_books_by_title_and_by_author() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+
+    let by_title = body_of(&app, "/library/search?q=wonderland", &credentials).await;
+    assert!(by_title.contains("<title>library | Search: wonderland</title>"));
+    assert!(by_title.contains("<opensearch:totalResults>1</opensearch:totalResults>"));
+    assert!(by_title.contains("Adventures in Wonderland"));
+
+    let by_author = body_of(&app, "/library/search?q=lovecraft", &credentials).await;
+    assert_eq!(count_items(&by_author), 1);
+    assert!(by_author.contains("At the Mountains of Madness"));
+}
+
+#[test]
+async fn an_atom_search_understands_calibres_search_language() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+    let content = body_of(&app, "/library/search?q=language%3Adeutsch%20or%20format%3Amobi", &credentials).await;
+
+    assert_eq!(count_items(&content), 2);
+    assert!(content.contains("Kritik der reinen Vernunft"));
+    assert!(content.contains("The sidereal messenger of Galileo Galilei"));
+}
+
+// Calibre's virtual libraries are listed with the library, Poetry not: it holds no books.
+#[test]
+async fn virtual_libraries_are_atom_feeds_of_their_own() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+
+    let root = body_of(&app, "/library", &credentials).await;
+    assert!(root.contains(r#"<link href="/library/virtual/Kids" "#));
+    assert!(root.contains(r#"<link href="/library/virtual/To%20Read" "#));
+    assert!(!root.contains("Poetry"));
+
+    let reference = body_of(&app, "/library/virtual/Reference", &credentials).await;
+    assert!(reference.contains("<title>library | Reference</title>"));
+    assert_eq!(count_items(&reference), 2);
+    assert!(reference.contains("Kritik der reinen Vernunft"));
+}
+
+// Saved searches and user categories are listed with the library, and each one
+// leads on the same way it does below `/v2`.
+#[test]
+async fn saved_searches_and_user_categories_are_atom_feeds() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+
+    let root = body_of(&app, "/library", &credentials).await;
+    assert!(root.contains(r#"<link href="/library/searches" "#));
+    assert!(root.contains(r#"<link href="/library/categories" "#));
+
+    let searches = body_of(&app, "/library/searches", &credentials).await;
+    assert!(searches.contains("<title>library | Saved Searches</title>"));
+    assert_eq!(count_items(&searches), 2);
+    assert!(searches.contains(r#"<link href="/library/searches/Horror" "#));
+    assert!(!searches.contains("Cookbooks"));
+
+    let horror = body_of(&app, "/library/searches/Horror", &credentials).await;
+    assert_eq!(count_items(&horror), 1);
+    assert!(horror.contains("At the Mountains of Madness"));
+
+    let favourites = body_of(&app, "/library/categories/Favourites", &credentials).await;
+    assert_eq!(count_items(&favourites), 3);
+    // The same entry the feed of all authors has for him.
+    assert!(favourites.contains("<id>urn:orca:library:author:9</id>"));
+    assert!(favourites.contains(r#"<link href="/library/authors/9" "#));
+}
+
+// The passage the quote comes from stands in for the synopsis.
+#[test]
+async fn an_atom_search_finds_a_quote_inside_a_book() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+    let content = body_of(&app, "/library/search?q=sixty%20semi-diameters&in=content", &credentials).await;
+
+    assert_eq!(count_items(&content), 1);
+    assert!(content.contains("<title>library | Search the text: sixty semi-diameters</title>"));
+    assert!(content.contains("**sixty semi-diameters**"), "{}", content);
+    assert!(content.contains(
+        r#"<link rel="self" href="http://localhost:8080/library/search?q=sixty%20semi%2Ddiameters&amp;in=content" "#
+    ));
+}
+
+// The term stays in the address of every page, escaped. One book is one page,
+// so page two is page one.
+#[test]
+async fn an_atom_search_pages_under_its_own_address() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+    let content = body_of(&app, "/library/search?q=of%20madness&page=2", &credentials).await;
+
+    assert!(content.contains(r#"<link rel="self" href="http://localhost:8080/library/search?q=of%20madness" "#));
+    assert_eq!(count_items(&content), 1);
+}
+
+// The facets of OPDS 1.2: the one in use is marked, and each keeps the others.
+#[test]
+async fn an_atom_feed_can_be_sorted_and_narrowed_down() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+    let content = body_of(&app, "/library/books?sort=added&format=mobi", &credentials).await;
+
+    assert_eq!(count_items(&content), 1);
+    assert!(content.contains(
+        r#"<link rel="self" href="http://localhost:8080/library/books?sort=added&amp;format=MOBI" "#
+    ), "{}", content);
+    assert!(content.contains(concat!(
+        r#"<link rel="http://opds-spec.org/facet" href="http://localhost:8080/library/books?sort=added&amp;format=MOBI" "#,
+        r#"title="Newest added" opds:facetGroup="Sort by" opds:activeFacet="true" "#
+    )));
+    assert!(content.contains(
+        r#"<link rel="http://opds-spec.org/facet" href="http://localhost:8080/library/books?sort=added" title="All formats" opds:facetGroup="Format" "#
+    ));
+}
+
+#[test]
+async fn an_empty_atom_search_finds_nothing() {
+    let app = setup(Http).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+
+    for path in ["/library/search", "/library/search?q=", "/library/search?q=nothing%20like%20this"] {
+        let content = body_of(&app, path, &credentials).await;
+        assert_eq!(count_items(&content), 0, "{}", path);
+        assert!(content.contains("<opensearch:totalResults>0</opensearch:totalResults>"), "{}", path);
+    }
+}
+
 // One acquisition link per format
 #[test]
 async fn each_format_is_offered_exactly_once() {
@@ -760,6 +1379,33 @@ async fn single_library_redirects_to_it() {
     assert_eq!(resp.headers().get(header::LOCATION).unwrap(), "/library");
 }
 
+// library2 lists no more than three of anything before it offers their first letters.
+#[test]
+async fn long_atom_lists_start_with_an_index_of_first_letters() {
+    let app = setup(Https).await;
+    let credentials = BASE64.encode("alice:secretpassword");
+
+    let root = body_of(&app, "/library2", &credentials).await;
+    assert!(root.contains(r#"<link href="/library2/titles" type="application/atom+xml;profile=opds-catalog;kind=navigation"/>"#));
+
+    let titles = body_of(&app, "/library2/titles", &credentials).await;
+    assert_eq!(count_items(&titles), 6);
+    assert!(titles.contains(r#"<link href="/library2/titles/A" "#));
+    assert!(titles.contains("<id>urn:orca:library2:titles:A</id>"));
+    assert_eq!(count_items(&body_of(&app, "/library2/titles/A", &credentials).await), 2);
+
+    let authors = body_of(&app, "/library2/authors", &credentials).await;
+    assert_eq!(count_items(&authors), 7);
+    assert!(authors.contains(r#"<link href="/library2/authors?letter=K" "#));
+    let k = body_of(&app, "/library2/authors?letter=K", &credentials).await;
+    assert_eq!(count_items(&k), 2);
+    assert!(k.contains(r#"<link rel="self" href="http://localhost:8080/library2/authors?letter=K" "#), "{}", k);
+
+    let s = body_of(&app, "/library2/tags?letter=S", &credentials).await;
+    assert_eq!(count_items(&s), 2);
+    assert!(s.contains("science fiction"));
+}
+
 // ------- Https Tests -------
 
 // The https config registers tests/calibre twice, so the root is a real
@@ -0,0 +1,244 @@
+//! Where readers are in their books, by Calibre's viewer and by KOReader
+//!
+//! Every test reads from a copy of the test library of its own: Calibre's
+//! positions are written into it, and with `write_positions` Orca writes too.
+
+mod common;
+
+use actix_http::Request;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::test;
+use common::{call_as, copy, json, koreader, text, ALICE};
+use orca::config::{read_config, Config};
+use orca::progress::partial_md5;
+use rusqlite::{params, Connection};
+use serde_json::{json, Value};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+const KANT: &str = "Immanuel Kant/Kritik der reinen Vernunft - 2. Auflage (5)/Kritik der reinen Vernunft - 2. Auflage - Immanuel Kant.epub";
+const ALICE_IN_WONDERLAND: &str =
+    "Lewis Carroll/Alice's Adventures in Wonderland (4)/Alice's Adventures in Wonderland - Lewis Carroll.epub";
+
+#[test]
+async fn koreader_and_calibre_s_viewer_fill_one_shelf() {
+    let libraries = Libraries::new();
+    let app = libraries.serve().await;
+
+    let before = call_as(&app, "/v2/library/reading", "alice").await;
+    let tag = before.headers().get(header::ETAG).cloned().expect("a tag");
+    assert_eq!(titles(&json(before).await["publications"]), ["Kritik der reinen Vernunft - 2. Auflage"]);
+
+    sync(&app, &libraries.digest(ALICE_IN_WONDERLAND), "/body/DocFragment[3]/body/p[1]/text().0", 0.25).await;
+
+    let after = call_as(&app, "/v2/library/reading", "alice").await;
+    assert_ne!(after.headers().get(header::ETAG), Some(&tag), "a sync changes the shelf");
+    let reading = json(after).await;
+    let publications = &reading["publications"];
+    assert_eq!(titles(publications), ["Alice's Adventures in Wonderland", "Kritik der reinen Vernunft - 2. Auflage"]);
+    assert_eq!(publications[0]["metadata"]["totalProgression"], 0.25);
+    assert_eq!(publications[1]["metadata"]["totalProgression"], 0.3);
+
+    // Galileo is finished: he says how far, but is no longer being read.
+    let galileo = json(call_as(&app, "/v2/library/book/6", "alice").await).await;
+    assert_eq!(galileo["metadata"]["totalProgression"], 1.0);
+
+    let root = json(call_as(&app, "/v2/library", "alice").await).await;
+    let shelf = root["navigation"].as_array().unwrap().iter().find(|link| link["title"] == "Currently Reading");
+    assert_eq!(shelf.expect("a way to the shelf")["properties"]["numberOfItems"], 2);
+}
+
+// Bob reads in the content server, where nobody has opened a book.
+#[test]
+async fn nobody_else_is_reading_what_alice_is() {
+    let libraries = Libraries::new();
+    let app = libraries.serve().await;
+
+    let root = json(call_as(&app, "/v2/library", "bob").await).await;
+    assert!(root["navigation"].as_array().unwrap().iter().all(|link| link["title"] != "Currently Reading"));
+    let reading = json(call_as(&app, "/v2/library/reading", "bob").await).await;
+    assert!(reading["publications"].as_array().is_none_or(Vec::is_empty), "{}", reading);
+    let kant = json(call_as(&app, "/v2/library/book/5", "bob").await).await;
+    assert_eq!(kant["metadata"].get("totalProgression"), None);
+}
+
+// Alice finished the first volume of the series; Bob has not opened it.
+#[test]
+async fn the_next_volume_is_for_whoever_read_the_last_one() {
+    let libraries = Libraries::new();
+    Connection::open(libraries.dir.path().join("library").join("metadata.db"))
+        .unwrap()
+        .execute(
+            "INSERT INTO last_read_positions (book, format, user, device, cfi, epoch, pos_frac)
+             VALUES (7, 'EPUB', '_', '_', 'epubcfi(/30/4/2/1:0)', 1710000000, 1.0);",
+            [],
+        )
+        .unwrap();
+    let app = libraries.serve().await;
+
+    let next_row = |root: &Value| {
+        root["groups"].as_array().unwrap().iter().find(|group| group["metadata"]["title"] == "Next in Series").cloned()
+    };
+    let root = json(call_as(&app, "/v2/library", "alice").await).await;
+    let row = next_row(&root).expect("a row for alice");
+    assert_eq!(row["links"][0]["href"], "http://localhost:8080/v2/library/next");
+    assert_eq!(titles(&row["publications"]), ["At the Mountains of Madness"]);
+    let next = json(call_as(&app, "/v2/library/next", "alice").await).await;
+    assert_eq!(titles(&next["publications"]), ["At the Mountains of Madness"]);
+
+    assert!(next_row(&json(call_as(&app, "/v2/library", "bob").await).await).is_none());
+}
+
+#[test]
+async fn the_atom_catalog_says_how_far_the_reader_got() {
+    let libraries = Libraries::new();
+    let app = libraries.serve().await;
+
+    let root = text(call_as(&app, "/library", "alice").await).await;
+    assert!(root.contains(r#"<link href="/library/reading""#), "{}", root);
+
+    let reading = text(call_as(&app, "/library/reading", "alice").await).await;
+    assert!(reading.contains("Read: 30%"), "{}", reading);
+    assert_eq!(reading.matches("<entry>").count(), 1, "Galileo is finished: {}", reading);
+}
+
+#[test]
+async fn koreader_picks_up_where_calibre_s_viewer_left_off() {
+    let libraries = Libraries::new();
+    let app = libraries.serve().await;
+    let kant = libraries.digest(KANT);
+
+    let viewed = json(progress(&app, &kant).await).await;
+    assert_eq!(viewed["document"], kant.as_str());
+    assert_eq!(viewed["progress"], "/body/DocFragment[5]");
+    assert_eq!(viewed["percentage"], 0.3);
+    assert_eq!(viewed["device"], "Calibre");
+
+    // Read on since, on the tablet.
+    sync(&app, &kant, "/body/DocFragment[7]/body/p[2]/text().4", 0.4).await;
+    let synced = json(progress(&app, &kant).await).await;
+    assert_eq!(synced["progress"], "/body/DocFragment[7]/body/p[2]/text().4");
+    assert_eq!(synced["device"], "Kobo");
+}
+
+#[test]
+async fn only_a_library_that_asks_for_it_is_written_to() {
+    let libraries = Libraries::new();
+    let app = libraries.serve().await;
+    sync(&app, &libraries.digest(ALICE_IN_WONDERLAND), "/body/DocFragment[3]/body/p[1]/text().0", 0.25).await;
+
+    let written = libraries.positions("library", 4);
+    assert_eq!(written, [("_".to_string(), "orca".to_string(), "EPUB".to_string(), "epubcfi(/6/4)".to_string(), 0.25)]);
+    assert_eq!(libraries.positions("untouched", 4), []);
+
+    // A page number is no place in an EPUB.
+    sync(&app, &libraries.digest(ALICE_IN_WONDERLAND), "42", 0.5).await;
+    assert_eq!(libraries.positions("library", 4)[0].3, "epubcfi(/6/4)");
+}
+
+// Cut down to a virtual library, the library is still Calibre's to write to.
+#[test]
+async fn a_restricted_library_is_written_to_as_well() {
+    let libraries = Libraries::with("virtual_library = \"Kids\"\n");
+    let app = libraries.serve().await;
+    sync(&app, &libraries.digest(ALICE_IN_WONDERLAND), "/body/DocFragment[3]/body/p[1]/text().0", 0.25).await;
+
+    let written = libraries.positions("library", 4);
+    assert_eq!(written, [("_".to_string(), "orca".to_string(), "EPUB".to_string(), "epubcfi(/6/4)".to_string(), 0.25)]);
+}
+
+// ------- Helper Functions -------
+
+/// Two copies of the test library: one Orca may write positions to, one it may not.
+struct Libraries {
+    dir: TempDir,
+    config: &'static Config,
+}
+
+impl Libraries {
+    fn new() -> Libraries {
+        Libraries::with("")
+    }
+
+    /// `more`: more of the config of the library Orca writes to.
+    fn with(more: &str) -> Libraries {
+        let dir = TempDir::new().unwrap();
+        for name in ["library", "untouched"] {
+            copy(Path::new("tests/calibre"), &dir.path().join(name));
+            // Alice opened Kant on the desktop, and finished Galileo.
+            let db = Connection::open(dir.path().join(name).join("metadata.db")).unwrap();
+            db.execute_batch(
+                "INSERT INTO last_read_positions (book, format, user, device, cfi, epoch, pos_frac)
+                 VALUES (5, 'EPUB', '_', '_', 'epubcfi(/10/4/2/1:0)', 1700000000, 0.3),
+                        (6, 'EPUB', '_', '_', 'epubcfi(/22/4/2/1:0)', 1690000000, 1.0);",
+            )
+            .unwrap();
+        }
+
+        let settings = fs::read_to_string("tests/orca.http.test.toml").unwrap();
+        let mut settings = settings[..settings.find("[calibre.libraries.library]").unwrap()].to_string();
+        for (name, writes) in [("library", true), ("untouched", false)] {
+            settings.push_str(&format!(
+                "[calibre.libraries.{name}]\npath = {path:?}\nsearch_index = \":memory:\"\nthumbnail_cache = {cache:?}\nwrite_positions = {writes}\n",
+                path = dir.path().join(name).display().to_string(),
+                cache = dir.path().join("thumbnails").display().to_string(),
+            ));
+            if writes {
+                settings.push_str(more);
+            }
+        }
+        let path = dir.path().join("orca.toml");
+        fs::write(&path, settings).unwrap();
+        let config = Box::leak(Box::new(read_config(path.to_str().unwrap()).expect("the config")));
+        Libraries { dir, config }
+    }
+
+    async fn serve(&self) -> impl Service<Request, Response = ServiceResponse, Error = actix_web::Error> {
+        common::serve(self.config).await
+    }
+
+    /// What KOReader calls a file of the library.
+    fn digest(&self, file: &str) -> String {
+        partial_md5(fs::File::open(self.dir.path().join("library").join(file)).unwrap()).unwrap()
+    }
+
+    /// Every position a library keeps in a book: user, device, format, CFI and fraction.
+    fn positions(&self, library: &str, book: i32) -> Vec<(String, String, String, String, f64)> {
+        let db = Connection::open(self.dir.path().join(library).join("metadata.db")).unwrap();
+        let mut stmt = db
+            .prepare("SELECT user, device, format, cfi, pos_frac FROM last_read_positions WHERE book = ?1 ORDER BY id;")
+            .unwrap();
+        let rows = stmt.query_map(params![book], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)));
+        rows.unwrap().map(Result::unwrap).collect()
+    }
+}
+
+/// Alice's Kobo, telling Orca where she is.
+async fn sync(
+    app: &impl Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+    document: &str,
+    progress: &str,
+    percentage: f64,
+) {
+    let update = json!({ "document": document, "progress": progress, "percentage": percentage, "device": "Kobo" });
+    let response = koreader(app, test::TestRequest::put().uri("/kosync/syncs/progress").set_json(update), ALICE).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+async fn progress(
+    app: &impl Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+    document: &str,
+) -> ServiceResponse {
+    koreader(app, test::TestRequest::get().uri(&format!("/kosync/syncs/progress/{}", document)), ALICE).await
+}
+
+fn titles(publications: &Value) -> Vec<String> {
+    publications
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|publication| publication["metadata"]["title"].as_str().unwrap().to_string())
+        .collect()
+}